@@ -100,7 +100,7 @@ So if you could go ahead and try to remember to do that from now on, that'd be g
                     );
                     input = true.into();
                 }
-                Event::Discard => {
+                Event::Discard { .. } => {
                     println!("Discard message.");
                     input = true.into();
                 }
@@ -159,6 +159,18 @@ So if you could go ahead and try to remember to do that from now on, that'd be g
                     // Return variable result back to interpreter
                     input = Input::result("hello world".into());
                 }
+                Event::CpuWarning { used, limit } => {
+                    println!("Script executed {used} instructions, soft limit is {limit}.");
+                    input = true.into();
+                }
+                Event::InstructionSkipped {
+                    name,
+                    line_num,
+                    line_pos,
+                } => {
+                    println!("Skipped unsupported {name:?} at line {line_num}, column {line_pos}.");
+                    input = true.into();
+                }
 
                 #[cfg(test)]
                 _ => unreachable!(),
@@ -190,6 +202,12 @@ So if you could go ahead and try to remember to do that from now on, that'd be g
                     RuntimeError::CPULimitReached => {
                         eprintln!("Script exceeded the configured CPU limit.");
                     }
+                    RuntimeError::TimeLimitReached => {
+                        eprintln!("Script exceeded the configured time limit.");
+                    }
+                    RuntimeError::EventLimitReached => {
+                        eprintln!("Script exceeded the configured event limit.");
+                    }
                 }
                 input = true.into();
             }
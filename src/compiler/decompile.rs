@@ -0,0 +1,1579 @@
+/*
+ * Copyright (c) 2020-2023, Stalwart Labs Ltd.
+ *
+ * This file is part of the Stalwart Sieve Interpreter.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ * in the LICENSE file at the top-level directory of this distribution.
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ * You can be released from the requirements of the AGPLv3 license by
+ * purchasing a commercial license. Please contact licensing@stalw.art
+ * for more details.
+*/
+
+use std::ops::Range;
+
+use ahash::AHashMap;
+
+use crate::{
+    runtime::actions::action_notify::validate_uri, Compiler, Envelope, FileCarbonCopy, Metadata,
+    Sieve,
+};
+
+use super::{
+    grammar::{
+        actions::{
+            action_flags::Action,
+            action_include::Location,
+            action_mime::MimeOpts,
+            action_redirect::{ByMode, ByTime, Notify as DsnNotify, NotifyItem, Ret},
+            action_set::{Modifier, UrlProfile},
+            action_vacation::{Period, TestVacation, Vacation},
+        },
+        expr::{BinaryOperator, Constant, Expression, UnaryOperator},
+        instruction::Instruction,
+        test::Test,
+        tests::{
+            test_body::BodyTransform,
+            test_date::{DatePart, Zone},
+            test_duplicate::DupMatch,
+        },
+        AddressPart, Comparator, MatchType, RelationalMatch,
+    },
+    ContentTypePart, HeaderPart, HeaderVariable, MessagePart, ReceivedHostname, ReceivedPart,
+    Value, VariableType,
+};
+
+impl Sieve {
+    /// Turns the compiled script back into Sieve source. Comments and the
+    /// original formatting are lost, local variables are renamed and calls
+    /// to external functions are written using their numeric id; use
+    /// [`Compiler::decompile`] to recover the function names.
+    ///
+    /// The output always uses the same layout, with all capabilities in a
+    /// single `require` and four-space indentation, so it can also be used
+    /// to normalize scripts.
+    #[doc(alias = "to_source")]
+    pub fn to_script(&self) -> String {
+        Decompiler::new(self, AHashMap::new()).decompile()
+    }
+}
+
+impl Compiler {
+    /// Turns a script compiled with this compiler back into Sieve source,
+    /// resolving expression function names from the registered functions.
+    pub fn decompile(&self, sieve: &Sieve) -> String {
+        Decompiler::new(
+            sieve,
+            self.functions
+                .iter()
+                .map(|(name, signature)| (signature.id, name.as_str()))
+                .collect(),
+        )
+        .decompile()
+    }
+}
+
+// References to undefined variables expand to nothing.
+const UNDEFINED_VAR: &str = "${_}";
+
+struct Decompiler<'x> {
+    sieve: &'x Sieve,
+    instructions: &'x [Instruction],
+    functions: AHashMap<u32, &'x str>,
+    block_vars: Vec<Range<usize>>,
+    loops: Vec<Loop>,
+    depth: usize,
+    out: String,
+}
+
+struct Loop {
+    start: usize,
+    is_foreverypart: bool,
+    header_end: usize,
+    label: Option<String>,
+}
+
+impl<'x> Decompiler<'x> {
+    fn new(sieve: &'x Sieve, functions: AHashMap<u32, &'x str>) -> Self {
+        Decompiler {
+            sieve,
+            instructions: &sieve.instructions,
+            functions,
+            block_vars: sieve
+                .instructions
+                .iter()
+                .filter_map(|instruction| match instruction {
+                    Instruction::Clear(clear) if clear.local_vars_num > 0 => Some(
+                        clear.local_vars_idx as usize
+                            ..(clear.local_vars_idx + clear.local_vars_num) as usize,
+                    ),
+                    _ => None,
+                })
+                .collect(),
+            loops: Vec::new(),
+            depth: 0,
+            out: String::new(),
+        }
+    }
+
+    fn decompile(mut self) -> String {
+        self.block(0, self.instructions.len());
+        self.out
+    }
+
+    fn block(&mut self, mut pos: usize, end: usize) {
+        while pos < end {
+            pos = self.statement(pos, end);
+        }
+    }
+
+    fn nested_block(&mut self, start: usize, end: usize) {
+        self.depth += 1;
+        self.block(start, end);
+        self.depth -= 1;
+    }
+
+    fn statement(&mut self, pos: usize, end: usize) -> usize {
+        let instructions = self.instructions;
+        match &instructions[pos] {
+            Instruction::Test(Test::Vacation(test))
+                if matches!(instructions.get(pos + 1), Some(Instruction::Jz(_))) =>
+            {
+                if let Some(Instruction::Vacation(vacation)) = instructions.get(pos + 2) {
+                    let line = self.vacation(test, vacation);
+                    self.line(&line);
+                    pos + 3
+                } else {
+                    pos + 1
+                }
+            }
+            Instruction::Test(_) | Instruction::Eval(_) if self.is_condition(pos) => {
+                self.if_chain(pos, end)
+            }
+            Instruction::Eval(expr) => {
+                let line = format!("eval {}", self.expression(expr));
+                self.line(&line);
+                pos + 1
+            }
+            Instruction::ForEveryPartPush => match instructions.get(pos + 1) {
+                Some(Instruction::ForEveryPart(fep)) => {
+                    self.indent();
+                    self.out.push_str("foreverypart");
+                    self.open_loop(pos + 1, true);
+                    self.nested_block(pos + 2, fep.jz_pos.saturating_sub(1));
+                    self.close_loop();
+                    fep.jz_pos.max(pos + 2)
+                }
+                _ => pos + 1,
+            },
+            Instruction::While(while_) => {
+                self.indent();
+                let expr = self.expression(&while_.expr);
+                self.out.push_str("while ");
+                self.out.push_str(&expr);
+                self.open_loop(pos, false);
+                self.nested_block(pos + 1, while_.jz_pos.saturating_sub(1));
+                self.close_loop();
+                while_.jz_pos.max(pos + 1)
+            }
+            Instruction::ForEveryPartPop(num_pops) => match instructions.get(pos + 1) {
+                Some(Instruction::Jmp(_)) => {
+                    self.break_foreverypart(*num_pops);
+                    pos + 2
+                }
+                Some(Instruction::Return) => {
+                    self.line("return");
+                    pos + 2
+                }
+                _ => pos + 1,
+            },
+            Instruction::Jmp(jmp_pos) => {
+                if self
+                    .loops
+                    .iter()
+                    .rev()
+                    .find(|l| !l.is_foreverypart)
+                    .is_some_and(|l| l.start == *jmp_pos)
+                {
+                    self.line("continue");
+                } else {
+                    self.line("break");
+                }
+                pos + 1
+            }
+            Instruction::Test(_)
+            | Instruction::Jz(_)
+            | Instruction::Jnz(_)
+            | Instruction::Clear(_)
+            | Instruction::ForEveryPart(_) => pos + 1,
+            instruction => {
+                let line = self.command(instruction);
+                self.line(&line);
+                pos + 1
+            }
+        }
+    }
+
+    fn open_loop(&mut self, start: usize, is_foreverypart: bool) {
+        self.loops.push(Loop {
+            start,
+            is_foreverypart,
+            header_end: self.out.len(),
+            label: None,
+        });
+        self.out.push_str(" {\n");
+    }
+
+    fn close_loop(&mut self) {
+        let l = self.loops.pop().unwrap();
+        if let Some(label) = l.label {
+            self.out
+                .insert_str(l.header_end, &format!(" :name {}", quote(&label)));
+        }
+        self.indent();
+        self.out.push_str("}\n");
+    }
+
+    fn break_foreverypart(&mut self, num_pops: usize) {
+        let num_loops = self.loops.len();
+        let target = self
+            .loops
+            .iter()
+            .enumerate()
+            .rev()
+            .filter(|(_, l)| l.is_foreverypart)
+            .nth(num_pops.saturating_sub(1))
+            .map(|(idx, _)| idx);
+
+        match target {
+            Some(idx) if idx + 1 != num_loops => {
+                let label = format!(
+                    "part{}",
+                    self.loops[..=idx]
+                        .iter()
+                        .filter(|l| l.is_foreverypart)
+                        .count()
+                );
+                let line = format!("break :name {}", quote(&label));
+                self.loops[idx].label = Some(label);
+                self.line(&line);
+            }
+            _ => self.line("break"),
+        }
+    }
+
+    fn if_chain(&mut self, mut pos: usize, end: usize) -> usize {
+        let instructions = self.instructions;
+        let mut keyword = "if";
+        self.indent();
+        loop {
+            let (test, jz_pos) = self.condition(pos);
+            let body_start = jz_pos + 1;
+            let target = match &instructions[jz_pos] {
+                Instruction::Jz(target) | Instruction::Jnz(target) => {
+                    (*target).clamp(body_start, end)
+                }
+                _ => body_start,
+            };
+            let chain_end = match instructions.get(target.wrapping_sub(1)) {
+                Some(Instruction::Jmp(chain_end))
+                    if target > body_start && (target..=end).contains(chain_end) =>
+                {
+                    Some(*chain_end)
+                }
+                _ => None,
+            };
+
+            self.out.push_str(keyword);
+            self.out.push(' ');
+            self.out.push_str(&test);
+            self.out.push_str(" {\n");
+            self.nested_block(
+                body_start,
+                if chain_end.is_some() {
+                    target - 1
+                } else {
+                    target
+                },
+            );
+            self.indent();
+            self.out.push('}');
+
+            match chain_end {
+                Some(chain_end) if self.is_elsif(target, chain_end) => {
+                    self.out.push(' ');
+                    keyword = "elsif";
+                    pos = target;
+                }
+                Some(chain_end) => {
+                    self.out.push_str(" else {\n");
+                    self.nested_block(target, chain_end);
+                    self.indent();
+                    self.out.push_str("}\n");
+                    return chain_end;
+                }
+                None => {
+                    self.out.push('\n');
+                    return target;
+                }
+            }
+        }
+    }
+
+    fn is_elsif(&self, pos: usize, chain_end: usize) -> bool {
+        if !self.is_condition(pos) {
+            return false;
+        }
+        let (_, jz_pos) = self.condition_jumps(pos);
+        match &self.instructions[jz_pos] {
+            Instruction::Jz(target) => {
+                *target == chain_end
+                    || (*target > jz_pos + 1
+                        && matches!(
+                            self.instructions.get(target - 1),
+                            Some(Instruction::Jmp(jmp_pos)) if *jmp_pos == chain_end
+                        ))
+            }
+            _ => false,
+        }
+    }
+
+    fn is_condition(&self, pos: usize) -> bool {
+        matches!(
+            self.instructions.get(pos),
+            Some(Instruction::Eval(_)) | Some(Instruction::Test(_))
+        ) && !matches!(
+            self.instructions.get(pos),
+            Some(Instruction::Test(Test::Vacation(_)))
+        ) && matches!(
+            self.instructions.get(pos + 1),
+            Some(Instruction::Jz(_) | Instruction::Jnz(_))
+        )
+    }
+
+    // Returns the (is_jz, target) pairs that follow each test operand
+    // of the condition starting at `pos`, along with the position of
+    // the jump that closes the condition.
+    fn condition_jumps(&self, pos: usize) -> (Vec<(bool, usize)>, usize) {
+        let mut jumps = Vec::new();
+        let mut jmp_pos = pos + 1;
+        loop {
+            let (is_jz, target) = match &self.instructions[jmp_pos] {
+                Instruction::Jz(target) => (true, *target),
+                Instruction::Jnz(target) => (false, *target),
+                _ => unreachable!(),
+            };
+            jumps.push((is_jz, target));
+            if target > jmp_pos
+                && matches!(
+                    self.instructions.get(target),
+                    Some(Instruction::Jz(_) | Instruction::Jnz(_))
+                )
+                && matches!(
+                    self.instructions.get(jmp_pos + 1),
+                    Some(Instruction::Test(_) | Instruction::Eval(_))
+                )
+                && matches!(
+                    self.instructions.get(jmp_pos + 2),
+                    Some(Instruction::Jz(_) | Instruction::Jnz(_))
+                )
+            {
+                jmp_pos += 2;
+            } else {
+                return (jumps, jmp_pos);
+            }
+        }
+    }
+
+    fn condition(&self, pos: usize) -> (String, usize) {
+        let (jumps, jz_pos) = self.condition_jumps(pos);
+        (
+            self.condition_group(pos, &jumps, 0, jumps.len() - 1, jz_pos),
+            jz_pos,
+        )
+    }
+
+    // Tests in an allof/anyof are separated by Jz/Jnz jumps pointing to the
+    // jump that closes the group. Nested groups close at the separator that
+    // follows them, or share the closing jump when they are the last operand.
+    fn condition_group(
+        &self,
+        pos: usize,
+        jumps: &[(bool, usize)],
+        first: usize,
+        last: usize,
+        end_pos: usize,
+    ) -> String {
+        if first == last {
+            return self.condition_operand(pos + first * 2);
+        }
+        let mut separators = (first..last)
+            .filter(|&idx| jumps[idx].1 == end_pos)
+            .collect::<Vec<_>>();
+        if separators.is_empty() {
+            separators = (first..last).collect();
+        }
+        let is_jz = jumps[separators[0]].0;
+        let mut operands = Vec::new();
+        let mut from = first;
+        for idx in separators {
+            if jumps[idx].0 != is_jz {
+                break;
+            }
+            operands.push(self.condition_group(pos, jumps, from, idx, pos + idx * 2 + 1));
+            from = idx + 1;
+        }
+        operands.push(self.condition_group(pos, jumps, from, last, end_pos));
+
+        format!(
+            "{}({})",
+            if is_jz { "allof" } else { "anyof" },
+            operands.join(", ")
+        )
+    }
+
+    fn condition_operand(&self, pos: usize) -> String {
+        match &self.instructions[pos] {
+            Instruction::Test(test) => self.test(test),
+            Instruction::Eval(expr) => match expr.split_last() {
+                Some((Expression::UnaryOperator(UnaryOperator::Not), expr)) => {
+                    format!("not eval {}", self.expression(expr))
+                }
+                _ => format!("eval {}", self.expression(expr)),
+            },
+            _ => "false".to_string(),
+        }
+    }
+
+    fn line(&mut self, line: &str) {
+        self.indent();
+        self.out.push_str(line);
+        self.out.push_str(";\n");
+    }
+
+    fn indent(&mut self) {
+        for _ in 0..self.depth {
+            self.out.push_str("    ");
+        }
+    }
+
+    fn command(&self, instruction: &Instruction) -> String {
+        let mut cmd = String::new();
+        match instruction {
+            Instruction::Require(capabilities) => {
+                cmd.push_str("require ");
+                cmd.push_str(&list(capabilities.iter().map(|c| quote(&c.to_string()))));
+            }
+            Instruction::Keep(keep) => {
+                cmd.push_str("keep");
+                if !keep.flags.is_empty() {
+                    cmd.push_str(" :flags ");
+                    cmd.push_str(&self.values(&keep.flags));
+                }
+            }
+            Instruction::FileInto(fileinto) => {
+                cmd.push_str("fileinto");
+                if fileinto.copy {
+                    cmd.push_str(" :copy");
+                }
+                if fileinto.create {
+                    cmd.push_str(" :create");
+                }
+                if !fileinto.flags.is_empty() {
+                    cmd.push_str(" :flags ");
+                    cmd.push_str(&self.values(&fileinto.flags));
+                }
+                self.tag_value(&mut cmd, ":mailboxid", fileinto.mailbox_id.as_ref());
+                self.tag_value(&mut cmd, ":specialuse", fileinto.special_use.as_ref());
+                cmd.push(' ');
+                cmd.push_str(&self.value(&fileinto.folder));
+            }
+            Instruction::Redirect(redirect) => {
+                cmd.push_str("redirect");
+                if redirect.copy {
+                    cmd.push_str(" :copy");
+                }
+                if redirect.list {
+                    cmd.push_str(" :list");
+                }
+                let (mode, trace) = match &redirect.by_time {
+                    ByTime::Relative {
+                        rlimit,
+                        mode,
+                        trace,
+                    } => {
+                        cmd.push_str(&format!(" :bytimerelative {rlimit}"));
+                        (mode, *trace)
+                    }
+                    ByTime::Absolute {
+                        alimit,
+                        mode,
+                        trace,
+                    } => {
+                        cmd.push_str(" :bytimeabsolute ");
+                        cmd.push_str(&self.value(alimit));
+                        (mode, *trace)
+                    }
+                    ByTime::None => (&ByMode::Default, false),
+                };
+                match mode {
+                    ByMode::Notify => cmd.push_str(" :bymode \"notify\""),
+                    ByMode::Return => cmd.push_str(" :bymode \"return\""),
+                    ByMode::Default => (),
+                }
+                if trace {
+                    cmd.push_str(" :bytrace");
+                }
+                match &redirect.return_of_content {
+                    Ret::Full => cmd.push_str(" :ret \"FULL\""),
+                    Ret::Hdrs => cmd.push_str(" :ret \"HDRS\""),
+                    Ret::Default => (),
+                }
+                match &redirect.notify {
+                    DsnNotify::Never => cmd.push_str(" :notify \"NEVER\""),
+                    DsnNotify::Items(items) => {
+                        cmd.push_str(" :notify ");
+                        cmd.push_str(&quote(
+                            &items
+                                .iter()
+                                .map(|item| match item {
+                                    NotifyItem::Success => "SUCCESS",
+                                    NotifyItem::Failure => "FAILURE",
+                                    NotifyItem::Delay => "DELAY",
+                                })
+                                .collect::<Vec<_>>()
+                                .join(","),
+                        ));
+                    }
+                    DsnNotify::Default => (),
+                }
+                cmd.push(' ');
+                cmd.push_str(&self.value(&redirect.address));
+            }
+            Instruction::Discard(discard) => {
+                cmd.push_str("discard");
+                self.tag_value(&mut cmd, ":reason", discard.reason.as_ref());
+            }
+            Instruction::Stop => cmd.push_str("stop"),
+            Instruction::Return => cmd.push_str("return"),
+            Instruction::Invalid(invalid) => cmd.push_str(&invalid.name),
+            Instruction::Replace(replace) => {
+                cmd.push_str("replace");
+                if replace.mime {
+                    cmd.push_str(" :mime");
+                }
+                self.tag_value(&mut cmd, ":subject", replace.subject.as_ref());
+                self.tag_value(&mut cmd, ":from", replace.from.as_ref());
+                cmd.push(' ');
+                cmd.push_str(&self.value(&replace.replacement));
+            }
+            Instruction::Enclose(enclose) => {
+                cmd.push_str("enclose");
+                self.tag_value(&mut cmd, ":subject", enclose.subject.as_ref());
+                if !enclose.headers.is_empty() {
+                    cmd.push_str(" :headers ");
+                    cmd.push_str(&self.values(&enclose.headers));
+                }
+                cmd.push(' ');
+                cmd.push_str(&self.value(&enclose.value));
+            }
+            Instruction::ExtractText(extract) => {
+                cmd.push_str("extracttext");
+                if self.is_script_var(&extract.name) {
+                    cmd.push_str(" :local");
+                }
+                if let Some(first) = extract.first {
+                    cmd.push_str(&format!(" :first {first}"));
+                }
+                self.modifiers(&mut cmd, &extract.modifiers);
+                cmd.push(' ');
+                cmd.push_str(&quote(&self.variable_name(&extract.name)));
+            }
+            Instruction::Convert(convert) => {
+                cmd.push_str("convert ");
+                cmd.push_str(&self.value(&convert.from_media_type));
+                cmd.push(' ');
+                cmd.push_str(&self.value(&convert.to_media_type));
+                cmd.push(' ');
+                cmd.push_str(&self.values(&convert.transcoding_params));
+            }
+            Instruction::AddHeader(add_header) => {
+                cmd.push_str("addheader");
+                if add_header.last {
+                    cmd.push_str(" :last");
+                }
+                cmd.push(' ');
+                cmd.push_str(&self.value(&add_header.field_name));
+                cmd.push(' ');
+                cmd.push_str(&self.value(&add_header.value));
+            }
+            Instruction::DeleteHeader(delete_header) => {
+                cmd.push_str("deleteheader");
+                index(&mut cmd, delete_header.index);
+                match_type(&mut cmd, &delete_header.match_type);
+                comparator(&mut cmd, &delete_header.comparator);
+                if delete_header.mime_anychild {
+                    cmd.push_str(" :mime :anychild");
+                }
+                cmd.push(' ');
+                cmd.push_str(&self.value(&delete_header.field_name));
+                if !delete_header.value_patterns.is_empty() {
+                    cmd.push(' ');
+                    cmd.push_str(&self.values(&delete_header.value_patterns));
+                }
+            }
+            Instruction::Set(set) => {
+                cmd.push_str("set");
+                if self.is_script_var(&set.name) {
+                    cmd.push_str(" :local");
+                }
+                self.modifiers(&mut cmd, &set.modifiers);
+                cmd.push(' ');
+                cmd.push_str(&quote(&self.variable_name(&set.name)));
+                cmd.push(' ');
+                cmd.push_str(&self.value(&set.value));
+            }
+            Instruction::Notify(notify) => {
+                cmd.push_str("notify");
+                self.tag_value(&mut cmd, ":from", notify.from.as_ref());
+                self.tag_value(&mut cmd, ":importance", notify.importance.as_ref());
+                if !notify.options.is_empty() {
+                    cmd.push_str(" :options ");
+                    cmd.push_str(&self.values(&notify.options));
+                }
+                self.tag_value(&mut cmd, ":message", notify.message.as_ref());
+                self.fcc(&mut cmd, notify.fcc.as_ref());
+                cmd.push(' ');
+                cmd.push_str(&match &notify.method {
+                    // Only constant methods are validated, so an invalid one
+                    // must have been built from variables.
+                    Value::Text(uri) if validate_uri(uri).is_none() => {
+                        quote(&format!("{uri}{UNDEFINED_VAR}"))
+                    }
+                    method => self.value(method),
+                });
+            }
+            Instruction::Reject(reject) => {
+                cmd.push_str(if reject.ereject {
+                    "ereject "
+                } else {
+                    "reject "
+                });
+                cmd.push_str(&self.value(&reject.reason));
+            }
+            Instruction::Vacation(vacation) => {
+                cmd = self.vacation(
+                    &TestVacation {
+                        addresses: Vec::new(),
+                        period: Period::Default,
+                        handle: None,
+                        reason: vacation.reason.clone(),
+                    },
+                    vacation,
+                );
+            }
+            Instruction::Error(error) => {
+                cmd.push_str("error ");
+                cmd.push_str(&self.value(&error.message));
+            }
+            Instruction::EditFlags(edit_flags) => {
+                cmd.push_str(match edit_flags.action {
+                    Action::Set => "setflag",
+                    Action::Add => "addflag",
+                    Action::Remove => "removeflag",
+                });
+                if let Some(name) = &edit_flags.name {
+                    cmd.push(' ');
+                    cmd.push_str(&quote(&self.variable_name(name)));
+                }
+                cmd.push(' ');
+                cmd.push_str(&self.values(&edit_flags.flags));
+            }
+            Instruction::Include(include) => {
+                cmd.push_str("include");
+                if matches!(include.location, Location::Global) {
+                    cmd.push_str(" :global");
+                }
+                if include.once {
+                    cmd.push_str(" :once");
+                }
+                if include.optional {
+                    cmd.push_str(" :optional");
+                }
+                cmd.push(' ');
+                cmd.push_str(&self.value(&include.value));
+            }
+            Instruction::Let(let_) => {
+                cmd.push_str("let ");
+                cmd.push_str(&quote(&self.variable_name(&let_.name)));
+                cmd.push(' ');
+                cmd.push_str(&self.expression(&let_.expr));
+            }
+            #[cfg(test)]
+            Instruction::TestCmd(arguments) => {
+                cmd.push_str(&self.test_arguments(arguments));
+            }
+            Instruction::Test(_)
+            | Instruction::Jmp(_)
+            | Instruction::Jz(_)
+            | Instruction::Jnz(_)
+            | Instruction::ForEveryPartPush
+            | Instruction::ForEveryPart(_)
+            | Instruction::ForEveryPartPop(_)
+            | Instruction::Clear(_)
+            | Instruction::While(_)
+            | Instruction::Eval(_) => (),
+        }
+        cmd
+    }
+
+    fn vacation(&self, test: &TestVacation, vacation: &Vacation) -> String {
+        let mut cmd = String::from("vacation");
+        if vacation.mime {
+            cmd.push_str(" :mime");
+        }
+        match &test.period {
+            Period::Days(days) => cmd.push_str(&format!(" :days {days}")),
+            Period::Seconds(seconds) => cmd.push_str(&format!(" :seconds {seconds}")),
+            Period::Default => (),
+        }
+        self.tag_value(&mut cmd, ":subject", vacation.subject.as_ref());
+        self.tag_value(&mut cmd, ":from", vacation.from.as_ref());
+        self.tag_value(&mut cmd, ":handle", test.handle.as_ref());
+        if !test.addresses.is_empty() {
+            cmd.push_str(" :addresses ");
+            cmd.push_str(&self.values(&test.addresses));
+        }
+        self.fcc(&mut cmd, vacation.fcc.as_ref());
+        cmd.push(' ');
+        cmd.push_str(&self.value(&vacation.reason));
+        cmd
+    }
+
+    fn test(&self, test: &Test) -> String {
+        let mut cmd = String::new();
+        let is_not = match test {
+            Test::True => {
+                cmd.push_str("true");
+                false
+            }
+            Test::False => {
+                cmd.push_str("false");
+                false
+            }
+            Test::Address(test) => {
+                cmd.push_str("address");
+                address_part(&mut cmd, test.address_part);
+                match_type(&mut cmd, &test.match_type);
+                comparator(&mut cmd, &test.comparator);
+                index(&mut cmd, test.index);
+                if test.mime_anychild {
+                    cmd.push_str(" :mime :anychild");
+                }
+                self.arguments(&mut cmd, [&test.header_list, &test.key_list]);
+                test.is_not
+            }
+            Test::Envelope(test) => {
+                cmd.push_str("envelope");
+                address_part(&mut cmd, test.address_part);
+                match_type(&mut cmd, &test.match_type);
+                comparator(&mut cmd, &test.comparator);
+                if let Some(zone) = test.zone {
+                    cmd.push_str(" :zone ");
+                    cmd.push_str(&quote(&time_zone(zone)));
+                }
+                cmd.push(' ');
+                cmd.push_str(&list(
+                    test.envelope_list
+                        .iter()
+                        .map(|e| quote(envelope_name(e, false))),
+                ));
+                self.arguments(&mut cmd, [&test.key_list]);
+                test.is_not
+            }
+            Test::Exists(test) => {
+                cmd.push_str("exists");
+                if test.mime_anychild {
+                    cmd.push_str(" :mime :anychild");
+                }
+                self.arguments(&mut cmd, [&test.header_names]);
+                test.is_not
+            }
+            Test::Header(test) => {
+                cmd.push_str("header");
+                match_type(&mut cmd, &test.match_type);
+                comparator(&mut cmd, &test.comparator);
+                index(&mut cmd, test.index);
+                if test.mime_anychild || test.mime_opts != MimeOpts::None {
+                    cmd.push_str(" :mime");
+                }
+                if test.mime_anychild {
+                    cmd.push_str(" :anychild");
+                }
+                match &test.mime_opts {
+                    MimeOpts::Type => cmd.push_str(" :type"),
+                    MimeOpts::Subtype => cmd.push_str(" :subtype"),
+                    MimeOpts::ContentType => cmd.push_str(" :contenttype"),
+                    MimeOpts::Param(params) => {
+                        cmd.push_str(" :param ");
+                        cmd.push_str(&self.values(params));
+                    }
+                    MimeOpts::None => (),
+                }
+                if test.match_type == MatchType::KeywordSet {
+                    self.tag_value(&mut cmd, ":set", test.key_list.first());
+                    self.arguments(&mut cmd, [&test.header_list]);
+                } else {
+                    self.arguments(&mut cmd, [&test.header_list, &test.key_list]);
+                }
+                test.is_not
+            }
+            Test::Size(test) => {
+                cmd.push_str(match test.relation {
+                    RelationalMatch::Gt => "size :over",
+                    RelationalMatch::Lt => "size :under",
+                    RelationalMatch::Ge => "size :count \"ge\"",
+                    RelationalMatch::Le => "size :count \"le\"",
+                    RelationalMatch::Eq => "size :count \"eq\"",
+                    RelationalMatch::Ne => "size :count \"ne\"",
+                });
+                cmd.push_str(&format!(" {}", test.limit));
+                test.is_not
+            }
+            Test::Invalid(invalid) => {
+                cmd.push_str(&invalid.name);
+                false
+            }
+            Test::Body(test) => {
+                cmd.push_str("body");
+                match_type(&mut cmd, &test.match_type);
+                comparator(&mut cmd, &test.comparator);
+                match &test.body_transform {
+                    BodyTransform::Raw => cmd.push_str(" :raw"),
+                    BodyTransform::Content(content_types) => {
+                        cmd.push_str(" :content ");
+                        cmd.push_str(&self.values(content_types));
+                    }
+                    BodyTransform::Text => (),
+                }
+                if test.include_subject {
+                    cmd.push_str(" :subject");
+                }
+                if test.match_type == MatchType::KeywordSet {
+                    self.tag_value(&mut cmd, ":set", test.key_list.first());
+                } else {
+                    self.arguments(&mut cmd, [&test.key_list]);
+                }
+                test.is_not
+            }
+            Test::Convert(test) => {
+                cmd.push_str("convert ");
+                cmd.push_str(&self.value(&test.from_media_type));
+                cmd.push(' ');
+                cmd.push_str(&self.value(&test.to_media_type));
+                self.arguments(&mut cmd, [&test.transcoding_params]);
+                test.is_not
+            }
+            Test::Convertible(test) => {
+                cmd.push_str("convertible ");
+                cmd.push_str(&self.value(&test.from_media_type));
+                cmd.push(' ');
+                cmd.push_str(&self.value(&test.to_media_type));
+                test.is_not
+            }
+            Test::Date(test) => {
+                cmd.push_str("date");
+                match_type(&mut cmd, &test.match_type);
+                comparator(&mut cmd, &test.comparator);
+                index(&mut cmd, test.index);
+                if test.mime_anychild {
+                    cmd.push_str(" :mime :anychild");
+                }
+                match test.zone {
+                    Zone::Time(zone) => {
+                        cmd.push_str(" :zone ");
+                        cmd.push_str(&quote(&time_zone(zone)));
+                    }
+                    Zone::Original => cmd.push_str(" :originalzone"),
+                    Zone::Local => (),
+                }
+                cmd.push(' ');
+                cmd.push_str(&self.value(&test.header_name));
+                cmd.push(' ');
+                cmd.push_str(&quote(date_part(&test.date_part)));
+                self.arguments(&mut cmd, [&test.key_list]);
+                test.is_not
+            }
+            Test::CurrentDate(test) => {
+                cmd.push_str("currentdate");
+                if let Some(zone) = test.zone {
+                    cmd.push_str(" :zone ");
+                    cmd.push_str(&quote(&time_zone(zone)));
+                }
+                match_type(&mut cmd, &test.match_type);
+                comparator(&mut cmd, &test.comparator);
+                cmd.push(' ');
+                cmd.push_str(&quote(date_part(&test.date_part)));
+                self.arguments(&mut cmd, [&test.key_list]);
+                test.is_not
+            }
+            Test::Duplicate(test) => {
+                cmd.push_str("duplicate");
+                self.tag_value(&mut cmd, ":handle", test.handle.as_ref());
+                match &test.dup_match {
+                    DupMatch::Header(header) => self.tag_value(&mut cmd, ":header", Some(header)),
+                    DupMatch::UniqueId(id) => self.tag_value(&mut cmd, ":uniqueid", Some(id)),
+                    DupMatch::Default => (),
+                }
+                if let Some(seconds) = test.seconds {
+                    cmd.push_str(&format!(" :seconds {seconds}"));
+                }
+                if test.last {
+                    cmd.push_str(" :last");
+                }
+                test.is_not
+            }
+            Test::String(string) | Test::Environment(string) => {
+                let is_environment = matches!(test, Test::Environment(_));
+                let test = string;
+                cmd.push_str(if is_environment {
+                    "environment"
+                } else {
+                    "string"
+                });
+                match_type(&mut cmd, &test.match_type);
+                comparator(&mut cmd, &test.comparator);
+                match test.source.first() {
+                    Some(Value::Variable(VariableType::Environment(name))) if is_environment => {
+                        cmd.push(' ');
+                        cmd.push_str(&quote(name));
+                    }
+                    _ => self.arguments(&mut cmd, [&test.source]),
+                }
+                self.arguments(&mut cmd, [&test.key_list]);
+                test.is_not
+            }
+            Test::NotifyMethodCapability(test) => {
+                cmd.push_str("notify_method_capability");
+                match_type(&mut cmd, &test.match_type);
+                comparator(&mut cmd, &test.comparator);
+                cmd.push(' ');
+                cmd.push_str(&self.value(&test.notification_uri));
+                cmd.push(' ');
+                cmd.push_str(&self.value(&test.notification_capability));
+                self.arguments(&mut cmd, [&test.key_list]);
+                test.is_not
+            }
+            Test::ValidNotifyMethod(test) => {
+                cmd.push_str("valid_notify_method");
+                self.arguments(&mut cmd, [&test.notification_uris]);
+                test.is_not
+            }
+            Test::ValidExtList(test) => {
+                cmd.push_str("valid_ext_list");
+                self.arguments(&mut cmd, [&test.list_names]);
+                test.is_not
+            }
+            Test::Ihave(test) => {
+                cmd.push_str("ihave ");
+                cmd.push_str(&list(
+                    test.capabilities.iter().map(|c| quote(&c.to_string())),
+                ));
+                test.is_not
+            }
+            Test::HasFlag(test) => {
+                cmd.push_str("hasflag");
+                match_type(&mut cmd, &test.match_type);
+                comparator(&mut cmd, &test.comparator);
+                if !test.variable_list.is_empty() {
+                    if test.variable_list.iter().any(|v| self.is_script_var(v)) {
+                        cmd.push_str(" :local");
+                    }
+                    cmd.push(' ');
+                    cmd.push_str(&list(
+                        test.variable_list
+                            .iter()
+                            .map(|v| quote(&self.variable_name(v))),
+                    ));
+                }
+                self.arguments(&mut cmd, [&test.flags]);
+                test.is_not
+            }
+            Test::MailboxExists(test) => {
+                cmd.push_str("mailboxexists");
+                self.arguments(&mut cmd, [&test.mailbox_names]);
+                test.is_not
+            }
+            Test::Metadata(test) => {
+                cmd.push_str(match &test.medatata {
+                    Metadata::Server { .. } => "servermetadata",
+                    Metadata::Mailbox { .. } => "metadata",
+                });
+                match_type(&mut cmd, &test.match_type);
+                comparator(&mut cmd, &test.comparator);
+                match &test.medatata {
+                    Metadata::Server { annotation } => {
+                        cmd.push(' ');
+                        cmd.push_str(&self.value(annotation));
+                    }
+                    Metadata::Mailbox { name, annotation } => {
+                        cmd.push(' ');
+                        cmd.push_str(&self.value(name));
+                        cmd.push(' ');
+                        cmd.push_str(&self.value(annotation));
+                    }
+                }
+                self.arguments(&mut cmd, [&test.key_list]);
+                test.is_not
+            }
+            Test::MetadataExists(test) => {
+                if let Some(mailbox) = &test.mailbox {
+                    cmd.push_str("metadataexists ");
+                    cmd.push_str(&self.value(mailbox));
+                } else {
+                    cmd.push_str("servermetadataexists");
+                }
+                self.arguments(&mut cmd, [&test.annotation_names]);
+                test.is_not
+            }
+            Test::MailboxIdExists(test) => {
+                cmd.push_str("mailboxidexists");
+                self.arguments(&mut cmd, [&test.mailbox_ids]);
+                test.is_not
+            }
+            Test::SpamTest(test) => {
+                cmd.push_str("spamtest");
+                if test.percent {
+                    cmd.push_str(" :percent");
+                }
+                match_type(&mut cmd, &test.match_type);
+                comparator(&mut cmd, &test.comparator);
+                cmd.push(' ');
+                cmd.push_str(&self.value(&test.value));
+                test.is_not
+            }
+            Test::VirusTest(test) => {
+                cmd.push_str("virustest");
+                match_type(&mut cmd, &test.match_type);
+                comparator(&mut cmd, &test.comparator);
+                cmd.push(' ');
+                cmd.push_str(&self.value(&test.value));
+                test.is_not
+            }
+            Test::SpecialUseExists(test) => {
+                cmd.push_str("specialuse_exists");
+                if let Some(mailbox) = &test.mailbox {
+                    cmd.push(' ');
+                    cmd.push_str(&self.value(mailbox));
+                }
+                self.arguments(&mut cmd, [&test.attributes]);
+                test.is_not
+            }
+            Test::Vacation(_) => {
+                cmd.push_str("false");
+                false
+            }
+            #[cfg(test)]
+            Test::TestCmd { arguments, is_not } => {
+                cmd.push_str(&self.test_arguments(arguments));
+                *is_not
+            }
+        };
+
+        if is_not {
+            format!("not {cmd}")
+        } else {
+            cmd
+        }
+    }
+
+    #[cfg(test)]
+    fn test_arguments(&self, arguments: &[Value]) -> String {
+        arguments
+            .iter()
+            .enumerate()
+            .map(|(pos, argument)| match argument {
+                Value::Text(text) if pos == 0 || text.starts_with(':') => text.to_string(),
+                _ => self.value(argument),
+            })
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+
+    fn arguments<const N: usize>(&self, cmd: &mut String, arguments: [&Vec<Value>; N]) {
+        for argument in arguments {
+            cmd.push(' ');
+            cmd.push_str(&self.values(argument));
+        }
+    }
+
+    fn tag_value(&self, cmd: &mut String, tag: &str, value: Option<&Value>) {
+        if let Some(value) = value {
+            cmd.push(' ');
+            cmd.push_str(tag);
+            cmd.push(' ');
+            cmd.push_str(&self.value(value));
+        }
+    }
+
+    fn fcc(&self, cmd: &mut String, fcc: Option<&FileCarbonCopy<Value>>) {
+        if let Some(fcc) = fcc {
+            self.tag_value(cmd, ":fcc", Some(&fcc.mailbox));
+            if fcc.create {
+                cmd.push_str(" :create");
+            }
+            if !fcc.flags.is_empty() {
+                cmd.push_str(" :flags ");
+                cmd.push_str(&self.values(&fcc.flags));
+            }
+            self.tag_value(cmd, ":specialuse", fcc.special_use.as_ref());
+            self.tag_value(cmd, ":mailboxid", fcc.mailbox_id.as_ref());
+        }
+    }
+
+    fn modifiers(&self, cmd: &mut String, modifiers: &[Modifier]) {
+        for modifier in modifiers {
+            match modifier {
+                Modifier::Lower => cmd.push_str(" :lower"),
+                Modifier::Upper => cmd.push_str(" :upper"),
+                Modifier::LowerFirst => cmd.push_str(" :lowerfirst"),
+                Modifier::UpperFirst => cmd.push_str(" :upperfirst"),
+                Modifier::QuoteWildcard => cmd.push_str(" :quotewildcard"),
+                Modifier::QuoteRegex => cmd.push_str(" :quoteregex"),
+                Modifier::QuoteMailto => cmd.push_str(" :quotemailto"),
+                Modifier::EncodeUrl { profile } => {
+                    cmd.push_str(" :encodeurl");
+                    if *profile != UrlProfile::default() {
+                        cmd.push_str(" :");
+                        cmd.push_str(profile.as_str());
+                    }
+                }
+                Modifier::DecodeUrl => cmd.push_str(" :decodeurl"),
+                Modifier::Length => cmd.push_str(" :length"),
+                Modifier::Trim => cmd.push_str(" :trim"),
+                Modifier::TrimStart => cmd.push_str(" :trimstart"),
+                Modifier::TrimEnd => cmd.push_str(" :trimend"),
+                Modifier::Replace { find, replace } => {
+                    cmd.push_str(" :replace ");
+                    cmd.push_str(&self.value(find));
+                    cmd.push(' ');
+                    cmd.push_str(&self.value(replace));
+                }
+                Modifier::Default { value } => {
+                    self.tag_value(cmd, ":default", Some(value));
+                }
+                Modifier::Hash { algorithm } => {
+                    cmd.push_str(" :hash ");
+                    cmd.push_str(&quote(algorithm.as_str()));
+                }
+                Modifier::Encode { encoding } => {
+                    cmd.push_str(" :encode ");
+                    cmd.push_str(&quote(encoding.as_str()));
+                }
+                Modifier::Decode { encoding } => {
+                    cmd.push_str(" :decode ");
+                    cmd.push_str(&quote(encoding.as_str()));
+                }
+            }
+        }
+    }
+
+    // Variables created with ":local" live for the whole script rather than
+    // the enclosing block, so they are never released by a Clear instruction.
+    fn is_script_var(&self, var: &VariableType) -> bool {
+        match var {
+            VariableType::Local(id) => {
+                self.depth > 0 && !self.block_vars.iter().any(|r| r.contains(id))
+            }
+            _ => false,
+        }
+    }
+
+    fn values(&self, values: &[Value]) -> String {
+        list(values.iter().map(|v| self.value(v)))
+    }
+
+    fn value(&self, value: &Value) -> String {
+        let mut text = String::new();
+        self.write_value(value, &mut text);
+        quote(&text)
+    }
+
+    fn write_value(&self, value: &Value, text: &mut String) {
+        match value {
+            Value::Text(t) => text.push_str(t),
+            Value::Number(n) => text.push_str(&n.to_string()),
+            Value::Variable(var) => {
+                text.push_str("${");
+                text.push_str(&self.variable(var));
+                text.push('}');
+            }
+            Value::Regex(regex) => text.push_str(&regex.expr),
+            Value::List(items) => {
+                // Adjacent literals are left behind by references to undefined
+                // variables, which expand to nothing; keep the split as is.
+                let mut last_is_literal = false;
+                for item in items {
+                    let is_literal = !matches!(item, Value::Variable(_));
+                    if is_literal && last_is_literal {
+                        text.push_str(UNDEFINED_VAR);
+                    }
+                    last_is_literal = is_literal;
+                    self.write_value(item, text);
+                }
+            }
+        }
+    }
+
+    fn variable_name(&self, var: &VariableType) -> String {
+        match var {
+            VariableType::Envelope(envelope) => {
+                format!("envelope.{}", envelope_name(envelope, false))
+            }
+            _ => self.variable(var),
+        }
+    }
+
+    fn variable(&self, var: &VariableType) -> String {
+        match var {
+            VariableType::Local(id) => format!("var{id}"),
+            VariableType::Match(num) => num.to_string(),
+            VariableType::MatchGroups(_) => "match.groups".to_string(),
+            VariableType::Global(name) => format!("global.{name}"),
+            VariableType::Environment(name) => format!("env.{name}"),
+            VariableType::Envelope(envelope) => {
+                format!("envelope.{}", envelope_name(envelope, true))
+            }
+            VariableType::Header(header) => header_variable(self.sieve, header),
+            VariableType::Part(part) => match part {
+                MessagePart::TextBody(false) => "body.text",
+                MessagePart::TextBody(true) => "body.to_text",
+                MessagePart::HtmlBody(false) => "body.html",
+                MessagePart::HtmlBody(true) => "body.to_html",
+                MessagePart::Contents => "part.text",
+                MessagePart::Raw => "part.raw",
+                MessagePart::Headers => "part.headers",
+            }
+            .to_string(),
+        }
+    }
+
+    fn expression(&self, expr: &[Expression]) -> String {
+        let mut stack: Vec<String> = Vec::new();
+        for item in expr {
+            let item = match item {
+                Expression::Variable(var) => self.variable(var),
+                Expression::Constant(Constant::Integer(n)) => n.to_string(),
+                Expression::Constant(Constant::Float(n)) => {
+                    let n = n.to_string();
+                    if n.contains('.') {
+                        n
+                    } else {
+                        format!("{n}.0")
+                    }
+                }
+                Expression::Constant(Constant::String(s)) => {
+                    if s.contains('\'') {
+                        format!("\"{s}\"")
+                    } else {
+                        format!("'{s}'")
+                    }
+                }
+                Expression::BinaryOperator(op) => {
+                    let b = stack.pop().unwrap_or_default();
+                    let a = stack.pop().unwrap_or_default();
+                    format!(
+                        "({a} {} {b})",
+                        match op {
+                            BinaryOperator::Add => "+",
+                            BinaryOperator::Subtract => "-",
+                            BinaryOperator::Multiply => "*",
+                            BinaryOperator::Divide => "/",
+                            BinaryOperator::And => "&&",
+                            BinaryOperator::Or => "||",
+                            BinaryOperator::Xor => "^",
+                            BinaryOperator::Eq => "==",
+                            BinaryOperator::Ne => "!=",
+                            BinaryOperator::Lt => "<",
+                            BinaryOperator::Le => "<=",
+                            BinaryOperator::Gt => ">",
+                            BinaryOperator::Ge => ">=",
+                        }
+                    )
+                }
+                Expression::UnaryOperator(op) => {
+                    let a = stack.pop().unwrap_or_default();
+                    match op {
+                        UnaryOperator::Not => format!("(!({a}))"),
+                        UnaryOperator::Minus => format!("(-({a}))"),
+                    }
+                }
+                Expression::JmpIf { .. } => continue,
+                Expression::Function { id, num_args } => {
+                    let args = stack.split_off(stack.len().saturating_sub(*num_args as usize));
+                    format!(
+                        "{}({})",
+                        self.functions
+                            .get(id)
+                            .map(|name| name.to_string())
+                            .unwrap_or_else(|| format!("fnc{id}")),
+                        args.join(", ")
+                    )
+                }
+                Expression::ArrayAccess => {
+                    let idx = stack.pop().unwrap_or_default();
+                    let array = stack.pop().unwrap_or_default();
+                    format!("({array})[{idx}]")
+                }
+                Expression::ArrayBuild(num_items) => {
+                    let items = stack.split_off(stack.len().saturating_sub(*num_items as usize));
+                    format!("[{}]", items.join(", "))
+                }
+            };
+            stack.push(item);
+        }
+        quote(&stack.pop().unwrap_or_default())
+    }
+}
+
+fn quote(text: &str) -> String {
+    // The tokenizer treats the first backslash of a run as the escape
+    // character and every following one as a literal backslash.
+    let mut quoted = String::with_capacity(text.len() + 2);
+    let mut backslashes = 0;
+    quoted.push('"');
+    for ch in text.chars() {
+        if ch == '\\' {
+            backslashes += 1;
+            continue;
+        } else if backslashes > 0 {
+            for _ in 0..=backslashes {
+                quoted.push('\\');
+            }
+            backslashes = 0;
+        } else if ch == '"' {
+            quoted.push('\\');
+        }
+        quoted.push(ch);
+    }
+    if backslashes > 0 {
+        for _ in 0..=backslashes {
+            quoted.push('\\');
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+fn list(mut items: impl Iterator<Item = String>) -> String {
+    match (items.next(), items.next()) {
+        (Some(first), None) => first,
+        (first, second) => {
+            let items = first
+                .into_iter()
+                .chain(second)
+                .chain(items)
+                .collect::<Vec<_>>();
+            format!("[{}]", items.join(", "))
+        }
+    }
+}
+
+fn match_type(cmd: &mut String, match_type: &MatchType) {
+    let (tag, relation) = match match_type {
+        MatchType::Is => return,
+        MatchType::Contains => (":contains", None),
+        MatchType::Matches(_) => (":matches", None),
+        MatchType::Regex(_) => (":regex", None),
+        MatchType::List => (":list", None),
+        MatchType::KeywordSet => (":contains", None),
+        MatchType::Value(relation) => (":value", Some(relation)),
+        MatchType::Count(relation) => (":count", Some(relation)),
+    };
+    cmd.push(' ');
+    cmd.push_str(tag);
+    if let Some(relation) = relation {
+        cmd.push_str(match relation {
+            RelationalMatch::Gt => " \"gt\"",
+            RelationalMatch::Ge => " \"ge\"",
+            RelationalMatch::Lt => " \"lt\"",
+            RelationalMatch::Le => " \"le\"",
+            RelationalMatch::Eq => " \"eq\"",
+            RelationalMatch::Ne => " \"ne\"",
+        });
+    }
+}
+
+fn comparator(cmd: &mut String, comparator: &Comparator) {
+    let name = match comparator {
+        Comparator::AsciiCaseMap => return,
+        Comparator::Elbonia => "elbonia",
+        Comparator::Octet => "i;octet",
+        Comparator::AsciiNumeric => "i;ascii-numeric",
+        Comparator::UnicodeCaseMap => "i;unicode-casemap",
+        Comparator::Other(name) => name,
+    };
+    cmd.push_str(" :comparator ");
+    cmd.push_str(&quote(name));
+}
+
+fn address_part(cmd: &mut String, address_part: AddressPart) {
+    cmd.push_str(match address_part {
+        AddressPart::All => "",
+        AddressPart::LocalPart => " :localpart",
+        AddressPart::Domain => " :domain",
+        AddressPart::User => " :user",
+        AddressPart::Detail => " :detail",
+        AddressPart::Name => " :name",
+    });
+}
+
+fn index(cmd: &mut String, index: Option<i32>) {
+    match index {
+        Some(index) if index < 0 => cmd.push_str(&format!(" :index {} :last", -index)),
+        Some(index) => cmd.push_str(&format!(" :index {index}")),
+        None => (),
+    }
+}
+
+fn time_zone(zone: i64) -> String {
+    format!(
+        "{}{:02}{:02}",
+        if zone < 0 { '-' } else { '+' },
+        zone.abs() / 3600,
+        zone.abs() % 3600 / 60
+    )
+}
+
+fn date_part(date_part: &DatePart) -> &'static str {
+    match date_part {
+        DatePart::Year => "year",
+        DatePart::Month => "month",
+        DatePart::Day => "day",
+        DatePart::Date => "date",
+        DatePart::Julian => "julian",
+        DatePart::Hour => "hour",
+        DatePart::Minute => "minute",
+        DatePart::Second => "second",
+        DatePart::Time => "time",
+        DatePart::Iso8601 => "iso8601",
+        DatePart::Std11 => "std11",
+        DatePart::Zone => "zone",
+        DatePart::Weekday => "weekday",
+    }
+}
+
+// Envelope parts are spelled differently in the "envelope" test and
+// in variable names.
+fn envelope_name(envelope: &Envelope, is_variable: bool) -> &'static str {
+    match (envelope, is_variable) {
+        (Envelope::From, _) => "from",
+        (Envelope::To, _) => "to",
+        (Envelope::ByTimeAbsolute, true) => "by_time_absolute",
+        (Envelope::ByTimeAbsolute, false) => "bytimeabsolute",
+        (Envelope::ByTimeRelative, true) => "by_time_relative",
+        (Envelope::ByTimeRelative, false) => "bytimerelative",
+        (Envelope::ByMode, true) => "by_mode",
+        (Envelope::ByMode, false) => "bymode",
+        (Envelope::ByTrace, true) => "by_trace",
+        (Envelope::ByTrace, false) => "bytrace",
+        (Envelope::Notify, _) => "notify",
+        (Envelope::Orcpt, _) => "orcpt",
+        (Envelope::Ret, _) => "ret",
+        (Envelope::Envid, _) => "envid",
+        (Envelope::Auth, _) => "auth",
+    }
+}
+
+fn header_variable(sieve: &Sieve, header: &HeaderVariable) -> String {
+    let mut var = String::from("header.");
+    let default_index = if header.name.is_empty() {
+        var.push('*');
+        0
+    } else {
+        var.push_str(
+            &header
+                .name
+                .iter()
+                .filter_map(|id| sieve.header_name(*id))
+                .map(|name| name.as_str().to_lowercase())
+                .collect::<Vec<_>>()
+                .join(":"),
+        );
+        -1
+    };
+    if header.index_hdr != default_index {
+        header_index(&mut var, header.index_hdr);
+    }
+    let part = match &header.part {
+        HeaderPart::Text => "text".to_string(),
+        HeaderPart::Date => "date".to_string(),
+        HeaderPart::Id => "id".to_string(),
+        HeaderPart::Raw => "raw".to_string(),
+        HeaderPart::RawName => "raw_name".to_string(),
+        HeaderPart::Exists => "exists".to_string(),
+        HeaderPart::Address(part) => match part {
+            AddressPart::Name => "name",
+            AddressPart::All => "addr",
+            AddressPart::Domain => "addr.addr.domain",
+            AddressPart::LocalPart => "addr.addr.local",
+            AddressPart::User => "addr.addr.user",
+            AddressPart::Detail => "addr.addr.detail",
+        }
+        .to_string(),
+        HeaderPart::ContentType(part) => match part {
+            ContentTypePart::Type => "type".to_string(),
+            ContentTypePart::Subtype => "subtype".to_string(),
+            ContentTypePart::Attribute(attr) => format!("attr.{attr}"),
+        },
+        HeaderPart::Received(part) => format!(
+            "rcvd.{}",
+            match part {
+                ReceivedPart::From(ReceivedHostname::Any) => "from",
+                ReceivedPart::From(ReceivedHostname::Name) => "from.name",
+                ReceivedPart::From(ReceivedHostname::Ip) => "from.ip",
+                ReceivedPart::FromIp => "ip",
+                ReceivedPart::FromIpRev => "iprev",
+                ReceivedPart::By(ReceivedHostname::Any) => "by",
+                ReceivedPart::By(ReceivedHostname::Name) => "by.name",
+                ReceivedPart::By(ReceivedHostname::Ip) => "by.ip",
+                ReceivedPart::For => "for",
+                ReceivedPart::With => "with",
+                ReceivedPart::TlsVersion => "tls",
+                ReceivedPart::TlsCipher => "cipher",
+                ReceivedPart::Id => "id",
+                ReceivedPart::Ident => "ident",
+                ReceivedPart::Via => "via",
+                ReceivedPart::Date => "date",
+                ReceivedPart::DateRaw => "date.raw",
+            }
+        ),
+    };
+    if header.part != HeaderPart::Text || header.index_part != default_index {
+        var.push('.');
+        var.push_str(&part);
+        if header.index_part != default_index {
+            header_index(&mut var, header.index_part);
+        }
+    }
+    var
+}
+
+fn header_index(var: &mut String, index: i32) {
+    if index == 0 {
+        var.push_str("[*]");
+    } else {
+        var.push_str(&format!("[{index}]"));
+    }
+}
@@ -0,0 +1,257 @@
+/*
+ * Copyright (c) 2020-2023, Stalwart Labs Ltd.
+ *
+ * This file is part of the Stalwart Sieve Interpreter.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ * in the LICENSE file at the top-level directory of this distribution.
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ * You can be released from the requirements of the AGPLv3 license by
+ * purchasing a commercial license. Please contact licensing@stalw.art
+ * for more details.
+*/
+
+// Control and data flow helpers shared by the compile warnings and
+// `Compiler::lint`.
+
+use std::ops::Range;
+
+use crate::Sieve;
+
+use super::{
+    grammar::{
+        actions::{
+            action_flags::EditFlags,
+            action_mime::ExtractText,
+            action_set::{Let, Set},
+        },
+        expr::Expression,
+        instruction::{CommandSpan, Instruction, VariableDecl},
+        test::Test,
+        While,
+    },
+    visit::Visitor,
+    Value, VariableType,
+};
+
+// Positions the instruction at `pos` can continue at. The conditional jump
+// after a constant `true` or `false` test is treated as unconditional.
+pub(crate) fn successors(instructions: &[Instruction], pos: usize, next: &mut Vec<usize>) {
+    let constant = match pos.checked_sub(1).map(|pos| &instructions[pos]) {
+        Some(Instruction::Test(Test::True)) => Some(true),
+        Some(Instruction::Test(Test::False)) => Some(false),
+        _ => None,
+    };
+    match &instructions[pos] {
+        Instruction::Jmp(target) => next.push(*target),
+        Instruction::Jz(target) => match constant {
+            Some(true) => next.push(pos + 1),
+            Some(false) => next.push(*target),
+            None => next.extend([pos + 1, *target]),
+        },
+        Instruction::Jnz(target) => match constant {
+            Some(true) => next.push(*target),
+            Some(false) => next.push(pos + 1),
+            None => next.extend([pos + 1, *target]),
+        },
+        Instruction::ForEveryPart(fep) => next.extend([pos + 1, fep.jz_pos]),
+        Instruction::While(while_) => next.extend([pos + 1, while_.jz_pos]),
+        Instruction::Stop | Instruction::Return | Instruction::Error(_) => (),
+        _ => next.push(pos + 1),
+    }
+}
+
+// The local variable ids cleared by each `Clear` instruction. Ids are reused
+// by sibling blocks, so every clear of an id starts a new variable.
+pub(crate) fn local_clears(instructions: &[Instruction]) -> Vec<(usize, Range<usize>)> {
+    instructions
+        .iter()
+        .enumerate()
+        .filter_map(|(pos, instruction)| match instruction {
+            Instruction::Clear(clear) if clear.local_vars_num > 0 => Some((
+                pos,
+                clear.local_vars_idx as usize
+                    ..(clear.local_vars_idx + clear.local_vars_num) as usize,
+            )),
+            _ => None,
+        })
+        .collect()
+}
+
+// Name of the local variable `id` as seen by the instruction at `pos`
+pub(crate) fn local_var_name<'x>(
+    var_decls: &'x [VariableDecl],
+    clears: &[(usize, Range<usize>)],
+    pos: usize,
+    id: usize,
+) -> Option<&'x str> {
+    let generation = clears
+        .iter()
+        .filter(|(clear_pos, ids)| *clear_pos < pos && ids.contains(&id))
+        .count();
+    var_decls
+        .iter()
+        .filter(|decl| decl.var_id == Some(id))
+        .nth(generation)
+        .map(|decl| decl.name.as_str())
+}
+
+// The command that emitted the instruction at `pos`
+pub(crate) fn command_span(spans: &[CommandSpan], pos: usize) -> Option<&CommandSpan> {
+    spans
+        .partition_point(|span| span.pos <= pos)
+        .checked_sub(1)
+        .map(|idx| &spans[idx])
+}
+
+// Local variables read and written by each instruction, as pairs of
+// instruction position and variable id
+#[derive(Default)]
+pub(crate) struct VariableAccess {
+    pub(crate) reads: Vec<(usize, usize)>,
+    pub(crate) writes: Vec<(usize, usize)>,
+}
+
+impl VariableAccess {
+    pub(crate) fn new(sieve: &Sieve) -> Self {
+        let mut access = VariableAccess::default();
+        sieve.walk(&mut access);
+        access
+    }
+
+    fn read_expr(&mut self, pos: usize, expr: &[Expression]) {
+        for item in expr {
+            if let Expression::Variable(VariableType::Local(id)) = item {
+                self.reads.push((pos, *id));
+            }
+        }
+    }
+
+    fn write(&mut self, pos: usize, var: &VariableType) {
+        if let VariableType::Local(id) = var {
+            self.writes.push((pos, *id));
+        }
+    }
+}
+
+impl Visitor for VariableAccess {
+    fn visit_set(&mut self, pos: usize, set: &Set) {
+        self.write(pos, &set.name);
+    }
+
+    fn visit_let(&mut self, pos: usize, let_: &Let) {
+        self.read_expr(pos, &let_.expr);
+        self.write(pos, &let_.name);
+    }
+
+    fn visit_extracttext(&mut self, pos: usize, extracttext: &ExtractText) {
+        self.write(pos, &extracttext.name);
+    }
+
+    fn visit_editflags(&mut self, pos: usize, editflags: &EditFlags) {
+        if let Some(name) = &editflags.name {
+            self.write(pos, name);
+        }
+    }
+
+    fn visit_eval(&mut self, pos: usize, expr: &[Expression]) {
+        self.read_expr(pos, expr);
+    }
+
+    fn visit_while(&mut self, pos: usize, while_: &While) {
+        self.read_expr(pos, &while_.expr);
+    }
+
+    fn visit_value_part(&mut self, pos: usize, part: &Value) {
+        if let Value::Variable(VariableType::Local(id)) = part {
+            self.reads.push((pos, *id));
+        }
+    }
+}
+
+// Reads of a local variable that is not assigned on every path leading to
+// them, as pairs of instruction position and variable id. Global variables
+// are not checked, they may be set by the host or by other scripts.
+pub(crate) fn unassigned_reads(sieve: &Sieve) -> Vec<(usize, usize)> {
+    let instructions = &sieve.instructions;
+    let access = VariableAccess::new(sieve);
+    if access.reads.is_empty() {
+        return Vec::new();
+    }
+
+    // Bitmaps of the variables assigned on every path reaching each
+    // instruction, `None` for instructions not reached yet
+    let num_words = sieve.num_vars.div_ceil(64);
+    let mut writes = vec![Vec::new(); instructions.len()];
+    for (pos, id) in &access.writes {
+        writes[*pos].push(*id);
+    }
+    let mut clears = vec![Vec::new(); instructions.len()];
+    for (pos, ids) in local_clears(instructions) {
+        clears[pos].extend(ids);
+    }
+    let mut assigned: Vec<Option<Vec<u64>>> = vec![None; instructions.len()];
+    let mut pending = Vec::new();
+    let mut next = Vec::new();
+    if !instructions.is_empty() {
+        assigned[0] = Some(vec![0; num_words]);
+        pending.push(0);
+    }
+    while let Some(pos) = pending.pop() {
+        let mut state = assigned[pos].clone().unwrap();
+        for id in &writes[pos] {
+            state[id / 64] |= 1 << (id % 64);
+        }
+        for id in &clears[pos] {
+            state[id / 64] &= !(1 << (id % 64));
+        }
+
+        successors(instructions, pos, &mut next);
+        for next_pos in next.drain(..) {
+            let Some(next_state) = assigned.get_mut(next_pos) else {
+                continue;
+            };
+            let changed = match next_state {
+                Some(next_state) => {
+                    let mut changed = false;
+                    for (word, value) in next_state.iter_mut().zip(&state) {
+                        if *word & value != *word {
+                            *word &= value;
+                            changed = true;
+                        }
+                    }
+                    changed
+                }
+                None => {
+                    *next_state = Some(state.clone());
+                    true
+                }
+            };
+            if changed {
+                pending.push(next_pos);
+            }
+        }
+    }
+
+    let mut unassigned = access
+        .reads
+        .into_iter()
+        .filter(|(pos, id)| {
+            assigned[*pos]
+                .as_ref()
+                .is_some_and(|state| state[id / 64] & (1 << (id % 64)) == 0)
+        })
+        .collect::<Vec<_>>();
+    unassigned.dedup();
+    unassigned
+}
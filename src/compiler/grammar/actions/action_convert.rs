@@ -32,13 +32,22 @@ use crate::compiler::{
 };
 
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
-pub(crate) struct Convert {
+#[non_exhaustive]
+pub struct Convert {
     pub from_media_type: Value,
     pub to_media_type: Value,
     pub transcoding_params: Vec<Value>,
     pub is_not: bool,
 }
 
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[non_exhaustive]
+pub struct TestConvertible {
+    pub from_media_type: Value,
+    pub to_media_type: Value,
+    pub is_not: bool,
+}
+
 impl<'x> CompilerState<'x> {
     pub(crate) fn parse_test_convert(&mut self) -> Result<Test, CompileError> {
         Ok(Test::Convert(Convert {
@@ -49,6 +58,14 @@ impl<'x> CompilerState<'x> {
         }))
     }
 
+    pub(crate) fn parse_test_convertible(&mut self) -> Result<Test, CompileError> {
+        Ok(Test::Convertible(TestConvertible {
+            from_media_type: self.parse_string()?,
+            to_media_type: self.parse_string()?,
+            is_not: false,
+        }))
+    }
+
     pub(crate) fn parse_convert(&mut self) -> Result<(), CompileError> {
         let cmd = Instruction::Convert(Convert {
             from_media_type: self.parse_string()?,
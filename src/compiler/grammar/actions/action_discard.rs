@@ -0,0 +1,58 @@
+/*
+ * Copyright (c) 2020-2023, Stalwart Labs Ltd.
+ *
+ * This file is part of the Stalwart Sieve Interpreter.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ * in the LICENSE file at the top-level directory of this distribution.
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ * You can be released from the requirements of the AGPLv3 license by
+ * purchasing a commercial license. Please contact licensing@stalw.art
+ * for more details.
+*/
+
+use serde::{Deserialize, Serialize};
+
+use crate::compiler::{
+    grammar::{
+        instruction::{CompilerState, Instruction},
+        Capability,
+    },
+    lexer::{word::Word, Token},
+    CompileError, Value,
+};
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[non_exhaustive]
+pub struct Discard {
+    /// Reason reported on the discard event, set with the
+    /// `vnd.stalwart.discard-reason` extension.
+    pub reason: Option<Value>,
+}
+
+impl<'x> CompilerState<'x> {
+    pub(crate) fn parse_discard(&mut self) -> Result<(), CompileError> {
+        let cmd = Instruction::Discard(Discard {
+            reason: match self.tokens.peek().map(|r| r.map(|t| &t.token)) {
+                Some(Ok(Token::Tag(Word::Reason))) => {
+                    let token_info = self.tokens.unwrap_next()?;
+                    self.validate_argument(0, Capability::DiscardReason.into(), &token_info)?;
+                    self.parse_string()?.into()
+                }
+                _ => None,
+            },
+        });
+        self.instructions.push(cmd);
+        Ok(())
+    }
+}
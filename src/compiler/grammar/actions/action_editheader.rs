@@ -36,7 +36,8 @@ use crate::compiler::{
 use crate::compiler::grammar::MatchType;
 
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
-pub(crate) struct AddHeader {
+#[non_exhaustive]
+pub struct AddHeader {
     pub last: bool,
     pub field_name: Value,
     pub value: Value,
@@ -50,7 +51,8 @@ pub(crate) struct AddHeader {
 
 */
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
-pub(crate) struct DeleteHeader {
+#[non_exhaustive]
+pub struct DeleteHeader {
     pub index: Option<i32>,
     pub comparator: Comparator,
     pub match_type: MatchType,
@@ -69,7 +71,7 @@ impl<'x> CompilerState<'x> {
             let token_info = self.tokens.unwrap_next()?;
             match token_info.token {
                 Token::Tag(Word::Last) => {
-                    self.validate_argument(1, None, token_info.line_num, token_info.line_pos)?;
+                    self.validate_argument(1, None, &token_info)?;
                     last = true;
                 }
                 _ => {
@@ -137,39 +139,29 @@ impl<'x> CompilerState<'x> {
                             Word::List => Capability::ExtLists.into(),
                             _ => None,
                         },
-                        token_info.line_num,
-                        token_info.line_pos,
+                        &token_info,
                     )?;
                     match_type = self.parse_match_type(word)?;
                 }
                 Token::Tag(Word::Comparator) => {
-                    self.validate_argument(2, None, token_info.line_num, token_info.line_pos)?;
-                    comparator = self.parse_comparator()?;
+                    self.validate_argument(2, None, &token_info)?;
+                    comparator = self.parse_comparator(&token_info)?;
                 }
                 Token::Tag(Word::Index) => {
-                    self.validate_argument(3, None, token_info.line_num, token_info.line_pos)?;
-                    index = (self.tokens.expect_number(u16::MAX as usize)? as i32).into();
+                    self.validate_argument(3, None, &token_info)?;
+                    index =
+                        (self.tokens.expect_number_in_range(1, u16::MAX as usize)? as i32).into();
                 }
                 Token::Tag(Word::Last) => {
-                    self.validate_argument(4, None, token_info.line_num, token_info.line_pos)?;
+                    self.validate_argument(4, None, &token_info)?;
                     index_last = true;
                 }
                 Token::Tag(Word::Mime) => {
-                    self.validate_argument(
-                        5,
-                        Capability::Mime.into(),
-                        token_info.line_num,
-                        token_info.line_pos,
-                    )?;
+                    self.validate_argument(5, Capability::Mime.into(), &token_info)?;
                     mime = true;
                 }
                 Token::Tag(Word::AnyChild) => {
-                    self.validate_argument(
-                        6,
-                        Capability::Mime.into(),
-                        token_info.line_num,
-                        token_info.line_pos,
-                    )?;
+                    self.validate_argument(6, Capability::Mime.into(), &token_info)?;
                     mime_anychild = true;
                 }
                 _ => {
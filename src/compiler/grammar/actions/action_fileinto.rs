@@ -33,7 +33,8 @@ use crate::compiler::{
 };
 
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
-pub(crate) struct FileInto {
+#[non_exhaustive]
+pub struct FileInto {
     pub copy: bool,
     pub create: bool,
     pub folder: Value,
@@ -55,49 +56,24 @@ impl<'x> CompilerState<'x> {
             let token_info = self.tokens.unwrap_next()?;
             match token_info.token {
                 Token::Tag(Word::Copy) => {
-                    self.validate_argument(
-                        1,
-                        Capability::Copy.into(),
-                        token_info.line_num,
-                        token_info.line_pos,
-                    )?;
+                    self.validate_argument(1, Capability::Copy.into(), &token_info)?;
                     copy = true;
                 }
                 Token::Tag(Word::Create) => {
-                    self.validate_argument(
-                        2,
-                        Capability::Mailbox.into(),
-                        token_info.line_num,
-                        token_info.line_pos,
-                    )?;
+                    self.validate_argument(2, Capability::Mailbox.into(), &token_info)?;
                     create = true;
                 }
                 Token::Tag(Word::Flags) => {
-                    self.validate_argument(
-                        3,
-                        Capability::Imap4Flags.into(),
-                        token_info.line_num,
-                        token_info.line_pos,
-                    )?;
+                    self.validate_argument(3, Capability::Imap4Flags.into(), &token_info)?;
                     flags = self.parse_strings(false)?;
                 }
                 Token::Tag(Word::MailboxId) => {
-                    self.validate_argument(
-                        4,
-                        Capability::Mailbox.into(),
-                        token_info.line_num,
-                        token_info.line_pos,
-                    )?;
+                    self.validate_argument(4, Capability::MailboxId.into(), &token_info)?;
                     mailbox_id = self.parse_string()?.into();
                 }
                 Token::Tag(Word::SpecialUse) => {
-                    self.validate_argument(
-                        5,
-                        Capability::SpecialUse.into(),
-                        token_info.line_num,
-                        token_info.line_pos,
-                    )?;
-                    special_use = self.parse_string()?.into();
+                    self.validate_argument(5, Capability::SpecialUse.into(), &token_info)?;
+                    special_use = self.parse_special_use()?.into();
                 }
                 _ => {
                     folder = self.parse_string_token(token_info)?;
@@ -30,14 +30,16 @@ use crate::compiler::{
 };
 
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
-pub(crate) struct EditFlags {
+#[non_exhaustive]
+pub struct EditFlags {
     pub action: Action,
     pub name: Option<VariableType>,
     pub flags: Vec<Value>,
 }
 
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
-pub(crate) enum Action {
+#[non_exhaustive]
+pub enum Action {
     Set,
     Add,
     Remove,
@@ -21,12 +21,21 @@
  * for more details.
 */
 
+use ahash::AHashSet;
+use mail_parser::HeaderName;
 use serde::{Deserialize, Serialize};
 
-use crate::compiler::{
-    grammar::instruction::{CompilerState, Instruction},
-    lexer::{word::Word, Token},
-    CompileError, Value,
+use crate::{
+    compiler::{
+        flow::command_span,
+        grammar::{
+            instruction::{CompilerState, Instruction, MapLocalVars},
+            Clear,
+        },
+        lexer::{tokenizer::TokenInfo, word::Word, Token},
+        CompileError, ErrorType, Value,
+    },
+    Compiler, Script, Sieve,
 };
 
 /*
@@ -37,7 +46,8 @@ include [LOCATION] [":once"] [":optional"] <value: string>
 */
 
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
-pub(crate) struct Include {
+#[non_exhaustive]
+pub struct Include {
     pub location: Location,
     pub once: bool,
     pub optional: bool,
@@ -45,7 +55,8 @@ pub(crate) struct Include {
 }
 
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
-pub(crate) enum Location {
+#[non_exhaustive]
+pub enum Location {
     Personal,
     Global,
 }
@@ -61,19 +72,19 @@ impl<'x> CompilerState<'x> {
             let token_info = self.tokens.unwrap_next()?;
             match token_info.token {
                 Token::Tag(Word::Once) => {
-                    self.validate_argument(1, None, token_info.line_num, token_info.line_pos)?;
+                    self.validate_argument(1, None, &token_info)?;
                     once = true;
                 }
                 Token::Tag(Word::Optional) => {
-                    self.validate_argument(2, None, token_info.line_num, token_info.line_pos)?;
+                    self.validate_argument(2, None, &token_info)?;
                     optional = true;
                 }
                 Token::Tag(Word::Personal) => {
-                    self.validate_argument(3, None, token_info.line_num, token_info.line_pos)?;
+                    self.validate_argument(3, None, &token_info)?;
                     location = Location::Personal;
                 }
                 Token::Tag(Word::Global) => {
-                    self.validate_argument(3, None, token_info.line_num, token_info.line_pos)?;
+                    self.validate_argument(3, None, &token_info)?;
                     location = Location::Global;
                 }
                 _ => {
@@ -92,3 +103,258 @@ impl<'x> CompilerState<'x> {
         Ok(())
     }
 }
+
+pub(crate) struct IncludeLinker<'x> {
+    resolver: &'x mut dyn FnMut(&str, bool) -> Option<Vec<u8>>,
+    stack: Vec<Script>,
+    included: AHashSet<Script>,
+    num_includes: usize,
+    // Header names interned so far, shared by every inlined script
+    pub(crate) header_names: Vec<HeaderName<'static>>,
+}
+
+impl Compiler {
+    /// Compiles a script, resolving its `include` commands at compile time.
+    ///
+    /// The resolver receives the script name and whether it is `:global`, and
+    /// returns the script contents. Included scripts are inlined into the
+    /// returned `Sieve`, each running with its own local variables. Includes
+    /// with a non-constant name are left to be resolved at runtime, and an
+    /// `:once` include is skipped if the same script was inlined earlier.
+    /// Inlined scripts count towards the redirect limit of the script
+    /// including them.
+    pub fn compile_with_includes<F>(
+        &self,
+        script: &[u8],
+        mut resolver: F,
+    ) -> Result<Sieve, CompileError>
+    where
+        F: FnMut(&str, bool) -> Option<Vec<u8>>,
+    {
+        self.compile_(
+            script,
+            Some(&mut IncludeLinker {
+                resolver: &mut resolver,
+                stack: Vec::new(),
+                included: AHashSet::new(),
+                num_includes: 0,
+                header_names: Vec::new(),
+            }),
+            None,
+            None,
+            None,
+        )
+        .map_err(|err| self.missing_capabilities(script, err))
+    }
+
+    /// Compiles a set of scripts sharing one global variable namespace, such
+    /// as a library and the user scripts that `include` it. Global variables
+    /// are looked up by name in the `Context` running the scripts, so a name
+    /// declared with `global` refers to the same variable in all of them.
+    ///
+    /// A name declared `global` in one script may not be used as a local
+    /// variable in another, this fails with `ErrorType::VariableIsLocal`
+    /// pointing at the first script, in slice order, declaring it as local.
+    /// The scripts also share a single redirect limit.
+    pub fn compile_linked(&self, scripts: &[&[u8]]) -> Result<Vec<Sieve>, CompileError> {
+        let mut sieves = Vec::with_capacity(scripts.len());
+        let mut var_decls = Vec::with_capacity(scripts.len());
+        let mut redirects_num = 0;
+        for script in scripts {
+            let mut decls = Vec::new();
+            let mut spans = Vec::new();
+            let sieve = self
+                .compile_(script, None, None, Some(&mut decls), Some(&mut spans))
+                .map_err(|err| self.missing_capabilities(script, err))?;
+
+            // The scripts run together and share a single redirect limit
+            for (pos, instruction) in sieve.instructions.iter().enumerate() {
+                if let Instruction::Redirect(_) = instruction {
+                    redirects_num += 1;
+                    if redirects_num > self.max_redirects {
+                        if let Some(span) = command_span(&spans, pos) {
+                            return Err(span.error(ErrorType::TooManyRedirects));
+                        }
+                    }
+                }
+            }
+
+            sieves.push(sieve);
+            var_decls.push(decls);
+        }
+
+        for (script_idx, decls) in var_decls.iter().enumerate() {
+            for decl in decls.iter().filter(|decl| !decl.is_global) {
+                if var_decls.iter().enumerate().any(|(idx, decls)| {
+                    idx != script_idx
+                        && decls
+                            .iter()
+                            .any(|other| other.is_global && other.name == decl.name)
+                }) {
+                    return Err(CompileError {
+                        line_num: decl.line_num,
+                        line_pos: decl.line_pos,
+                        offset_start: decl.offset,
+                        offset_end: decl.offset,
+                        error_type: ErrorType::VariableIsLocal(decl.name.clone()),
+                    });
+                }
+            }
+        }
+
+        Ok(sieves)
+    }
+}
+
+impl IncludeLinker<'_> {
+    pub(crate) fn resolve(
+        &mut self,
+        compiler: &Compiler,
+        include: &Include,
+        token_info: &TokenInfo,
+    ) -> Result<Option<Option<Sieve>>, CompileError> {
+        let name = if let Value::Text(name) = &include.value {
+            name.to_string()
+        } else {
+            return Ok(None);
+        };
+        let is_global = include.location == Location::Global;
+        let script = if is_global {
+            Script::Global(name.clone())
+        } else {
+            Script::Personal(name.clone())
+        };
+
+        if self.stack.contains(&script) {
+            return Err(error(token_info, ErrorType::IncludeCycle(name)));
+        } else if include.once && self.included.contains(&script) {
+            return Ok(Some(None));
+        }
+
+        let bytes = if let Some(bytes) = (self.resolver)(&name, is_global) {
+            bytes
+        } else if include.optional {
+            return Ok(Some(None));
+        } else {
+            return Err(error(token_info, ErrorType::IncludeNotFound(name)));
+        };
+
+        self.num_includes += 1;
+        if self.num_includes > compiler.max_includes {
+            return Err(error(token_info, ErrorType::TooManyIncludes));
+        }
+
+        self.stack.push(script.clone());
+        let mut sieve = compiler.compile_(&bytes, Some(self), None, None, None)?;
+        self.header_names = std::mem::take(&mut sieve.header_names);
+        self.stack.pop();
+        self.included.insert(script);
+
+        Ok(Some(Some(sieve)))
+    }
+}
+
+impl Sieve {
+    pub(crate) fn redirects_num(&self) -> usize {
+        self.instructions
+            .iter()
+            .filter(|instruction| matches!(instruction, Instruction::Redirect(_)))
+            .count()
+    }
+
+    pub(crate) fn link(self, linked: Vec<(usize, Option<Sieve>)>) -> Sieve {
+        let mut instructions = Vec::with_capacity(self.instructions.len());
+        let mut lines = Vec::with_capacity(self.lines.len());
+        let mut new_pos = Vec::with_capacity(self.instructions.len() + 1);
+        let mut parent_pos = Vec::with_capacity(self.instructions.len());
+        let mut num_vars = self.num_vars;
+        let mut num_match_vars = self.num_match_vars;
+        let mut capabilities = self.capabilities;
+        let mut body_pos = usize::MAX;
+        let mut linked = linked.into_iter().peekable();
+
+        for (pos, instruction) in self.instructions.into_iter().enumerate() {
+            new_pos.push(instructions.len());
+            let line = self.lines.get(pos).copied().unwrap_or_default();
+
+            match linked.next_if(|(include_pos, _)| *include_pos == pos) {
+                Some((_, Some(sieve))) => {
+                    // Included scripts get their own local and match variables
+                    let vars_base = num_vars;
+                    num_vars += sieve.num_vars;
+                    num_match_vars = std::cmp::max(num_match_vars, sieve.num_match_vars);
+                    for capability in sieve.capabilities {
+                        if !capabilities.contains(&capability) {
+                            capabilities.push(capability);
+                        }
+                    }
+                    if sieve.num_vars > 0 || sieve.num_match_vars > 0 {
+                        instructions.push(Instruction::Clear(Clear {
+                            local_vars_idx: vars_base as u32,
+                            local_vars_num: sieve.num_vars as u32,
+                            match_vars: if sieve.num_match_vars > 0 {
+                                u64::MAX >> (64 - sieve.num_match_vars)
+                            } else {
+                                0
+                            },
+                        }));
+                        lines.push(line);
+                    }
+
+                    let start_pos = instructions.len();
+                    let end_pos = start_pos + sieve.instructions.len();
+                    if sieve.body_pos != usize::MAX {
+                        body_pos = body_pos.min(start_pos + sieve.body_pos);
+                    }
+                    lines.extend(&sieve.lines);
+                    for mut instruction in sieve.instructions {
+                        if let Instruction::Return = instruction {
+                            instruction = Instruction::Jmp(end_pos);
+                        } else {
+                            instruction.map_local_vars(&|id| id + vars_base);
+                            instruction.map_jumps(&|pos| pos + start_pos);
+                        }
+                        instructions.push(instruction);
+                    }
+                }
+                Some((_, None)) => (),
+                None => {
+                    if pos >= self.body_pos {
+                        body_pos = body_pos.min(instructions.len());
+                    }
+                    parent_pos.push(instructions.len());
+                    instructions.push(instruction);
+                    lines.push(line);
+                }
+            }
+        }
+
+        capabilities.sort_by_cached_key(|c| c.to_string());
+        new_pos.push(instructions.len());
+        for pos in parent_pos {
+            instructions[pos].map_jumps(&|pos| new_pos.get(pos).copied().unwrap_or(pos));
+        }
+
+        Sieve {
+            instructions,
+            num_vars,
+            num_match_vars,
+            capabilities,
+            header_names: self.header_names,
+            body_pos,
+            lines,
+            comments: self.comments,
+            warnings: self.warnings,
+        }
+    }
+}
+
+fn error(token_info: &TokenInfo, error_type: ErrorType) -> CompileError {
+    CompileError {
+        line_num: token_info.line_num,
+        line_pos: token_info.line_pos,
+        offset_start: token_info.offset_start,
+        offset_end: token_info.offset_end,
+        error_type,
+    }
+}
@@ -33,7 +33,8 @@ use crate::compiler::{
 };
 
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
-pub(crate) struct Keep {
+#[non_exhaustive]
+pub struct Keep {
     pub flags: Vec<Value>,
 }
 
@@ -42,13 +43,8 @@ impl<'x> CompilerState<'x> {
         let cmd = Instruction::Keep(Keep {
             flags: match self.tokens.peek().map(|r| r.map(|t| &t.token)) {
                 Some(Ok(Token::Tag(Word::Flags))) => {
-                    let token_info = self.tokens.next().unwrap().unwrap();
-                    self.validate_argument(
-                        0,
-                        Capability::Imap4Flags.into(),
-                        token_info.line_num,
-                        token_info.line_pos,
-                    )?;
+                    let token_info = self.tokens.unwrap_next()?;
+                    self.validate_argument(0, Capability::Imap4Flags.into(), &token_info)?;
                     self.parse_strings(false)?
                 }
                 _ => Vec::new(),
@@ -32,12 +32,14 @@ use crate::compiler::{
 use super::action_set::Modifier;
 
 #[derive(Debug, Eq, PartialEq, Clone, Serialize, Deserialize)]
-pub(crate) struct ForEveryPart {
+#[non_exhaustive]
+pub struct ForEveryPart {
     pub jz_pos: usize,
 }
 
 #[derive(Debug, Eq, PartialEq, Clone, Serialize, Deserialize)]
-pub(crate) struct Replace {
+#[non_exhaustive]
+pub struct Replace {
     pub subject: Option<Value>,
     pub from: Option<Value>,
     pub replacement: Value,
@@ -45,21 +47,24 @@ pub(crate) struct Replace {
 }
 
 #[derive(Debug, Eq, PartialEq, Clone, Serialize, Deserialize)]
-pub(crate) struct Enclose {
+#[non_exhaustive]
+pub struct Enclose {
     pub subject: Option<Value>,
     pub headers: Vec<Value>,
     pub value: Value,
 }
 
 #[derive(Debug, Eq, PartialEq, Clone, Serialize, Deserialize)]
-pub(crate) struct ExtractText {
+#[non_exhaustive]
+pub struct ExtractText {
     pub modifiers: Vec<Modifier>,
     pub first: Option<usize>,
     pub name: VariableType,
 }
 
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
-pub(crate) enum MimeOpts<T> {
+#[non_exhaustive]
+pub enum MimeOpts<T> {
     Type,
     Subtype,
     ContentType,
@@ -78,15 +83,15 @@ impl<'x> CompilerState<'x> {
             let token_info = self.tokens.unwrap_next()?;
             match token_info.token {
                 Token::Tag(Word::Mime) => {
-                    self.validate_argument(1, None, token_info.line_num, token_info.line_pos)?;
+                    self.validate_argument(1, None, &token_info)?;
                     mime = true;
                 }
                 Token::Tag(Word::Subject) => {
-                    self.validate_argument(2, None, token_info.line_num, token_info.line_pos)?;
+                    self.validate_argument(2, None, &token_info)?;
                     subject = self.parse_string()?.into();
                 }
                 Token::Tag(Word::From) => {
-                    self.validate_argument(3, None, token_info.line_num, token_info.line_pos)?;
+                    self.validate_argument(3, None, &token_info)?;
                     from = self.parse_string()?.into();
                 }
                 _ => {
@@ -114,11 +119,11 @@ impl<'x> CompilerState<'x> {
             let token_info = self.tokens.unwrap_next()?;
             match token_info.token {
                 Token::Tag(Word::Subject) => {
-                    self.validate_argument(1, None, token_info.line_num, token_info.line_pos)?;
+                    self.validate_argument(1, None, &token_info)?;
                     subject = self.parse_string()?.into();
                 }
                 Token::Tag(Word::Headers) => {
-                    self.validate_argument(2, None, token_info.line_num, token_info.line_pos)?;
+                    self.validate_argument(2, None, &token_info)?;
                     headers = self.parse_strings(false)?;
                 }
                 _ => {
@@ -146,7 +151,7 @@ impl<'x> CompilerState<'x> {
             let token_info = self.tokens.unwrap_next()?;
             match token_info.token {
                 Token::Tag(Word::First) => {
-                    self.validate_argument(1, None, token_info.line_num, token_info.line_pos)?;
+                    self.validate_argument(1, None, &token_info)?;
                     first = self.tokens.expect_number(usize::MAX)?.into();
                 }
                 Token::Tag(
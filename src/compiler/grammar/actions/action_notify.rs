@@ -46,7 +46,8 @@ notify [":from" string]
 */
 
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
-pub(crate) struct Notify {
+#[non_exhaustive]
+pub struct Notify {
     pub from: Option<Value>,
     pub importance: Option<Value>,
     pub options: Vec<Value>,
@@ -73,7 +74,7 @@ impl<'x> CompilerState<'x> {
             let token_info = self.tokens.unwrap_next()?;
             match token_info.token {
                 Token::Tag(Word::From) => {
-                    self.validate_argument(1, None, token_info.line_num, token_info.line_pos)?;
+                    self.validate_argument(1, None, &token_info)?;
                     let address = self.parse_string()?;
                     if let Value::Text(address) = &address {
                         if address.is_empty() || !validate_from(address) {
@@ -83,60 +84,35 @@ impl<'x> CompilerState<'x> {
                     from = address.into();
                 }
                 Token::Tag(Word::Message) => {
-                    self.validate_argument(2, None, token_info.line_num, token_info.line_pos)?;
+                    self.validate_argument(2, None, &token_info)?;
                     message = self.parse_string()?.into();
                 }
                 Token::Tag(Word::Importance) => {
-                    self.validate_argument(3, None, token_info.line_num, token_info.line_pos)?;
+                    self.validate_argument(3, None, &token_info)?;
                     importance = self.parse_string()?.into();
                 }
                 Token::Tag(Word::Options) => {
-                    self.validate_argument(4, None, token_info.line_num, token_info.line_pos)?;
+                    self.validate_argument(4, None, &token_info)?;
                     options = self.parse_strings(false)?;
                 }
                 Token::Tag(Word::Create) => {
-                    self.validate_argument(
-                        5,
-                        Capability::Mailbox.into(),
-                        token_info.line_num,
-                        token_info.line_pos,
-                    )?;
+                    self.validate_argument(5, Capability::Mailbox.into(), &token_info)?;
                     create = true;
                 }
                 Token::Tag(Word::SpecialUse) => {
-                    self.validate_argument(
-                        6,
-                        Capability::SpecialUse.into(),
-                        token_info.line_num,
-                        token_info.line_pos,
-                    )?;
-                    special_use = self.parse_string()?.into();
+                    self.validate_argument(6, Capability::SpecialUse.into(), &token_info)?;
+                    special_use = self.parse_special_use()?.into();
                 }
                 Token::Tag(Word::MailboxId) => {
-                    self.validate_argument(
-                        7,
-                        Capability::MailboxId.into(),
-                        token_info.line_num,
-                        token_info.line_pos,
-                    )?;
+                    self.validate_argument(7, Capability::MailboxId.into(), &token_info)?;
                     mailbox_id = self.parse_string()?.into();
                 }
                 Token::Tag(Word::Fcc) => {
-                    self.validate_argument(
-                        8,
-                        Capability::Fcc.into(),
-                        token_info.line_num,
-                        token_info.line_pos,
-                    )?;
+                    self.validate_argument(8, Capability::Fcc.into(), &token_info)?;
                     fcc = self.parse_string()?.into();
                 }
                 Token::Tag(Word::Flags) => {
-                    self.validate_argument(
-                        9,
-                        Capability::Imap4Flags.into(),
-                        token_info.line_num,
-                        token_info.line_pos,
-                    )?;
+                    self.validate_argument(9, Capability::Imap4Flags.into(), &token_info)?;
                     flags = self.parse_strings(false)?;
                 }
                 _ => {
@@ -182,10 +158,10 @@ impl<'x> CompilerState<'x> {
 }
 
 impl MapLocalVars for FileCarbonCopy<Value> {
-    fn map_local_vars(&mut self, last_id: usize) {
-        self.mailbox.map_local_vars(last_id);
-        self.mailbox_id.map_local_vars(last_id);
-        self.flags.map_local_vars(last_id);
-        self.special_use.map_local_vars(last_id);
+    fn map_local_vars(&mut self, map: &dyn Fn(usize) -> usize) {
+        self.mailbox.map_local_vars(map);
+        self.mailbox_id.map_local_vars(map);
+        self.flags.map_local_vars(map);
+        self.special_use.map_local_vars(map);
     }
 }
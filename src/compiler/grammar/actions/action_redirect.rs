@@ -29,11 +29,12 @@ use crate::compiler::{
         Capability,
     },
     lexer::{word::Word, Token},
-    CompileError, Value,
+    CompileError, ErrorType, Value,
 };
 
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
-pub(crate) struct Redirect {
+#[non_exhaustive]
+pub struct Redirect {
     pub copy: bool,
     pub address: Value,
     pub notify: Notify,
@@ -110,39 +111,19 @@ impl<'x> CompilerState<'x> {
             let token_info = self.tokens.unwrap_next()?;
             match token_info.token {
                 Token::Tag(Word::Copy) => {
-                    self.validate_argument(
-                        1,
-                        Capability::Copy.into(),
-                        token_info.line_num,
-                        token_info.line_pos,
-                    )?;
+                    self.validate_argument(1, Capability::Copy.into(), &token_info)?;
                     copy = true;
                 }
                 Token::Tag(Word::List) => {
-                    self.validate_argument(
-                        2,
-                        Capability::ExtLists.into(),
-                        token_info.line_num,
-                        token_info.line_pos,
-                    )?;
+                    self.validate_argument(2, Capability::ExtLists.into(), &token_info)?;
                     list = true;
                 }
                 Token::Tag(Word::ByTrace) => {
-                    self.validate_argument(
-                        3,
-                        Capability::RedirectDeliverBy.into(),
-                        token_info.line_num,
-                        token_info.line_pos,
-                    )?;
+                    self.validate_argument(3, Capability::RedirectDeliverBy.into(), &token_info)?;
                     by_trace = true;
                 }
                 Token::Tag(Word::ByMode) => {
-                    self.validate_argument(
-                        4,
-                        Capability::RedirectDeliverBy.into(),
-                        token_info.line_num,
-                        token_info.line_pos,
-                    )?;
+                    self.validate_argument(4, Capability::RedirectDeliverBy.into(), &token_info)?;
                     let by_mode_ = self.tokens.expect_static_string()?;
                     if by_mode_.eq_ignore_ascii_case("notify") {
                         by_mode = ByMode::Notify;
@@ -153,30 +134,15 @@ impl<'x> CompilerState<'x> {
                     }
                 }
                 Token::Tag(Word::ByTimeRelative) => {
-                    self.validate_argument(
-                        5,
-                        Capability::RedirectDeliverBy.into(),
-                        token_info.line_num,
-                        token_info.line_pos,
-                    )?;
+                    self.validate_argument(5, Capability::RedirectDeliverBy.into(), &token_info)?;
                     by_rlimit = (self.tokens.expect_number(u64::MAX as usize)? as u64).into();
                 }
                 Token::Tag(Word::ByTimeAbsolute) => {
-                    self.validate_argument(
-                        5,
-                        Capability::RedirectDeliverBy.into(),
-                        token_info.line_num,
-                        token_info.line_pos,
-                    )?;
+                    self.validate_argument(5, Capability::RedirectDeliverBy.into(), &token_info)?;
                     by_alimit = self.parse_string()?.into();
                 }
                 Token::Tag(Word::Ret) => {
-                    self.validate_argument(
-                        6,
-                        Capability::RedirectDsn.into(),
-                        token_info.line_num,
-                        token_info.line_pos,
-                    )?;
+                    self.validate_argument(6, Capability::RedirectDsn.into(), &token_info)?;
                     let ret_ = self.tokens.expect_static_string()?;
                     if ret_.eq_ignore_ascii_case("full") {
                         ret = Ret::Full;
@@ -187,35 +153,36 @@ impl<'x> CompilerState<'x> {
                     }
                 }
                 Token::Tag(Word::Notify) => {
-                    self.validate_argument(
-                        7,
-                        Capability::RedirectDsn.into(),
-                        token_info.line_num,
-                        token_info.line_pos,
-                    )?;
+                    self.validate_argument(7, Capability::RedirectDsn.into(), &token_info)?;
                     let notify_ = self.tokens.expect_static_string()?;
-                    if notify_.eq_ignore_ascii_case("never") {
-                        notify = Notify::Never;
-                    } else {
-                        let mut items = Vec::new();
-                        for item in notify_.split(',') {
-                            let item = item.trim();
-                            if item.eq_ignore_ascii_case("success") {
-                                items.push(NotifyItem::Success);
-                            } else if item.eq_ignore_ascii_case("failure") {
-                                items.push(NotifyItem::Failure);
-                            } else if item.eq_ignore_ascii_case("delay") {
-                                items.push(NotifyItem::Delay);
-                            }
-                        }
-                        if !items.is_empty() {
-                            notify = Notify::Items(items);
+                    let mut never = false;
+                    let mut items = Vec::new();
+                    for item in notify_.split(',') {
+                        let item = item.trim();
+                        let item = if item.eq_ignore_ascii_case("never") {
+                            never = true;
+                            continue;
+                        } else if item.eq_ignore_ascii_case("success") {
+                            NotifyItem::Success
+                        } else if item.eq_ignore_ascii_case("failure") {
+                            NotifyItem::Failure
+                        } else if item.eq_ignore_ascii_case("delay") {
+                            NotifyItem::Delay
                         } else {
                             return Err(
                                 token_info.expected("\"NEVER\" or \"SUCCESS, FAILURE, DELAY, ..\"")
                             );
+                        };
+                        if !items.contains(&item) {
+                            items.push(item);
                         }
                     }
+                    // NEVER cannot be combined with other values (RFC 3461, section 4.1)
+                    notify = match (never, items.is_empty()) {
+                        (true, true) => Notify::Never,
+                        (false, false) => Notify::Items(items),
+                        _ => return Err(token_info.custom(ErrorType::InvalidArguments)),
+                    };
                 }
                 _ => {
                     address = self.parse_string_token(token_info)?;
@@ -251,9 +218,9 @@ impl<'x> CompilerState<'x> {
 }
 
 impl MapLocalVars for ByTime<Value> {
-    fn map_local_vars(&mut self, last_id: usize) {
+    fn map_local_vars(&mut self, map: &dyn Fn(usize) -> usize) {
         if let ByTime::Absolute { alimit, .. } = self {
-            alimit.map_local_vars(last_id)
+            alimit.map_local_vars(map)
         }
     }
 }
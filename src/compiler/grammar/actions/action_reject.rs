@@ -29,7 +29,8 @@ use crate::compiler::{
 };
 
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
-pub(crate) struct Reject {
+#[non_exhaustive]
+pub struct Reject {
     pub ereject: bool,
     pub reason: Value,
 }
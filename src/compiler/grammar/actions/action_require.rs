@@ -26,25 +26,38 @@ use crate::compiler::{
         instruction::{CompilerState, Instruction},
         Capability,
     },
-    lexer::Token,
-    CompileError,
+    lexer::{tokenizer::TokenInfo, Token},
+    CompileError, WarningType,
 };
 
 impl<'x> CompilerState<'x> {
-    fn add_capability(&mut self, capabilities: &mut Vec<Capability>, capability: Capability) {
+    fn add_capability(
+        &mut self,
+        capabilities: &mut Vec<Capability>,
+        capability: Capability,
+        token_info: &TokenInfo,
+    ) {
+        if self.require_locations.contains_key(&capability) {
+            self.warnings
+                .push(token_info.warning(WarningType::DuplicateRequire(capability.clone())));
+        } else {
+            self.require_locations.insert(
+                capability.clone(),
+                (token_info.line_num, token_info.line_pos),
+            );
+        }
+
         if !self.has_capability(&capability) {
-            let parent_capability = if matches!(&capability, Capability::SpamTestPlus) {
-                Some(Capability::SpamTest)
-            } else {
-                None
-            };
             capabilities.push(capability.clone());
-            self.block.capabilities.insert(capability);
+            self.block.capabilities.insert(capability.clone());
 
-            if let Some(capability) = parent_capability {
-                if !self.has_capability(&capability) {
+            for capability in capability.implied() {
+                self.require_locations
+                    .entry(capability.clone())
+                    .or_insert((token_info.line_num, token_info.line_pos));
+                if !self.has_capability(capability) {
                     capabilities.push(capability.clone());
-                    self.block.capabilities.insert(capability);
+                    self.block.capabilities.insert(capability.clone());
                 }
             }
         }
@@ -58,10 +71,11 @@ impl<'x> CompilerState<'x> {
             Token::BracketOpen => loop {
                 let token_info = self.tokens.unwrap_next()?;
                 match token_info.token {
-                    Token::StringConstant(value) => {
+                    Token::StringConstant(ref value) => {
                         self.add_capability(
                             &mut capabilities,
                             Capability::parse(value.to_string().as_ref()),
+                            &token_info,
                         );
                         let token_info = self.tokens.unwrap_next()?;
                         match token_info.token {
@@ -77,10 +91,11 @@ impl<'x> CompilerState<'x> {
                     }
                 }
             },
-            Token::StringConstant(value) => {
+            Token::StringConstant(ref value) => {
                 self.add_capability(
                     &mut capabilities,
                     Capability::parse(value.to_string().as_ref()),
+                    &token_info,
                 );
             }
             _ => {
@@ -89,13 +104,17 @@ impl<'x> CompilerState<'x> {
         }
 
         if !capabilities.is_empty() {
+            // Keep capabilities sorted by name so compiled scripts serialize
+            // identically regardless of the order they were required in.
             if self.block.require_pos == usize::MAX {
+                capabilities.sort_by_cached_key(|capability| capability.to_string());
                 self.block.require_pos = self.instructions.len();
                 self.instructions.push(Instruction::Require(capabilities));
             } else if let Some(Instruction::Require(capabilties)) =
                 self.instructions.get_mut(self.block.require_pos)
             {
-                capabilties.extend(capabilities)
+                capabilties.extend(capabilities);
+                capabilties.sort_by_cached_key(|capability| capability.to_string());
             } else {
                 #[cfg(test)]
                 panic!(
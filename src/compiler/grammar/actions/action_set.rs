@@ -28,24 +28,65 @@ use crate::{
         grammar::{
             expr::Expression,
             instruction::{CompilerState, Instruction},
+            Capability,
         },
         lexer::{tokenizer::TokenInfo, word::Word, Token},
-        CompileError, ErrorType, Value, VariableType,
+        CompileError, CompileWarning, ErrorType, Value, VariableType, WarningType,
     },
     Envelope,
 };
 
+#[allow(clippy::large_enum_variant)]
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
-pub(crate) enum Modifier {
+#[non_exhaustive]
+pub enum Modifier {
     Lower,
     Upper,
     LowerFirst,
     UpperFirst,
     QuoteWildcard,
     QuoteRegex,
-    EncodeUrl,
+    QuoteMailto,
+    EncodeUrl { profile: UrlProfile },
+    DecodeUrl,
+    Trim,
+    TrimStart,
+    TrimEnd,
     Length,
     Replace { find: Value, replace: Value },
+    Default { value: Value },
+    Hash { algorithm: HashAlgo },
+    Encode { encoding: TransferEncoding },
+    Decode { encoding: TransferEncoding },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[non_exhaustive]
+pub enum TransferEncoding {
+    QuotedPrintable,
+}
+
+/// Characters left unencoded by `:encodeurl`, selected with a `:component`,
+/// `:path` or `:query` tag following it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[non_exhaustive]
+pub enum UrlProfile {
+    /// Only the RFC 3986 unreserved characters.
+    #[default]
+    Component,
+    /// Also keeps the sub-delimiters, `:`, `@` and `/`.
+    Path,
+    /// Like `Path` but also keeps `?`, while `+` is encoded so it is not
+    /// read back as a space.
+    Query,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[non_exhaustive]
+pub enum HashAlgo {
+    Md5,
+    Sha1,
+    Sha256,
 }
 
 impl Modifier {
@@ -57,22 +98,31 @@ impl Modifier {
             Modifier::UpperFirst => 30,
             Modifier::QuoteWildcard => 20,
             Modifier::QuoteRegex => 21,
-            Modifier::EncodeUrl => 15,
+            Modifier::QuoteMailto => 15,
+            Modifier::EncodeUrl { .. } => 15,
+            Modifier::DecodeUrl => 45,
+            Modifier::Trim | Modifier::TrimStart | Modifier::TrimEnd => 42,
             Modifier::Length => 10,
             Modifier::Replace { .. } => 40,
+            Modifier::Default { .. } => 50,
+            Modifier::Hash { .. } => 5,
+            Modifier::Decode { .. } => 45,
+            Modifier::Encode { .. } => 12,
         }
     }
 }
 
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
-pub(crate) struct Set {
+#[non_exhaustive]
+pub struct Set {
     pub modifiers: Vec<Modifier>,
     pub name: VariableType,
     pub value: Value,
 }
 
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
-pub(crate) struct Let {
+#[non_exhaustive]
+pub struct Let {
     pub name: VariableType,
     pub expr: Vec<Expression>,
 }
@@ -94,14 +144,38 @@ impl<'x> CompilerState<'x> {
                     | Word::UpperFirst
                     | Word::QuoteWildcard
                     | Word::QuoteRegex
+                    | Word::QuoteMailto
                     | Word::Length
-                    | Word::EncodeUrl),
+                    | Word::DecodeUrl
+                    | Word::Trim
+                    | Word::TrimStart
+                    | Word::TrimEnd),
                 ) => {
                     let modifier = word.into();
                     if !modifiers.contains(&modifier) {
                         modifiers.push(modifier);
                     }
                 }
+                Token::Tag(Word::EncodeUrl) => {
+                    let profile = match self.tokens.peek().map(|r| r.map(|t| &t.token)) {
+                        Some(Ok(Token::Tag(
+                            word @ (Word::Component | Word::Path | Word::Query),
+                        ))) => {
+                            let profile = match word {
+                                Word::Path => UrlProfile::Path,
+                                Word::Query => UrlProfile::Query,
+                                _ => UrlProfile::Component,
+                            };
+                            self.tokens.unwrap_next()?;
+                            profile
+                        }
+                        _ => UrlProfile::Component,
+                    };
+                    let modifier = Modifier::EncodeUrl { profile };
+                    if !modifiers.contains(&modifier) {
+                        modifiers.push(modifier);
+                    }
+                }
                 Token::Tag(Word::Replace) => {
                     let find = self.tokens.unwrap_next()?;
                     let replace = self.tokens.unwrap_next()?;
@@ -110,6 +184,44 @@ impl<'x> CompilerState<'x> {
                         replace: self.parse_string_token(replace)?,
                     });
                 }
+                Token::Tag(Word::Hash) => {
+                    self.validate_argument(0, Capability::Hash.into(), &token_info)?;
+                    let algorithm = self.tokens.unwrap_next()?;
+                    let algorithm = match &algorithm.token {
+                        Token::StringConstant(value) => HashAlgo::parse(value.to_string().as_ref()),
+                        _ => None,
+                    }
+                    .ok_or_else(|| algorithm.custom(ErrorType::InvalidArguments))?;
+                    let modifier = Modifier::Hash { algorithm };
+                    if !modifiers.contains(&modifier) {
+                        modifiers.push(modifier);
+                    }
+                }
+                Token::Tag(word @ (Word::Encode | Word::Decode)) => {
+                    self.validate_argument(0, Capability::Encoding.into(), &token_info)?;
+                    let encoding = self.tokens.unwrap_next()?;
+                    let encoding = match &encoding.token {
+                        Token::StringConstant(value) => {
+                            TransferEncoding::parse(value.to_string().as_ref())
+                        }
+                        _ => None,
+                    }
+                    .ok_or_else(|| encoding.custom(ErrorType::InvalidArguments))?;
+                    let modifier = if word == Word::Encode {
+                        Modifier::Encode { encoding }
+                    } else {
+                        Modifier::Decode { encoding }
+                    };
+                    if !modifiers.contains(&modifier) {
+                        modifiers.push(modifier);
+                    }
+                }
+                Token::Tag(Word::Default) => {
+                    let value = self.tokens.unwrap_next()?;
+                    modifiers.push(Modifier::Default {
+                        value: self.parse_string_token(value)?,
+                    });
+                }
                 Token::Tag(Word::Local) => {
                     is_local = true;
                 }
@@ -149,13 +261,26 @@ impl<'x> CompilerState<'x> {
         register_as_local: bool,
     ) -> Result<VariableType, CompileError> {
         match token_info.token {
-            Token::StringConstant(value) => self
-                .register_variable(value.into_string(), register_as_local)
-                .map_err(|error_type| CompileError {
-                    line_num: token_info.line_num,
-                    line_pos: token_info.line_pos,
-                    error_type,
-                }),
+            Token::StringConstant(value) => {
+                let name = value.into_string();
+                if register_as_local && self.is_var_block_scoped(&name) {
+                    self.warnings.push(CompileWarning {
+                        line_num: token_info.line_num,
+                        line_pos: token_info.line_pos,
+                        offset_start: token_info.offset_start,
+                        offset_end: token_info.offset_end,
+                        warning_type: WarningType::ShadowedVariable(name.clone()),
+                    });
+                }
+                self.register_variable(name, register_as_local)
+                    .map_err(|error_type| CompileError {
+                        line_num: token_info.line_num,
+                        line_pos: token_info.line_pos,
+                        offset_start: token_info.offset_start,
+                        offset_end: token_info.offset_end,
+                        error_type,
+                    })
+            }
             _ => Err(token_info.custom(ErrorType::ExpectedConstantString)),
         }
     }
@@ -169,9 +294,10 @@ impl<'x> CompilerState<'x> {
         if let Some((namespace, part)) = name.split_once('.') {
             match namespace {
                 "global" | "t" => Ok(VariableType::Global(part.to_string())),
-                "envelope" => Envelope::try_from(part)
+                "envelope" => Envelope::from_variable_name(part)
                     .map(VariableType::Envelope)
-                    .map_err(|_| ErrorType::InvalidNamespace(namespace.to_string())),
+                    .ok_or_else(|| ErrorType::InvalidEnvelope(part.to_string())),
+                "vnd" if part == "stalwart.score" => self.score_variable(),
                 _ => Err(ErrorType::InvalidNamespace(namespace.to_string())),
             }
         } else {
@@ -184,6 +310,54 @@ impl<'x> CompilerState<'x> {
     }
 }
 
+impl TransferEncoding {
+    pub fn parse(name: &str) -> Option<Self> {
+        if name.eq_ignore_ascii_case("qp") || name.eq_ignore_ascii_case("quoted-printable") {
+            Some(TransferEncoding::QuotedPrintable)
+        } else {
+            None
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            TransferEncoding::QuotedPrintable => "qp",
+        }
+    }
+}
+
+impl UrlProfile {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            UrlProfile::Component => "component",
+            UrlProfile::Path => "path",
+            UrlProfile::Query => "query",
+        }
+    }
+}
+
+impl HashAlgo {
+    pub fn parse(name: &str) -> Option<Self> {
+        if name.eq_ignore_ascii_case("md5") {
+            Some(HashAlgo::Md5)
+        } else if name.eq_ignore_ascii_case("sha1") {
+            Some(HashAlgo::Sha1)
+        } else if name.eq_ignore_ascii_case("sha256") {
+            Some(HashAlgo::Sha256)
+        } else {
+            None
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            HashAlgo::Md5 => "md5",
+            HashAlgo::Sha1 => "sha1",
+            HashAlgo::Sha256 => "sha256",
+        }
+    }
+}
+
 impl From<Word> for Modifier {
     fn from(word: Word) -> Self {
         match word {
@@ -193,8 +367,12 @@ impl From<Word> for Modifier {
             Word::UpperFirst => Modifier::UpperFirst,
             Word::QuoteWildcard => Modifier::QuoteWildcard,
             Word::QuoteRegex => Modifier::QuoteRegex,
+            Word::QuoteMailto => Modifier::QuoteMailto,
             Word::Length => Modifier::Length,
-            Word::EncodeUrl => Modifier::EncodeUrl,
+            Word::DecodeUrl => Modifier::DecodeUrl,
+            Word::Trim => Modifier::Trim,
+            Word::TrimStart => Modifier::TrimStart,
+            Word::TrimEnd => Modifier::TrimEnd,
             _ => unreachable!(),
         }
     }
@@ -37,7 +37,8 @@ use crate::{
 };
 
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
-pub(crate) struct Vacation {
+#[non_exhaustive]
+pub struct Vacation {
     pub subject: Option<Value>,
     pub from: Option<Value>,
     pub mime: bool,
@@ -46,7 +47,8 @@ pub(crate) struct Vacation {
 }
 
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
-pub(crate) struct TestVacation {
+#[non_exhaustive]
+pub struct TestVacation {
     pub addresses: Vec<Value>,
     pub period: Period,
     pub handle: Option<Value>,
@@ -54,7 +56,8 @@ pub(crate) struct TestVacation {
 }
 
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
-pub(crate) enum Period {
+#[non_exhaustive]
+pub enum Period {
     Days(u64),
     Seconds(u64),
     Default,
@@ -110,81 +113,53 @@ impl<'x> CompilerState<'x> {
             let token_info = self.tokens.unwrap_next()?;
             match token_info.token {
                 Token::Tag(Word::Mime) => {
-                    self.validate_argument(1, None, token_info.line_num, token_info.line_pos)?;
+                    self.validate_argument(1, None, &token_info)?;
                     mime = true;
                 }
                 Token::Tag(Word::Create) => {
-                    self.validate_argument(
-                        2,
-                        Capability::Mailbox.into(),
-                        token_info.line_num,
-                        token_info.line_pos,
-                    )?;
+                    self.validate_argument(2, Capability::Mailbox.into(), &token_info)?;
                     create = true;
                 }
                 Token::Tag(Word::Days) => {
-                    self.validate_argument(3, None, token_info.line_num, token_info.line_pos)?;
-                    period = Period::Days(self.tokens.expect_number(u64::MAX as usize)? as u64);
+                    self.validate_argument(3, None, &token_info)?;
+                    period = Period::Days(
+                        self.tokens.expect_number_in_range(1, u64::MAX as usize)? as u64,
+                    );
                 }
                 Token::Tag(Word::Seconds) => {
-                    self.validate_argument(
-                        3,
-                        Capability::VacationSeconds.into(),
-                        token_info.line_num,
-                        token_info.line_pos,
-                    )?;
+                    self.validate_argument(3, Capability::VacationSeconds.into(), &token_info)?;
                     period = Period::Seconds(self.tokens.expect_number(u64::MAX as usize)? as u64);
                 }
                 Token::Tag(Word::Subject) => {
-                    self.validate_argument(4, None, token_info.line_num, token_info.line_pos)?;
+                    self.validate_argument(4, None, &token_info)?;
                     subject = self.parse_string()?.into();
                 }
                 Token::Tag(Word::From) => {
-                    self.validate_argument(5, None, token_info.line_num, token_info.line_pos)?;
+                    self.validate_argument(5, None, &token_info)?;
                     from = self.parse_string()?.into();
                 }
                 Token::Tag(Word::Handle) => {
-                    self.validate_argument(6, None, token_info.line_num, token_info.line_pos)?;
+                    self.validate_argument(6, None, &token_info)?;
                     handle = self.parse_string()?.into();
                 }
                 Token::Tag(Word::SpecialUse) => {
-                    self.validate_argument(
-                        7,
-                        Capability::SpecialUse.into(),
-                        token_info.line_num,
-                        token_info.line_pos,
-                    )?;
-                    special_use = self.parse_string()?.into();
+                    self.validate_argument(7, Capability::SpecialUse.into(), &token_info)?;
+                    special_use = self.parse_special_use()?.into();
                 }
                 Token::Tag(Word::MailboxId) => {
-                    self.validate_argument(
-                        8,
-                        Capability::MailboxId.into(),
-                        token_info.line_num,
-                        token_info.line_pos,
-                    )?;
+                    self.validate_argument(8, Capability::MailboxId.into(), &token_info)?;
                     mailbox_id = self.parse_string()?.into();
                 }
                 Token::Tag(Word::Fcc) => {
-                    self.validate_argument(
-                        9,
-                        Capability::Fcc.into(),
-                        token_info.line_num,
-                        token_info.line_pos,
-                    )?;
+                    self.validate_argument(9, Capability::Fcc.into(), &token_info)?;
                     fcc = self.parse_string()?.into();
                 }
                 Token::Tag(Word::Flags) => {
-                    self.validate_argument(
-                        10,
-                        Capability::Imap4Flags.into(),
-                        token_info.line_num,
-                        token_info.line_pos,
-                    )?;
+                    self.validate_argument(10, Capability::Imap4Flags.into(), &token_info)?;
                     flags = self.parse_strings(false)?;
                 }
                 Token::Tag(Word::Addresses) => {
-                    self.validate_argument(11, None, token_info.line_num, token_info.line_pos)?;
+                    self.validate_argument(11, None, &token_info)?;
                     addresses = self.parse_strings(false)?;
                 }
                 _ => {
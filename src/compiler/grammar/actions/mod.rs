@@ -22,6 +22,7 @@
 */
 
 pub mod action_convert;
+pub mod action_discard;
 pub mod action_editheader;
 pub mod action_fileinto;
 pub mod action_flags;
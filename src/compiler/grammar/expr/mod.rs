@@ -31,7 +31,8 @@ pub mod parser;
 pub mod tokenizer;
 
 #[derive(Debug, PartialEq, Eq, Clone, Serialize, Deserialize)]
-pub(crate) enum Expression {
+#[non_exhaustive]
+pub enum Expression {
     Variable(VariableType),
     Constant(Constant),
     BinaryOperator(BinaryOperator),
@@ -43,7 +44,8 @@ pub(crate) enum Expression {
 }
 
 #[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
-pub(crate) enum Constant {
+#[non_exhaustive]
+pub enum Constant {
     Integer(i64),
     Float(f64),
     String(Arc<String>),
@@ -67,7 +69,8 @@ impl From<String> for Constant {
 }
 
 #[derive(Debug, PartialEq, Eq, Clone, Copy, Serialize, Deserialize)]
-pub(crate) enum BinaryOperator {
+#[non_exhaustive]
+pub enum BinaryOperator {
     Add,
     Subtract,
     Multiply,
@@ -86,7 +89,8 @@ pub(crate) enum BinaryOperator {
 }
 
 #[derive(Debug, PartialEq, Eq, Clone, Copy, Serialize, Deserialize)]
-pub(crate) enum UnaryOperator {
+#[non_exhaustive]
+pub enum UnaryOperator {
     Not,
     Minus,
 }
@@ -97,6 +101,7 @@ pub(crate) enum Token {
     Function {
         name: String,
         id: u32,
+        min_args: u32,
         num_args: u32,
     },
     Number(Number),
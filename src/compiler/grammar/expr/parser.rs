@@ -21,6 +21,8 @@
  * for more details.
 */
 
+use crate::compiler::ErrorType;
+
 use super::{tokenizer::Tokenizer, BinaryOperator, Expression, Token};
 
 pub(crate) struct ExpressionParser<'x, F>
@@ -50,10 +52,14 @@ where
         }
     }
 
-    pub fn parse(mut self) -> Result<Self, String> {
+    pub fn parse(mut self) -> Result<Self, ErrorType> {
         let mut last_is_var_or_fnc = false;
 
-        while let Some(token) = self.tokenizer.next()? {
+        while let Some(token) = self
+            .tokenizer
+            .next()
+            .map_err(ErrorType::InvalidExpression)?
+        {
             let mut is_var_or_fnc = false;
             match token {
                 Token::Variable(v) => {
@@ -91,22 +97,37 @@ where
                             Some((Token::UnaryOperator(uop), _)) => {
                                 self.output.push(Expression::UnaryOperator(uop))
                             }
-                            _ => return Err("Mismatched parentheses".to_string()),
+                            _ => {
+                                return Err(ErrorType::InvalidExpression(
+                                    "Mismatched parentheses".to_string(),
+                                ))
+                            }
                         }
                     }
 
-                    if let Some((Token::Function { id, num_args, name }, _)) =
-                        self.operator_stack.last()
+                    if let Some((
+                        Token::Function {
+                            id,
+                            min_args,
+                            num_args,
+                            name,
+                        },
+                        _,
+                    )) = self.operator_stack.last()
                     {
-                        let got_args = self.arg_count.pop().unwrap();
-                        if got_args != *num_args as i32 {
-                            return Err(if *id != u32::MAX {
-                                format!(
-                                    "Expression function {:?} expected {} arguments, got {}",
-                                    name, num_args, got_args
-                                )
+                        let got_args = self.arg_count.pop().ok_or_else(|| {
+                            ErrorType::InvalidExpression("Mismatched parentheses".to_string())
+                        })?;
+                        if got_args < *min_args as i32 || got_args > *num_args as i32 {
+                            return Err(if *id != ID_ARRAY_ACCESS {
+                                ErrorType::InvalidFunctionArguments {
+                                    name: name.clone(),
+                                    min_args: *min_args,
+                                    max_args: *num_args,
+                                    found: got_args.max(0) as u32,
+                                }
                             } else {
-                                "Missing array index".to_string()
+                                ErrorType::InvalidExpression("Missing array index".to_string())
                             });
                         }
 
@@ -115,7 +136,7 @@ where
                             ID_ARRAY_BUILD => Expression::ArrayBuild(*num_args),
                             id => Expression::Function {
                                 id,
-                                num_args: *num_args,
+                                num_args: got_args as u32,
                             },
                         };
 
@@ -165,11 +186,10 @@ where
                     self.operator_stack
                         .push((Token::BinaryOperator(bop), jmp_pos));
                 }
-                Token::Function { id, name, num_args } => {
+                Token::Function { .. } => {
                     self.inc_arg_count();
                     self.arg_count.push(0);
-                    self.operator_stack
-                        .push((Token::Function { id, name, num_args }, None))
+                    self.operator_stack.push((token, None))
                 }
                 Token::OpenBracket => {
                     // Array functions
@@ -184,6 +204,7 @@ where
                         Token::Function {
                             id,
                             name: String::from("array"),
+                            min_args: num_args,
                             num_args,
                         },
                         None,
@@ -220,7 +241,11 @@ where
                     self.output.push(Expression::BinaryOperator(bop))
                 }
                 Token::UnaryOperator(uop) => self.output.push(Expression::UnaryOperator(uop)),
-                _ => return Err("Invalid token on the operator stack".to_string()),
+                _ => {
+                    return Err(ErrorType::InvalidExpression(
+                        "Invalid token on the operator stack".to_string(),
+                    ))
+                }
             }
         }
 
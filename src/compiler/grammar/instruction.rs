@@ -21,46 +21,60 @@
  * for more details.
 */
 
+use std::{
+    cell::RefCell,
+    io::{ErrorKind, Read},
+    sync::Arc,
+};
+
 use ahash::{AHashMap, AHashSet};
+use mail_parser::HeaderName;
 use serde::{Deserialize, Serialize};
 
 use crate::{
     compiler::{
+        flow::{command_span, local_clears, local_var_name, unassigned_reads},
         grammar::{test::Test, MatchType},
         lexer::{tokenizer::Tokenizer, word::Word, Token},
-        CompileError, ErrorType, Value, VariableType,
+        visit::{walk_instruction, Visitor},
+        CompileError, CompileWarning, ErrorType, Value, VariableType, WarningType,
     },
-    Compiler, Sieve,
+    CapabilityUse, Compiler, Sieve,
 };
 
 use super::{
     actions::{
         action_convert::Convert,
+        action_discard::Discard,
         action_editheader::{AddHeader, DeleteHeader},
         action_fileinto::FileInto,
         action_flags::EditFlags,
-        action_include::Include,
+        action_include::{Include, IncludeLinker},
         action_keep::Keep,
         action_mime::{Enclose, ExtractText, ForEveryPart, Replace},
         action_notify::Notify,
         action_redirect::Redirect,
         action_reject::Reject,
-        action_set::{Let, Set},
+        action_set::{Let, Modifier, Set},
         action_vacation::Vacation,
     },
     expr::Expression,
     Capability, Clear, Invalid, While,
 };
 
-use super::tests::test_ihave::Error;
+use super::tests::{
+    test_address::TestAddress, test_date::TestDate, test_exists::TestExists,
+    test_header::TestHeader, test_ihave::Error,
+};
 
 #[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
-pub(crate) enum Instruction {
+#[non_exhaustive]
+pub enum Instruction {
     Require(Vec<Capability>),
     Keep(Keep),
     FileInto(FileInto),
     Redirect(Redirect),
-    Discard,
+    Discard(Discard),
     Stop,
     Invalid(Invalid),
     Test(Test),
@@ -126,14 +140,19 @@ pub(crate) struct Block {
     pub(crate) label: Option<String>,
     pub(crate) line_num: usize,
     pub(crate) line_pos: usize,
+    pub(crate) offset: usize,
     pub(crate) last_block_start: usize,
     pub(crate) if_jmps: Vec<usize>,
     pub(crate) break_jmps: Vec<usize>,
     pub(crate) match_test_pos: Vec<usize>,
     pub(crate) match_test_vars: u64,
+    // Only used for lookups, ids are handed out in declaration order so
+    // the compiled output does not depend on the map's iteration order.
     pub(crate) vars_local: AHashMap<String, usize>,
     pub(crate) capabilities: AHashSet<Capability>,
     pub(crate) require_pos: usize,
+    pub(crate) is_true: bool,
+    pub(crate) is_stopped: bool,
 }
 
 pub(crate) struct CompilerState<'x> {
@@ -150,33 +169,234 @@ pub(crate) struct CompilerState<'x> {
     pub(crate) vars_local: usize,
     pub(crate) param_check: [bool; MAX_PARAMS],
     pub(crate) includes_num: usize,
+    pub(crate) redirects_num: usize,
+    pub(crate) last_block_is_true: bool,
+    pub(crate) warnings: Vec<CompileWarning>,
+    pub(crate) body_pos: usize,
+    pub(crate) requires: Vec<CapabilityUse>,
+    pub(crate) require_locations: AHashMap<Capability, (usize, usize)>,
+    pub(crate) regex_cache: AHashMap<String, Arc<fancy_regex::Regex>>,
+    pub(crate) var_decls: Vec<VariableDecl>,
+    pub(crate) command_spans: Vec<CommandSpan>,
+    pub(crate) header_names: RefCell<Vec<HeaderName<'static>>>,
+}
+
+// A variable declared by a script, used to check that scripts compiled with
+// `compile_linked` agree on which names are global.
+#[derive(Debug, Clone)]
+pub(crate) struct VariableDecl {
+    pub(crate) name: String,
+    pub(crate) is_global: bool,
+    pub(crate) line_num: usize,
+    pub(crate) line_pos: usize,
+    pub(crate) offset: usize,
+    // Id of a local variable after block-scoped ids have been mapped
+    pub(crate) var_id: Option<usize>,
+}
+
+// Where a command starts in the source and the position of its first
+// instruction, used for line numbers at runtime and by `Compiler::lint`.
+#[derive(Debug, Clone)]
+pub(crate) struct CommandSpan {
+    pub(crate) pos: usize,
+    pub(crate) command: Word,
+    pub(crate) line_num: usize,
+    pub(crate) line_pos: usize,
+    pub(crate) offset_start: usize,
+    pub(crate) offset_end: usize,
+}
+
+impl CommandSpan {
+    pub(crate) fn error(&self, error_type: ErrorType) -> CompileError {
+        CompileError {
+            line_num: self.line_num,
+            line_pos: self.line_pos,
+            offset_start: self.offset_start,
+            offset_end: self.offset_end,
+            error_type,
+        }
+    }
 }
 
 impl Compiler {
+    /// Compiles a script. Any byte sequence is accepted as input: malformed
+    /// scripts, including invalid UTF-8, produce a `CompileError` rather
+    /// than a panic, so this can be used directly as a fuzzing target.
     pub fn compile(&self, script: &[u8]) -> Result<Sieve, CompileError> {
+        self.compile_(script, None, None, None, None)
+            .map_err(|err| self.missing_capabilities(script, err))
+    }
+
+    // In strict mode, replaces the first undeclared capability error with
+    // the full list of capabilities the script uses without a `require`.
+    pub(crate) fn missing_capabilities(&self, script: &[u8], err: CompileError) -> CompileError {
+        if !self.strict_capabilities
+            || !matches!(err.error_type, ErrorType::UndeclaredCapability(_))
+        {
+            return err;
+        }
+        let mut missing = match self
+            .clone()
+            .with_strict_capabilities(false)
+            .analyze_requires(script)
+        {
+            Ok(requires) => requires
+                .into_iter()
+                .filter_map(|(capability, location)| location.is_none().then_some(capability))
+                .collect::<Vec<_>>(),
+            Err(_) => return err,
+        };
+        if missing.is_empty() {
+            return err;
+        }
+        missing.sort_by_cached_key(|c| c.to_string());
+        CompileError {
+            error_type: ErrorType::MissingCapabilities(missing),
+            ..err
+        }
+    }
+
+    /// Compiles a script read from `reader`. This is a convenience wrapper
+    /// around `compile`: the script is buffered in memory before compiling,
+    /// but reading stops with `ErrorType::ScriptTooLong` as soon as more than
+    /// `max_script_size` bytes have been received, without consuming the
+    /// rest of the input. Read and size errors point at the first byte that
+    /// could not be read or went over the limit.
+    pub fn compile_reader<R: Read>(&self, mut reader: R) -> Result<Sieve, CompileError> {
+        let mut script = Vec::new();
+        let mut buf = [0u8; 8192];
+        let mut line_num = 1;
+        let mut line_start = usize::MAX;
+
+        loop {
+            match reader.read(&mut buf) {
+                Ok(0) => break,
+                Ok(bytes_read) => {
+                    let chunk = &buf[..bytes_read];
+                    let accepted = chunk.len().min(self.max_script_size - script.len());
+                    for (offset, ch) in chunk[..accepted].iter().enumerate() {
+                        if *ch == b'\n' {
+                            line_num += 1;
+                            line_start = script.len() + offset;
+                        }
+                    }
+                    if accepted < chunk.len() {
+                        let offset = script.len() + accepted;
+                        return Err(CompileError {
+                            line_num,
+                            line_pos: offset.wrapping_sub(line_start),
+                            offset_start: offset,
+                            offset_end: offset + 1,
+                            error_type: ErrorType::ScriptTooLong,
+                        });
+                    }
+                    script.extend_from_slice(chunk);
+                }
+                Err(err) if err.kind() == ErrorKind::Interrupted => (),
+                Err(err) => {
+                    return Err(CompileError {
+                        line_num,
+                        line_pos: script.len().wrapping_sub(line_start),
+                        offset_start: script.len(),
+                        offset_end: script.len(),
+                        error_type: ErrorType::ReadError(err.to_string()),
+                    });
+                }
+            }
+        }
+
+        self.compile(&script)
+    }
+
+    // Compiles a single test, without the surrounding `if` or a block, into
+    // a script that leaves the result of the test in `Context::test_result`.
+    pub(crate) fn compile_test(&self, test: &[u8]) -> Result<Sieve, CompileError> {
+        if test.len() > self.max_script_size {
+            return Err(CompileError {
+                line_num: 0,
+                line_pos: 0,
+                offset_start: 0,
+                offset_end: test.len(),
+                error_type: ErrorType::ScriptTooLong,
+            });
+        }
+
+        let mut state = CompilerState::new(self, test);
+        state.parse_test()?;
+        if let Some(token_info) = state.tokens.next() {
+            return Err(token_info?.expected("end of test"));
+        }
+
+        // Tests in an allof/anyof jump to the closing Jz, which now has
+        // nothing left to skip.
+        let end_pos = state.instructions.len();
+        if let Some(Instruction::Jz(jmp_pos)) = state.instructions.last_mut() {
+            *jmp_pos = end_pos;
+        }
+
+        let mut capabilities = state
+            .requires
+            .into_iter()
+            .map(|(capability, _)| capability)
+            .collect::<Vec<_>>();
+        capabilities.sort_by_cached_key(|c| c.to_string());
+        let body_pos = state
+            .instructions
+            .iter()
+            .position(Instruction::requires_body)
+            .map_or(state.body_pos, |pos| pos.min(state.body_pos));
+
+        Ok(Sieve {
+            instructions: state.instructions,
+            num_vars: std::cmp::max(state.vars_num_max, state.vars_num),
+            num_match_vars: state.vars_match_max,
+            capabilities,
+            header_names: state.header_names.into_inner(),
+            body_pos,
+            lines: Vec::new(),
+            comments: Vec::new(),
+            warnings: state.warnings,
+        })
+    }
+
+    /// Lists the capabilities used by a script in order of first use, each
+    /// paired with the line and column where it was required, or `None` if
+    /// the script uses it without a matching `require`.
+    pub fn analyze_requires(&self, script: &[u8]) -> Result<Vec<CapabilityUse>, CompileError> {
+        let mut requires = Vec::new();
+        self.clone().with_no_capability_check(true).compile_(
+            script,
+            None,
+            Some(&mut requires),
+            None,
+            None,
+        )?;
+        Ok(requires)
+    }
+
+    pub(crate) fn compile_(
+        &self,
+        script: &[u8],
+        mut linker: Option<&mut IncludeLinker>,
+        requires: Option<&mut Vec<CapabilityUse>>,
+        var_decls: Option<&mut Vec<VariableDecl>>,
+        spans: Option<&mut Vec<CommandSpan>>,
+    ) -> Result<Sieve, CompileError> {
         if script.len() > self.max_script_size {
             return Err(CompileError {
                 line_num: 0,
                 line_pos: 0,
+                offset_start: 0,
+                offset_end: script.len(),
                 error_type: ErrorType::ScriptTooLong,
             });
         }
 
-        let mut state = CompilerState {
-            compiler: self,
-            tokens: Tokenizer::new(self, script),
-            instructions: Vec::new(),
-            block_stack: Vec::new(),
-            block: Block::new(Word::Not),
-            last_block_type: Word::Not,
-            vars_global: AHashSet::new(),
-            vars_num: 0,
-            vars_num_max: 0,
-            vars_match_max: 0,
-            vars_local: 0,
-            param_check: [false; MAX_PARAMS],
-            includes_num: 0,
-        };
+        let mut state = CompilerState::new(self, script);
+        let mut linked = Vec::new();
+        if let Some(linker) = linker.as_deref_mut() {
+            state.header_names = RefCell::new(std::mem::take(&mut linker.header_names));
+        }
 
         while let Some(token_info) = state.tokens.next() {
             let token_info = token_info?;
@@ -186,25 +406,58 @@ impl Compiler {
                 Token::Identifier(instruction) => {
                     let mut is_new_block = None;
 
+                    state.command_spans.push(CommandSpan {
+                        pos: state.instructions.len(),
+                        command: instruction,
+                        line_num: token_info.line_num,
+                        line_pos: token_info.line_pos,
+                        offset_start: token_info.offset_start,
+                        offset_end: token_info.offset_end,
+                    });
+
+                    if state.block.is_stopped {
+                        state.block.is_stopped = false;
+                        state
+                            .warnings
+                            .push(token_info.warning(WarningType::UnreachableCode));
+                    }
+
                     match instruction {
                         Word::Require => {
                             state.parse_require()?;
                         }
                         Word::If => {
+                            let test_pos = state.instructions.len();
                             state.parse_test()?;
                             state.block.if_jmps.clear();
-                            is_new_block = Block::new(Word::If).into();
+                            let mut block = Block::new(Word::If);
+                            block.is_true = state.is_constant_true(test_pos);
+                            is_new_block = block.into();
                         }
                         Word::ElsIf => {
                             if let Word::If | Word::ElsIf = &state.last_block_type {
+                                if state.last_block_is_true {
+                                    state
+                                        .warnings
+                                        .push(token_info.warning(WarningType::UnreachableBranch));
+                                }
+                                let test_pos = state.instructions.len();
                                 state.parse_test()?;
-                                is_new_block = Block::new(Word::ElsIf).into();
+                                let mut block = Block::new(Word::ElsIf);
+                                block.is_true =
+                                    state.last_block_is_true || state.is_constant_true(test_pos);
+                                is_new_block = block.into();
                             } else {
                                 return Err(token_info.expected("'if' before 'elsif'"));
                             }
                         }
                         Word::Else => {
                             if let Word::If | Word::ElsIf = &state.last_block_type {
+                                if state.last_block_is_true {
+                                    state
+                                        .warnings
+                                        .push(token_info.warning(WarningType::UnreachableBranch));
+                                }
                                 is_new_block = Block::new(Word::Else).into();
                             } else {
                                 return Err(token_info.expected("'if' or 'elsif' before 'else'"));
@@ -214,22 +467,23 @@ impl Compiler {
                             state.parse_keep()?;
                         }
                         Word::FileInto => {
-                            state.validate_argument(
-                                0,
-                                Capability::FileInto.into(),
-                                token_info.line_num,
-                                token_info.line_pos,
-                            )?;
+                            state.validate_argument(0, Capability::FileInto.into(), &token_info)?;
                             state.parse_fileinto()?;
                         }
                         Word::Redirect => {
-                            state.parse_redirect()?;
+                            if state.redirects_num < self.max_redirects {
+                                state.parse_redirect()?;
+                                state.redirects_num += 1;
+                            } else {
+                                return Err(token_info.custom(ErrorType::TooManyRedirects));
+                            }
                         }
                         Word::Discard => {
-                            state.instructions.push(Instruction::Discard);
+                            state.parse_discard()?;
                         }
                         Word::Stop => {
                             state.instructions.push(Instruction::Stop);
+                            state.block.is_stopped = true;
                         }
 
                         // RFC 5703
@@ -237,8 +491,7 @@ impl Compiler {
                             state.validate_argument(
                                 0,
                                 Capability::ForEveryPart.into(),
-                                token_info.line_num,
-                                token_info.line_pos,
+                                &token_info,
                             )?;
 
                             if state
@@ -256,10 +509,10 @@ impl Compiler {
                             is_new_block = if let Some(Ok(Token::Tag(Word::Name))) =
                                 state.tokens.peek().map(|r| r.map(|t| &t.token))
                             {
-                                let tag = state.tokens.next().unwrap().unwrap();
+                                let tag = state.tokens.unwrap_next()?;
                                 let label = state.tokens.expect_static_string()?;
                                 for block in &state.block_stack {
-                                    if block.label.as_ref().map_or(false, |n| n.eq(&label)) {
+                                    if block.label.as_ref().is_some_and(|n| n.eq(&label)) {
                                         return Err(
                                             tag.custom(ErrorType::LabelAlreadyDefined(label))
                                         );
@@ -285,11 +538,10 @@ impl Compiler {
                                 state.validate_argument(
                                     0,
                                     Capability::ForEveryPart.into(),
-                                    token_info.line_num,
-                                    token_info.line_pos,
+                                    &token_info,
                                 )?;
 
-                                let tag = state.tokens.next().unwrap().unwrap();
+                                let tag = state.tokens.unwrap_next()?;
                                 let label = state.tokens.expect_static_string()?;
                                 let mut label_found = false;
                                 let mut num_pops = 0;
@@ -300,7 +552,7 @@ impl Compiler {
                                 {
                                     if let Word::ForEveryPart = &block.btype {
                                         num_pops += 1;
-                                        if block.label.as_ref().map_or(false, |n| n.eq(&label)) {
+                                        if block.label.as_ref().is_some_and(|n| n.eq(&label)) {
                                             state
                                                 .instructions
                                                 .push(Instruction::ForEveryPartPop(num_pops));
@@ -341,41 +593,25 @@ impl Compiler {
                             state.instructions.push(Instruction::Jmp(usize::MAX));
                         }
                         Word::Replace => {
-                            state.validate_argument(
-                                0,
-                                Capability::Replace.into(),
-                                token_info.line_num,
-                                token_info.line_pos,
-                            )?;
+                            state.validate_argument(0, Capability::Replace.into(), &token_info)?;
                             state.parse_replace()?;
                         }
                         Word::Enclose => {
-                            state.validate_argument(
-                                0,
-                                Capability::Enclose.into(),
-                                token_info.line_num,
-                                token_info.line_pos,
-                            )?;
+                            state.validate_argument(0, Capability::Enclose.into(), &token_info)?;
                             state.parse_enclose()?;
                         }
                         Word::ExtractText => {
                             state.validate_argument(
                                 0,
                                 Capability::ExtractText.into(),
-                                token_info.line_num,
-                                token_info.line_pos,
+                                &token_info,
                             )?;
                             state.parse_extracttext()?;
                         }
 
                         // RFC 6558
                         Word::Convert => {
-                            state.validate_argument(
-                                0,
-                                Capability::Convert.into(),
-                                token_info.line_num,
-                                token_info.line_pos,
-                            )?;
+                            state.validate_argument(0, Capability::Convert.into(), &token_info)?;
                             state.parse_convert()?;
                         }
 
@@ -384,8 +620,7 @@ impl Compiler {
                             state.validate_argument(
                                 0,
                                 Capability::EditHeader.into(),
-                                token_info.line_num,
-                                token_info.line_pos,
+                                &token_info,
                             )?;
                             state.parse_addheader()?;
                         }
@@ -393,8 +628,7 @@ impl Compiler {
                             state.validate_argument(
                                 0,
                                 Capability::EditHeader.into(),
-                                token_info.line_num,
-                                token_info.line_pos,
+                                &token_info,
                             )?;
                             state.parse_deleteheader()?;
                         }
@@ -404,62 +638,36 @@ impl Compiler {
                             state.validate_argument(
                                 0,
                                 Capability::Variables.into(),
-                                token_info.line_num,
-                                token_info.line_pos,
+                                &token_info,
                             )?;
                             state.parse_set()?;
                         }
 
                         // RFC 5435
                         Word::Notify => {
-                            state.validate_argument(
-                                0,
-                                Capability::Enotify.into(),
-                                token_info.line_num,
-                                token_info.line_pos,
-                            )?;
+                            state.validate_argument(0, Capability::Enotify.into(), &token_info)?;
                             state.parse_notify()?;
                         }
 
                         // RFC 5429
                         Word::Reject => {
-                            state.validate_argument(
-                                0,
-                                Capability::Reject.into(),
-                                token_info.line_num,
-                                token_info.line_pos,
-                            )?;
+                            state.validate_argument(0, Capability::Reject.into(), &token_info)?;
                             state.parse_reject(false)?;
                         }
                         Word::Ereject => {
-                            state.validate_argument(
-                                0,
-                                Capability::Ereject.into(),
-                                token_info.line_num,
-                                token_info.line_pos,
-                            )?;
+                            state.validate_argument(0, Capability::Ereject.into(), &token_info)?;
                             state.parse_reject(true)?;
                         }
 
                         // RFC 5230
                         Word::Vacation => {
-                            state.validate_argument(
-                                0,
-                                Capability::Vacation.into(),
-                                token_info.line_num,
-                                token_info.line_pos,
-                            )?;
+                            state.validate_argument(0, Capability::Vacation.into(), &token_info)?;
                             state.parse_vacation()?;
                         }
 
                         // RFC 5463
                         Word::Error => {
-                            state.validate_argument(
-                                0,
-                                Capability::Ihave.into(),
-                                token_info.line_num,
-                                token_info.line_pos,
-                            )?;
+                            state.validate_argument(0, Capability::Ihave.into(), &token_info)?;
                             state.parse_error()?;
                         }
 
@@ -468,8 +676,7 @@ impl Compiler {
                             state.validate_argument(
                                 0,
                                 Capability::Imap4Flags.into(),
-                                token_info.line_num,
-                                token_info.line_pos,
+                                &token_info,
                             )?;
                             state.parse_flag_action(instruction)?;
                         }
@@ -480,22 +687,31 @@ impl Compiler {
                                 state.validate_argument(
                                     0,
                                     Capability::Include.into(),
-                                    token_info.line_num,
-                                    token_info.line_pos,
+                                    &token_info,
                                 )?;
                                 state.parse_include()?;
                                 state.includes_num += 1;
+
+                                if let Some(linker) = linker.as_deref_mut() {
+                                    if let Some(Instruction::Include(include)) =
+                                        state.instructions.last()
+                                    {
+                                        linker.header_names = state.header_names.take();
+                                        let sieve = linker.resolve(self, include, &token_info)?;
+                                        state
+                                            .header_names
+                                            .replace(std::mem::take(&mut linker.header_names));
+                                        if let Some(sieve) = sieve {
+                                            linked.push((state.instructions.len() - 1, sieve));
+                                        }
+                                    }
+                                }
                             } else {
                                 return Err(token_info.custom(ErrorType::TooManyIncludes));
                             }
                         }
                         Word::Return => {
-                            state.validate_argument(
-                                0,
-                                Capability::Include.into(),
-                                token_info.line_num,
-                                token_info.line_pos,
-                            )?;
+                            state.validate_argument(0, Capability::Include.into(), &token_info)?;
                             let mut num_pops = 0;
 
                             for block in [&state.block]
@@ -516,21 +732,23 @@ impl Compiler {
                             state.instructions.push(Instruction::Return);
                         }
                         Word::Global => {
-                            state.validate_argument(
-                                0,
-                                Capability::Include.into(),
-                                token_info.line_num,
-                                token_info.line_pos,
-                            )?;
+                            state.validate_argument(0, Capability::Include.into(), &token_info)?;
                             state.validate_argument(
                                 0,
                                 Capability::Variables.into(),
-                                token_info.line_num,
-                                token_info.line_pos,
+                                &token_info,
                             )?;
                             for global in state.parse_static_strings()? {
                                 if !state.is_var_local(&global) {
                                     if global.len() < self.max_variable_name_size {
+                                        state.var_decls.push(VariableDecl {
+                                            name: global.to_ascii_lowercase(),
+                                            is_global: true,
+                                            line_num: token_info.line_num,
+                                            line_pos: token_info.line_pos,
+                                            offset: token_info.offset_start,
+                                            var_id: None,
+                                        });
                                         state.register_global_var(&global);
                                     } else {
                                         return Err(state
@@ -552,8 +770,7 @@ impl Compiler {
                             state.validate_argument(
                                 0,
                                 Capability::Expressions.into(),
-                                token_info.line_num,
-                                token_info.line_pos,
+                                &token_info,
                             )?;
                             state.parse_let()?;
                         }
@@ -561,8 +778,7 @@ impl Compiler {
                             state.validate_argument(
                                 0,
                                 Capability::Expressions.into(),
-                                token_info.line_num,
-                                token_info.line_pos,
+                                &token_info,
                             )?;
                             let expr = state.parse_expr()?;
                             state.instructions.push(Instruction::Eval(expr));
@@ -570,12 +786,7 @@ impl Compiler {
 
                         // While extension
                         Word::While => {
-                            state.validate_argument(
-                                0,
-                                Capability::While.into(),
-                                token_info.line_num,
-                                token_info.line_pos,
-                            )?;
+                            state.validate_argument(0, Capability::While.into(), &token_info)?;
 
                             is_new_block = Block::new(Word::While).into();
 
@@ -586,12 +797,7 @@ impl Compiler {
                             }));
                         }
                         Word::Continue => {
-                            state.validate_argument(
-                                0,
-                                Capability::While.into(),
-                                token_info.line_num,
-                                token_info.line_pos,
-                            )?;
+                            state.validate_argument(0, Capability::While.into(), &token_info)?;
                             let mut found_while = 0;
                             for block in [&state.block]
                                 .into_iter()
@@ -623,8 +829,10 @@ impl Compiler {
                                 continue;
                             } else {
                                 return Err(CompileError {
-                                    line_num: state.block.line_num,
-                                    line_pos: state.block.line_pos,
+                                    line_num: token_info.line_num,
+                                    line_pos: token_info.line_pos,
+                                    offset_start: token_info.offset_start,
+                                    offset_end: token_info.offset_end,
                                     error_type: ErrorType::UnexpectedToken {
                                         expected: "command".into(),
                                         found: instruction.to_string(),
@@ -635,18 +843,20 @@ impl Compiler {
                     }
 
                     if let Some(mut new_block) = is_new_block {
-                        new_block.line_num = state.tokens.line_num;
-                        new_block.line_pos = state.tokens.pos - state.tokens.line_start;
-
                         state.tokens.expect_token(Token::CurlyOpen)?;
+                        new_block.line_num = state.tokens.line_num;
+                        new_block.line_pos = state.tokens.line_pos();
+                        new_block.offset = state.tokens.pos;
                         if state.block_stack.len() < self.max_nested_blocks {
                             state.block.last_block_start = state.instructions.len() - 1;
                             state.block_stack.push(state.block);
                             state.block = new_block;
                         } else {
                             return Err(CompileError {
-                                line_num: state.block.line_num,
-                                line_pos: state.block.line_pos,
+                                line_num: new_block.line_num,
+                                line_pos: new_block.line_pos,
+                                offset_start: new_block.offset,
+                                offset_end: new_block.offset + 1,
                                 error_type: ErrorType::TooManyNestedBlocks,
                             });
                         }
@@ -708,6 +918,7 @@ impl Compiler {
                                 state.last_block_type = Word::Not;
                             } else {
                                 state.last_block_type = state.block.btype;
+                                state.last_block_is_true = state.block.is_true;
                             }
                         }
                         Word::Else => {
@@ -759,21 +970,25 @@ impl Compiler {
                         arguments.push(state.parse_string()?);
                         state.instructions.push(Instruction::TestCmd(arguments));
                         let mut new_block = Block::new(Word::Else);
-                        new_block.line_num = state.tokens.line_num;
-                        new_block.line_pos = state.tokens.pos - state.tokens.line_start;
                         state.tokens.expect_token(Token::CurlyOpen)?;
+                        new_block.line_num = state.tokens.line_num;
+                        new_block.line_pos = state.tokens.line_pos();
+                        new_block.offset = state.tokens.pos;
                         state.block.last_block_start = state.instructions.len() - 1;
                         state.block_stack.push(state.block);
                         state.block = new_block;
                     } else {
                         loop {
-                            arguments.push(match state.tokens.unwrap_next()?.token {
+                            let token_info = state.tokens.unwrap_next()?;
+                            arguments.push(match token_info.token {
                                 Token::StringConstant(s) => Value::from(s),
                                 Token::StringVariable(s) => state
                                     .tokenize_string(&s, true)
                                     .map_err(|error_type| CompileError {
                                         line_num: 0,
                                         line_pos: 0,
+                                        offset_start: 0,
+                                        offset_end: 0,
                                         error_type,
                                     })?,
                                 Token::Number(n) => {
@@ -783,7 +998,7 @@ impl Compiler {
                                 Token::Tag(s) => Value::Text(format!(":{s}").into()),
                                 Token::Unknown(s) => Value::Text(s.into()),
                                 Token::Semicolon => break,
-                                other => panic!("Invalid test param {other:?}"),
+                                _ => return Err(token_info.expected("test parameter")),
                             });
                         }
                         state.instructions.push(Instruction::TestCmd(arguments));
@@ -800,8 +1015,10 @@ impl Compiler {
                         }));
                     } else {
                         return Err(CompileError {
-                            line_num: state.block.line_num,
-                            line_pos: state.block.line_pos,
+                            line_num: token_info.line_num,
+                            line_pos: token_info.line_pos,
+                            offset_start: token_info.offset_start,
+                            offset_end: token_info.offset_end,
                             error_type: ErrorType::UnexpectedToken {
                                 expected: "command".into(),
                                 found: instruction,
@@ -819,26 +1036,158 @@ impl Compiler {
             return Err(CompileError {
                 line_num: state.block.line_num,
                 line_pos: state.block.line_pos,
+                offset_start: state.block.offset,
+                offset_end: state.block.offset + 1,
                 error_type: ErrorType::UnterminatedBlock,
             });
         }
 
+        // Declared capabilities and those implied by the commands in use
+        let mut capabilities = state
+            .require_locations
+            .keys()
+            .chain(state.requires.iter().map(|(capability, _)| capability))
+            .cloned()
+            .collect::<AHashSet<_>>()
+            .into_iter()
+            .collect::<Vec<_>>();
+        capabilities.sort_by_cached_key(|c| c.to_string());
+
+        if let Some(requires) = requires {
+            *requires = std::mem::take(&mut state.requires);
+        }
         // Map local variables
         let mut num_vars = std::cmp::max(state.vars_num_max, state.vars_num);
+        let map_var = |id: usize| {
+            if id > num_vars {
+                (usize::MAX - id) + num_vars
+            } else {
+                id
+            }
+        };
         if state.vars_local > 0 {
-            state.map_local_vars(num_vars);
-            num_vars += state.vars_local;
+            state.instructions.map_local_vars(&map_var);
+        }
+        for decl in state.var_decls.iter_mut() {
+            decl.var_id = decl.var_id.map(map_var);
+        }
+        num_vars += state.vars_local;
+
+        // Line of the command that emitted each instruction
+        let mut lines = Vec::with_capacity(state.instructions.len());
+        for (idx, span) in state.command_spans.iter().enumerate() {
+            let end = state
+                .command_spans
+                .get(idx + 1)
+                .map_or(state.instructions.len(), |next| next.pos);
+            lines.resize(end, span.line_num as u32);
         }
 
-        Ok(Sieve {
+        // Position of the first instruction that needs the message body
+        let body_pos = state
+            .instructions
+            .iter()
+            .position(Instruction::requires_body)
+            .map_or(state.body_pos, |pos| pos.min(state.body_pos));
+
+        let mut sieve = Sieve {
             instructions: state.instructions,
             num_vars,
             num_match_vars: state.vars_match_max,
+            capabilities,
+            header_names: state.header_names.into_inner(),
+            body_pos,
+            lines,
+            comments: std::mem::take(&mut state.tokens.comments),
+            warnings: state.warnings,
+        };
+
+        // Variables read before they are assigned on some path
+        let clears = local_clears(&sieve.instructions);
+        for (pos, id) in unassigned_reads(&sieve) {
+            if let (Some(name), Some(span)) = (
+                local_var_name(&state.var_decls, &clears, pos, id),
+                command_span(&state.command_spans, pos),
+            ) {
+                sieve.warnings.push(CompileWarning {
+                    line_num: span.line_num,
+                    line_pos: span.line_pos,
+                    offset_start: span.offset_start,
+                    offset_end: span.offset_end,
+                    warning_type: WarningType::UnassignedVariable(name.to_string()),
+                });
+            }
+        }
+        sieve.warnings.sort_by_key(|warning| warning.offset_start);
+
+        // Inlined scripts share the redirect limit of the script including
+        // them, the error points at the redirect or include exceeding it
+        if !linked.is_empty() {
+            let mut redirects_num = 0;
+            let mut included = linked.iter().peekable();
+            for (pos, instruction) in sieve.instructions.iter().enumerate() {
+                redirects_num += match included.next_if(|(include_pos, _)| *include_pos == pos) {
+                    Some((_, Some(sieve))) => sieve.redirects_num(),
+                    _ => matches!(instruction, Instruction::Redirect(_)) as usize,
+                };
+                if redirects_num > self.max_redirects {
+                    if let Some(span) = command_span(&state.command_spans, pos) {
+                        return Err(span.error(ErrorType::TooManyRedirects));
+                    }
+                }
+            }
+        }
+
+        if let Some(var_decls) = var_decls {
+            *var_decls = std::mem::take(&mut state.var_decls);
+        }
+        if let Some(spans) = spans {
+            *spans = std::mem::take(&mut state.command_spans);
+        }
+
+        Ok(if !linked.is_empty() {
+            sieve.link(linked)
+        } else {
+            sieve
         })
     }
 }
 
 impl<'x> CompilerState<'x> {
+    fn new(compiler: &'x Compiler, script: &'x [u8]) -> Self {
+        CompilerState {
+            compiler,
+            tokens: Tokenizer::new(compiler, script),
+            instructions: Vec::new(),
+            block_stack: Vec::new(),
+            block: Block::new(Word::Not),
+            last_block_type: Word::Not,
+            vars_global: AHashSet::new(),
+            vars_num: 0,
+            vars_num_max: 0,
+            vars_match_max: 0,
+            vars_local: 0,
+            param_check: [false; MAX_PARAMS],
+            includes_num: 0,
+            redirects_num: 0,
+            last_block_is_true: false,
+            warnings: Vec::new(),
+            body_pos: usize::MAX,
+            requires: Vec::new(),
+            require_locations: AHashMap::new(),
+            regex_cache: AHashMap::new(),
+            var_decls: Vec::new(),
+            command_spans: Vec::new(),
+            header_names: RefCell::new(Vec::new()),
+        }
+    }
+
+    // Whether the condition compiled at test_pos is a single, constant true test
+    fn is_constant_true(&self, test_pos: usize) -> bool {
+        self.instructions.len() == test_pos + 2
+            && matches!(self.instructions[test_pos], Instruction::Test(Test::True))
+    }
+
     pub(crate) fn is_var_local(&self, name: &str) -> bool {
         let name = name.to_ascii_lowercase();
         if self.block.vars_local.contains_key(&name) {
@@ -853,6 +1202,18 @@ impl<'x> CompilerState<'x> {
         }
     }
 
+    // Whether the variable is bound to a nested block rather than the script
+    pub(crate) fn is_var_block_scoped(&self, name: &str) -> bool {
+        let name = name.to_ascii_lowercase();
+        !self.block_stack.is_empty()
+            && (self.block.vars_local.contains_key(&name)
+                || self
+                    .block_stack
+                    .iter()
+                    .skip(1)
+                    .any(|block| block.vars_local.contains_key(&name)))
+    }
+
     pub(crate) fn is_var_global(&self, name: &str) -> bool {
         let name = name.to_ascii_lowercase();
         self.vars_global.contains(&name)
@@ -860,8 +1221,18 @@ impl<'x> CompilerState<'x> {
 
     pub(crate) fn register_local_var(&mut self, name: String, register_as_local: bool) -> usize {
         if let Some(var_id) = self.get_local_var(&name) {
-            var_id
-        } else if !register_as_local || self.block_stack.is_empty() {
+            return var_id;
+        }
+
+        self.var_decls.push(VariableDecl {
+            name: name.to_ascii_lowercase(),
+            is_global: false,
+            line_num: self.tokens.line_num,
+            line_pos: self.tokens.line_pos(),
+            offset: self.tokens.pos,
+            var_id: None,
+        });
+        let var_id = if !register_as_local || self.block_stack.is_empty() {
             let var_id = self.vars_num;
             self.block.vars_local.insert(name, var_id);
             self.vars_num += 1;
@@ -875,7 +1246,9 @@ impl<'x> CompilerState<'x> {
                 .insert(name, usize::MAX - self.vars_local);
             self.vars_local += 1;
             var_id
-        }
+        };
+        self.var_decls.last_mut().unwrap().var_id = Some(var_id);
+        var_id
     }
 
     pub(crate) fn register_global_var(&mut self, name: &str) {
@@ -912,7 +1285,7 @@ impl<'x> CompilerState<'x> {
             debug_assert!(num < 63);
 
             for pos in &block.match_test_pos {
-                if let Instruction::Test(test) = &mut self.instructions[*pos] {
+                if let Some(Instruction::Test(test)) = self.instructions.get_mut(*pos) {
                     let match_type = match test {
                         Test::Address(t) => &mut t.match_type,
                         Test::Body(t) => &mut t.match_type,
@@ -939,10 +1312,11 @@ impl<'x> CompilerState<'x> {
                         debug_assert!(false, "This should not have happened");
                         return false;
                     }
-                } else {
-                    debug_assert!(false, "This should not have happened");
-                    return false;
                 }
+                // Otherwise the match type belongs to an action such as
+                // deleteheader, which does not set match variables, or to
+                // the test being parsed, whose arguments can only refer to
+                // earlier matches.
             }
             true
         } else {
@@ -950,6 +1324,59 @@ impl<'x> CompilerState<'x> {
         }
     }
 
+    /// Returns the number of the capture group called `name` in the constant
+    /// `:regex` keys of the test that controls the current block.
+    pub(crate) fn capture_group(&self, name: &str) -> Option<usize> {
+        self.regex_keys().find_map(|regex| {
+            regex
+                .capture_names()
+                .position(|group| group.is_some_and(|group| group.eq_ignore_ascii_case(name)))
+        })
+    }
+
+    /// Returns the largest number of capture groups in the constant `:regex`
+    /// keys of the test that controls the current block.
+    pub(crate) fn capture_group_count(&self) -> Option<usize> {
+        self.regex_keys()
+            .map(|regex| regex.captures_len().saturating_sub(1))
+            .max()
+    }
+
+    fn regex_keys(&self) -> impl Iterator<Item = &fancy_regex::Regex> + use<'_, 'x> {
+        let block = std::iter::once(&self.block)
+            .chain(self.block_stack.iter().rev())
+            .find(|block| !block.match_test_pos.is_empty());
+
+        block
+            .into_iter()
+            .flat_map(|block| block.match_test_pos.iter())
+            .filter_map(|pos| {
+                let (match_type, key_list) = match self.instructions.get(*pos) {
+                    Some(Instruction::Test(test)) => match test {
+                        Test::Address(t) => (&t.match_type, t.key_list.as_slice()),
+                        Test::Body(t) => (&t.match_type, t.key_list.as_slice()),
+                        Test::Date(t) => (&t.match_type, t.key_list.as_slice()),
+                        Test::CurrentDate(t) => (&t.match_type, t.key_list.as_slice()),
+                        Test::Envelope(t) => (&t.match_type, t.key_list.as_slice()),
+                        Test::Header(t) => (&t.match_type, t.key_list.as_slice()),
+                        Test::Metadata(t) => (&t.match_type, t.key_list.as_slice()),
+                        Test::NotifyMethodCapability(t) => (&t.match_type, t.key_list.as_slice()),
+                        Test::String(t) | Test::Environment(t) => {
+                            (&t.match_type, t.key_list.as_slice())
+                        }
+                        _ => return None,
+                    },
+                    _ => return None,
+                };
+                matches!(match_type, MatchType::Regex(_)).then_some(key_list)
+            })
+            .flatten()
+            .filter_map(|key| match key {
+                Value::Regex(regex) => Some(regex.regex.as_ref()),
+                _ => None,
+            })
+    }
+
     pub(crate) fn block_end(&mut self) {
         let vars_num_block = self.block.vars_local.len();
         if vars_num_block > 0 {
@@ -970,238 +1397,328 @@ impl<'x> CompilerState<'x> {
             }));
         }
     }
+}
 
-    fn map_local_vars(&mut self, last_id: usize) {
-        for instruction in &mut self.instructions {
-            match instruction {
-                Instruction::Test(v) => v.map_local_vars(last_id),
-                Instruction::Keep(k) => k.flags.map_local_vars(last_id),
-                Instruction::FileInto(v) => {
-                    v.folder.map_local_vars(last_id);
-                    v.flags.map_local_vars(last_id);
-                    v.mailbox_id.map_local_vars(last_id);
-                    v.special_use.map_local_vars(last_id);
-                }
-                Instruction::Redirect(v) => {
-                    v.address.map_local_vars(last_id);
-                    v.by_time.map_local_vars(last_id);
-                }
-                Instruction::Replace(v) => {
-                    v.subject.map_local_vars(last_id);
-                    v.from.map_local_vars(last_id);
-                    v.replacement.map_local_vars(last_id);
-                }
-                Instruction::Enclose(v) => {
-                    v.subject.map_local_vars(last_id);
-                    v.headers.map_local_vars(last_id);
-                    v.value.map_local_vars(last_id);
-                }
-                Instruction::ExtractText(v) => {
-                    v.name.map_local_vars(last_id);
-                }
-                Instruction::Convert(v) => {
-                    v.from_media_type.map_local_vars(last_id);
-                    v.to_media_type.map_local_vars(last_id);
-                    v.transcoding_params.map_local_vars(last_id);
-                }
-                Instruction::AddHeader(v) => {
-                    v.field_name.map_local_vars(last_id);
-                    v.value.map_local_vars(last_id);
-                }
-                Instruction::DeleteHeader(v) => {
-                    v.field_name.map_local_vars(last_id);
-                    v.value_patterns.map_local_vars(last_id);
-                }
-                Instruction::Set(v) => {
-                    v.name.map_local_vars(last_id);
-                    v.value.map_local_vars(last_id);
-                }
-                Instruction::Let(v) => {
-                    v.name.map_local_vars(last_id);
-                    v.expr.map_local_vars(last_id);
-                }
-                Instruction::While(v) => {
-                    v.expr.map_local_vars(last_id);
-                }
-                Instruction::Eval(v) => {
-                    v.map_local_vars(last_id);
-                }
-                Instruction::Notify(v) => {
-                    v.from.map_local_vars(last_id);
-                    v.importance.map_local_vars(last_id);
-                    v.options.map_local_vars(last_id);
-                    v.message.map_local_vars(last_id);
-                    v.fcc.map_local_vars(last_id);
-                    v.method.map_local_vars(last_id);
-                }
-                Instruction::Reject(v) => {
-                    v.reason.map_local_vars(last_id);
-                }
-                Instruction::Vacation(v) => {
-                    v.subject.map_local_vars(last_id);
-                    v.from.map_local_vars(last_id);
-                    v.fcc.map_local_vars(last_id);
-                    v.reason.map_local_vars(last_id);
-                }
-                Instruction::Error(v) => {
-                    v.message.map_local_vars(last_id);
-                }
-                Instruction::EditFlags(v) => {
-                    v.name.map_local_vars(last_id);
-                    v.flags.map_local_vars(last_id);
-                }
-                Instruction::Include(v) => {
-                    v.value.map_local_vars(last_id);
-                }
-                _ => {}
+impl Instruction {
+    pub(crate) fn map_jumps(&mut self, map: &dyn Fn(usize) -> usize) {
+        match self {
+            Instruction::Jmp(pos) | Instruction::Jz(pos) | Instruction::Jnz(pos) => {
+                *pos = map(*pos);
+            }
+            Instruction::ForEveryPart(v) => {
+                v.jz_pos = map(v.jz_pos);
+            }
+            Instruction::While(v) => {
+                v.jz_pos = map(v.jz_pos);
             }
+            _ => {}
         }
     }
+
+    pub(crate) fn requires_body(&self) -> bool {
+        match self {
+            Instruction::ForEveryPartPush
+            | Instruction::ForEveryPart(_)
+            | Instruction::Replace(_)
+            | Instruction::Enclose(_)
+            | Instruction::ExtractText(_)
+            | Instruction::Convert(_)
+            | Instruction::AddHeader(_)
+            | Instruction::DeleteHeader(_) => true,
+            Instruction::Test(test) => match test {
+                Test::Body(_) | Test::Convert(_) => true,
+                Test::Header(TestHeader { mime_anychild, .. })
+                | Test::Address(TestAddress { mime_anychild, .. })
+                | Test::Exists(TestExists { mime_anychild, .. })
+                | Test::Date(TestDate { mime_anychild, .. }) => *mime_anychild,
+                _ => false,
+            },
+            _ => false,
+        }
+    }
+
+    // Returns `true` if running this instruction needs the message body
+    // parsed, either because of what it does or because one of its
+    // arguments reads a part variable such as `${body.text}`.
+    pub(crate) fn needs_body(&self) -> bool {
+        if self.requires_body() {
+            return true;
+        }
+        let mut access = PartAccess::default();
+        walk_instruction(&mut access, 0, self, &mut Vec::new());
+        access.reads_part
+    }
+}
+
+#[derive(Default)]
+struct PartAccess {
+    reads_part: bool,
+}
+
+impl Visitor for PartAccess {
+    fn visit_value_part(&mut self, _: usize, part: &Value) {
+        self.reads_part |= matches!(part, Value::Variable(VariableType::Part(_)));
+    }
+
+    fn visit_expression(&mut self, _: usize, expr: &[Expression]) {
+        self.reads_part |= expr
+            .iter()
+            .any(|expr| matches!(expr, Expression::Variable(VariableType::Part(_))));
+    }
 }
 
 pub trait MapLocalVars {
-    fn map_local_vars(&mut self, last_id: usize);
+    fn map_local_vars(&mut self, map: &dyn Fn(usize) -> usize);
+}
+
+impl MapLocalVars for Instruction {
+    fn map_local_vars(&mut self, map: &dyn Fn(usize) -> usize) {
+        match self {
+            Instruction::Test(v) => v.map_local_vars(map),
+            Instruction::Keep(k) => k.flags.map_local_vars(map),
+            Instruction::FileInto(v) => {
+                v.folder.map_local_vars(map);
+                v.flags.map_local_vars(map);
+                v.mailbox_id.map_local_vars(map);
+                v.special_use.map_local_vars(map);
+            }
+            Instruction::Redirect(v) => {
+                v.address.map_local_vars(map);
+                v.by_time.map_local_vars(map);
+            }
+            Instruction::Replace(v) => {
+                v.subject.map_local_vars(map);
+                v.from.map_local_vars(map);
+                v.replacement.map_local_vars(map);
+            }
+            Instruction::Enclose(v) => {
+                v.subject.map_local_vars(map);
+                v.headers.map_local_vars(map);
+                v.value.map_local_vars(map);
+            }
+            Instruction::ExtractText(v) => {
+                v.modifiers.map_local_vars(map);
+                v.name.map_local_vars(map);
+            }
+            Instruction::Convert(v) => {
+                v.from_media_type.map_local_vars(map);
+                v.to_media_type.map_local_vars(map);
+                v.transcoding_params.map_local_vars(map);
+            }
+            Instruction::AddHeader(v) => {
+                v.field_name.map_local_vars(map);
+                v.value.map_local_vars(map);
+            }
+            Instruction::DeleteHeader(v) => {
+                v.field_name.map_local_vars(map);
+                v.value_patterns.map_local_vars(map);
+            }
+            Instruction::Set(v) => {
+                v.modifiers.map_local_vars(map);
+                v.name.map_local_vars(map);
+                v.value.map_local_vars(map);
+            }
+            Instruction::Let(v) => {
+                v.name.map_local_vars(map);
+                v.expr.map_local_vars(map);
+            }
+            Instruction::While(v) => {
+                v.expr.map_local_vars(map);
+            }
+            Instruction::Eval(v) => {
+                v.map_local_vars(map);
+            }
+            Instruction::Notify(v) => {
+                v.from.map_local_vars(map);
+                v.importance.map_local_vars(map);
+                v.options.map_local_vars(map);
+                v.message.map_local_vars(map);
+                v.fcc.map_local_vars(map);
+                v.method.map_local_vars(map);
+            }
+            Instruction::Discard(v) => {
+                v.reason.map_local_vars(map);
+            }
+            Instruction::Reject(v) => {
+                v.reason.map_local_vars(map);
+            }
+            Instruction::Vacation(v) => {
+                v.subject.map_local_vars(map);
+                v.from.map_local_vars(map);
+                v.fcc.map_local_vars(map);
+                v.reason.map_local_vars(map);
+            }
+            Instruction::Error(v) => {
+                v.message.map_local_vars(map);
+            }
+            Instruction::EditFlags(v) => {
+                v.name.map_local_vars(map);
+                v.flags.map_local_vars(map);
+            }
+            Instruction::Include(v) => {
+                v.value.map_local_vars(map);
+            }
+            Instruction::Clear(v) if v.local_vars_num > 0 => {
+                v.local_vars_idx = map(v.local_vars_idx as usize) as u32;
+            }
+            _ => {}
+        }
+    }
 }
 
 impl MapLocalVars for Test {
-    fn map_local_vars(&mut self, last_id: usize) {
+    fn map_local_vars(&mut self, map: &dyn Fn(usize) -> usize) {
         match self {
             Test::Address(v) => {
-                v.header_list.map_local_vars(last_id);
-                v.key_list.map_local_vars(last_id);
+                v.header_list.map_local_vars(map);
+                v.key_list.map_local_vars(map);
             }
             Test::Envelope(v) => {
-                v.key_list.map_local_vars(last_id);
+                v.key_list.map_local_vars(map);
             }
             Test::Exists(v) => {
-                v.header_names.map_local_vars(last_id);
+                v.header_names.map_local_vars(map);
             }
             Test::Header(v) => {
-                v.key_list.map_local_vars(last_id);
-                v.header_list.map_local_vars(last_id);
-                v.mime_opts.map_local_vars(last_id);
+                v.key_list.map_local_vars(map);
+                v.header_list.map_local_vars(map);
+                v.mime_opts.map_local_vars(map);
             }
             Test::Body(v) => {
-                v.key_list.map_local_vars(last_id);
+                v.key_list.map_local_vars(map);
             }
             Test::Convert(v) => {
-                v.from_media_type.map_local_vars(last_id);
-                v.to_media_type.map_local_vars(last_id);
-                v.transcoding_params.map_local_vars(last_id);
+                v.from_media_type.map_local_vars(map);
+                v.to_media_type.map_local_vars(map);
+                v.transcoding_params.map_local_vars(map);
+            }
+            Test::Convertible(v) => {
+                v.from_media_type.map_local_vars(map);
+                v.to_media_type.map_local_vars(map);
             }
             Test::Date(v) => {
-                v.key_list.map_local_vars(last_id);
-                v.header_name.map_local_vars(last_id);
+                v.key_list.map_local_vars(map);
+                v.header_name.map_local_vars(map);
             }
             Test::CurrentDate(v) => {
-                v.key_list.map_local_vars(last_id);
+                v.key_list.map_local_vars(map);
             }
             Test::Duplicate(v) => {
-                v.handle.map_local_vars(last_id);
-                v.dup_match.map_local_vars(last_id);
+                v.handle.map_local_vars(map);
+                v.dup_match.map_local_vars(map);
             }
             Test::String(v) => {
-                v.source.map_local_vars(last_id);
-                v.key_list.map_local_vars(last_id);
+                v.source.map_local_vars(map);
+                v.key_list.map_local_vars(map);
             }
             Test::Environment(v) => {
-                v.source.map_local_vars(last_id);
-                v.key_list.map_local_vars(last_id);
+                v.source.map_local_vars(map);
+                v.key_list.map_local_vars(map);
             }
             Test::NotifyMethodCapability(v) => {
-                v.key_list.map_local_vars(last_id);
-                v.notification_capability.map_local_vars(last_id);
-                v.notification_uri.map_local_vars(last_id);
+                v.key_list.map_local_vars(map);
+                v.notification_capability.map_local_vars(map);
+                v.notification_uri.map_local_vars(map);
             }
             Test::ValidNotifyMethod(v) => {
-                v.notification_uris.map_local_vars(last_id);
+                v.notification_uris.map_local_vars(map);
             }
             Test::ValidExtList(v) => {
-                v.list_names.map_local_vars(last_id);
+                v.list_names.map_local_vars(map);
             }
             Test::HasFlag(v) => {
-                v.variable_list.map_local_vars(last_id);
-                v.flags.map_local_vars(last_id);
+                v.variable_list.map_local_vars(map);
+                v.flags.map_local_vars(map);
             }
             Test::MailboxExists(v) => {
-                v.mailbox_names.map_local_vars(last_id);
+                v.mailbox_names.map_local_vars(map);
             }
             Test::Metadata(v) => {
-                v.key_list.map_local_vars(last_id);
-                v.medatata.map_local_vars(last_id);
+                v.key_list.map_local_vars(map);
+                v.medatata.map_local_vars(map);
             }
             Test::MetadataExists(v) => {
-                v.annotation_names.map_local_vars(last_id);
-                v.mailbox.map_local_vars(last_id);
+                v.annotation_names.map_local_vars(map);
+                v.mailbox.map_local_vars(map);
             }
             Test::MailboxIdExists(v) => {
-                v.mailbox_ids.map_local_vars(last_id);
+                v.mailbox_ids.map_local_vars(map);
             }
             Test::SpamTest(v) => {
-                v.value.map_local_vars(last_id);
+                v.value.map_local_vars(map);
             }
             Test::VirusTest(v) => {
-                v.value.map_local_vars(last_id);
+                v.value.map_local_vars(map);
             }
             Test::SpecialUseExists(v) => {
-                v.mailbox.map_local_vars(last_id);
-                v.attributes.map_local_vars(last_id);
+                v.mailbox.map_local_vars(map);
+                v.attributes.map_local_vars(map);
             }
             Test::Vacation(v) => {
-                v.addresses.map_local_vars(last_id);
-                v.handle.map_local_vars(last_id);
-                v.reason.map_local_vars(last_id);
+                v.addresses.map_local_vars(map);
+                v.handle.map_local_vars(map);
+                v.reason.map_local_vars(map);
             }
             #[cfg(test)]
             Test::TestCmd { arguments, .. } => {
-                arguments.map_local_vars(last_id);
+                arguments.map_local_vars(map);
             }
             _ => (),
         }
     }
 }
 
-impl MapLocalVars for VariableType {
-    fn map_local_vars(&mut self, last_id: usize) {
+impl MapLocalVars for Modifier {
+    fn map_local_vars(&mut self, map: &dyn Fn(usize) -> usize) {
         match self {
-            VariableType::Local(id) if *id > last_id => {
-                *id = (usize::MAX - *id) + last_id;
+            Modifier::Replace { find, replace } => {
+                find.map_local_vars(map);
+                replace.map_local_vars(map);
             }
+            Modifier::Default { value } => value.map_local_vars(map),
             _ => (),
         }
     }
 }
 
+impl MapLocalVars for VariableType {
+    fn map_local_vars(&mut self, map: &dyn Fn(usize) -> usize) {
+        if let VariableType::Local(id) = self {
+            *id = map(*id);
+        }
+    }
+}
+
 impl MapLocalVars for Value {
-    fn map_local_vars(&mut self, last_id: usize) {
+    fn map_local_vars(&mut self, map: &dyn Fn(usize) -> usize) {
         match self {
-            Value::Variable(var) => var.map_local_vars(last_id),
-            Value::List(items) => items.map_local_vars(last_id),
+            Value::Variable(var) => var.map_local_vars(map),
+            Value::List(items) => items.map_local_vars(map),
             _ => (),
         }
     }
 }
 
 impl<T: MapLocalVars> MapLocalVars for Option<T> {
-    fn map_local_vars(&mut self, last_id: usize) {
+    fn map_local_vars(&mut self, map: &dyn Fn(usize) -> usize) {
         if let Some(value) = self {
-            value.map_local_vars(last_id);
+            value.map_local_vars(map);
         }
     }
 }
 
 impl MapLocalVars for Expression {
-    fn map_local_vars(&mut self, last_id: usize) {
+    fn map_local_vars(&mut self, map: &dyn Fn(usize) -> usize) {
         if let Expression::Variable(var) = self {
-            var.map_local_vars(last_id)
+            var.map_local_vars(map)
         }
     }
 }
 
 impl<T: MapLocalVars> MapLocalVars for Vec<T> {
-    fn map_local_vars(&mut self, last_id: usize) {
+    fn map_local_vars(&mut self, map: &dyn Fn(usize) -> usize) {
         for item in self {
-            item.map_local_vars(last_id);
+            item.map_local_vars(map);
         }
     }
 }
@@ -1213,6 +1730,7 @@ impl Block {
             label: None,
             line_num: 0,
             line_pos: 0,
+            offset: 0,
             last_block_start: 0,
             match_test_pos: vec![],
             match_test_vars: 0,
@@ -1221,6 +1739,8 @@ impl Block {
             vars_local: AHashMap::new(),
             capabilities: AHashSet::new(),
             require_pos: usize::MAX,
+            is_true: false,
+            is_stopped: false,
         }
     }
 
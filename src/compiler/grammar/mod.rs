@@ -21,7 +21,7 @@
  * for more details.
 */
 
-use std::fmt::Display;
+use std::{fmt::Display, sync::Arc};
 
 use phf::phf_map;
 use serde::{Deserialize, Serialize};
@@ -90,6 +90,11 @@ pub enum Capability {
     // Extensions
     Expressions,
     While,
+    Hash,
+    Score,
+    Encoding,
+    Keywords,
+    DiscardReason,
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
@@ -103,7 +108,8 @@ pub enum AddressPart {
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
-pub(crate) enum MatchType {
+#[non_exhaustive]
+pub enum MatchType {
     Is,
     Contains,
     Matches(u64),
@@ -111,10 +117,14 @@ pub(crate) enum MatchType {
     Value(RelationalMatch),
     Count(RelationalMatch),
     List,
+    /// `:contains :set`, the key list holds the names of keyword sets
+    /// registered on the `Runtime`.
+    KeywordSet,
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
-pub(crate) enum RelationalMatch {
+#[non_exhaustive]
+pub enum RelationalMatch {
     Gt,
     Ge,
     Lt,
@@ -129,6 +139,7 @@ pub enum Comparator {
     Octet,
     AsciiCaseMap,
     AsciiNumeric,
+    UnicodeCaseMap,
     Other(String),
 }
 
@@ -147,7 +158,8 @@ pub struct Invalid {
 }
 
 #[derive(Debug, Eq, PartialEq, Clone, Serialize, Deserialize)]
-pub(crate) struct While {
+#[non_exhaustive]
+pub struct While {
     pub expr: Vec<Expression>,
     pub jz_pos: usize,
 }
@@ -200,10 +212,8 @@ impl<'x> CompilerState<'x> {
                         d_count -= 1;
                     }
                 }
-                Token::Comma => {
-                    if d_count == 0 {
-                        break;
-                    }
+                Token::Comma if d_count == 0 => {
+                    break;
                 }
                 Token::CurlyOpen => {
                     break;
@@ -239,19 +249,33 @@ impl<'x> CompilerState<'x> {
                             MatchType::Count(*relational)
                         });
                     }
+                    return Err(token_info.custom(ErrorType::InvalidArguments));
                 }
                 Err(token_info.expected("relational match"))
             }
         }
     }
 
-    pub(crate) fn parse_comparator(&mut self) -> Result<Comparator, CompileError> {
+    pub(crate) fn parse_comparator(
+        &mut self,
+        token_info: &TokenInfo,
+    ) -> Result<Comparator, CompileError> {
         let comparator = self.tokens.expect_static_string()?;
-        Ok(if let Some(comparator) = COMPARATOR.get(&comparator) {
-            comparator.clone()
+        if let Some(comparator) = COMPARATOR.get(&comparator) {
+            Ok(comparator.clone())
+        } else if self.compiler.no_capability_check
+            || self.compiler.comparators.contains(&comparator)
+        {
+            Ok(Comparator::Other(comparator))
         } else {
-            Comparator::Other(comparator)
-        })
+            Err(CompileError {
+                line_num: token_info.line_num,
+                line_pos: token_info.line_pos,
+                offset_start: token_info.offset_start,
+                offset_end: token_info.offset_end,
+                error_type: ErrorType::UnsupportedComparator(comparator),
+            })
+        }
     }
 
     pub(crate) fn parse_static_strings(&mut self) -> Result<Vec<String>, CompileError> {
@@ -286,6 +310,8 @@ impl<'x> CompilerState<'x> {
                     .map_err(|error_type| CompileError {
                         line_num: next_token.line_num,
                         line_pos: next_token.line_pos,
+                        offset_start: next_token.offset_start,
+                        offset_end: next_token.offset_end,
                         error_type,
                     })
             }
@@ -311,6 +337,8 @@ impl<'x> CompilerState<'x> {
                     .map_err(|error_type| CompileError {
                         line_num: token_info.line_num,
                         line_pos: token_info.line_pos,
+                        offset_start: token_info.offset_start,
+                        offset_end: token_info.offset_end,
                         error_type,
                     })
             }
@@ -329,6 +357,8 @@ impl<'x> CompilerState<'x> {
                     .map_err(|error_type| CompileError {
                         line_num: token_info.line_num,
                         line_pos: token_info.line_pos,
+                        offset_start: token_info.offset_start,
+                        offset_end: token_info.offset_end,
                         error_type,
                     })
             }
@@ -348,6 +378,8 @@ impl<'x> CompilerState<'x> {
                     .map_err(|error_type| CompileError {
                         line_num: token_info.line_num,
                         line_pos: token_info.line_pos,
+                        offset_start: token_info.offset_start,
+                        offset_end: token_info.offset_end,
                         error_type,
                     })
             }
@@ -372,6 +404,8 @@ impl<'x> CompilerState<'x> {
                         CompileError {
                             line_num: token_info.line_num,
                             line_pos: token_info.line_pos,
+                            offset_start: token_info.offset_start,
+                            offset_end: token_info.offset_end,
                             error_type,
                         }
                     })?);
@@ -390,7 +424,9 @@ impl<'x> CompilerState<'x> {
             .into_iter()
             .chain(self.block_stack.iter())
             .any(|b| b.capabilities.contains(capability))
-            || (capability != &Capability::Ihave && self.compiler.no_capability_check)
+            || (capability != &Capability::Ihave
+                && self.compiler.no_capability_check
+                && !self.compiler.strict_capabilities)
     }
 
     #[inline(always)]
@@ -403,8 +439,7 @@ impl<'x> CompilerState<'x> {
         &mut self,
         arg_num: usize,
         capability: Option<Capability>,
-        line_num: usize,
-        line_pos: usize,
+        token_info: &TokenInfo,
     ) -> Result<(), CompileError> {
         if arg_num > 0 {
             if let Some(param) = self.param_check.get_mut(arg_num - 1) {
@@ -412,8 +447,10 @@ impl<'x> CompilerState<'x> {
                     *param = true;
                 } else {
                     return Err(CompileError {
-                        line_num,
-                        line_pos,
+                        line_num: token_info.line_num,
+                        line_pos: token_info.line_pos,
+                        offset_start: token_info.offset_start,
+                        offset_end: token_info.offset_end,
                         error_type: ErrorType::DuplicatedParameter,
                     });
                 }
@@ -423,10 +460,16 @@ impl<'x> CompilerState<'x> {
             }
         }
         if let Some(capability) = capability {
+            if !self.requires.iter().any(|(c, _)| c == &capability) {
+                let location = self.require_locations.get(&capability).copied();
+                self.requires.push((capability.clone(), location));
+            }
             if !self.has_capability(&capability) {
                 return Err(CompileError {
-                    line_num,
-                    line_pos,
+                    line_num: token_info.line_num,
+                    line_pos: token_info.line_pos,
+                    offset_start: token_info.offset_start,
+                    offset_end: token_info.offset_end,
                     error_type: ErrorType::UndeclaredCapability(capability),
                 });
             }
@@ -435,16 +478,46 @@ impl<'x> CompilerState<'x> {
         Ok(())
     }
 
+    // Keyword sets always match substrings, ':contains' must be explicit
+    pub(crate) fn parse_keyword_set_match(
+        &mut self,
+        match_type: &MatchType,
+    ) -> Result<MatchType, CompileError> {
+        if matches!(match_type, MatchType::Contains) {
+            Ok(MatchType::KeywordSet)
+        } else {
+            Err(self.tokens.unwrap_next()?.missing_tag(":contains"))
+        }
+    }
+
     pub(crate) fn validate_match(
         &mut self,
         match_type: &MatchType,
         key_list: &mut [Value],
     ) -> Result<(), CompileError> {
-        if matches!(match_type, MatchType::Regex(_)) {
+        if matches!(match_type, MatchType::Value(_) | MatchType::Count(_)) {
+            // Numeric looking literals are still strings to the comparator,
+            // which reads them by their leading digits (RFC 4790).
+            for key in key_list.iter_mut() {
+                if let Value::Number(n) = key {
+                    *key = Value::Text(n.to_string().into());
+                }
+            }
+        } else if matches!(match_type, MatchType::Regex(_)) {
             for key in key_list {
                 if let Value::Text(expr) = key {
+                    // Identical patterns share a single compiled regex
+                    if let Some(regex) = self.regex_cache.get(expr.as_str()) {
+                        *key = Value::Regex(Regex {
+                            regex: regex.clone(),
+                            expr: expr.to_string(),
+                        });
+                        continue;
+                    }
                     match fancy_regex::Regex::new(expr) {
                         Ok(regex) => {
+                            let regex = Arc::new(regex);
+                            self.regex_cache.insert(expr.to_string(), regex.clone());
                             *key = Value::Regex(Regex {
                                 regex,
                                 expr: expr.to_string(),
@@ -475,6 +548,48 @@ impl Capability {
         }
     }
 
+    /// Returns the capability with the given name, or `None` if it is not
+    /// supported by this interpreter.
+    #[allow(clippy::should_implement_trait)]
+    pub fn from_str(capability: &str) -> Option<Capability> {
+        CAPABILITIES.get(capability).cloned()
+    }
+
+    /// Returns the capabilities enabled by requiring this one, which scripts
+    /// may then leave out of their `require`:
+    ///
+    /// | Capability         | Implies    | Reference             |
+    /// |--------------------|------------|-----------------------|
+    /// | `spamtestplus`     | `spamtest` | RFC 5235, section 3.2 |
+    /// | `vacation-seconds` | `vacation` | RFC 6131, section 2   |
+    pub fn implied(&self) -> &'static [Capability] {
+        const SPAMTEST: &[Capability] = &[Capability::SpamTest];
+        const VACATION: &[Capability] = &[Capability::Vacation];
+
+        match self {
+            Capability::SpamTestPlus => SPAMTEST,
+            Capability::VacationSeconds => VACATION,
+            _ => &[],
+        }
+    }
+
+    /// Returns `true` for the `vnd.stalwart` extensions, these are not
+    /// allowed by a new `Runtime` unless enabled with `with_capability`.
+    pub fn is_extension(&self) -> bool {
+        matches!(
+            self,
+            Capability::Expressions
+                | Capability::While
+                | Capability::Hash
+                | Capability::Score
+                | Capability::Encoding
+                | Capability::Keywords
+                | Capability::DiscardReason
+        )
+    }
+
+    /// Returns every capability supported by this interpreter, including
+    /// the `vnd.stalwart` extensions.
     pub fn all() -> &'static [Capability] {
         &[
             Capability::Envelope,
@@ -485,6 +600,7 @@ impl Capability {
             Capability::Comparator(Comparator::Elbonia),
             Capability::Comparator(Comparator::AsciiCaseMap),
             Capability::Comparator(Comparator::AsciiNumeric),
+            Capability::Comparator(Comparator::UnicodeCaseMap),
             Capability::Comparator(Comparator::Octet),
             Capability::Body,
             Capability::Convert,
@@ -524,6 +640,13 @@ impl Capability {
             Capability::SpamTest,
             Capability::SpamTestPlus,
             Capability::VirusTest,
+            Capability::Expressions,
+            Capability::While,
+            Capability::Hash,
+            Capability::Score,
+            Capability::Encoding,
+            Capability::Keywords,
+            Capability::DiscardReason,
         ]
     }
 }
@@ -541,6 +664,7 @@ static COMPARATOR: phf::Map<&'static str, Comparator> = phf_map! {
     "i;octet" => Comparator::Octet,
     "i;ascii-casemap" => Comparator::AsciiCaseMap,
     "i;ascii-numeric" => Comparator::AsciiNumeric,
+    "i;unicode-casemap" => Comparator::UnicodeCaseMap,
 };
 
 impl Invalid {
@@ -585,7 +709,12 @@ impl Display for Capability {
             Capability::Comparator(Comparator::AsciiNumeric) => {
                 f.write_str("comparator-i;ascii-numeric")
             }
-            Capability::Comparator(Comparator::Other(comparator)) => f.write_str(comparator),
+            Capability::Comparator(Comparator::UnicodeCaseMap) => {
+                f.write_str("comparator-i;unicode-casemap")
+            }
+            Capability::Comparator(Comparator::Other(comparator)) => {
+                write!(f, "comparator-{comparator}")
+            }
             Capability::Body => f.write_str("body"),
             Capability::Convert => f.write_str("convert"),
             Capability::Copy => f.write_str("copy"),
@@ -626,6 +755,11 @@ impl Display for Capability {
             Capability::VirusTest => f.write_str("virustest"),
             Capability::While => f.write_str("vnd.stalwart.while"),
             Capability::Expressions => f.write_str("vnd.stalwart.expressions"),
+            Capability::Hash => f.write_str("vnd.stalwart.hash"),
+            Capability::Score => f.write_str("vnd.stalwart.score"),
+            Capability::Encoding => f.write_str("vnd.stalwart.encoding"),
+            Capability::Keywords => f.write_str("vnd.stalwart.keywords"),
+            Capability::DiscardReason => f.write_str("vnd.stalwart.discard-reason"),
             Capability::Other(capability) => f.write_str(capability),
         }
     }
@@ -641,6 +775,7 @@ static CAPABILITIES: phf::Map<&'static str, Capability> = phf_map! {
     "comparator-i;octet" => Capability::Comparator(Comparator::Octet),
     "comparator-i;ascii-casemap" => Capability::Comparator(Comparator::AsciiCaseMap),
     "comparator-i;ascii-numeric" => Capability::Comparator(Comparator::AsciiNumeric),
+    "comparator-i;unicode-casemap" => Capability::Comparator(Comparator::UnicodeCaseMap),
     "body" => Capability::Body,
     "convert" => Capability::Convert,
     "copy" => Capability::Copy,
@@ -683,4 +818,9 @@ static CAPABILITIES: phf::Map<&'static str, Capability> = phf_map! {
     // Extensions
     "vnd.stalwart.while" => Capability::While,
     "vnd.stalwart.expressions" => Capability::Expressions,
+    "vnd.stalwart.hash" => Capability::Hash,
+    "vnd.stalwart.score" => Capability::Score,
+    "vnd.stalwart.encoding" => Capability::Encoding,
+    "vnd.stalwart.keywords" => Capability::Keywords,
+    "vnd.stalwart.discard-reason" => Capability::DiscardReason,
 };
@@ -25,11 +25,14 @@ use serde::{Deserialize, Serialize};
 
 use crate::compiler::{
     lexer::{tokenizer::TokenInfo, word::Word, Token},
-    CompileError, ErrorType,
+    CompileError, ErrorType, VariableType,
 };
 
 use super::{
-    actions::{action_convert::Convert, action_vacation::TestVacation},
+    actions::{
+        action_convert::{Convert, TestConvertible},
+        action_vacation::TestVacation,
+    },
     expr::{parser::ExpressionParser, tokenizer::Tokenizer, Expression, UnaryOperator},
     instruction::{CompilerState, Instruction},
     tests::{
@@ -51,12 +54,13 @@ use super::{
         test_specialuse::TestSpecialUseExists,
         test_string::TestString,
     },
-    Capability, Invalid,
+    Capability, Invalid, MatchType,
 };
 
 #[allow(clippy::enum_variant_names)]
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
-pub(crate) enum Test {
+#[non_exhaustive]
+pub enum Test {
     True,
     False,
     Address(TestAddress),
@@ -71,6 +75,7 @@ pub(crate) enum Test {
 
     // RFC 6558
     Convert(Convert),
+    Convertible(TestConvertible),
 
     // RFC 5260
     Date(TestDate),
@@ -122,6 +127,7 @@ pub(crate) enum Test {
     },
 }
 
+#[allow(dead_code)]
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub(crate) struct EvalExpression {
     pub expr: Vec<Expression>,
@@ -246,6 +252,8 @@ impl<'x> CompilerState<'x> {
                             return Err(CompileError {
                                 line_num: token_info.line_num,
                                 line_pos: token_info.line_pos,
+                                offset_start: token_info.offset_start,
+                                offset_end: token_info.offset_end,
                                 error_type: ErrorType::TooManyNestedTests,
                             });
                         }
@@ -266,12 +274,7 @@ impl<'x> CompilerState<'x> {
                     .into(),
                     Token::Identifier(Word::Address) => self.parse_test_address()?.into(),
                     Token::Identifier(Word::Envelope) => {
-                        self.validate_argument(
-                            0,
-                            Capability::Envelope.into(),
-                            token_info.line_num,
-                            token_info.line_pos,
-                        )?;
+                        self.validate_argument(0, Capability::Envelope.into(), &token_info)?;
                         self.parse_test_envelope()?.into()
                     }
                     Token::Identifier(Word::Header) => self.parse_test_header()?.into(),
@@ -280,229 +283,123 @@ impl<'x> CompilerState<'x> {
 
                     // RFC 5173
                     Token::Identifier(Word::Body) => {
-                        self.validate_argument(
-                            0,
-                            Capability::Body.into(),
-                            token_info.line_num,
-                            token_info.line_pos,
-                        )?;
+                        self.validate_argument(0, Capability::Body.into(), &token_info)?;
                         self.parse_test_body()?.into()
                     }
 
                     // RFC 6558
                     Token::Identifier(Word::Convert) => {
-                        self.validate_argument(
-                            0,
-                            Capability::Convert.into(),
-                            token_info.line_num,
-                            token_info.line_pos,
-                        )?;
+                        self.validate_argument(0, Capability::Convert.into(), &token_info)?;
                         self.parse_test_convert()?.into()
                     }
+                    Token::Identifier(Word::Convertible) => {
+                        self.validate_argument(0, Capability::Convert.into(), &token_info)?;
+                        self.parse_test_convertible()?.into()
+                    }
 
                     // RFC 5260
                     Token::Identifier(Word::Date) => {
-                        self.validate_argument(
-                            0,
-                            Capability::Date.into(),
-                            token_info.line_num,
-                            token_info.line_pos,
-                        )?;
+                        self.validate_argument(0, Capability::Date.into(), &token_info)?;
                         self.parse_test_date()?.into()
                     }
                     Token::Identifier(Word::CurrentDate) => {
-                        self.validate_argument(
-                            0,
-                            Capability::Date.into(),
-                            token_info.line_num,
-                            token_info.line_pos,
-                        )?;
+                        self.validate_argument(0, Capability::Date.into(), &token_info)?;
                         self.parse_test_currentdate()?.into()
                     }
 
                     // RFC 7352
                     Token::Identifier(Word::Duplicate) => {
-                        self.validate_argument(
-                            0,
-                            Capability::Duplicate.into(),
-                            token_info.line_num,
-                            token_info.line_pos,
-                        )?;
+                        self.validate_argument(0, Capability::Duplicate.into(), &token_info)?;
                         self.parse_test_duplicate()?.into()
                     }
 
                     // RFC 5229
                     Token::Identifier(Word::String) => {
-                        self.validate_argument(
-                            0,
-                            Capability::Variables.into(),
-                            token_info.line_num,
-                            token_info.line_pos,
-                        )?;
+                        self.validate_argument(0, Capability::Variables.into(), &token_info)?;
                         self.parse_test_string()?.into()
                     }
 
                     // RFC 5435
                     Token::Identifier(Word::NotifyMethodCapability) => {
-                        self.validate_argument(
-                            0,
-                            Capability::Enotify.into(),
-                            token_info.line_num,
-                            token_info.line_pos,
-                        )?;
+                        self.validate_argument(0, Capability::Enotify.into(), &token_info)?;
                         self.parse_test_notify_method_capability()?.into()
                     }
                     Token::Identifier(Word::ValidNotifyMethod) => {
-                        self.validate_argument(
-                            0,
-                            Capability::Enotify.into(),
-                            token_info.line_num,
-                            token_info.line_pos,
-                        )?;
+                        self.validate_argument(0, Capability::Enotify.into(), &token_info)?;
                         self.parse_test_valid_notify_method()?.into()
                     }
 
                     // RFC 5183
                     Token::Identifier(Word::Environment) => {
-                        self.validate_argument(
-                            0,
-                            Capability::Environment.into(),
-                            token_info.line_num,
-                            token_info.line_pos,
-                        )?;
+                        self.validate_argument(0, Capability::Environment.into(), &token_info)?;
                         self.parse_test_environment()?.into()
                     }
 
                     // RFC 6134
                     Token::Identifier(Word::ValidExtList) => {
-                        self.validate_argument(
-                            0,
-                            Capability::ExtLists.into(),
-                            token_info.line_num,
-                            token_info.line_pos,
-                        )?;
+                        self.validate_argument(0, Capability::ExtLists.into(), &token_info)?;
                         self.parse_test_valid_ext_list()?.into()
                     }
 
                     // RFC 5463
                     Token::Identifier(Word::Ihave) => {
-                        self.validate_argument(
-                            0,
-                            Capability::Ihave.into(),
-                            token_info.line_num,
-                            token_info.line_pos,
-                        )?;
+                        self.validate_argument(0, Capability::Ihave.into(), &token_info)?;
                         self.parse_test_ihave()?.into()
                     }
 
                     // RFC 5232
                     Token::Identifier(Word::HasFlag) => {
-                        self.validate_argument(
-                            0,
-                            Capability::Imap4Flags.into(),
-                            token_info.line_num,
-                            token_info.line_pos,
-                        )?;
+                        self.validate_argument(0, Capability::Imap4Flags.into(), &token_info)?;
                         self.parse_test_hasflag()?.into()
                     }
 
                     // RFC 5490
                     Token::Identifier(Word::MailboxExists) => {
-                        self.validate_argument(
-                            0,
-                            Capability::Mailbox.into(),
-                            token_info.line_num,
-                            token_info.line_pos,
-                        )?;
+                        self.validate_argument(0, Capability::Mailbox.into(), &token_info)?;
                         self.parse_test_mailboxexists()?.into()
                     }
                     Token::Identifier(Word::Metadata) => {
-                        self.validate_argument(
-                            0,
-                            Capability::MboxMetadata.into(),
-                            token_info.line_num,
-                            token_info.line_pos,
-                        )?;
+                        self.validate_argument(0, Capability::MboxMetadata.into(), &token_info)?;
                         self.parse_test_metadata()?.into()
                     }
                     Token::Identifier(Word::MetadataExists) => {
-                        self.validate_argument(
-                            0,
-                            Capability::MboxMetadata.into(),
-                            token_info.line_num,
-                            token_info.line_pos,
-                        )?;
+                        self.validate_argument(0, Capability::MboxMetadata.into(), &token_info)?;
                         self.parse_test_metadataexists()?.into()
                     }
                     Token::Identifier(Word::ServerMetadata) => {
-                        self.validate_argument(
-                            0,
-                            Capability::ServerMetadata.into(),
-                            token_info.line_num,
-                            token_info.line_pos,
-                        )?;
+                        self.validate_argument(0, Capability::ServerMetadata.into(), &token_info)?;
                         self.parse_test_servermetadata()?.into()
                     }
                     Token::Identifier(Word::ServerMetadataExists) => {
-                        self.validate_argument(
-                            0,
-                            Capability::ServerMetadata.into(),
-                            token_info.line_num,
-                            token_info.line_pos,
-                        )?;
+                        self.validate_argument(0, Capability::ServerMetadata.into(), &token_info)?;
                         self.parse_test_servermetadataexists()?.into()
                     }
 
                     // RFC 9042
                     Token::Identifier(Word::MailboxIdExists) => {
-                        self.validate_argument(
-                            0,
-                            Capability::MailboxId.into(),
-                            token_info.line_num,
-                            token_info.line_pos,
-                        )?;
+                        self.validate_argument(0, Capability::MailboxId.into(), &token_info)?;
                         self.parse_test_mailboxidexists()?.into()
                     }
 
                     // RFC 5235
                     Token::Identifier(Word::SpamTest) => {
-                        self.validate_argument(
-                            0,
-                            Capability::SpamTest.into(),
-                            token_info.line_num,
-                            token_info.line_pos,
-                        )?;
+                        self.validate_argument(0, Capability::SpamTest.into(), &token_info)?;
                         self.parse_test_spamtest()?.into()
                     }
                     Token::Identifier(Word::VirusTest) => {
-                        self.validate_argument(
-                            0,
-                            Capability::VirusTest.into(),
-                            token_info.line_num,
-                            token_info.line_pos,
-                        )?;
+                        self.validate_argument(0, Capability::VirusTest.into(), &token_info)?;
                         self.parse_test_virustest()?.into()
                     }
 
                     // RFC 8579
                     Token::Identifier(Word::SpecialUseExists) => {
-                        self.validate_argument(
-                            0,
-                            Capability::SpecialUse.into(),
-                            token_info.line_num,
-                            token_info.line_pos,
-                        )?;
+                        self.validate_argument(0, Capability::SpecialUse.into(), &token_info)?;
                         self.parse_test_specialuseexists()?.into()
                     }
 
                     // Expressions extension
                     Token::Identifier(Word::Eval) => {
-                        self.validate_argument(
-                            0,
-                            Capability::Expressions.into(),
-                            token_info.line_num,
-                            token_info.line_pos,
-                        )?;
+                        self.validate_argument(0, Capability::Expressions.into(), &token_info)?;
 
                         Instruction::Eval(self.parse_expr()?)
                     }
@@ -527,13 +424,16 @@ impl<'x> CompilerState<'x> {
                                 | Token::ParenthesisClose
                                 | Token::CurlyOpen))
                         ) {
-                            arguments.push(match self.tokens.unwrap_next()?.token {
+                            let token_info = self.tokens.unwrap_next()?;
+                            arguments.push(match token_info.token {
                                 Token::StringConstant(s) => Value::from(s),
                                 Token::StringVariable(s) => self
                                     .tokenize_string(&s, true)
                                     .map_err(|error_type| CompileError {
                                         line_num: 0,
                                         line_pos: 0,
+                                        offset_start: 0,
+                                        offset_end: 0,
                                         error_type,
                                     })?,
                                 Token::Number(n) => {
@@ -542,7 +442,7 @@ impl<'x> CompilerState<'x> {
                                 Token::Identifier(s) => Value::Text(s.to_string().into()),
                                 Token::Tag(s) => Value::Text(format!(":{s}").into()),
                                 Token::Unknown(s) => Value::Text(s.into()),
-                                other => panic!("Invalid test param {other:?}"),
+                                _ => return Err(token_info.expected("test parameter")),
                             });
                         }
                         Test::TestCmd {
@@ -594,13 +494,25 @@ impl<'x> CompilerState<'x> {
         ))
         .parse()
         {
-            Ok(parser) => Ok(parser.output),
+            Ok(parser) => {
+                let output = parser.output;
+                if output
+                    .iter()
+                    .any(|expr| matches!(expr, Expression::Variable(VariableType::Part(_))))
+                {
+                    self.body_pos = self.body_pos.min(self.instructions.len());
+                }
+                Ok(output)
+            }
             Err(err) => {
-                let err = ErrorType::InvalidExpression(format!(
-                    "{}: {}",
-                    std::str::from_utf8(&expr).unwrap_or_default(),
-                    err
-                ));
+                let err = match err {
+                    ErrorType::InvalidExpression(err) => ErrorType::InvalidExpression(format!(
+                        "{}: {}",
+                        std::str::from_utf8(&expr).unwrap_or_default(),
+                        err
+                    )),
+                    err => err,
+                };
                 next_token.token = Token::StringVariable(expr);
                 Err(next_token.custom(err))
             }
@@ -614,6 +526,26 @@ impl From<Test> for Instruction {
     }
 }
 
+impl Test {
+    pub(crate) fn match_type(&self) -> Option<&MatchType> {
+        match self {
+            Test::Address(t) => Some(&t.match_type),
+            Test::Body(t) => Some(&t.match_type),
+            Test::Date(t) => Some(&t.match_type),
+            Test::CurrentDate(t) => Some(&t.match_type),
+            Test::Envelope(t) => Some(&t.match_type),
+            Test::HasFlag(t) => Some(&t.match_type),
+            Test::Header(t) => Some(&t.match_type),
+            Test::Metadata(t) => Some(&t.match_type),
+            Test::NotifyMethodCapability(t) => Some(&t.match_type),
+            Test::SpamTest(t) => Some(&t.match_type),
+            Test::String(t) | Test::Environment(t) => Some(&t.match_type),
+            Test::VirusTest(t) => Some(&t.match_type),
+            _ => None,
+        }
+    }
+}
+
 impl Instruction {
     pub fn set_not(mut self) -> Self {
         match &mut self {
@@ -641,6 +573,9 @@ impl Instruction {
                 Test::Convert(op) => {
                     op.is_not = true;
                 }
+                Test::Convertible(op) => {
+                    op.is_not = true;
+                }
                 Test::Date(op) => {
                     op.is_not = true;
                 }
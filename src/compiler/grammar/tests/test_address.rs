@@ -32,7 +32,8 @@ use crate::compiler::{
 use crate::compiler::grammar::{AddressPart, MatchType};
 
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
-pub(crate) struct TestAddress {
+#[non_exhaustive]
+pub struct TestAddress {
     pub header_list: Vec<Value>,
     pub key_list: Vec<Value>,
     pub address_part: AddressPart,
@@ -75,8 +76,7 @@ impl<'x> CompilerState<'x> {
                         } else {
                             None
                         },
-                        token_info.line_num,
-                        token_info.line_pos,
+                        &token_info,
                     )?;
                     address_part = word.into();
                 }
@@ -97,49 +97,29 @@ impl<'x> CompilerState<'x> {
                             Word::List => Capability::ExtLists.into(),
                             _ => None,
                         },
-                        token_info.line_num,
-                        token_info.line_pos,
+                        &token_info,
                     )?;
                     match_type = self.parse_match_type(word)?;
                 }
                 Token::Tag(Word::Comparator) => {
-                    self.validate_argument(3, None, token_info.line_num, token_info.line_pos)?;
-                    comparator = self.parse_comparator()?;
+                    self.validate_argument(3, None, &token_info)?;
+                    comparator = self.parse_comparator(&token_info)?;
                 }
                 Token::Tag(Word::Index) => {
-                    self.validate_argument(
-                        4,
-                        Capability::Index.into(),
-                        token_info.line_num,
-                        token_info.line_pos,
-                    )?;
-                    index = (self.tokens.expect_number(u16::MAX as usize)? as i32).into();
+                    self.validate_argument(4, Capability::Index.into(), &token_info)?;
+                    index =
+                        (self.tokens.expect_number_in_range(1, u16::MAX as usize)? as i32).into();
                 }
                 Token::Tag(Word::Last) => {
-                    self.validate_argument(
-                        5,
-                        Capability::Index.into(),
-                        token_info.line_num,
-                        token_info.line_pos,
-                    )?;
+                    self.validate_argument(5, Capability::Index.into(), &token_info)?;
                     index_last = true;
                 }
                 Token::Tag(Word::Mime) => {
-                    self.validate_argument(
-                        6,
-                        Capability::Mime.into(),
-                        token_info.line_num,
-                        token_info.line_pos,
-                    )?;
+                    self.validate_argument(6, Capability::Mime.into(), &token_info)?;
                     mime = true;
                 }
                 Token::Tag(Word::AnyChild) => {
-                    self.validate_argument(
-                        7,
-                        Capability::Mime.into(),
-                        token_info.line_num,
-                        token_info.line_pos,
-                    )?;
+                    self.validate_argument(7, Capability::Mime.into(), &token_info)?;
                     mime_anychild = true;
                 }
                 _ => {
@@ -25,14 +25,15 @@ use serde::{Deserialize, Serialize};
 
 use crate::compiler::{
     grammar::{instruction::CompilerState, Capability, Comparator},
-    lexer::{word::Word, Token},
+    lexer::{tokenizer::TokenInfo, word::Word, Token},
     CompileError, Value,
 };
 
 use crate::compiler::grammar::{test::Test, MatchType};
 
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
-pub(crate) struct TestBody {
+#[non_exhaustive]
+pub struct TestBody {
     pub key_list: Vec<Value>,
     pub body_transform: BodyTransform,
     pub match_type: MatchType,
@@ -42,7 +43,8 @@ pub(crate) struct TestBody {
 }
 
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
-pub(crate) enum BodyTransform {
+#[non_exhaustive]
+pub enum BodyTransform {
     Raw,
     Content(Vec<Value>),
     Text,
@@ -55,24 +57,38 @@ impl<'x> CompilerState<'x> {
         let mut comparator = Comparator::AsciiCaseMap;
         let mut key_list;
         let mut include_subject = false;
+        let mut keyword_set: Option<String> = None;
 
         loop {
+            if keyword_set.is_some()
+                && !matches!(
+                    self.tokens.peek(),
+                    Some(Ok(TokenInfo {
+                        token: Token::Tag(_),
+                        ..
+                    }))
+                )
+            {
+                key_list = vec![Value::Text(keyword_set.take().unwrap().into())];
+                match_type = self.parse_keyword_set_match(&match_type)?;
+                break;
+            }
             let token_info = self.tokens.unwrap_next()?;
             match token_info.token {
                 Token::Tag(Word::Raw) => {
-                    self.validate_argument(1, None, token_info.line_num, token_info.line_pos)?;
+                    self.validate_argument(1, None, &token_info)?;
                     body_transform = BodyTransform::Raw;
                 }
                 Token::Tag(Word::Text) => {
-                    self.validate_argument(1, None, token_info.line_num, token_info.line_pos)?;
+                    self.validate_argument(1, None, &token_info)?;
                     body_transform = BodyTransform::Text;
                 }
                 Token::Tag(Word::Content) => {
-                    self.validate_argument(1, None, token_info.line_num, token_info.line_pos)?;
+                    self.validate_argument(1, None, &token_info)?;
                     body_transform = BodyTransform::Content(self.parse_strings(false)?);
                 }
                 Token::Tag(Word::Subject) => {
-                    self.validate_argument(4, None, token_info.line_num, token_info.line_pos)?;
+                    self.validate_argument(4, None, &token_info)?;
                     include_subject = true;
                 }
                 Token::Tag(
@@ -91,15 +107,18 @@ impl<'x> CompilerState<'x> {
                             Word::List => Capability::ExtLists.into(),
                             _ => None,
                         },
-                        token_info.line_num,
-                        token_info.line_pos,
+                        &token_info,
                     )?;
 
                     match_type = self.parse_match_type(word)?;
                 }
                 Token::Tag(Word::Comparator) => {
-                    self.validate_argument(3, None, token_info.line_num, token_info.line_pos)?;
-                    comparator = self.parse_comparator()?;
+                    self.validate_argument(3, None, &token_info)?;
+                    comparator = self.parse_comparator(&token_info)?;
+                }
+                Token::Tag(Word::Set) => {
+                    self.validate_argument(5, Capability::Keywords.into(), &token_info)?;
+                    keyword_set = self.tokens.expect_static_string()?.into();
                 }
                 _ => {
                     key_list = self.parse_strings_token(token_info)?;
@@ -34,7 +34,8 @@ use crate::compiler::{
 use crate::compiler::grammar::{test::Test, MatchType};
 
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
-pub(crate) struct TestDate {
+#[non_exhaustive]
+pub struct TestDate {
     pub header_name: Value,
     pub key_list: Vec<Value>,
     pub match_type: MatchType,
@@ -47,7 +48,8 @@ pub(crate) struct TestDate {
 }
 
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
-pub(crate) struct TestCurrentDate {
+#[non_exhaustive]
+pub struct TestCurrentDate {
     pub zone: Option<i64>,
     pub match_type: MatchType,
     pub comparator: Comparator,
@@ -57,14 +59,16 @@ pub(crate) struct TestCurrentDate {
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
-pub(crate) enum Zone {
+#[non_exhaustive]
+pub enum Zone {
     Time(i64),
     Original,
     Local,
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
-pub(crate) enum DatePart {
+#[non_exhaustive]
+pub enum DatePart {
     Year,
     Month,
     Day,
@@ -114,58 +118,38 @@ impl<'x> CompilerState<'x> {
                             Word::List => Capability::ExtLists.into(),
                             _ => None,
                         },
-                        token_info.line_num,
-                        token_info.line_pos,
+                        &token_info,
                     )?;
 
                     match_type = self.parse_match_type(word)?;
                 }
                 Token::Tag(Word::Comparator) => {
-                    self.validate_argument(2, None, token_info.line_num, token_info.line_pos)?;
-                    comparator = self.parse_comparator()?;
+                    self.validate_argument(2, None, &token_info)?;
+                    comparator = self.parse_comparator(&token_info)?;
                 }
                 Token::Tag(Word::Index) => {
-                    self.validate_argument(
-                        3,
-                        Capability::Index.into(),
-                        token_info.line_num,
-                        token_info.line_pos,
-                    )?;
-                    index = (self.tokens.expect_number(u16::MAX as usize)? as i32).into();
+                    self.validate_argument(3, Capability::Index.into(), &token_info)?;
+                    index =
+                        (self.tokens.expect_number_in_range(1, u16::MAX as usize)? as i32).into();
                 }
                 Token::Tag(Word::Last) => {
-                    self.validate_argument(
-                        4,
-                        Capability::Index.into(),
-                        token_info.line_num,
-                        token_info.line_pos,
-                    )?;
+                    self.validate_argument(4, Capability::Index.into(), &token_info)?;
                     index_last = true;
                 }
                 Token::Tag(Word::Mime) => {
-                    self.validate_argument(
-                        5,
-                        Capability::Mime.into(),
-                        token_info.line_num,
-                        token_info.line_pos,
-                    )?;
+                    self.validate_argument(5, Capability::Mime.into(), &token_info)?;
                     mime = true;
                 }
                 Token::Tag(Word::AnyChild) => {
-                    self.validate_argument(
-                        6,
-                        Capability::Mime.into(),
-                        token_info.line_num,
-                        token_info.line_pos,
-                    )?;
+                    self.validate_argument(6, Capability::Mime.into(), &token_info)?;
                     mime_anychild = true;
                 }
                 Token::Tag(Word::OriginalZone) => {
-                    self.validate_argument(7, None, token_info.line_num, token_info.line_pos)?;
+                    self.validate_argument(7, None, &token_info)?;
                     zone = Zone::Original;
                 }
                 Token::Tag(Word::Zone) => {
-                    self.validate_argument(7, None, token_info.line_num, token_info.line_pos)?;
+                    self.validate_argument(7, None, &token_info)?;
                     zone = Zone::Time(self.parse_timezone()?);
                 }
                 _ => {
@@ -243,18 +227,17 @@ impl<'x> CompilerState<'x> {
                             Word::List => Capability::ExtLists.into(),
                             _ => None,
                         },
-                        token_info.line_num,
-                        token_info.line_pos,
+                        &token_info,
                     )?;
 
                     match_type = self.parse_match_type(word)?;
                 }
                 Token::Tag(Word::Comparator) => {
-                    self.validate_argument(2, None, token_info.line_num, token_info.line_pos)?;
-                    comparator = self.parse_comparator()?;
+                    self.validate_argument(2, None, &token_info)?;
+                    comparator = self.parse_comparator(&token_info)?;
                 }
                 Token::Tag(Word::Zone) => {
-                    self.validate_argument(3, None, token_info.line_num, token_info.line_pos)?;
+                    self.validate_argument(3, None, &token_info)?;
                     zone = self.parse_timezone()?.into();
                 }
                 _ => {
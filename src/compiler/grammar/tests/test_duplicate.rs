@@ -33,16 +33,19 @@ use crate::compiler::{
 use crate::compiler::grammar::test::Test;
 
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
-pub(crate) struct TestDuplicate {
+#[non_exhaustive]
+pub struct TestDuplicate {
     pub handle: Option<Value>,
     pub dup_match: DupMatch,
+    // Zero tracks the id for the life of the handle
     pub seconds: Option<u64>,
     pub last: bool,
     pub is_not: bool,
 }
 
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
-pub(crate) enum DupMatch {
+#[non_exhaustive]
+pub enum DupMatch {
     Header(Value),
     UniqueId(Value),
     Default,
@@ -57,18 +60,16 @@ impl<'x> CompilerState<'x> {
 
         while let Some(token_info) = self.tokens.peek() {
             let token_info = token_info?;
-            let line_num = token_info.line_num;
-            let line_pos = token_info.line_pos;
 
             match token_info.token {
                 Token::Tag(Word::Handle) => {
-                    self.validate_argument(1, None, line_num, line_pos)?;
-                    self.tokens.next();
+                    let token_info = self.tokens.unwrap_next()?;
+                    self.validate_argument(1, None, &token_info)?;
                     handle = self.parse_string()?.into();
                 }
                 Token::Tag(Word::Header) => {
-                    self.validate_argument(2, None, line_num, line_pos)?;
-                    self.tokens.next();
+                    let token_info = self.tokens.unwrap_next()?;
+                    self.validate_argument(2, None, &token_info)?;
                     let header = self.parse_string()?;
                     if let Value::Text(header_name) = &header {
                         if HeaderName::parse(header_name.as_ref()).is_none() {
@@ -81,18 +82,18 @@ impl<'x> CompilerState<'x> {
                     dup_match = DupMatch::Header(header);
                 }
                 Token::Tag(Word::UniqueId) => {
-                    self.validate_argument(2, None, line_num, line_pos)?;
-                    self.tokens.next();
+                    let token_info = self.tokens.unwrap_next()?;
+                    self.validate_argument(2, None, &token_info)?;
                     dup_match = DupMatch::UniqueId(self.parse_string()?);
                 }
                 Token::Tag(Word::Seconds) => {
-                    self.validate_argument(3, None, line_num, line_pos)?;
-                    self.tokens.next();
+                    let token_info = self.tokens.unwrap_next()?;
+                    self.validate_argument(3, None, &token_info)?;
                     seconds = (self.tokens.expect_number(u64::MAX as usize)? as u64).into();
                 }
                 Token::Tag(Word::Last) => {
-                    self.validate_argument(4, None, line_num, line_pos)?;
-                    self.tokens.next();
+                    let token_info = self.tokens.unwrap_next()?;
+                    self.validate_argument(4, None, &token_info)?;
                     last = true;
                 }
                 _ => break,
@@ -110,10 +111,10 @@ impl<'x> CompilerState<'x> {
 }
 
 impl MapLocalVars for DupMatch {
-    fn map_local_vars(&mut self, last_id: usize) {
+    fn map_local_vars(&mut self, map: &dyn Fn(usize) -> usize) {
         match self {
-            DupMatch::Header(header) => header.map_local_vars(last_id),
-            DupMatch::UniqueId(unique_id) => unique_id.map_local_vars(last_id),
+            DupMatch::Header(header) => header.map_local_vars(map),
+            DupMatch::UniqueId(unique_id) => unique_id.map_local_vars(map),
             DupMatch::Default => {}
         }
     }
@@ -36,7 +36,8 @@ use crate::{
 use crate::compiler::grammar::{test::Test, AddressPart, MatchType};
 
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
-pub(crate) struct TestEnvelope {
+#[non_exhaustive]
+pub struct TestEnvelope {
     pub envelope_list: Vec<Envelope>,
     pub key_list: Vec<Value>,
     pub address_part: AddressPart,
@@ -68,8 +69,7 @@ impl<'x> CompilerState<'x> {
                         } else {
                             None
                         },
-                        token_info.line_num,
-                        token_info.line_pos,
+                        &token_info,
                     )?;
                     address_part = word.into();
                 }
@@ -90,43 +90,37 @@ impl<'x> CompilerState<'x> {
                             Word::List => Capability::ExtLists.into(),
                             _ => None,
                         },
-                        token_info.line_num,
-                        token_info.line_pos,
+                        &token_info,
                     )?;
 
                     match_type = self.parse_match_type(word)?;
                 }
                 Token::Tag(Word::Comparator) => {
-                    self.validate_argument(3, None, token_info.line_num, token_info.line_pos)?;
-                    comparator = self.parse_comparator()?;
+                    self.validate_argument(3, None, &token_info)?;
+                    comparator = self.parse_comparator(&token_info)?;
                 }
                 Token::Tag(Word::Zone) => {
-                    self.validate_argument(
-                        4,
-                        Capability::EnvelopeDeliverBy.into(),
-                        token_info.line_num,
-                        token_info.line_pos,
-                    )?;
+                    self.validate_argument(4, Capability::EnvelopeDeliverBy.into(), &token_info)?;
                     zone = self.parse_timezone()?.into();
                 }
                 _ => {
                     if envelope_list.is_none() {
                         let mut envelopes = Vec::new();
-                        let line_num = token_info.line_num;
-                        let line_pos = token_info.line_pos;
 
-                        match token_info.token {
-                            Token::StringConstant(s) => match Envelope::try_from(s.into_string()) {
-                                Ok(envelope) => {
-                                    envelopes.push(envelope);
-                                }
-                                Err(invalid) => {
-                                    token_info.token = Token::Comma;
-                                    return Err(
-                                        token_info.custom(ErrorType::InvalidEnvelope(invalid))
-                                    );
+                        match &token_info.token {
+                            Token::StringConstant(s) => {
+                                match Envelope::try_from(s.clone().into_string()) {
+                                    Ok(envelope) => {
+                                        envelopes.push(envelope);
+                                    }
+                                    Err(invalid) => {
+                                        token_info.token = Token::Comma;
+                                        return Err(
+                                            token_info.custom(ErrorType::InvalidEnvelope(invalid))
+                                        );
+                                    }
                                 }
-                            },
+                            }
                             Token::BracketOpen => loop {
                                 let mut token_info = self.tokens.unwrap_next()?;
                                 match token_info.token {
@@ -161,8 +155,7 @@ impl<'x> CompilerState<'x> {
                                     self.validate_argument(
                                         0,
                                         Capability::EnvelopeDeliverBy.into(),
-                                        line_num,
-                                        line_pos,
+                                        &token_info,
                                     )?;
                                 }
 
@@ -173,8 +166,7 @@ impl<'x> CompilerState<'x> {
                                     self.validate_argument(
                                         0,
                                         Capability::EnvelopeDsn.into(),
-                                        line_num,
-                                        line_pos,
+                                        &token_info,
                                     )?;
                                 }
                                 _ => (),
@@ -227,6 +219,26 @@ impl<'x> TryFrom<&'x str> for Envelope {
     }
 }
 
+impl Envelope {
+    // Variables spell the deliver-by parts with underscores
+    pub(crate) fn from_variable_name(name: &str) -> Option<Self> {
+        match name {
+            "from" => Envelope::From.into(),
+            "to" => Envelope::To.into(),
+            "by_time_absolute" => Envelope::ByTimeAbsolute.into(),
+            "by_time_relative" => Envelope::ByTimeRelative.into(),
+            "by_mode" => Envelope::ByMode.into(),
+            "by_trace" => Envelope::ByTrace.into(),
+            "notify" => Envelope::Notify.into(),
+            "orcpt" => Envelope::Orcpt.into(),
+            "ret" => Envelope::Ret.into(),
+            "envid" => Envelope::Envid.into(),
+            "auth" => Envelope::Auth.into(),
+            _ => None,
+        }
+    }
+}
+
 pub(crate) static ENVELOPE: phf::Map<&'static str, Envelope> = phf_map! {
     "from" => Envelope::From,
     "to" => Envelope::To,
@@ -238,4 +250,5 @@ pub(crate) static ENVELOPE: phf::Map<&'static str, Envelope> = phf_map! {
     "orcpt" => Envelope::Orcpt,
     "ret" => Envelope::Ret,
     "envid" => Envelope::Envid,
+    "auth" => Envelope::Auth,
 };
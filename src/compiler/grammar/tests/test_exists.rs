@@ -33,7 +33,8 @@ use crate::compiler::{
 use crate::compiler::grammar::test::Test;
 
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
-pub(crate) struct TestExists {
+#[non_exhaustive]
+pub struct TestExists {
     pub header_names: Vec<Value>,
     pub mime_anychild: bool,
     pub is_not: bool,
@@ -50,21 +51,11 @@ impl<'x> CompilerState<'x> {
             let token_info = self.tokens.unwrap_next()?;
             match token_info.token {
                 Token::Tag(Word::Mime) => {
-                    self.validate_argument(
-                        1,
-                        Capability::Mime.into(),
-                        token_info.line_num,
-                        token_info.line_pos,
-                    )?;
+                    self.validate_argument(1, Capability::Mime.into(), &token_info)?;
                     mime = true;
                 }
                 Token::Tag(Word::AnyChild) => {
-                    self.validate_argument(
-                        2,
-                        Capability::Mime.into(),
-                        token_info.line_num,
-                        token_info.line_pos,
-                    )?;
+                    self.validate_argument(2, Capability::Mime.into(), &token_info)?;
                     mime_anychild = true;
                 }
                 _ => {
@@ -30,7 +30,8 @@ use crate::compiler::Value;
 use crate::compiler::grammar::test::Test;
 
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
-pub(crate) struct TestValidExtList {
+#[non_exhaustive]
+pub struct TestValidExtList {
     pub list_names: Vec<Value>,
     pub is_not: bool,
 }
@@ -38,7 +38,8 @@ use crate::compiler::grammar::{test::Test, MatchType};
 */
 
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
-pub(crate) struct TestHasFlag {
+#[non_exhaustive]
+pub struct TestHasFlag {
     pub comparator: Comparator,
     pub match_type: MatchType,
     pub variable_list: Vec<VariableType>,
@@ -73,15 +74,14 @@ impl<'x> CompilerState<'x> {
                             Word::List => Capability::ExtLists.into(),
                             _ => None,
                         },
-                        token_info.line_num,
-                        token_info.line_pos,
+                        &token_info,
                     )?;
 
                     match_type = self.parse_match_type(word)?;
                 }
                 Token::Tag(Word::Comparator) => {
-                    self.validate_argument(2, None, token_info.line_num, token_info.line_pos)?;
-                    comparator = self.parse_comparator()?;
+                    self.validate_argument(2, None, &token_info)?;
+                    comparator = self.parse_comparator(&token_info)?;
                 }
                 Token::Tag(Word::Local) => {
                     is_local = true;
@@ -98,10 +98,14 @@ impl<'x> CompilerState<'x> {
                 token: Token::StringConstant(_) | Token::StringVariable(_) | Token::BracketOpen,
                 line_num,
                 line_pos,
+                offset_start,
+                offset_end,
             })) => {
                 if !maybe_variables.is_empty() {
                     let line_num = *line_num;
                     let line_pos = *line_pos;
+                    let offset_start = *offset_start;
+                    let offset_end = *offset_end;
 
                     let mut variable_list = Vec::with_capacity(maybe_variables.len());
                     for variable in maybe_variables {
@@ -112,6 +116,8 @@ impl<'x> CompilerState<'x> {
                                         .map_err(|error_type| CompileError {
                                             line_num,
                                             line_pos,
+                                            offset_start,
+                                            offset_end,
                                             error_type,
                                         })?,
                                 );
@@ -37,7 +37,8 @@ use crate::compiler::{
 use crate::compiler::grammar::{test::Test, MatchType};
 
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
-pub(crate) struct TestHeader {
+#[non_exhaustive]
+pub struct TestHeader {
     pub header_list: Vec<Value>,
     pub key_list: Vec<Value>,
     pub match_type: MatchType,
@@ -61,6 +62,7 @@ impl<'x> CompilerState<'x> {
         let mut mime = false;
         let mut mime_opts = MimeOpts::None;
         let mut mime_anychild = false;
+        let mut keyword_set = None;
 
         loop {
             let token_info = self.tokens.unwrap_next()?;
@@ -82,66 +84,45 @@ impl<'x> CompilerState<'x> {
                             Word::List => Capability::ExtLists.into(),
                             _ => None,
                         },
-                        token_info.line_num,
-                        token_info.line_pos,
+                        &token_info,
                     )?;
 
                     match_type = self.parse_match_type(word)?;
                 }
                 Token::Tag(Word::Comparator) => {
-                    self.validate_argument(2, None, token_info.line_num, token_info.line_pos)?;
+                    self.validate_argument(2, None, &token_info)?;
 
-                    comparator = self.parse_comparator()?;
+                    comparator = self.parse_comparator(&token_info)?;
                 }
                 Token::Tag(Word::Index) => {
-                    self.validate_argument(
-                        3,
-                        Capability::Index.into(),
-                        token_info.line_num,
-                        token_info.line_pos,
-                    )?;
+                    self.validate_argument(3, Capability::Index.into(), &token_info)?;
 
-                    index = (self.tokens.expect_number(u16::MAX as usize)? as i32).into();
+                    index =
+                        (self.tokens.expect_number_in_range(1, u16::MAX as usize)? as i32).into();
                 }
                 Token::Tag(Word::Last) => {
-                    self.validate_argument(
-                        4,
-                        Capability::Index.into(),
-                        token_info.line_num,
-                        token_info.line_pos,
-                    )?;
+                    self.validate_argument(4, Capability::Index.into(), &token_info)?;
 
                     index_last = true;
                 }
                 Token::Tag(Word::Mime) => {
-                    self.validate_argument(
-                        5,
-                        Capability::Mime.into(),
-                        token_info.line_num,
-                        token_info.line_pos,
-                    )?;
+                    self.validate_argument(5, Capability::Mime.into(), &token_info)?;
                     mime = true;
                 }
                 Token::Tag(Word::AnyChild) => {
-                    self.validate_argument(
-                        6,
-                        Capability::Mime.into(),
-                        token_info.line_num,
-                        token_info.line_pos,
-                    )?;
+                    self.validate_argument(6, Capability::Mime.into(), &token_info)?;
                     mime_anychild = true;
                 }
                 Token::Tag(
                     word @ (Word::Type | Word::Subtype | Word::ContentType | Word::Param),
                 ) => {
-                    self.validate_argument(
-                        7,
-                        Capability::Mime.into(),
-                        token_info.line_num,
-                        token_info.line_pos,
-                    )?;
+                    self.validate_argument(7, Capability::Mime.into(), &token_info)?;
                     mime_opts = self.parse_mimeopts(word)?;
                 }
+                Token::Tag(Word::Set) => {
+                    self.validate_argument(8, Capability::Keywords.into(), &token_info)?;
+                    keyword_set = self.tokens.expect_static_string()?.into();
+                }
                 _ => {
                     if header_list.is_none() {
                         let headers = self.parse_strings_token(token_info)?;
@@ -156,6 +137,11 @@ impl<'x> CompilerState<'x> {
                             }
                         }
                         header_list = headers.into();
+                        if let Some(keyword_set) = keyword_set.take() {
+                            key_list = vec![Value::Text(keyword_set.into())];
+                            match_type = self.parse_keyword_set_match(&match_type)?;
+                            break;
+                        }
                     } else {
                         key_list = self.parse_strings_token(token_info)?;
                         break;
@@ -183,9 +169,9 @@ impl<'x> CompilerState<'x> {
 }
 
 impl MapLocalVars for MimeOpts<Value> {
-    fn map_local_vars(&mut self, last_id: usize) {
+    fn map_local_vars(&mut self, map: &dyn Fn(usize) -> usize) {
         if let MimeOpts::Param(value) = self {
-            value.map_local_vars(last_id)
+            value.map_local_vars(map)
         }
     }
 }
@@ -31,13 +31,15 @@ use crate::compiler::Value;
 use crate::compiler::grammar::test::Test;
 
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
-pub(crate) struct TestIhave {
+#[non_exhaustive]
+pub struct TestIhave {
     pub capabilities: Vec<Capability>,
     pub is_not: bool,
 }
 
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
-pub(crate) struct Error {
+#[non_exhaustive]
+pub struct Error {
     pub message: Value,
 }
 
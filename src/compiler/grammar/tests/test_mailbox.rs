@@ -38,13 +38,15 @@ use crate::{
 use crate::compiler::grammar::{test::Test, MatchType};
 
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
-pub(crate) struct TestMailboxExists {
+#[non_exhaustive]
+pub struct TestMailboxExists {
     pub mailbox_names: Vec<Value>,
     pub is_not: bool,
 }
 
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
-pub(crate) struct TestMetadataExists {
+#[non_exhaustive]
+pub struct TestMetadataExists {
     pub mailbox: Option<Value>,
     pub annotation_names: Vec<Value>,
     pub is_not: bool,
@@ -59,7 +61,8 @@ metadata [MATCH-TYPE] [COMPARATOR]
 */
 
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
-pub(crate) struct TestMetadata {
+#[non_exhaustive]
+pub struct TestMetadata {
     pub match_type: MatchType,
     pub comparator: Comparator,
     pub medatata: Metadata<Value>,
@@ -124,15 +127,14 @@ impl<'x> CompilerState<'x> {
                             Word::List => Capability::ExtLists.into(),
                             _ => None,
                         },
-                        token_info.line_num,
-                        token_info.line_pos,
+                        &token_info,
                     )?;
 
                     match_type = self.parse_match_type(word)?;
                 }
                 Token::Tag(Word::Comparator) => {
-                    self.validate_argument(2, None, token_info.line_num, token_info.line_pos)?;
-                    comparator = self.parse_comparator()?;
+                    self.validate_argument(2, None, &token_info)?;
+                    comparator = self.parse_comparator(&token_info)?;
                 }
                 _ => {
                     if mailbox.is_none() {
@@ -185,15 +187,14 @@ impl<'x> CompilerState<'x> {
                             Word::List => Capability::ExtLists.into(),
                             _ => None,
                         },
-                        token_info.line_num,
-                        token_info.line_pos,
+                        &token_info,
                     )?;
 
                     match_type = self.parse_match_type(word)?;
                 }
                 Token::Tag(Word::Comparator) => {
-                    self.validate_argument(2, None, token_info.line_num, token_info.line_pos)?;
-                    comparator = self.parse_comparator()?;
+                    self.validate_argument(2, None, &token_info)?;
+                    comparator = self.parse_comparator(&token_info)?;
                 }
                 _ => {
                     if annotation_name.is_none() {
@@ -220,14 +221,14 @@ impl<'x> CompilerState<'x> {
 }
 
 impl MapLocalVars for Metadata<Value> {
-    fn map_local_vars(&mut self, last_id: usize) {
+    fn map_local_vars(&mut self, map: &dyn Fn(usize) -> usize) {
         match self {
             Metadata::Mailbox { name, annotation } => {
-                name.map_local_vars(last_id);
-                annotation.map_local_vars(last_id);
+                name.map_local_vars(map);
+                annotation.map_local_vars(map);
             }
             Metadata::Server { annotation } => {
-                annotation.map_local_vars(last_id);
+                annotation.map_local_vars(map);
             }
         }
     }
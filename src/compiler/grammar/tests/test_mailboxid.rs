@@ -30,7 +30,8 @@ use crate::compiler::Value;
 use crate::compiler::grammar::test::Test;
 
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
-pub(crate) struct TestMailboxIdExists {
+#[non_exhaustive]
+pub struct TestMailboxIdExists {
     pub mailbox_ids: Vec<Value>,
     pub is_not: bool,
 }
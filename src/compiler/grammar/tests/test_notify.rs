@@ -32,7 +32,8 @@ use crate::compiler::{
 use crate::compiler::grammar::{test::Test, MatchType};
 
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
-pub(crate) struct TestNotifyMethodCapability {
+#[non_exhaustive]
+pub struct TestNotifyMethodCapability {
     pub comparator: Comparator,
     pub match_type: MatchType,
     pub notification_uri: Value,
@@ -42,7 +43,8 @@ pub(crate) struct TestNotifyMethodCapability {
 }
 
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
-pub(crate) struct TestValidNotifyMethod {
+#[non_exhaustive]
+pub struct TestValidNotifyMethod {
     pub notification_uris: Vec<Value>,
     pub is_not: bool,
 }
@@ -81,15 +83,14 @@ impl<'x> CompilerState<'x> {
                             Word::List => Capability::ExtLists.into(),
                             _ => None,
                         },
-                        token_info.line_num,
-                        token_info.line_pos,
+                        &token_info,
                     )?;
 
                     match_type = self.parse_match_type(word)?;
                 }
                 Token::Tag(Word::Comparator) => {
-                    self.validate_argument(2, None, token_info.line_num, token_info.line_pos)?;
-                    comparator = self.parse_comparator()?;
+                    self.validate_argument(2, None, &token_info)?;
+                    comparator = self.parse_comparator(&token_info)?;
                 }
                 _ => {
                     if notification_uri.is_none() {
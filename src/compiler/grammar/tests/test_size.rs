@@ -24,39 +24,87 @@
 use serde::{Deserialize, Serialize};
 
 use crate::compiler::{
-    grammar::instruction::CompilerState,
+    grammar::{instruction::CompilerState, Capability, MatchType, RelationalMatch},
     lexer::{word::Word, Token},
-    CompileError,
+    CompileError, ErrorType,
 };
 
 use crate::compiler::grammar::test::Test;
 
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
-pub(crate) struct TestSize {
-    pub over: bool,
+#[non_exhaustive]
+pub struct TestSize {
+    pub relation: RelationalMatch,
     pub limit: usize,
     pub is_not: bool,
 }
 
 impl<'x> CompilerState<'x> {
     pub(crate) fn parse_test_size(&mut self) -> Result<Test, CompileError> {
-        let token_info = self.tokens.unwrap_next()?;
-        let over = match token_info.token {
-            Token::Tag(Word::Over) => true,
-            Token::Tag(Word::Under) => false,
-            _ => {
-                return Err(token_info.expected("':over' or ':under'"));
+        let mut relation = None;
+        let mut is_count = false;
+        let mut comparator = None;
+
+        loop {
+            let token_info = self.tokens.unwrap_next()?;
+            match token_info.token {
+                Token::Tag(Word::Over) if relation.is_none() => {
+                    relation = RelationalMatch::Gt.into();
+                }
+                Token::Tag(Word::Under) if relation.is_none() => {
+                    relation = RelationalMatch::Lt.into();
+                }
+                Token::Tag(Word::Count) if relation.is_none() => {
+                    self.validate_argument(1, Capability::Relational.into(), &token_info)?;
+                    if let MatchType::Count(rel_match) = self.parse_match_type(Word::Count)? {
+                        relation = rel_match.into();
+                        is_count = true;
+                    }
+                }
+                Token::Tag(Word::Comparator) => {
+                    self.validate_argument(2, None, &token_info)?;
+                    let name = self.tokens.expect_static_string()?;
+                    if name != "i;ascii-numeric" {
+                        return Err(token_info.custom(ErrorType::UnsupportedComparator(name)));
+                    }
+                    comparator = token_info.into();
+                }
+                _ => {
+                    let Some(relation) = relation else {
+                        return Err(token_info.expected("':over', ':under' or ':count'"));
+                    };
+                    // A comparator only applies to relational matches
+                    if let Some(comparator) = comparator.filter(|_| !is_count) {
+                        return Err(comparator.missing_tag(":count"));
+                    }
+
+                    let limit = match token_info.token {
+                        Token::Number(limit) => limit,
+                        Token::StringConstant(ref limit) => {
+                            let limit = limit.to_string().into_owned();
+                            limit
+                                .parse::<usize>()
+                                .map_err(|_| token_info.custom(ErrorType::InvalidNumber(limit)))?
+                        }
+                        _ => return Err(token_info.expected("number")),
+                    };
+                    return Ok(self.test_size(relation, limit));
+                }
             }
-        };
-        let token_info = self.tokens.unwrap_next()?;
-        if let Token::Number(limit) = token_info.token {
-            Ok(Test::Size(TestSize {
-                over,
+        }
+    }
+
+    // Folds the test into a constant when the message size is known at
+    // compile time
+    fn test_size(&self, relation: RelationalMatch, limit: usize) -> Test {
+        match self.compiler.known_size {
+            Some(size) if relation.cmp(&size, &limit) => Test::True,
+            Some(_) => Test::False,
+            None => Test::Size(TestSize {
+                relation,
                 limit,
                 is_not: false,
-            }))
-        } else {
-            Err(token_info.expected("number"))
+            }),
         }
     }
 }
@@ -37,7 +37,8 @@ use crate::compiler::grammar::{test::Test, MatchType};
 */
 
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
-pub(crate) struct TestSpamTest {
+#[non_exhaustive]
+pub struct TestSpamTest {
     pub value: Value,
     pub match_type: MatchType,
     pub comparator: Comparator,
@@ -46,7 +47,8 @@ pub(crate) struct TestSpamTest {
 }
 
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
-pub(crate) struct TestVirusTest {
+#[non_exhaustive]
+pub struct TestVirusTest {
     pub value: Value,
     pub match_type: MatchType,
     pub comparator: Comparator,
@@ -79,23 +81,17 @@ impl<'x> CompilerState<'x> {
                             Word::List => Capability::ExtLists.into(),
                             _ => None,
                         },
-                        token_info.line_num,
-                        token_info.line_pos,
+                        &token_info,
                     )?;
 
                     match_type = self.parse_match_type(word)?;
                 }
                 Token::Tag(Word::Comparator) => {
-                    self.validate_argument(2, None, token_info.line_num, token_info.line_pos)?;
-                    comparator = self.parse_comparator()?;
+                    self.validate_argument(2, None, &token_info)?;
+                    comparator = self.parse_comparator(&token_info)?;
                 }
                 Token::Tag(Word::Percent) => {
-                    self.validate_argument(
-                        3,
-                        Capability::SpamTestPlus.into(),
-                        token_info.line_num,
-                        token_info.line_pos,
-                    )?;
+                    self.validate_argument(3, Capability::SpamTestPlus.into(), &token_info)?;
                     percent = true;
                 }
                 _ => {
@@ -138,15 +134,14 @@ impl<'x> CompilerState<'x> {
                             Word::List => Capability::ExtLists.into(),
                             _ => None,
                         },
-                        token_info.line_num,
-                        token_info.line_pos,
+                        &token_info,
                     )?;
 
                     match_type = self.parse_match_type(word)?;
                 }
                 Token::Tag(Word::Comparator) => {
-                    self.validate_argument(2, None, token_info.line_num, token_info.line_pos)?;
-                    comparator = self.parse_comparator()?;
+                    self.validate_argument(2, None, &token_info)?;
+                    comparator = self.parse_comparator(&token_info)?;
                 }
                 _ => {
                     value = self.parse_string_token(token_info)?;
@@ -23,7 +23,11 @@
 
 use serde::{Deserialize, Serialize};
 
-use crate::compiler::{grammar::instruction::CompilerState, lexer::Token, CompileError, Value};
+use crate::compiler::{
+    grammar::instruction::CompilerState,
+    lexer::{tokenizer::TokenInfo, Token},
+    CompileError, ErrorType, Value,
+};
 
 use crate::compiler::grammar::test::Test;
 
@@ -33,22 +37,41 @@ use crate::compiler::grammar::test::Test;
 */
 
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
-pub(crate) struct TestSpecialUseExists {
+#[non_exhaustive]
+pub struct TestSpecialUseExists {
     pub mailbox: Option<Value>,
     pub attributes: Vec<Value>,
     pub is_not: bool,
 }
 
+// Mailbox attributes registered for special use (RFC 6154, RFC 8457)
+const SPECIAL_USE: &[&str] = &[
+    "\\All",
+    "\\Archive",
+    "\\Drafts",
+    "\\Flagged",
+    "\\Important",
+    "\\Junk",
+    "\\Memos",
+    "\\Scheduled",
+    "\\Sent",
+    "\\Snoozed",
+    "\\Trash",
+];
+
 impl<'x> CompilerState<'x> {
     pub(crate) fn parse_test_specialuseexists(&mut self) -> Result<Test, CompileError> {
-        let mut maybe_attributes = self.parse_strings(false)?;
+        let token_info = self.tokens.unwrap_next()?;
+        let mut maybe_attributes = self.parse_strings_token(token_info.clone())?;
 
         match self.tokens.peek().map(|r| r.map(|t| &t.token)) {
             Some(Ok(Token::StringConstant(_) | Token::StringVariable(_) | Token::BracketOpen)) => {
                 if maybe_attributes.len() == 1 {
+                    let token_info = self.tokens.unwrap_next()?;
+                    let attributes = self.parse_strings_token(token_info.clone())?;
                     Ok(Test::SpecialUseExists(TestSpecialUseExists {
                         mailbox: maybe_attributes.pop(),
-                        attributes: self.parse_strings(false)?,
+                        attributes: validate_special_use(attributes, token_info)?,
                         is_not: false,
                     }))
                 } else {
@@ -57,9 +80,48 @@ impl<'x> CompilerState<'x> {
             }
             _ => Ok(Test::SpecialUseExists(TestSpecialUseExists {
                 mailbox: None,
-                attributes: maybe_attributes,
+                attributes: validate_special_use(maybe_attributes, token_info)?,
                 is_not: false,
             })),
         }
     }
+
+    pub(crate) fn parse_special_use(&mut self) -> Result<Value, CompileError> {
+        let token_info = self.tokens.unwrap_next()?;
+        let attribute = self.parse_string_token(token_info.clone())?;
+        if is_special_use(&attribute) {
+            Ok(attribute)
+        } else {
+            Err(token_info.custom(ErrorType::InvalidArguments))
+        }
+    }
+}
+
+fn validate_special_use(
+    attributes: Vec<Value>,
+    token_info: TokenInfo,
+) -> Result<Vec<Value>, CompileError> {
+    if attributes.iter().all(is_special_use) {
+        Ok(attributes)
+    } else {
+        Err(token_info.custom(ErrorType::InvalidArguments))
+    }
+}
+
+// Attributes containing variables can only be checked at runtime. Vendor
+// attributes are accepted as long as they use the "\Vnd" prefix.
+fn is_special_use(attribute: &Value) -> bool {
+    match attribute {
+        Value::Text(attribute) => {
+            SPECIAL_USE
+                .iter()
+                .any(|name| name.eq_ignore_ascii_case(attribute))
+                || (attribute.len() > 4
+                    && attribute
+                        .get(..4)
+                        .is_some_and(|prefix| prefix.eq_ignore_ascii_case("\\Vnd")))
+        }
+        Value::Number(_) => false,
+        _ => true,
+    }
 }
@@ -30,13 +30,14 @@ use crate::{
         grammar::{
             expr::{self},
             instruction::CompilerState,
-            AddressPart,
+            AddressPart, Capability,
         },
-        ContentTypePart, ErrorType, HeaderPart, HeaderVariable, MessagePart, Number,
-        ReceivedHostname, ReceivedPart, Value, VariableType,
+        known_header_id, CompileWarning, ContentTypePart, ErrorType, HeaderId, HeaderPart,
+        HeaderVariable, MessagePart, Number, ReceivedHostname, ReceivedPart, Value, VariableType,
+        WarningType, HEADER_ID_OTHER, KNOWN_HEADERS,
     },
     runtime::eval::IntoString,
-    Envelope, MAX_MATCH_VARIABLES,
+    Envelope, MAX_MATCH_VARIABLES, SCORE_VARIABLE,
 };
 
 enum State {
@@ -105,45 +106,56 @@ impl<'x> CompilerState<'x> {
                         var_has_namespace = true;
                     }
                     b'0'..=b'9' => {}
-                    b'}' => {
-                        if pos > var_start_pos {
-                            // Add any text before the variable
-                            if !decode_buf.is_empty() {
-                                self.add_value(
-                                    &mut items,
-                                    &decode_buf,
-                                    parse_decoded,
-                                    text_has_digits,
-                                    text_has_dots,
-                                )?;
-                                decode_buf.clear();
-                                text_has_digits = true;
-                                text_has_dots = false;
-                            }
+                    b'}' if pos > var_start_pos => {
+                        // Add any text before the variable
+                        if !decode_buf.is_empty() {
+                            self.add_value(
+                                &mut items,
+                                &decode_buf,
+                                parse_decoded,
+                                text_has_digits,
+                                text_has_dots,
+                            )?;
+                            decode_buf.clear();
+                            text_has_digits = true;
+                            text_has_dots = false;
+                        }
 
-                            // Parse variable type
-                            let var_name = std::str::from_utf8(&bytes[var_start_pos..pos]).unwrap();
-                            let var_type = if !var_is_number {
-                                self.parse_variable(var_name, var_has_namespace)
-                            } else {
-                                self.parse_match_variable(var_name)
-                            };
-
-                            match var_type {
-                                Ok(Some(var)) => items.push(Value::Variable(var)),
-                                Ok(None) => {}
-                                Err(
-                                    ErrorType::InvalidNamespace(_) | ErrorType::InvalidEnvelope(_),
-                                ) => {
-                                    is_var_error = true;
+                        // Parse variable type
+                        let var_name = std::str::from_utf8(&bytes[var_start_pos..pos]).unwrap();
+                        let var_type = if !var_is_number {
+                            self.parse_named_variable(var_name, var_has_namespace)
+                        } else {
+                            self.parse_match_variable(var_name)
+                        };
+
+                        match var_type {
+                            Ok(Some(var)) => {
+                                if matches!(var, VariableType::Part(_)) {
+                                    self.body_pos = self.body_pos.min(self.instructions.len());
                                 }
-                                Err(e) => return Err(e),
+                                items.push(Value::Variable(var));
                             }
-
-                            state = State::None;
-                        } else {
-                            is_var_error = true;
+                            Ok(None) => {
+                                if !var_is_number && !var_has_namespace {
+                                    self.warnings.push(CompileWarning {
+                                        line_num: self.tokens.line_num,
+                                        line_pos: self.tokens.line_pos(),
+                                        offset_start: self.tokens.pos,
+                                        offset_end: self.tokens.pos,
+                                        warning_type: WarningType::UnassignedVariable(
+                                            var_name.to_string(),
+                                        ),
+                                    });
+                                }
+                            }
+                            Err(ErrorType::InvalidNamespace(_) | ErrorType::InvalidEnvelope(_)) => {
+                                is_var_error = true;
+                            }
+                            Err(e) => return Err(e),
                         }
+
+                        state = State::None;
                     }
                     b':' => {
                         if parse_decoded && !var_has_namespace {
@@ -274,6 +286,57 @@ impl<'x> CompilerState<'x> {
         let num = var_name
             .parse()
             .map_err(|_| ErrorType::InvalidNumber(var_name.to_string()))?;
+        self.register_match_num(num)
+    }
+
+    // Named capture groups of an enclosing `:regex` test are accessed as
+    // match variables, a declared variable with the same name takes
+    // precedence.
+    fn parse_named_variable(
+        &mut self,
+        var_name: &str,
+        maybe_namespace: bool,
+    ) -> Result<Option<VariableType>, ErrorType> {
+        if maybe_namespace {
+            return if var_name.eq_ignore_ascii_case("match.groups") {
+                self.parse_match_groups()
+            } else {
+                self.parse_variable(var_name, maybe_namespace)
+            };
+        }
+        let var = self.parse_variable(var_name, maybe_namespace)?;
+        match (self.capture_group(var_name), var) {
+            (Some(_), Some(var)) => {
+                self.warnings.push(CompileWarning {
+                    line_num: self.tokens.line_num,
+                    line_pos: self.tokens.line_pos(),
+                    offset_start: self.tokens.pos,
+                    offset_end: self.tokens.pos,
+                    warning_type: WarningType::ShadowedCaptureGroup(var_name.to_string()),
+                });
+                Ok(Some(var))
+            }
+            (Some(num), None) => self.register_match_num(num),
+            (None, var) => Ok(var),
+        }
+    }
+
+    // All capture groups of the enclosing `:regex` test, each group is
+    // registered as a match variable so the test captures it.
+    fn parse_match_groups(&mut self) -> Result<Option<VariableType>, ErrorType> {
+        let num_groups = match self.capture_group_count() {
+            Some(num_groups) if num_groups > 0 => num_groups.min(MAX_MATCH_VARIABLES - 1),
+            _ => return Ok(None),
+        };
+        for num in 1..=num_groups {
+            if self.register_match_num(num)?.is_none() {
+                return Ok(None);
+            }
+        }
+        Ok(Some(VariableType::MatchGroups(num_groups)))
+    }
+
+    fn register_match_num(&mut self, num: usize) -> Result<Option<VariableType>, ErrorType> {
         if num < MAX_MATCH_VARIABLES {
             if self.register_match_var(num) {
                 let total_vars = num + 1;
@@ -310,24 +373,10 @@ impl<'x> CompilerState<'x> {
                 Some(("env", var_name)) if !var_name.is_empty() => {
                     VariableType::Environment(var_name.to_string())
                 }
-                Some(("envelope", var_name)) if !var_name.is_empty() => {
-                    let envelope = match var_name {
-                        "from" => Envelope::From,
-                        "to" => Envelope::To,
-                        "by_time_absolute" => Envelope::ByTimeAbsolute,
-                        "by_time_relative" => Envelope::ByTimeRelative,
-                        "by_mode" => Envelope::ByMode,
-                        "by_trace" => Envelope::ByTrace,
-                        "notify" => Envelope::Notify,
-                        "orcpt" => Envelope::Orcpt,
-                        "ret" => Envelope::Ret,
-                        "envid" => Envelope::Envid,
-                        _ => {
-                            return Err(ErrorType::InvalidEnvelope(var_name.to_string()));
-                        }
-                    };
-                    VariableType::Envelope(envelope)
-                }
+                Some(("envelope", var_name)) if !var_name.is_empty() => VariableType::Envelope(
+                    Envelope::from_variable_name(var_name)
+                        .ok_or_else(|| ErrorType::InvalidEnvelope(var_name.to_string()))?,
+                ),
                 Some(("header", var_name)) if !var_name.is_empty() => {
                     self.parse_header_variable(var_name)?
                 }
@@ -341,8 +390,10 @@ impl<'x> CompilerState<'x> {
                 Some(("part", var_name)) if !var_name.is_empty() => match var_name {
                     "text" => VariableType::Part(MessagePart::Contents),
                     "raw" => VariableType::Part(MessagePart::Raw),
+                    "headers" => VariableType::Part(MessagePart::Headers),
                     _ => return Err(ErrorType::InvalidNamespace(var_name.to_string())),
                 },
+                Some(("vnd", "stalwart.score")) => self.score_variable()?,
                 None => {
                     if self.is_var_global(var_name) {
                         VariableType::Global(var_name.to_string())
@@ -359,6 +410,33 @@ impl<'x> CompilerState<'x> {
         }
     }
 
+    /// `vnd.stalwart.score` is a global the host reads back with
+    /// `Context::score` once the script has finished.
+    pub(crate) fn score_variable(&self) -> Result<VariableType, ErrorType> {
+        if self.has_capability(&Capability::Score) {
+            Ok(VariableType::Global(SCORE_VARIABLE.to_string()))
+        } else {
+            Err(ErrorType::UndeclaredCapability(Capability::Score))
+        }
+    }
+
+    // Headers known to the MIME parser have a fixed id, other names are
+    // added to the script's table the first time they are referenced.
+    fn header_id(&self, name: HeaderName<'static>) -> HeaderId {
+        if let Some(id) = known_header_id(&name) {
+            return id;
+        }
+        let mut header_names = self.header_names.borrow_mut();
+        let pos = header_names
+            .iter()
+            .position(|header_name| header_name == &name)
+            .unwrap_or_else(|| {
+                header_names.push(name);
+                header_names.len() - 1
+            });
+        HEADER_ID_OTHER + pos as HeaderId
+    }
+
     fn parse_header_variable(&self, var_name: &str) -> Result<VariableType, ErrorType> {
         #[derive(Debug)]
         enum State {
@@ -398,11 +476,11 @@ impl<'x> CompilerState<'x> {
                         has_wildcard = true;
                     }
                     ':' if !hdr_name.is_empty() && !has_wildcard => {
-                        name.push(
+                        name.push(self.header_id(
                             HeaderName::parse(std::mem::take(&mut hdr_name)).ok_or_else(|| {
                                 ErrorType::InvalidExpression(var_name.to_string())
                             })?,
-                        );
+                        ));
                     }
                     _ if !has_name && !has_wildcard => {
                         hdr_name.push(ch);
@@ -443,8 +521,10 @@ impl<'x> CompilerState<'x> {
 
         if !hdr_name.is_empty() {
             name.push(
-                HeaderName::parse(hdr_name)
-                    .ok_or_else(|| ErrorType::InvalidExpression(var_name.to_string()))?,
+                self.header_id(
+                    HeaderName::parse(hdr_name)
+                        .ok_or_else(|| ErrorType::InvalidExpression(var_name.to_string()))?,
+                ),
             );
         }
 
@@ -495,11 +575,12 @@ impl<'x> CompilerState<'x> {
         match self.parse_variable(var_name, maybe_namespace) {
             Ok(Some(var)) => Ok(expr::Token::Variable(var)),
             _ => {
-                if let Some((id, num_args)) = self.compiler.functions.get(var_name) {
+                if let Some(signature) = self.compiler.functions.get(var_name) {
                     Ok(expr::Token::Function {
                         name: var_name.to_string(),
-                        id: *id,
-                        num_args: *num_args,
+                        id: signature.id,
+                        min_args: signature.min_args,
+                        num_args: signature.max_args,
                     })
                 } else {
                     Err(format!("Invalid variable or function name {var_name:?}"))
@@ -668,6 +749,7 @@ impl Display for VariableType {
         match self {
             VariableType::Local(v) => write!(f, "${{{v}}}"),
             VariableType::Match(v) => write!(f, "${{{v}}}"),
+            VariableType::MatchGroups(_) => f.write_str("${match.groups}"),
             VariableType::Global(v) => write!(f, "${{global.{v}}}"),
             VariableType::Environment(v) => write!(f, "${{env.{v}}}"),
 
@@ -681,14 +763,19 @@ impl Display for VariableType {
                 Envelope::Notify => "${{envelope.notify}}",
                 Envelope::Orcpt => "${{envelope.orcpt}}",
                 Envelope::Ret => "${{envelope.ret}}",
-                Envelope::Envid => "${{envelope.envit}}",
+                Envelope::Envid => "${{envelope.envid}}",
+                Envelope::Auth => "${{envelope.auth}}",
             }),
 
             VariableType::Header(hdr) => {
                 write!(
                     f,
                     "${{header.{}",
-                    hdr.name.first().map(|h| h.as_str()).unwrap_or_default()
+                    hdr.name
+                        .first()
+                        .and_then(|id| KNOWN_HEADERS.get(*id as usize))
+                        .map(|h| h.as_str())
+                        .unwrap_or_default()
                 )?;
                 if hdr.index_hdr != 0 {
                     write!(f, "[{}]", hdr.index_hdr)?;
@@ -726,6 +813,7 @@ impl Display for VariableType {
                         MessagePart::HtmlBody(false) => "body.html",
                         MessagePart::Contents => "part.text",
                         MessagePart::Raw => "part.raw",
+                        MessagePart::Headers => "part.headers",
                     }
                 )?;
                 f.write_str("}")
@@ -736,6 +824,7 @@ impl Display for VariableType {
 
 #[cfg(test)]
 mod tests {
+    use std::cell::RefCell;
 
     use mail_parser::HeaderName;
 
@@ -746,8 +835,8 @@ mod tests {
     use crate::compiler::grammar::{Comparator, MatchType};
     use crate::compiler::lexer::tokenizer::Tokenizer;
     use crate::compiler::lexer::word::Word;
-    use crate::compiler::{AddressPart, HeaderPart, HeaderVariable, VariableType};
-    use crate::{AHashSet, Compiler};
+    use crate::compiler::{known_header_id, AddressPart, HeaderPart, HeaderVariable, VariableType};
+    use crate::{AHashMap, AHashSet, Compiler};
 
     #[test]
     fn tokenize_string() {
@@ -774,6 +863,16 @@ mod tests {
             vars_match_max: usize::MAX,
             param_check: [false; MAX_PARAMS],
             includes_num: 0,
+            redirects_num: 0,
+            last_block_is_true: false,
+            warnings: Vec::new(),
+            body_pos: usize::MAX,
+            requires: Vec::new(),
+            require_locations: AHashMap::new(),
+            regex_cache: AHashMap::new(),
+            var_decls: Vec::new(),
+            command_spans: Vec::new(),
+            header_names: RefCell::new(Vec::new()),
         };
 
         for (input, expected_result) in [
@@ -862,7 +961,7 @@ mod tests {
             (
                 "${header.from}",
                 Value::Variable(VariableType::Header(HeaderVariable {
-                    name: vec![HeaderName::From],
+                    name: vec![known_header_id(&HeaderName::From).unwrap()],
                     part: HeaderPart::Text,
                     index_hdr: -1,
                     index_part: -1,
@@ -871,7 +970,7 @@ mod tests {
             (
                 "${header.from.addr}",
                 Value::Variable(VariableType::Header(HeaderVariable {
-                    name: vec![HeaderName::From],
+                    name: vec![known_header_id(&HeaderName::From).unwrap()],
                     part: HeaderPart::Address(AddressPart::All),
                     index_hdr: -1,
                     index_part: -1,
@@ -880,7 +979,7 @@ mod tests {
             (
                 "${header.from[1]}",
                 Value::Variable(VariableType::Header(HeaderVariable {
-                    name: vec![HeaderName::From],
+                    name: vec![known_header_id(&HeaderName::From).unwrap()],
                     part: HeaderPart::Text,
                     index_hdr: 1,
                     index_part: -1,
@@ -889,7 +988,7 @@ mod tests {
             (
                 "${header.from[*]}",
                 Value::Variable(VariableType::Header(HeaderVariable {
-                    name: vec![HeaderName::From],
+                    name: vec![known_header_id(&HeaderName::From).unwrap()],
                     part: HeaderPart::Text,
                     index_hdr: 0,
                     index_part: -1,
@@ -898,7 +997,7 @@ mod tests {
             (
                 "${header.from[20].name}",
                 Value::Variable(VariableType::Header(HeaderVariable {
-                    name: vec![HeaderName::From],
+                    name: vec![known_header_id(&HeaderName::From).unwrap()],
                     part: HeaderPart::Address(AddressPart::Name),
                     index_hdr: 20,
                     index_part: -1,
@@ -907,7 +1006,7 @@ mod tests {
             (
                 "${header.from[*].addr}",
                 Value::Variable(VariableType::Header(HeaderVariable {
-                    name: vec![HeaderName::From],
+                    name: vec![known_header_id(&HeaderName::From).unwrap()],
                     part: HeaderPart::Address(AddressPart::All),
                     index_hdr: 0,
                     index_part: -1,
@@ -916,7 +1015,7 @@ mod tests {
             (
                 "${header.from[-5].name[2]}",
                 Value::Variable(VariableType::Header(HeaderVariable {
-                    name: vec![HeaderName::From],
+                    name: vec![known_header_id(&HeaderName::From).unwrap()],
                     part: HeaderPart::Address(AddressPart::Name),
                     index_hdr: -5,
                     index_part: 2,
@@ -925,7 +1024,7 @@ mod tests {
             (
                 "${header.from[*].raw[*]}",
                 Value::Variable(VariableType::Header(HeaderVariable {
-                    name: vec![HeaderName::From],
+                    name: vec![known_header_id(&HeaderName::From).unwrap()],
                     part: HeaderPart::Raw,
                     index_hdr: 0,
                     index_part: 0,
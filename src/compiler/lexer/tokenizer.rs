@@ -43,21 +43,29 @@ pub(crate) struct Tokenizer<'x> {
 
     pub text_line_num: usize,
     pub text_line_pos: usize,
+    pub text_offset: usize,
 
     pub token_line_num: usize,
     pub token_line_pos: usize,
+    pub token_offset: usize,
 
     pub token_is_tag: bool,
 
     pub last_ch: u8,
     pub state: State,
+
+    pub bytes: &'x [u8],
+    pub comment_start: usize,
+    pub comments: Vec<(usize, String)>,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub(crate) struct TokenInfo {
     pub(crate) token: Token,
     pub(crate) line_num: usize,
     pub(crate) line_pos: usize,
+    pub(crate) offset_start: usize,
+    pub(crate) offset_end: usize,
 }
 
 pub(crate) enum State {
@@ -84,30 +92,38 @@ impl<'x> Tokenizer<'x> {
             buf: Vec::with_capacity(bytes.len() / 2),
             pos: usize::MAX,
             line_num: 1,
-            line_start: 0,
+            line_start: usize::MAX,
             text_line_num: 0,
             text_line_pos: 0,
+            text_offset: 0,
             token_line_num: 0,
             token_line_pos: 0,
+            token_offset: 0,
             token_is_tag: false,
             next_token: Vec::with_capacity(2),
             last_ch: 0,
             state: State::None,
+            bytes,
+            comment_start: 0,
+            comments: Vec::new(),
         }
     }
 
     pub fn get_current_token(&mut self) -> Option<TokenInfo> {
         if !self.buf.is_empty() {
-            let word = std::str::from_utf8(&self.buf).unwrap();
+            let offset_start = self.token_offset - usize::from(self.token_is_tag);
+            let line_pos = self.token_line_pos - usize::from(self.token_is_tag);
+            let offset_end = self.token_offset + self.buf.len();
+            let word = String::from_utf8_lossy(&self.buf);
+            let word = word.as_ref();
             let token = if let Some(word) = WORDS.get(word) {
                 if self.token_is_tag {
-                    self.token_line_pos -= 1;
                     Token::Tag(*word)
                 } else {
                     Token::Identifier(*word)
                 }
-            } else if self.buf.first().unwrap().is_ascii_digit() {
-                let multiplier = match self.buf.last().unwrap() {
+            } else if self.buf[0].is_ascii_digit() {
+                let multiplier = match self.buf[self.buf.len() - 1] {
                     b'k' => 1024,
                     b'm' => 1048576,
                     b'g' => 1073741824,
@@ -115,7 +131,7 @@ impl<'x> Tokenizer<'x> {
                 };
 
                 if let Ok(number) = (if multiplier > 1 && self.buf.len() > 1 {
-                    std::str::from_utf8(&self.buf[..self.buf.len() - 1]).unwrap()
+                    &word[..word.len() - 1]
                 } else {
                     word
                 })
@@ -138,7 +154,9 @@ impl<'x> Tokenizer<'x> {
             Some(TokenInfo {
                 token,
                 line_num: self.token_line_num,
-                line_pos: self.token_line_pos,
+                line_pos,
+                offset_start,
+                offset_end,
             })
         } else {
             None
@@ -160,7 +178,9 @@ impl<'x> Tokenizer<'x> {
         let next_token = TokenInfo {
             token,
             line_num: self.line_num,
-            line_pos: self.pos - self.line_start,
+            line_pos: self.line_pos(),
+            offset_start: self.pos,
+            offset_end: self.pos + 1,
         };
         if let Some(token) = self.get_current_token() {
             self.next_token.push(next_token);
@@ -211,11 +231,15 @@ impl<'x> Tokenizer<'x> {
                 token,
                 line_num: self.text_line_num,
                 line_pos: self.text_line_pos,
+                offset_start: self.text_offset,
+                offset_end: self.pos.wrapping_add(1),
             })
         } else {
             Err(CompileError {
                 line_num: self.text_line_num,
                 line_pos: self.text_line_pos,
+                offset_start: self.text_offset,
+                offset_end: self.pos.wrapping_add(1),
                 error_type: ErrorType::StringTooLong,
             })
         }
@@ -225,11 +249,19 @@ impl<'x> Tokenizer<'x> {
     pub fn push_byte(&mut self, ch: u8) {
         if self.buf.is_empty() {
             self.token_line_num = self.line_num;
-            self.token_line_pos = self.pos - self.line_start;
+            self.token_line_pos = self.line_pos();
+            self.token_offset = self.pos;
         }
         self.buf.push(ch);
     }
 
+    // 1-based column of the current byte, lines start right after the
+    // previous line feed (or before the first byte of the script).
+    #[inline(always)]
+    pub fn line_pos(&self) -> usize {
+        self.pos.wrapping_sub(self.line_start)
+    }
+
     #[inline(always)]
     pub fn new_line(&mut self) {
         self.line_num += 1;
@@ -237,9 +269,23 @@ impl<'x> Tokenizer<'x> {
     }
 
     #[inline(always)]
+    // Keeps the comment ending before `end` when comments are preserved,
+    // without the line break that ends a hash comment
+    fn comment_end(&mut self, end: usize) {
+        if self.compiler.preserve_comments {
+            let comment = self.bytes.get(self.comment_start..end).unwrap_or_default();
+            let comment = comment.strip_suffix(b"\r").unwrap_or(comment);
+            self.comments.push((
+                self.comment_start,
+                String::from_utf8_lossy(comment).into_owned(),
+            ));
+        }
+    }
+
     pub fn text_start(&mut self) {
         self.text_line_num = self.line_num;
-        self.text_line_pos = self.pos - self.line_start;
+        self.text_line_pos = self.line_pos();
+        self.text_offset = self.pos;
     }
 
     #[inline(always)]
@@ -273,7 +319,9 @@ impl<'x> Tokenizer<'x> {
         } else {
             Err(CompileError {
                 line_num: self.line_num,
-                line_pos: self.pos - self.line_start,
+                line_pos: self.line_pos(),
+                offset_start: self.pos.wrapping_add(1),
+                offset_end: self.pos.wrapping_add(1),
                 error_type: ErrorType::UnexpectedEOF,
             })
         }
@@ -311,9 +359,19 @@ impl<'x> Tokenizer<'x> {
     }
 
     pub fn expect_number(&mut self, max_value: usize) -> Result<usize, CompileError> {
+        self.expect_number_in_range(0, max_value)
+    }
+
+    pub fn expect_number_in_range(
+        &mut self,
+        min_value: usize,
+        max_value: usize,
+    ) -> Result<usize, CompileError> {
         let next_token = self.unwrap_next()?;
         if let Token::Number(n) = next_token.token {
-            if n < max_value {
+            if n < min_value {
+                Err(next_token.custom(ErrorType::InvalidNumber(n.to_string())))
+            } else if n < max_value {
                 Ok(n)
             } else {
                 Err(next_token.expected(format!("number lower than {max_value}")))
@@ -326,7 +384,9 @@ impl<'x> Tokenizer<'x> {
     pub fn invalid_character(&self) -> CompileError {
         CompileError {
             line_num: self.line_num,
-            line_pos: self.pos - self.line_start,
+            line_pos: self.line_pos(),
+            offset_start: self.pos,
+            offset_end: self.pos + 1,
             error_type: ErrorType::InvalidCharacter(self.last_ch),
         }
     }
@@ -367,6 +427,7 @@ impl<'x> Iterator for Tokenizer<'x> {
                         } else if self.token_bytes().eq_ignore_ascii_case(b"text") {
                             self.state = State::MultiLine(StringType::default());
                             self.text_start();
+                            self.text_offset = self.token_offset;
                             while let Some((ch, _)) = self.next_byte() {
                                 if ch == b'\n' {
                                     self.new_line();
@@ -413,6 +474,7 @@ impl<'x> Iterator for Tokenizer<'x> {
                     b'/' => {
                         if let Some((b'*', _)) = self.next_byte() {
                             self.last_ch = 0;
+                            self.comment_start = self.pos - 1;
                             self.state = State::BracketComment;
                             self.text_start();
                             if let Some(token) = self.get_current_token() {
@@ -424,6 +486,7 @@ impl<'x> Iterator for Tokenizer<'x> {
                     }
                     b'#' => {
                         self.state = State::HashComment;
+                        self.comment_start = self.pos;
                         if let Some(token) = self.get_current_token() {
                             return Some(Ok(token));
                         }
@@ -443,9 +506,10 @@ impl<'x> Iterator for Tokenizer<'x> {
                         return Some(Err(self.invalid_character()));
                     }
                 },
-                State::BracketComment { .. } => match ch {
+                State::BracketComment => match ch {
                     b'/' if last_ch == b'*' => {
                         self.state = State::None;
+                        self.comment_end(self.pos + 1);
                     }
                     b'\n' => {
                         self.new_line();
@@ -455,6 +519,7 @@ impl<'x> Iterator for Tokenizer<'x> {
                 State::HashComment => {
                     if ch == b'\n' {
                         self.state = State::None;
+                        self.comment_end(self.pos);
                         self.new_line();
                     }
                 }
@@ -580,10 +645,18 @@ impl<'x> Iterator for Tokenizer<'x> {
                 Some(Err(CompileError {
                     line_num: self.text_line_num,
                     line_pos: self.text_line_pos,
+                    offset_start: self.text_offset,
+                    offset_end: self.pos.wrapping_add(1),
                     error_type: (&self.state).into(),
                 }))
             }
-            _ => None,
+            // A word cut short by the end of the input
+            State::None => self.get_current_token().map(Ok),
+            State::HashComment => {
+                self.state = State::None;
+                self.comment_end(self.bytes.len());
+                None
+            }
         }
     }
 }
@@ -598,3 +671,29 @@ impl From<&State> for ErrorType {
         }
     }
 }
+
+/// Unescapes the contents of a quoted string, given without the enclosing
+/// double quotes, as the tokenizer does (RFC 5228, section 2.4.2): a
+/// backslash yields the character that follows it, except for `\n`, `\r`
+/// and `\t` which are read as line feed, carriage return and tab.
+/// Variable references such as `${name}` are returned as they are.
+pub fn unescape_string(raw: &[u8]) -> Result<String, ErrorType> {
+    let mut result = Vec::with_capacity(raw.len());
+    let mut iter = raw.iter();
+
+    while let Some(&ch) = iter.next() {
+        match ch {
+            b'\\' => match iter.next() {
+                Some(b'n') => result.push(b'\n'),
+                Some(b'r') => result.push(b'\r'),
+                Some(b't') => result.push(b'\t'),
+                Some(&ch) => result.push(ch),
+                None => return Err(ErrorType::UnterminatedString),
+            },
+            b'"' => return Err(ErrorType::InvalidCharacter(ch)),
+            _ => result.push(ch),
+        }
+    }
+
+    String::from_utf8(result).map_err(|_| ErrorType::InvalidUtf8String)
+}
@@ -46,12 +46,14 @@ pub(crate) enum Word {
     Content,
     ContentType,
     Convert,
+    Convertible,
     Copy,
     Count,
     Create,
     CurrentDate,
     Date,
     Days,
+    Default,
     DeleteHeader,
     Detail,
     Discard,
@@ -112,9 +114,11 @@ pub(crate) enum Word {
     Param,
     Percent,
     Personal,
+    QuoteMailto,
     QuoteRegex,
     QuoteWildcard,
     Raw,
+    Reason,
     Redirect,
     Regex,
     Reject,
@@ -157,6 +161,16 @@ pub(crate) enum Word {
     While,
     Let,
     Continue,
+    Hash,
+    Encode,
+    Decode,
+    DecodeUrl,
+    Path,
+    Query,
+    Component,
+    Trim,
+    TrimStart,
+    TrimEnd,
 }
 
 pub(crate) static WORDS: phf::Map<&'static str, Word> = phf_map! {
@@ -179,12 +193,14 @@ pub(crate) static WORDS: phf::Map<&'static str, Word> = phf_map! {
     "content" => Word::Content,
     "contenttype" => Word::ContentType,
     "convert" => Word::Convert,
+    "convertible" => Word::Convertible,
     "copy" => Word::Copy,
     "count" => Word::Count,
     "create" => Word::Create,
     "currentdate" => Word::CurrentDate,
     "date" => Word::Date,
     "days" => Word::Days,
+    "default" => Word::Default,
     "deleteheader" => Word::DeleteHeader,
     "detail" => Word::Detail,
     "discard" => Word::Discard,
@@ -245,9 +261,11 @@ pub(crate) static WORDS: phf::Map<&'static str, Word> = phf_map! {
     "param" => Word::Param,
     "percent" => Word::Percent,
     "personal" => Word::Personal,
+    "quotemailto" => Word::QuoteMailto,
     "quoteregex" => Word::QuoteRegex,
     "quotewildcard" => Word::QuoteWildcard,
     "raw" => Word::Raw,
+    "reason" => Word::Reason,
     "redirect" => Word::Redirect,
     "regex" => Word::Regex,
     "reject" => Word::Reject,
@@ -288,6 +306,16 @@ pub(crate) static WORDS: phf::Map<&'static str, Word> = phf_map! {
     "while" => Word::While,
     "let" => Word::Let,
     "continue" => Word::Continue,
+    "hash" => Word::Hash,
+    "encode" => Word::Encode,
+    "decode" => Word::Decode,
+    "decodeurl" => Word::DecodeUrl,
+    "path" => Word::Path,
+    "query" => Word::Query,
+    "component" => Word::Component,
+    "trim" => Word::Trim,
+    "trimstart" => Word::TrimStart,
+    "trimend" => Word::TrimEnd,
 };
 
 impl Display for Word {
@@ -312,12 +340,14 @@ impl Display for Word {
             Word::Content => f.write_str("content"),
             Word::ContentType => f.write_str("contenttype"),
             Word::Convert => f.write_str("convert"),
+            Word::Convertible => f.write_str("convertible"),
             Word::Copy => f.write_str("copy"),
             Word::Count => f.write_str("count"),
             Word::Create => f.write_str("create"),
             Word::CurrentDate => f.write_str("currentdate"),
             Word::Date => f.write_str("date"),
             Word::Days => f.write_str("days"),
+            Word::Default => f.write_str("default"),
             Word::DeleteHeader => f.write_str("deleteheader"),
             Word::Detail => f.write_str("detail"),
             Word::Discard => f.write_str("discard"),
@@ -378,9 +408,11 @@ impl Display for Word {
             Word::Param => f.write_str("param"),
             Word::Percent => f.write_str("percent"),
             Word::Personal => f.write_str("personal"),
+            Word::QuoteMailto => f.write_str("quotemailto"),
             Word::QuoteRegex => f.write_str("quoteregex"),
             Word::QuoteWildcard => f.write_str("quotewildcard"),
             Word::Raw => f.write_str("raw"),
+            Word::Reason => f.write_str("reason"),
             Word::Redirect => f.write_str("redirect"),
             Word::Regex => f.write_str("regex"),
             Word::Reject => f.write_str("reject"),
@@ -421,6 +453,16 @@ impl Display for Word {
             Word::While => f.write_str("while"),
             Word::Let => f.write_str("let"),
             Word::Continue => f.write_str("continue"),
+            Word::Hash => f.write_str("hash"),
+            Word::Encode => f.write_str("encode"),
+            Word::Decode => f.write_str("decode"),
+            Word::DecodeUrl => f.write_str("decodeurl"),
+            Word::Path => f.write_str("path"),
+            Word::Query => f.write_str("query"),
+            Word::Component => f.write_str("component"),
+            Word::Trim => f.write_str("trim"),
+            Word::TrimStart => f.write_str("trimstart"),
+            Word::TrimEnd => f.write_str("trimend"),
         }
     }
 }
@@ -0,0 +1,318 @@
+/*
+ * Copyright (c) 2020-2023, Stalwart Labs Ltd.
+ *
+ * This file is part of the Stalwart Sieve Interpreter.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ * in the LICENSE file at the top-level directory of this distribution.
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ * You can be released from the requirements of the AGPLv3 license by
+ * purchasing a commercial license. Please contact licensing@stalw.art
+ * for more details.
+*/
+
+//! Opt-in checks for scripts that compile but most likely contain a
+//! mistake, see `Compiler::lint`.
+
+use std::{fmt::Display, ops::Range};
+
+use crate::{runtime::actions::action_redirect::sanitize_address, Compiler};
+
+use super::{
+    flow::{command_span, local_clears, successors, VariableAccess},
+    grammar::instruction::{CommandSpan, Instruction},
+    CompileError, CompileWarning, Value, WarningType,
+};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Lint {
+    severity: LintSeverity,
+    message: String,
+    line_num: usize,
+    line_pos: usize,
+    offset_start: usize,
+    offset_end: usize,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LintSeverity {
+    /// The script does not compile or a command can never succeed.
+    Error,
+    /// The script runs, but likely not as its author intended.
+    Warning,
+}
+
+impl Compiler {
+    /// Checks a script for code that compiles but is most likely a mistake:
+    /// commands that can never run, such as the body of `if false` or
+    /// anything after `stop`, actions following a `discard` in the same
+    /// block, redirects to a constant that is not a valid address and local
+    /// variables that are set but never read. Compile warnings are included
+    /// as well. A script that does not compile yields its error as the only
+    /// lint.
+    ///
+    /// Lints are returned in source order. Linting is separate from
+    /// compiling, `compile` neither runs these checks nor reports them.
+    pub fn lint(&self, script: &[u8]) -> Vec<Lint> {
+        let mut var_decls = Vec::new();
+        let mut spans = Vec::new();
+        let sieve = match self.compile_(script, None, None, Some(&mut var_decls), Some(&mut spans))
+        {
+            Ok(sieve) => sieve,
+            Err(err) => return vec![self.missing_capabilities(script, err).into()],
+        };
+        let instructions = &sieve.instructions;
+
+        // Unreachable code is found below from the jump graph, which also
+        // covers what these warnings report
+        let mut lints = sieve
+            .warnings
+            .iter()
+            .filter(|warning| {
+                !matches!(
+                    warning.warning_type,
+                    WarningType::UnreachableBranch | WarningType::UnreachableCode
+                )
+            })
+            .map(Lint::from)
+            .collect::<Vec<_>>();
+
+        // Report the first command of every unreachable sequence, skipping
+        // commands such as `else` that emit no instructions of their own
+        let reachable = reachable_instructions(instructions);
+        let mut last_reachable = true;
+        for (idx, span) in spans.iter().enumerate() {
+            if span.pos >= instructions.len()
+                || spans.get(idx + 1).is_some_and(|next| next.pos == span.pos)
+            {
+                continue;
+            }
+            if !reachable[span.pos] && last_reachable {
+                lints.push(Lint::warning(
+                    span,
+                    format!("Command '{}' is unreachable", span.command),
+                ));
+            }
+            last_reachable = reachable[span.pos];
+        }
+
+        let jump_targets = instructions
+            .iter()
+            .filter_map(|instruction| match instruction {
+                Instruction::Jmp(target) | Instruction::Jz(target) | Instruction::Jnz(target) => {
+                    Some(*target)
+                }
+                Instruction::ForEveryPart(fep) => Some(fep.jz_pos),
+                Instruction::While(while_) => Some(while_.jz_pos),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        for (pos, instruction) in instructions.iter().enumerate() {
+            match instruction {
+                Instruction::Discard(_) if reachable[pos] => {
+                    // Actions that run right after a discard undo it
+                    for (next_pos, next) in instructions.iter().enumerate().skip(pos + 1) {
+                        if jump_targets.contains(&next_pos) || next.is_control_flow() {
+                            break;
+                        } else if next.is_action() {
+                            if let Some(span) = command_span(&spans, next_pos) {
+                                lints.push(Lint::warning(
+                                    span,
+                                    format!(
+                                        "Command '{}' after 'discard' cancels the discard",
+                                        span.command
+                                    ),
+                                ));
+                            }
+                            break;
+                        }
+                    }
+                }
+                Instruction::Redirect(redirect) => {
+                    if let Value::Text(address) = &redirect.address {
+                        if sanitize_address(address).is_none() {
+                            if let Some(span) = command_span(&spans, pos) {
+                                lints.push(Lint::error(
+                                    span,
+                                    format!("Redirect to invalid address {address:?}"),
+                                ));
+                            }
+                        }
+                    }
+                }
+                _ => (),
+            }
+        }
+
+        // Variable ids are reused by sibling blocks, each clear of an id
+        // starts a new variable
+        let clears = local_clears(instructions);
+        let generation = |pos: usize, id: usize| {
+            clears
+                .iter()
+                .filter(|(clear_pos, ids)| *clear_pos < pos && ids.contains(&id))
+                .count()
+        };
+        let access = VariableAccess::new(&sieve);
+
+        let mut declared = Vec::new();
+        for decl in &var_decls {
+            let Some(var_id) = decl.var_id else {
+                continue;
+            };
+            let var_gen = declared.iter().filter(|id| **id == var_id).count();
+            declared.push(var_id);
+
+            let is_read = access
+                .reads
+                .iter()
+                .any(|(pos, id)| *id == var_id && generation(*pos, *id) == var_gen);
+            let first_write = access
+                .writes
+                .iter()
+                .find(|(pos, id)| *id == var_id && generation(*pos, *id) == var_gen);
+            if let (false, Some((pos, _))) = (is_read, first_write) {
+                if let Some(span) = command_span(&spans, *pos) {
+                    lints.push(Lint::warning(
+                        span,
+                        format!("Variable {:?} is set but never read", decl.name),
+                    ));
+                }
+            }
+        }
+
+        lints.sort_by_key(|lint| lint.offset_start);
+        lints
+    }
+}
+
+// Marks the instructions that can run
+fn reachable_instructions(instructions: &[Instruction]) -> Vec<bool> {
+    let mut reachable = vec![false; instructions.len()];
+    let mut stack = vec![0];
+
+    while let Some(pos) = stack.pop() {
+        if pos >= instructions.len() || reachable[pos] {
+            continue;
+        }
+        reachable[pos] = true;
+        successors(instructions, pos, &mut stack);
+    }
+
+    reachable
+}
+
+impl Instruction {
+    fn is_control_flow(&self) -> bool {
+        matches!(
+            self,
+            Instruction::Jmp(_)
+                | Instruction::Jz(_)
+                | Instruction::Jnz(_)
+                | Instruction::Test(_)
+                | Instruction::ForEveryPart(_)
+                | Instruction::ForEveryPartPush
+                | Instruction::ForEveryPartPop(_)
+                | Instruction::While(_)
+                | Instruction::Clear(_)
+                | Instruction::Stop
+                | Instruction::Return
+                | Instruction::Error(_)
+        )
+    }
+
+    fn is_action(&self) -> bool {
+        matches!(
+            self,
+            Instruction::Keep(_)
+                | Instruction::FileInto(_)
+                | Instruction::Redirect(_)
+                | Instruction::Reject(_)
+                | Instruction::Notify(_)
+                | Instruction::Vacation(_)
+        )
+    }
+}
+
+impl Lint {
+    fn error(span: &CommandSpan, message: String) -> Self {
+        Lint::new(LintSeverity::Error, span, message)
+    }
+
+    fn warning(span: &CommandSpan, message: String) -> Self {
+        Lint::new(LintSeverity::Warning, span, message)
+    }
+
+    fn new(severity: LintSeverity, span: &CommandSpan, message: String) -> Self {
+        Lint {
+            severity,
+            message,
+            line_num: span.line_num,
+            line_pos: span.line_pos,
+            offset_start: span.offset_start,
+            offset_end: span.offset_end,
+        }
+    }
+
+    pub fn severity(&self) -> LintSeverity {
+        self.severity
+    }
+
+    pub fn message(&self) -> &str {
+        &self.message
+    }
+
+    pub fn line_num(&self) -> usize {
+        self.line_num
+    }
+
+    pub fn line_pos(&self) -> usize {
+        self.line_pos
+    }
+
+    pub fn span(&self) -> Range<usize> {
+        self.offset_start..self.offset_end
+    }
+}
+
+impl From<CompileError> for Lint {
+    fn from(err: CompileError) -> Self {
+        Lint {
+            severity: LintSeverity::Error,
+            message: err.to_string(),
+            line_num: err.line_num,
+            line_pos: err.line_pos,
+            offset_start: err.offset_start,
+            offset_end: err.offset_end,
+        }
+    }
+}
+
+impl From<&CompileWarning> for Lint {
+    fn from(warning: &CompileWarning) -> Self {
+        Lint {
+            severity: LintSeverity::Warning,
+            message: warning.to_string(),
+            line_num: warning.line_num,
+            line_pos: warning.line_pos,
+            offset_start: warning.offset_start,
+            offset_end: warning.offset_end,
+        }
+    }
+}
+
+impl Display for Lint {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.message.fmt(f)
+    }
+}
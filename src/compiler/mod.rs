@@ -21,30 +21,93 @@
  * for more details.
 */
 
-use std::{borrow::Cow, fmt::Display, sync::Arc};
+use std::{borrow::Cow, cell::RefCell, cmp::Ordering, fmt::Display, ops::Range, sync::Arc};
 
-use ahash::AHashMap;
+use ahash::{AHashMap, AHashSet};
 use mail_parser::HeaderName;
 use serde::{Deserialize, Deserializer, Serialize, Serializer};
 
-use crate::{runtime::RuntimeError, Compiler, Envelope, FunctionMap};
+use crate::{
+    runtime::{RuntimeError, DEFAULT_PROTECTED_HEADERS},
+    ActionKind, Callback, CallbackSet, CfEdge, Compiler, Envelope, ExternalId, FunctionMap,
+    ScriptCost, Sieve, COST_BODY, COST_INCLUDE, COST_INSTRUCTION, COST_LOOP_FACTOR, COST_REGEX,
+    COST_TEST,
+};
 
 use self::{
-    grammar::{AddressPart, Capability},
+    grammar::{
+        actions::{
+            action_editheader::{AddHeader, DeleteHeader},
+            action_include::Include,
+            action_mime::ExtractText,
+            action_redirect::Redirect,
+            action_set::{Let, Set},
+        },
+        expr::{parser::ID_EXTERNAL, Expression},
+        instruction::Instruction,
+        test::Test,
+        tests::{
+            test_address::TestAddress,
+            test_date::{TestCurrentDate, TestDate},
+            test_envelope::TestEnvelope,
+            test_header::TestHeader,
+            test_string::TestString,
+        },
+        AddressPart, Capability, MatchType,
+    },
     lexer::tokenizer::TokenInfo,
+    visit::Visitor,
 };
 
+pub mod decompile;
+mod flow;
 pub mod grammar;
 pub mod lexer;
+pub mod lint;
+pub mod visit;
+
+/// Numbering base used when reporting a line/column position.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum IndexBase {
+    Zero,
+    One,
+}
 
 #[derive(Debug)]
+#[cfg_attr(feature = "serde_errors", derive(Serialize))]
 pub struct CompileError {
     line_num: usize,
     line_pos: usize,
+    offset_start: usize,
+    offset_end: usize,
     error_type: ErrorType,
 }
 
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde_errors", derive(Serialize))]
+pub struct CompileWarning {
+    line_num: usize,
+    line_pos: usize,
+    offset_start: usize,
+    offset_end: usize,
+    warning_type: WarningType,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde_errors", derive(Serialize))]
+pub enum WarningType {
+    UnreachableBranch,
+    UnreachableCode,
+    DuplicateRequire(Capability),
+    ShadowedVariable(String),
+    ShadowedCaptureGroup(String),
+    /// A variable is read before it is set on at least one path, or is never
+    /// set at all, so it expands to an empty string.
+    UnassignedVariable(String),
+}
+
 #[derive(Debug)]
+#[cfg_attr(feature = "serde_errors", derive(Serialize))]
 pub enum ErrorType {
     InvalidCharacter(u8),
     InvalidNumber(String),
@@ -56,6 +119,12 @@ pub enum ErrorType {
     InvalidUtf8String,
     InvalidHeaderName,
     InvalidArguments,
+    InvalidFunctionArguments {
+        name: String,
+        min_args: u32,
+        max_args: u32,
+        found: u32,
+    },
     InvalidAddress,
     InvalidURI,
     InvalidEnvelope(String),
@@ -78,6 +147,9 @@ pub enum ErrorType {
     TooManyNestedTests,
     TooManyNestedForEveryParts,
     TooManyIncludes,
+    TooManyRedirects,
+    IncludeNotFound(String),
+    IncludeCycle(String),
     LabelAlreadyDefined(String),
     LabelUndefined(String),
     BreakOutsideLoop,
@@ -86,6 +158,8 @@ pub enum ErrorType {
     DuplicatedParameter,
     UndeclaredCapability(Capability),
     MissingTag(Cow<'static, str>),
+    ReadError(String),
+    MissingCapabilities(Vec<Capability>),
 }
 
 impl Default for Compiler {
@@ -95,7 +169,8 @@ impl Default for Compiler {
 }
 
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
-pub(crate) enum Value {
+#[non_exhaustive]
+pub enum Value {
     Text(Arc<String>),
     Number(Number),
     Variable(VariableType),
@@ -105,7 +180,7 @@ pub(crate) enum Value {
 
 #[derive(Debug, Clone)]
 pub struct Regex {
-    pub regex: fancy_regex::Regex,
+    pub regex: Arc<fancy_regex::Regex>,
     pub expr: String,
 }
 
@@ -113,6 +188,9 @@ pub struct Regex {
 pub enum VariableType {
     Local(usize),
     Match(usize),
+    /// The capture groups `1..=n` of the enclosing `:regex` test as a list,
+    /// referenced as `${match.groups}`.
+    MatchGroups(usize),
     Global(String),
     Environment(String),
     Envelope(Envelope),
@@ -126,9 +204,106 @@ pub struct Transform {
     pub functions: Vec<usize>,
 }
 
+/// Identifies a header name referenced by a script, see `Sieve::header_name`.
+/// Headers known to the MIME parser, such as `Subject`, have the same id in
+/// every script. Other names are interned in a table owned by each script,
+/// so their ids are only meaningful for the script that references them and
+/// are not shared between scripts.
+pub type HeaderId = u32;
+
+// Ids of headers not known to the MIME parser start after the known ones
+pub(crate) const HEADER_ID_OTHER: HeaderId = 37;
+
+// Headers known to the MIME parser, indexed by their id
+pub(crate) static KNOWN_HEADERS: [HeaderName<'static>; HEADER_ID_OTHER as usize] = [
+    HeaderName::Subject,
+    HeaderName::From,
+    HeaderName::To,
+    HeaderName::Cc,
+    HeaderName::Date,
+    HeaderName::Bcc,
+    HeaderName::ReplyTo,
+    HeaderName::Sender,
+    HeaderName::Comments,
+    HeaderName::InReplyTo,
+    HeaderName::Keywords,
+    HeaderName::Received,
+    HeaderName::MessageId,
+    HeaderName::References,
+    HeaderName::ReturnPath,
+    HeaderName::MimeVersion,
+    HeaderName::ContentDescription,
+    HeaderName::ContentId,
+    HeaderName::ContentLanguage,
+    HeaderName::ContentLocation,
+    HeaderName::ContentTransferEncoding,
+    HeaderName::ContentType,
+    HeaderName::ContentDisposition,
+    HeaderName::ResentTo,
+    HeaderName::ResentFrom,
+    HeaderName::ResentBcc,
+    HeaderName::ResentCc,
+    HeaderName::ResentSender,
+    HeaderName::ResentDate,
+    HeaderName::ResentMessageId,
+    HeaderName::ListArchive,
+    HeaderName::ListHelp,
+    HeaderName::ListId,
+    HeaderName::ListOwner,
+    HeaderName::ListPost,
+    HeaderName::ListSubscribe,
+    HeaderName::ListUnsubscribe,
+];
+
+// Ids are part of the serialized script, so they are assigned here rather
+// than taken from `HeaderName::id`, which may change between mail-parser
+// releases. Changing an id requires bumping `Compiler::VERSION`.
+pub(crate) fn known_header_id(name: &HeaderName) -> Option<HeaderId> {
+    Some(match name {
+        HeaderName::Subject => 0,
+        HeaderName::From => 1,
+        HeaderName::To => 2,
+        HeaderName::Cc => 3,
+        HeaderName::Date => 4,
+        HeaderName::Bcc => 5,
+        HeaderName::ReplyTo => 6,
+        HeaderName::Sender => 7,
+        HeaderName::Comments => 8,
+        HeaderName::InReplyTo => 9,
+        HeaderName::Keywords => 10,
+        HeaderName::Received => 11,
+        HeaderName::MessageId => 12,
+        HeaderName::References => 13,
+        HeaderName::ReturnPath => 14,
+        HeaderName::MimeVersion => 15,
+        HeaderName::ContentDescription => 16,
+        HeaderName::ContentId => 17,
+        HeaderName::ContentLanguage => 18,
+        HeaderName::ContentLocation => 19,
+        HeaderName::ContentTransferEncoding => 20,
+        HeaderName::ContentType => 21,
+        HeaderName::ContentDisposition => 22,
+        HeaderName::ResentTo => 23,
+        HeaderName::ResentFrom => 24,
+        HeaderName::ResentBcc => 25,
+        HeaderName::ResentCc => 26,
+        HeaderName::ResentSender => 27,
+        HeaderName::ResentDate => 28,
+        HeaderName::ResentMessageId => 29,
+        HeaderName::ListArchive => 30,
+        HeaderName::ListHelp => 31,
+        HeaderName::ListId => 32,
+        HeaderName::ListOwner => 33,
+        HeaderName::ListPost => 34,
+        HeaderName::ListSubscribe => 35,
+        HeaderName::ListUnsubscribe => 36,
+        _ => return None,
+    })
+}
+
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct HeaderVariable {
-    pub name: Vec<HeaderName<'static>>,
+    pub name: Vec<HeaderId>,
     pub part: HeaderPart,
     pub index_hdr: i32,
     pub index_part: i32,
@@ -140,6 +315,7 @@ pub enum MessagePart {
     HtmlBody(bool),
     Contents,
     Raw,
+    Headers,
 }
 
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
@@ -202,10 +378,49 @@ impl Number {
     }
 }
 
+impl Number {
+    /// Orders two numbers exactly, returning `None` if either one is NaN.
+    /// Infinity is kept ordered since `i;ascii-numeric` maps non-numeric
+    /// strings to positive infinity (RFC 4790, section 9.1.1).
+    pub fn compare(&self, other: &Number) -> Option<Ordering> {
+        match (self, other) {
+            (Number::Integer(a), Number::Integer(b)) => Some(a.cmp(b)),
+            (Number::Float(a), Number::Float(b)) => a.partial_cmp(b),
+            (Number::Integer(a), Number::Float(b)) if !b.is_nan() => {
+                Some(compare_int_float(*a, *b))
+            }
+            (Number::Float(a), Number::Integer(b)) if !a.is_nan() => {
+                Some(compare_int_float(*b, *a).reverse())
+            }
+            _ => None,
+        }
+    }
+}
+
+fn compare_int_float(a: i64, b: f64) -> Ordering {
+    // 2^63 is exactly representable, anything at or beyond it (including
+    // infinity) is out of range
+    if b >= 9223372036854775808.0 {
+        Ordering::Less
+    } else if b < -9223372036854775808.0 {
+        Ordering::Greater
+    } else {
+        let trunc = b.trunc();
+        a.cmp(&(trunc as i64)).then(if b > trunc {
+            Ordering::Less
+        } else if b < trunc {
+            Ordering::Greater
+        } else {
+            Ordering::Equal
+        })
+    }
+}
+
 impl From<Number> for usize {
     fn from(value: Number) -> Self {
         match value {
-            Number::Integer(i) => i as usize,
+            Number::Integer(i) => i.max(0) as usize,
+            // Float to integer casts saturate and map NaN to zero
             Number::Float(fl) => fl as usize,
         }
     }
@@ -215,13 +430,20 @@ impl Display for Number {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
             Number::Integer(i) => i.fmt(f),
+            // Floats always have a decimal part and only use an exponent
+            // when the fixed notation would be unreasonably long.
+            Number::Float(fl) if !fl.is_finite() => fl.fmt(f),
+            Number::Float(fl) if *fl != 0.0 && !(1e-7..1e21).contains(&fl.abs()) => {
+                write!(f, "{fl:e}")
+            }
+            Number::Float(fl) if fl.fract() == 0.0 => write!(f, "{fl:.1}"),
             Number::Float(fl) => fl.fmt(f),
         }
     }
 }
 
 impl Compiler {
-    pub const VERSION: u32 = 2;
+    pub const VERSION: u32 = 3;
 
     pub fn new() -> Self {
         Compiler {
@@ -235,8 +457,13 @@ impl Compiler {
             max_local_variables: 128,
             max_header_size: 1024,
             max_includes: 6,
+            max_redirects: 32,
             functions: AHashMap::new(),
+            comparators: AHashSet::new(),
             no_capability_check: false,
+            strict_capabilities: false,
+            preserve_comments: false,
+            known_size: None,
         }
     }
 
@@ -258,6 +485,15 @@ impl Compiler {
         self
     }
 
+    pub fn set_max_redirects(&mut self, size: usize) {
+        self.max_redirects = size;
+    }
+
+    pub fn with_max_redirects(mut self, size: usize) -> Self {
+        self.max_redirects = size;
+        self
+    }
+
     pub fn set_max_nested_blocks(&mut self, size: usize) {
         self.max_nested_blocks = size;
     }
@@ -330,8 +566,22 @@ impl Compiler {
         self
     }
 
+    /// Registers the function names used by scripts. The names are kept in
+    /// `fnc_map` so they can still be looked up with `FunctionMap::names`
+    /// and `FunctionMap::external_name`.
     pub fn register_functions(mut self, fnc_map: &mut FunctionMap) -> Self {
-        self.functions = std::mem::take(&mut fnc_map.map);
+        self.functions = fnc_map.map.clone();
+        self
+    }
+
+    pub fn set_comparator(&mut self, name: impl Into<String>) {
+        self.comparators.insert(name.into());
+    }
+
+    /// Accepts `name` as a `:comparator` argument. The implementation is
+    /// supplied at runtime through `Runtime::with_comparator`.
+    pub fn register_comparator(mut self, name: impl Into<String>) -> Self {
+        self.set_comparator(name);
         self
     }
 
@@ -343,22 +593,407 @@ impl Compiler {
     pub fn set_no_capability_check(&mut self, value: bool) {
         self.no_capability_check = value;
     }
+
+    /// Requires every capability used by a script, vendor extensions
+    /// included, to be declared with `require`, overriding
+    /// `no_capability_check`. Scripts that are missing declarations fail with
+    /// `ErrorType::MissingCapabilities` listing all of them.
+    pub fn with_strict_capabilities(mut self, value: bool) -> Self {
+        self.strict_capabilities = value;
+        self
+    }
+
+    pub fn set_strict_capabilities(&mut self, value: bool) {
+        self.strict_capabilities = value;
+    }
+
+    /// Keeps the hash and bracket comments of compiled scripts, see
+    /// `Sieve::comments`. Comments never affect the compiled instructions.
+    pub fn with_preserve_comments(mut self, value: bool) -> Self {
+        self.preserve_comments = value;
+        self
+    }
+
+    pub fn set_preserve_comments(&mut self, value: bool) {
+        self.preserve_comments = value;
+    }
+
+    /// Compiles `size` tests against a message size that is known in
+    /// advance, folding them into constant tests so that the branches they
+    /// rule out are never taken. Scripts compiled this way must only be run
+    /// on messages of exactly this size.
+    pub fn with_known_size(mut self, size: Option<usize>) -> Self {
+        self.known_size = size;
+        self
+    }
+
+    pub fn set_known_size(&mut self, size: Option<usize>) {
+        self.known_size = size;
+    }
+}
+
+impl Sieve {
+    /// Returns the capabilities declared with `require` together with those
+    /// used by the script's commands and arguments, sorted by name. This
+    /// includes capabilities used without a `require` when the script was
+    /// compiled with `no_capability_check`, as well as those of any scripts
+    /// linked in with `compile_with_includes`. Capabilities implied by a
+    /// required one (see `Capability::implied`) are listed too.
+    #[doc(alias = "required_capabilities")]
+    pub fn capabilities(&self) -> &[Capability] {
+        &self.capabilities
+    }
+
+    /// Returns the compiled instructions. Jump targets (`Jmp`, `Jz`, `Jnz`, etc.)
+    /// are indexes into this slice.
+    ///
+    /// `Instruction` and the types it contains are `#[non_exhaustive]`: new
+    /// commands and arguments are added in minor releases, so matches need a
+    /// wildcard arm and the types can only be inspected, not built.
+    pub fn instructions(&self) -> &[Instruction] {
+        &self.instructions
+    }
+
+    // Line of the command that emitted the instruction at `pos`
+    pub(crate) fn line_num(&self, pos: usize) -> Option<usize> {
+        self.lines.get(pos).map(|line| *line as usize)
+    }
+
+    /// Returns the jumps in the compiled instructions as pairs of source
+    /// instruction index and edge, in instruction order. Every instruction
+    /// not listed simply falls through to the next one.
+    pub fn control_flow(&self) -> Vec<(usize, CfEdge)> {
+        self.instructions
+            .iter()
+            .enumerate()
+            .filter_map(|(pos, instruction)| {
+                let edge = match instruction {
+                    Instruction::Jmp(target) => CfEdge::Jump(*target),
+                    Instruction::Jz(target) => CfEdge::JumpIfFalse(*target),
+                    Instruction::Jnz(target) => CfEdge::JumpIfTrue(*target),
+                    Instruction::ForEveryPart(fep) => CfEdge::ForEveryPart(fep.jz_pos),
+                    Instruction::ForEveryPartPop(levels) => CfEdge::ForEveryPartPop {
+                        levels: *levels,
+                        target: pos + 1,
+                    },
+                    Instruction::While(while_) => CfEdge::While(while_.jz_pos),
+                    _ => return None,
+                };
+                Some((pos, edge))
+            })
+            .collect()
+    }
+
+    /// Returns an upper bound on the cost of running the script without
+    /// running it. The estimate is not exact, but only grows as the script
+    /// gets more expensive, so it can be compared against a threshold when
+    /// scripts are uploaded. Scripts loaded with `include` at runtime are not
+    /// included.
+    pub fn estimated_cost(&self) -> ScriptCost {
+        let loops = self
+            .instructions
+            .iter()
+            .enumerate()
+            .filter_map(|(pos, instruction)| match instruction {
+                Instruction::ForEveryPart(fep) => Some((pos, fep.jz_pos)),
+                Instruction::While(while_) => Some((pos, while_.jz_pos)),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+
+        let mut cost = ScriptCost::default();
+        for (pos, instruction) in self.instructions.iter().enumerate() {
+            let depth = loops
+                .iter()
+                .filter(|(start, end)| pos > *start && pos < *end)
+                .count();
+            cost.max_loop_depth = cost.max_loop_depth.max(depth);
+            cost.total = cost.total.saturating_add(
+                instruction_cost(instruction)
+                    .saturating_mul(COST_LOOP_FACTOR.saturating_pow(depth as u32)),
+            );
+        }
+        cost
+    }
+
+    /// Returns the table of header names referenced by the script that are
+    /// not known to the MIME parser, indexed by `HeaderId` minus the number
+    /// of known headers. The table belongs to this script, together with the
+    /// scripts it inlined, and is stored with it when serialized.
+    pub fn header_names(&self) -> &[HeaderName<'static>] {
+        &self.header_names
+    }
+
+    /// Resolves a `HeaderId` used by this script to its header name.
+    pub fn header_name(&self, id: HeaderId) -> Option<&HeaderName<'static>> {
+        KNOWN_HEADERS.get(id as usize).or_else(|| {
+            self.header_names
+                .get(id.checked_sub(HEADER_ID_OTHER)? as usize)
+        })
+    }
+
+    /// Returns the comments of the script as pairs of byte offset and text,
+    /// in source order. Hash comments do not include the line break ending
+    /// them, bracket comments include their `/*` and `*/` delimiters. Only
+    /// collected when compiling with `Compiler::with_preserve_comments`.
+    pub fn comments(&self) -> &[(usize, String)] {
+        &self.comments
+    }
+
+    /// Returns the non-fatal issues found while compiling the script.
+    pub fn warnings(&self) -> &[CompileWarning] {
+        &self.warnings
+    }
+
+    /// Returns `true` if the script reads the message body, either through a
+    /// body test, a MIME part operation (`foreverypart`, `extracttext`,
+    /// `replace`, `enclose`, `convert`) or a part variable. Scripts loaded
+    /// with `include` at runtime have to be checked separately.
+    pub fn requires_body(&self) -> bool {
+        self.body_pos != usize::MAX
+    }
+
+    /// Returns `true` if the script compares values with the `:value` or
+    /// `:count` match types of the relational extension (RFC 5231).
+    pub fn uses_relational(&self) -> bool {
+        self.instructions.iter().any(|instruction| {
+            matches!(
+                match instruction {
+                    Instruction::Test(test) => test.match_type(),
+                    Instruction::DeleteHeader(delete_header) => Some(&delete_header.match_type),
+                    _ => None,
+                },
+                Some(MatchType::Value(_) | MatchType::Count(_))
+            )
+        })
+    }
+
+    /// Returns the host callbacks this script may trigger when executed.
+    pub fn required_callbacks(&self) -> CallbackSet {
+        let mut callbacks = CallbackSet::new();
+
+        for instruction in &self.instructions {
+            match instruction {
+                Instruction::Include(_) => {
+                    callbacks.insert(Callback::IncludeScript);
+                }
+                Instruction::Convert(_) => {
+                    callbacks.insert(Callback::Convert);
+                }
+                Instruction::Test(test) => match test {
+                    Test::MailboxExists(_)
+                    | Test::MailboxIdExists(_)
+                    | Test::SpecialUseExists(_) => {
+                        callbacks.insert(Callback::MailboxExists);
+                    }
+                    Test::Duplicate(_) | Test::Vacation(_) => {
+                        callbacks.insert(Callback::DuplicateId);
+                    }
+                    Test::Metadata(_) | Test::MetadataExists(_) => {
+                        callbacks.insert(Callback::Metadata);
+                    }
+                    Test::SpamTest(_) => {
+                        callbacks.insert(Callback::SpamTest);
+                    }
+                    Test::VirusTest(_) => {
+                        callbacks.insert(Callback::VirusTest);
+                    }
+                    Test::Convert(_) => {
+                        callbacks.insert(Callback::Convert);
+                    }
+                    Test::Address(TestAddress {
+                        match_type: MatchType::List,
+                        ..
+                    })
+                    | Test::Envelope(TestEnvelope {
+                        match_type: MatchType::List,
+                        ..
+                    })
+                    | Test::Header(TestHeader {
+                        match_type: MatchType::List,
+                        ..
+                    })
+                    | Test::Date(TestDate {
+                        match_type: MatchType::List,
+                        ..
+                    })
+                    | Test::CurrentDate(TestCurrentDate {
+                        match_type: MatchType::List,
+                        ..
+                    })
+                    | Test::String(TestString {
+                        match_type: MatchType::List,
+                        ..
+                    })
+                    | Test::Environment(TestString {
+                        match_type: MatchType::List,
+                        ..
+                    }) => {
+                        callbacks.insert(Callback::ListContains);
+                    }
+                    _ => (),
+                },
+                Instruction::Set(Set {
+                    name: VariableType::Envelope(_),
+                    ..
+                })
+                | Instruction::ExtractText(ExtractText {
+                    name: VariableType::Envelope(_),
+                    ..
+                }) => {
+                    callbacks.insert(Callback::SetEnvelope);
+                }
+                Instruction::Let(Let {
+                    name: VariableType::Envelope(_),
+                    ..
+                }) => {
+                    callbacks.insert(Callback::SetEnvelope);
+                }
+                _ => (),
+            }
+        }
+        callbacks.extend(
+            self.external_functions()
+                .into_iter()
+                .map(Callback::Function),
+        );
+
+        callbacks
+    }
+
+    /// Returns `true` if the script may perform the given action.
+    pub fn uses_action(&self, kind: ActionKind) -> bool {
+        self.scan_actions().actions.contains(&kind)
+    }
+
+    /// Returns the actions requiring policy review used by this script,
+    /// in order of first appearance.
+    pub fn dangerous_actions(&self) -> Vec<ActionKind> {
+        self.scan_actions().actions
+    }
+
+    /// Returns the distinct external functions called by this script, in
+    /// order of first appearance. Names can be resolved with
+    /// `FunctionMap::external_name`.
+    pub fn external_functions(&self) -> Vec<ExternalId> {
+        self.scan_actions().functions
+    }
+
+    fn scan_actions(&self) -> ActionScan {
+        let mut scan = ActionScan::default();
+        self.walk(&mut scan);
+        scan
+    }
+}
+
+#[derive(Default)]
+struct ActionScan {
+    actions: Vec<ActionKind>,
+    functions: Vec<ExternalId>,
+}
+
+impl ActionScan {
+    fn add_action(&mut self, kind: ActionKind) {
+        if !self.actions.contains(&kind) {
+            self.actions.push(kind);
+        }
+    }
+
+    fn edit_header(&mut self, field_name: &Value) {
+        // Field names that are only known at runtime are treated as protected.
+        let is_protected = match field_name {
+            Value::Text(name) => DEFAULT_PROTECTED_HEADERS
+                .iter()
+                .any(|header| header.eq_ignore_ascii_case(name)),
+            _ => true,
+        };
+        if is_protected {
+            self.add_action(ActionKind::EditProtectedHeader);
+        }
+    }
+}
+
+impl Visitor for ActionScan {
+    fn visit_redirect(&mut self, _: usize, _: &Redirect) {
+        self.add_action(ActionKind::Redirect);
+    }
+
+    fn visit_include(&mut self, _: usize, _: &Include) {
+        self.add_action(ActionKind::Include);
+    }
+
+    fn visit_addheader(&mut self, _: usize, addheader: &AddHeader) {
+        self.edit_header(&addheader.field_name);
+    }
+
+    fn visit_deleteheader(&mut self, _: usize, deleteheader: &DeleteHeader) {
+        self.edit_header(&deleteheader.field_name);
+    }
+
+    fn visit_expression(&mut self, _: usize, expr: &[Expression]) {
+        for expr in expr {
+            if let Expression::Function { id, .. } = expr {
+                if *id > u32::MAX / 2 {
+                    let id = ID_EXTERNAL - *id;
+                    if !self.functions.contains(&id) {
+                        self.functions.push(id);
+                    }
+                    self.add_action(ActionKind::Execute);
+                }
+            }
+        }
+    }
 }
 
 impl CompileError {
+    /// Line number of the error, starting at 1.
     pub fn line_num(&self) -> usize {
         self.line_num
     }
 
+    /// Column of the error in bytes from the start of the line, starting at 1.
     pub fn line_pos(&self) -> usize {
         self.line_pos
     }
 
+    /// Returns the `(line, column)` of the error using the requested base.
+    pub fn position(&self, base: IndexBase) -> (usize, usize) {
+        match base {
+            IndexBase::One => (self.line_num, self.line_pos),
+            IndexBase::Zero => (
+                self.line_num.saturating_sub(1),
+                self.line_pos.saturating_sub(1),
+            ),
+        }
+    }
+
+    pub fn span(&self) -> Range<usize> {
+        self.offset_start..self.offset_end
+    }
+
     pub fn error_type(&self) -> &ErrorType {
         &self.error_type
     }
 }
 
+impl CompileWarning {
+    pub fn line_num(&self) -> usize {
+        self.line_num
+    }
+
+    pub fn line_pos(&self) -> usize {
+        self.line_pos
+    }
+
+    pub fn span(&self) -> Range<usize> {
+        self.offset_start..self.offset_end
+    }
+
+    pub fn warning_type(&self) -> &WarningType {
+        &self.warning_type
+    }
+}
+
 impl PartialEq for Regex {
     fn eq(&self, other: &Self) -> bool {
         self.expr == other.expr
@@ -382,18 +1017,65 @@ impl<'de> Deserialize<'de> for Regex {
         D: Deserializer<'de>,
     {
         <String>::deserialize(deserializer).and_then(|expr| {
-            fancy_regex::Regex::new(&expr)
-                .map(|regex| Regex { regex, expr })
-                .map_err(|err| serde::de::Error::custom(err.to_string()))
+            let cached = REGEX_CACHE.with(|cache| {
+                cache
+                    .borrow()
+                    .as_ref()
+                    .and_then(|cache| cache.get(&expr).cloned())
+            });
+            let regex = if let Some(regex) = cached {
+                regex
+            } else {
+                let regex = Arc::new(
+                    fancy_regex::Regex::new(&expr)
+                        .map_err(|err| serde::de::Error::custom(err.to_string()))?,
+                );
+                REGEX_CACHE.with(|cache| {
+                    if let Some(cache) = cache.borrow_mut().as_mut() {
+                        cache.insert(expr.clone(), regex.clone());
+                    }
+                });
+                regex
+            };
+            Ok(Regex { regex, expr })
         })
     }
 }
 
+thread_local! {
+    // Regexes compiled while deserializing the instructions of a `Sieve`,
+    // so that identical patterns share one compiled regex as they do when
+    // the script is compiled
+    static REGEX_CACHE: RefCell<Option<AHashMap<String, Arc<fancy_regex::Regex>>>> =
+        const { RefCell::new(None) };
+}
+
+pub(crate) fn deserialize_instructions<'de, D>(
+    deserializer: D,
+) -> Result<Vec<Instruction>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    struct RestoreCache(Option<AHashMap<String, Arc<fancy_regex::Regex>>>);
+
+    impl Drop for RestoreCache {
+        fn drop(&mut self) {
+            let prev = self.0.take();
+            REGEX_CACHE.with(|cache| *cache.borrow_mut() = prev);
+        }
+    }
+
+    let _restore = RestoreCache(REGEX_CACHE.with(|cache| cache.replace(Some(AHashMap::new()))));
+    Vec::<Instruction>::deserialize(deserializer)
+}
+
 impl TokenInfo {
     pub fn expected(self, expected: impl Into<Cow<'static, str>>) -> CompileError {
         CompileError {
             line_num: self.line_num,
             line_pos: self.line_pos,
+            offset_start: self.offset_start,
+            offset_end: self.offset_end,
             error_type: ErrorType::UnexpectedToken {
                 expected: expected.into(),
                 found: self.token.to_string(),
@@ -405,14 +1087,28 @@ impl TokenInfo {
         CompileError {
             line_num: self.line_num,
             line_pos: self.line_pos,
+            offset_start: self.offset_start,
+            offset_end: self.offset_end,
             error_type: ErrorType::MissingTag(tag.into()),
         }
     }
 
+    pub fn warning(&self, warning_type: WarningType) -> CompileWarning {
+        CompileWarning {
+            line_num: self.line_num,
+            line_pos: self.line_pos,
+            offset_start: self.offset_start,
+            offset_end: self.offset_end,
+            warning_type,
+        }
+    }
+
     pub fn custom(self, error_type: ErrorType) -> CompileError {
         CompileError {
             line_num: self.line_num,
             line_pos: self.line_pos,
+            offset_start: self.offset_start,
+            offset_end: self.offset_end,
             error_type,
         }
     }
@@ -437,6 +1133,24 @@ impl Display for CompileError {
             ErrorType::InvalidUtf8String => write!(f, "Invalid UTF-8 string"),
             ErrorType::InvalidHeaderName => write!(f, "Invalid header name"),
             ErrorType::InvalidArguments => write!(f, "Invalid Arguments"),
+            ErrorType::InvalidFunctionArguments {
+                name,
+                min_args,
+                max_args,
+                found,
+            } => {
+                if min_args == max_args {
+                    write!(
+                        f,
+                        "Function {name:?} expects {max_args} arguments, got {found}"
+                    )
+                } else {
+                    write!(
+                        f,
+                        "Function {name:?} expects {min_args} to {max_args} arguments, got {found}"
+                    )
+                }
+            }
             ErrorType::InvalidAddress => write!(f, "Invalid Address"),
             ErrorType::InvalidURI => write!(f, "Invalid URI"),
             ErrorType::InvalidEnvelope(value) => write!(f, "Invalid envelope {value:?}"),
@@ -462,6 +1176,11 @@ impl Display for CompileError {
                 write!(f, "Too many nested foreverypart blocks")
             }
             ErrorType::TooManyIncludes => write!(f, "Too many includes"),
+            ErrorType::TooManyRedirects => write!(f, "Too many redirects"),
+            ErrorType::IncludeNotFound(value) => write!(f, "Included script {value:?} not found"),
+            ErrorType::IncludeCycle(value) => {
+                write!(f, "Script {value:?} is included recursively")
+            }
             ErrorType::LabelAlreadyDefined(value) => write!(f, "Label {value:?} already defined"),
             ErrorType::LabelUndefined(value) => write!(f, "Label {value:?} does not exist"),
             ErrorType::BreakOutsideLoop => write!(f, "Break used outside of foreverypart loop"),
@@ -474,6 +1193,17 @@ impl Display for CompileError {
                 write!(f, "Undeclared capability '{value}'")
             }
             ErrorType::MissingTag(value) => write!(f, "Missing tag {value:?}"),
+            ErrorType::ReadError(value) => write!(f, "Failed to read script: {value}"),
+            ErrorType::MissingCapabilities(value) => {
+                f.write_str("Missing require for capabilities ")?;
+                for (pos, capability) in value.iter().enumerate() {
+                    if pos > 0 {
+                        f.write_str(", ")?;
+                    }
+                    write!(f, "{:?}", capability.to_string())?;
+                }
+                Ok(())
+            }
         }?;
 
         write!(
@@ -485,6 +1215,39 @@ impl Display for CompileError {
     }
 }
 
+impl Display for CompileWarning {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.warning_type {
+            WarningType::UnreachableBranch => write!(f, "Unreachable branch"),
+            WarningType::UnreachableCode => write!(f, "Unreachable code after 'stop'"),
+            WarningType::DuplicateRequire(capability) => {
+                write!(f, "Duplicate require of capability \"{capability}\"")
+            }
+            WarningType::ShadowedVariable(name) => {
+                write!(
+                    f,
+                    "Local variable {name:?} is shadowed by an enclosing block"
+                )
+            }
+            WarningType::ShadowedCaptureGroup(name) => {
+                write!(
+                    f,
+                    "Capture group {name:?} is shadowed by a variable with the same name"
+                )
+            }
+            WarningType::UnassignedVariable(name) => {
+                write!(f, "Variable {name:?} is used before it is set")
+            }
+        }?;
+        write!(
+            f,
+            " at line {}, column {}.",
+            self.line_num(),
+            self.line_pos()
+        )
+    }
+}
+
 impl Display for RuntimeError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
@@ -509,15 +1272,73 @@ impl Display for RuntimeError {
                 f,
                 "Script exceeded the maximum number of instructions allowed to execute."
             ),
+            RuntimeError::TimeLimitReached => {
+                write!(f, "Script exceeded the maximum time allowed to execute.")
+            }
+            RuntimeError::EventLimitReached => {
+                write!(f, "Script exceeded the maximum number of events allowed.")
+            }
         }
     }
 }
 
+fn instruction_cost(instruction: &Instruction) -> u64 {
+    let test = match instruction {
+        Instruction::Test(test) => test,
+        Instruction::ForEveryPart(_)
+        | Instruction::ExtractText(_)
+        | Instruction::Replace(_)
+        | Instruction::Enclose(_)
+        | Instruction::Convert(_) => return COST_BODY,
+        Instruction::Include(_) => return COST_INCLUDE,
+        _ => return COST_INSTRUCTION,
+    };
+    let match_type = match test {
+        Test::Body(_) | Test::Convert(_) => return COST_BODY,
+        Test::Header(t) if t.mime_anychild => return COST_BODY,
+        Test::Address(t) => &t.match_type,
+        Test::CurrentDate(t) => &t.match_type,
+        Test::Date(t) => &t.match_type,
+        Test::Envelope(t) => &t.match_type,
+        Test::HasFlag(t) => &t.match_type,
+        Test::Header(t) => &t.match_type,
+        Test::Metadata(t) => &t.match_type,
+        Test::NotifyMethodCapability(t) => &t.match_type,
+        Test::SpamTest(t) => &t.match_type,
+        Test::String(t) | Test::Environment(t) => &t.match_type,
+        Test::VirusTest(t) => &t.match_type,
+        _ => return COST_TEST,
+    };
+    if matches!(match_type, MatchType::Regex(_)) {
+        COST_REGEX
+    } else {
+        COST_TEST
+    }
+}
+
 #[cfg(test)]
 mod tests {
-    use std::{fs, path::PathBuf};
-
-    use crate::Compiler;
+    use std::{cmp::Ordering, fs, path::PathBuf, sync::Arc};
+
+    use mail_parser::{HeaderName, MessageParser};
+
+    use crate::{
+        ActionKind, Callback, CfEdge, Compiler, Context, Event, FunctionMap, Input, Instruction,
+        Runtime, ScriptCost, Sieve, COST_BODY, COST_INSTRUCTION, COST_LOOP_FACTOR, COST_REGEX,
+        COST_TEST,
+    };
+
+    use super::{
+        grammar::{
+            actions::{action_discard::Discard, action_fileinto::FileInto, action_keep::Keep},
+            test::Test,
+            Capability,
+        },
+        known_header_id,
+        lint::LintSeverity,
+        visit::Visitor,
+        ErrorType, HeaderId, IndexBase, Number, Value, VariableType, WarningType, KNOWN_HEADERS,
+    };
 
     #[test]
     fn parse_rfc() {
@@ -530,7 +1351,7 @@ mod tests {
 
         for file_name in fs::read_dir(&test_dir).unwrap() {
             let mut file_name = file_name.unwrap().path();
-            if file_name.extension().map_or(false, |e| e == "sieve") {
+            if file_name.extension().is_some_and(|e| e == "sieve") {
                 println!("Parsing {}", file_name.display());
 
                 /*if !file_name
@@ -574,4 +1395,1675 @@ mod tests {
             test_dir.display()
         );
     }
+
+    #[test]
+    fn decompile_round_trip() {
+        fn read_dir(path: PathBuf, files: &mut Vec<PathBuf>) {
+            for entry in fs::read_dir(path).unwrap() {
+                let entry = entry.unwrap().path();
+                if entry.is_dir() {
+                    read_dir(entry, files);
+                } else if entry.extension().is_some_and(|e| e == "sieve") {
+                    files.push(entry);
+                }
+            }
+        }
+
+        let mut files = Vec::new();
+        read_dir(
+            PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests"),
+            &mut files,
+        );
+        let compiler = Compiler::new().with_max_nested_foreverypart(10);
+        let mut tests_run = 0;
+
+        for file_name in files {
+            // Skip the scripts that are expected to fail compilation
+            let Ok(sieve) = compiler.compile(&fs::read(&file_name).unwrap()) else {
+                continue;
+            };
+            println!("Decompiling {}", file_name.display());
+
+            let script = sieve.to_script();
+            let recompiled = compiler.compile(script.as_bytes()).unwrap_or_else(|err| {
+                panic!(
+                    "Failed to recompile {}: {err}\n{script}",
+                    file_name.display()
+                )
+            });
+
+            assert_eq!(
+                sieve.instructions,
+                recompiled.instructions,
+                "Decompiled {} does not round-trip:\n{script}",
+                file_name.display()
+            );
+            assert_eq!(sieve.num_vars, recompiled.num_vars);
+            assert_eq!(sieve.num_match_vars, recompiled.num_match_vars);
+            tests_run += 1;
+        }
+
+        assert!(tests_run > 0, "Did not find any scripts to decompile.");
+    }
+
+    #[test]
+    fn error_span() {
+        let compiler = Compiler::new();
+
+        for (script, expected) in [
+            (
+                &b"require \"fileinto\";\nfileinto :copy \"INBOX\";"[..],
+                ":copy",
+            ),
+            (b"if true {\n  keep;\n", "{"),
+            (b"discard;\nstop 42;", "42"),
+            (b"redirect \"a@b\" \"c@d\";", "\"c@d\""),
+            (b"keep;\nkeep :zzz;", ":zzz"),
+            (b"keep;\nfileinto \"x\";", "fileinto"),
+        ] {
+            let err = compiler.compile(script).unwrap_err();
+            assert_eq!(
+                std::str::from_utf8(&script[err.span()]).unwrap(),
+                expected,
+                "{}: {err}",
+                std::str::from_utf8(script).unwrap()
+            );
+        }
+    }
+
+    #[test]
+    fn instructions() {
+        let sieve = Compiler::new()
+            .compile(b"if true { discard; } else { keep; }")
+            .unwrap();
+        let instructions = sieve.instructions();
+
+        assert!(matches!(instructions[0], Instruction::Test(_)));
+        for instruction in instructions {
+            if let Instruction::Jmp(pos) | Instruction::Jz(pos) | Instruction::Jnz(pos) =
+                instruction
+            {
+                assert!(*pos <= instructions.len());
+            }
+        }
+        assert!(instructions.contains(&Instruction::Discard(Discard { reason: None })));
+    }
+
+    #[test]
+    fn compile_with_includes() {
+        let compiler = Compiler::new();
+        let resolver = |name: &str, _: bool| match name {
+            "sub" => Some(
+                b"require [\"include\", \"variables\", \"fileinto\"];
+                set \"a\" \"sub\";
+                if true { fileinto \"${a}\"; return; }
+                fileinto \"unreachable\";"
+                    .to_vec(),
+            ),
+            "loop" => Some(b"require \"include\"; include \"loop\";".to_vec()),
+            _ => None,
+        };
+
+        let sieve = compiler
+            .compile_with_includes(
+                b"require [\"include\", \"variables\", \"fileinto\"];
+                set \"a\" \"main\";
+                include \"sub\";
+                include :optional \"missing\";
+                include :once \"sub\";
+                fileinto \"${a}\";",
+                resolver,
+            )
+            .unwrap();
+        assert!(!sieve
+            .instructions()
+            .iter()
+            .any(|i| matches!(i, Instruction::Include(_) | Instruction::Return)));
+
+        let runtime = Runtime::new();
+        let mut instance = Context::new(
+            &runtime,
+            MessageParser::new()
+                .parse(b"Subject: test\r\n\r\ntest\r\n")
+                .unwrap(),
+        );
+        let mut input = Input::script("main", sieve);
+        let mut folders = Vec::new();
+        while let Some(result) = instance.run(input) {
+            if let Event::FileInto { folder, .. } = result.unwrap() {
+                folders.push(folder);
+            }
+            input = Input::True;
+        }
+        assert_eq!(folders, ["sub", "main"]);
+
+        assert!(matches!(
+            compiler
+                .compile_with_includes(b"require \"include\"; include \"loop\";", resolver)
+                .unwrap_err()
+                .error_type(),
+            ErrorType::IncludeCycle(name) if name == "loop"
+        ));
+        assert!(matches!(
+            compiler
+                .compile_with_includes(b"require \"include\"; include \"nope\";", resolver)
+                .unwrap_err()
+                .error_type(),
+            ErrorType::IncludeNotFound(name) if name == "nope"
+        ));
+    }
+
+    #[test]
+    fn estimated_cost() {
+        let compiler = Compiler::new();
+        let cost = |script: &str| {
+            compiler
+                .compile(script.as_bytes())
+                .unwrap()
+                .estimated_cost()
+        };
+
+        let header = cost("require \"regex\"; if header :is \"subject\" \"a\" { keep; }");
+        let regex = cost("require \"regex\"; if header :regex \"subject\" \"a\" { keep; }");
+        let body = cost("require \"body\"; if body :contains \"a\" { keep; }");
+        assert_eq!(
+            header,
+            ScriptCost {
+                total: COST_TEST + 3 * COST_INSTRUCTION,
+                max_loop_depth: 0
+            }
+        );
+        assert_eq!(regex.total - header.total, COST_REGEX - COST_TEST);
+        assert_eq!(body.total - header.total, COST_BODY - COST_TEST);
+
+        // Every statement adds to the cost
+        assert!(
+            cost("require \"regex\"; if header :is \"subject\" \"a\" { keep; } keep;").total
+                > header.total
+        );
+
+        // Loops multiply the cost of their body
+        let looped = cost(concat!(
+            "require [\"foreverypart\", \"regex\"];",
+            "foreverypart { foreverypart {",
+            "if header :regex \"subject\" \"a\" { keep; }",
+            "} }"
+        ));
+        assert_eq!(looped.max_loop_depth, 2);
+        assert!(looped.total >= regex.total * COST_LOOP_FACTOR * COST_LOOP_FACTOR);
+    }
+
+    #[test]
+    fn variable_ordering() {
+        let script = concat!(
+            "require [\"variables\", \"include\"];\n",
+            "global [\"zeta\", \"alpha\", \"mid\"];\n",
+            "set \"zeta\" \"1\"; set \"alpha\" \"2\"; set \"mid\" \"3\";\n",
+            "set \"z\" \"1\"; set \"y\" \"2\"; set \"x\" \"3\"; set \"w\" \"4\";\n",
+            "if true {\n",
+            "  set :local \"q\" \"1\"; set \"p\" \"2\"; set :local \"o\" \"3\";\n",
+            "  if true { set :local \"n\" \"${z}${q}\"; set \"m\" \"${n}${o}\"; }\n",
+            "}\n",
+        );
+
+        // Local variable ids follow declaration order on every compile
+        let local_ids = |sieve: &Sieve| {
+            sieve
+                .instructions()
+                .iter()
+                .filter_map(|instruction| match instruction {
+                    Instruction::Set(set) => match &set.name {
+                        VariableType::Local(id) => Some(*id),
+                        _ => None,
+                    },
+                    _ => None,
+                })
+                .collect::<Vec<_>>()
+        };
+        let sieve = Compiler::new().compile(script.as_bytes()).unwrap();
+        assert_eq!(local_ids(&sieve), [0, 1, 2, 3, 6, 4, 7, 8, 5]);
+        for _ in 0..8 {
+            let recompiled = Compiler::new().compile(script.as_bytes()).unwrap();
+            assert_eq!(local_ids(&recompiled), local_ids(&sieve));
+            assert_eq!(recompiled.serialize().unwrap(), sieve.serialize().unwrap());
+        }
+
+        // Global variable names are listed in a stable order
+        let runtime = Runtime::new();
+        let mut instance = Context::new(
+            &runtime,
+            MessageParser::new()
+                .parse(b"Subject: test\r\n\r\ntest\r\n")
+                .unwrap(),
+        );
+        let mut input = Input::script("", sieve);
+        while let Some(result) = instance.run(input) {
+            result.unwrap();
+            input = Input::True;
+        }
+        assert_eq!(
+            instance.global_variable_names().collect::<Vec<_>>(),
+            ["alpha", "mid", "zeta"]
+        );
+    }
+
+    #[test]
+    fn known_header_ids() {
+        for (id, name) in KNOWN_HEADERS.iter().enumerate() {
+            assert_eq!(known_header_id(name), Some(id as HeaderId), "{name:?}");
+            assert_eq!(
+                HeaderName::parse(name.as_str().to_string()).as_ref(),
+                Some(name),
+                "{name:?}"
+            );
+        }
+        assert_eq!(known_header_id(&HeaderName::Other("X-Spam".into())), None);
+    }
+
+    #[test]
+    fn header_ids() {
+        let compiler = Compiler::new();
+        let header_ids = |sieve: &Sieve| {
+            let mut ids = Vec::new();
+            for instruction in sieve.instructions() {
+                if let Instruction::Set(set) = instruction {
+                    if let Value::Variable(VariableType::Header(header)) = &set.value {
+                        ids.extend(header.name.iter().copied());
+                    }
+                }
+            }
+            ids
+        };
+
+        let first = compiler
+            .compile(
+                br#"require "variables";
+                set "a" "${header.subject}";
+                set "b" "${header.x-spam-flag}";
+                set "c" "${header.X-Spam-Flag}";"#,
+            )
+            .unwrap();
+        let second = compiler
+            .compile(
+                br#"require "variables";
+                set "a" "${header.x-mailer}";
+                set "b" "${header.Subject}";"#,
+            )
+            .unwrap();
+
+        // Known headers share their id across scripts, other names are
+        // interned once per script
+        let first_ids = header_ids(&first);
+        let second_ids = header_ids(&second);
+        assert_eq!(first_ids[0], second_ids[1]);
+        assert_eq!(first_ids[1], first_ids[2]);
+        assert_eq!(first.header_names().len(), 1);
+        assert_eq!(first.header_name(first_ids[0]), Some(&HeaderName::Subject));
+        assert_eq!(
+            first.header_name(first_ids[1]).map(|name| name.as_str()),
+            Some("x-spam-flag")
+        );
+        assert_eq!(
+            second.header_name(second_ids[0]).map(|name| name.as_str()),
+            Some("x-mailer")
+        );
+
+        let first = Sieve::deserialize(&first.serialize().unwrap()).unwrap();
+        assert_eq!(first.header_names().len(), 1);
+        assert_eq!(
+            first.header_name(first_ids[1]).map(|name| name.as_str()),
+            Some("x-spam-flag")
+        );
+
+        // Inlined scripts share the table of the script including them
+        let linked = compiler
+            .compile_with_includes(
+                br#"require ["include", "variables"];
+                set "a" "${header.x-list}";
+                include "sub";"#,
+                |_, _| {
+                    Some(
+                        br#"require "variables";
+                        set "b" "${header.x-spam-flag}";
+                        set "c" "${header.x-list}";"#
+                            .to_vec(),
+                    )
+                },
+            )
+            .unwrap();
+        let names = header_ids(&linked)
+            .into_iter()
+            .map(|id| linked.header_name(id).unwrap().as_str().to_string())
+            .collect::<Vec<_>>();
+        assert_eq!(names, ["x-list", "x-spam-flag", "x-list"]);
+        assert_eq!(linked.header_names().len(), 2);
+    }
+
+    #[test]
+    fn redirects_across_includes() {
+        let compiler = Compiler::new().with_max_redirects(2);
+        let resolver = |name: &str, _: bool| match name {
+            "two" => Some(b"redirect \"a@example.org\"; redirect \"b@example.org\";".to_vec()),
+            "nested" => Some(b"require \"include\"; include \"two\";".to_vec()),
+            _ => None,
+        };
+
+        // Each script is within the limit, but not once they are inlined
+        assert!(compiler
+            .compile_with_includes(b"require \"include\"; include \"two\";", resolver)
+            .is_ok());
+        let err = compiler
+            .compile_with_includes(
+                b"require \"include\";\nredirect \"c@example.org\";\ninclude \"nested\";",
+                resolver,
+            )
+            .unwrap_err();
+        assert!(matches!(err.error_type(), ErrorType::TooManyRedirects));
+        assert_eq!((err.line_num(), err.line_pos()), (3, 1));
+        let err = compiler
+            .compile_with_includes(
+                b"require \"include\";\ninclude \"two\";\ninclude \"two\";",
+                resolver,
+            )
+            .unwrap_err();
+        assert!(matches!(err.error_type(), ErrorType::TooManyRedirects));
+        assert_eq!(err.line_num(), 3);
+
+        // Linked scripts share the limit as well
+        let err = compiler
+            .compile_linked(&[
+                b"redirect \"a@example.org\";",
+                b"redirect \"b@example.org\";\nredirect \"c@example.org\";",
+            ])
+            .unwrap_err();
+        assert!(matches!(err.error_type(), ErrorType::TooManyRedirects));
+        assert_eq!(err.line_num(), 2);
+    }
+
+    #[test]
+    fn compile_linked() {
+        let compiler = Compiler::new();
+        let library_script = b"require [\"include\", \"variables\"];
+                global \"folder\";
+                set \"prefix\" \"Lists\";
+                set \"folder\" \"${prefix}/Rust\";";
+        let mut sieves = compiler
+            .compile_linked(&[
+                library_script,
+                b"require [\"include\", \"variables\", \"fileinto\"];
+                global \"folder\";
+                set \"prefix\" \"Archive\";
+                include :global \"library\";
+                fileinto \"${folder}\";
+                fileinto \"${prefix}\";",
+            ])
+            .unwrap();
+        let user = sieves.pop().unwrap();
+        let library = sieves.pop().unwrap();
+
+        let runtime = Runtime::new();
+        let mut instance = Context::new(
+            &runtime,
+            MessageParser::new()
+                .parse(b"Subject: test\r\n\r\ntest\r\n")
+                .unwrap(),
+        );
+        let mut input = Input::script("user", user);
+        let mut folders = Vec::new();
+        while let Some(result) = instance.run(input) {
+            input = match result.unwrap() {
+                Event::IncludeScript { .. } => Input::script("library", library.clone()),
+                Event::FileInto { folder, .. } => {
+                    folders.push(folder);
+                    Input::True
+                }
+                _ => Input::True,
+            };
+        }
+        assert_eq!(folders, ["Lists/Rust", "Archive"]);
+
+        let err = compiler
+            .compile_linked(&[
+                library_script,
+                b"require \"variables\";\nset \"folder\" \"INBOX\";",
+            ])
+            .unwrap_err();
+        assert!(matches!(
+            err.error_type(),
+            ErrorType::VariableIsLocal(name) if name == "folder"
+        ));
+        assert_eq!(err.line_num, 2);
+    }
+
+    #[test]
+    fn required_callbacks() {
+        let sieve = Compiler::new()
+            .compile(
+                br#"require ["mboxmetadata", "convert", "extlists", "variables"];
+                if metadata "INBOX" "/private/comment" "skip" {
+                    stop;
+                }
+                if header :list "from" ":addrbook:personal" {
+                    set :lower "folder" "known";
+                }
+                convert "image/tiff" "image/jpeg" ["pix-x=320","pix-y=240"];
+                "#,
+            )
+            .unwrap();
+        let callbacks = sieve.required_callbacks();
+        assert_eq!(callbacks.len(), 3);
+        for callback in [
+            Callback::Metadata,
+            Callback::Convert,
+            Callback::ListContains,
+        ] {
+            assert!(callbacks.contains(&callback), "{callback:?} missing");
+        }
+
+        assert!(Compiler::new()
+            .compile(b"keep;")
+            .unwrap()
+            .required_callbacks()
+            .is_empty());
+    }
+
+    #[test]
+    fn analyze_requires() {
+        let requires = Compiler::new()
+            .analyze_requires(
+                br#"require ["fileinto",
+                    "imap4flags"];
+                if header :contains "subject" "x" {
+                    fileinto :copy "Junk";
+                    addflag "\\Seen";
+                }
+                "#,
+            )
+            .unwrap();
+        assert_eq!(
+            requires,
+            vec![
+                (Capability::FileInto, Some((1, 10))),
+                (Capability::Copy, None),
+                (Capability::Imap4Flags, Some((2, 21))),
+            ]
+        );
+    }
+
+    #[test]
+    fn require_sorted() {
+        let sieve = Compiler::new()
+            .compile(
+                br#"require ["variables", "fileinto"];
+                require "body";
+                require ["copy", "imap4flags"];
+                "#,
+            )
+            .unwrap();
+        assert_eq!(
+            sieve.instructions(),
+            [Instruction::Require(vec![
+                Capability::Body,
+                Capability::Copy,
+                Capability::FileInto,
+                Capability::Imap4Flags,
+                Capability::Variables,
+            ])]
+        );
+    }
+
+    #[test]
+    fn number_compare() {
+        for (a, b, expected) in [
+            (
+                Number::Float(1.9),
+                Number::Integer(1),
+                Some(Ordering::Greater),
+            ),
+            (Number::Integer(1), Number::Float(1.9), Some(Ordering::Less)),
+            (
+                Number::Integer(-2),
+                Number::Float(-1.5),
+                Some(Ordering::Less),
+            ),
+            (
+                Number::Float(-1.5),
+                Number::Integer(-1),
+                Some(Ordering::Less),
+            ),
+            (
+                Number::Integer(3),
+                Number::Float(3.0),
+                Some(Ordering::Equal),
+            ),
+            (
+                Number::Integer(i64::MAX),
+                Number::Float(i64::MAX as f64),
+                Some(Ordering::Less),
+            ),
+            (
+                Number::Integer(i64::MAX),
+                Number::Float(f64::INFINITY),
+                Some(Ordering::Less),
+            ),
+            (Number::Integer(1), Number::Float(f64::NAN), None),
+            (Number::Float(f64::NAN), Number::Float(f64::NAN), None),
+        ] {
+            assert_eq!(a.compare(&b), expected, "{a:?} {b:?}");
+        }
+
+        assert_eq!(usize::from(Number::Integer(-1)), 0);
+        assert_eq!(usize::from(Number::Float(-1.5)), 0);
+        assert_eq!(usize::from(Number::Float(f64::NAN)), 0);
+    }
+
+    #[test]
+    fn compile_reader() {
+        struct ChunkedReader<'x>(&'x [u8]);
+
+        impl std::io::Read for ChunkedReader<'_> {
+            fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+                let len = self.0.len().min(buf.len()).min(3);
+                buf[..len].copy_from_slice(&self.0[..len]);
+                self.0 = &self.0[len..];
+                Ok(len)
+            }
+        }
+
+        let compiler = Compiler::new();
+        let script = br#"require "fileinto";
+            if header :contains "subject" "test" {
+                fileinto "Test";
+            }
+            "#;
+        assert_eq!(
+            compiler.compile_reader(ChunkedReader(script)).unwrap(),
+            compiler.compile(script).unwrap()
+        );
+
+        let script = b"require \"fileinto\";\nif true {\n  fileinto;\n}\n";
+        let expected = compiler.compile(script).unwrap_err();
+        let err = compiler.compile_reader(ChunkedReader(script)).unwrap_err();
+        assert_eq!(
+            (err.line_num(), err.line_pos()),
+            (expected.line_num(), expected.line_pos())
+        );
+
+        // Reading an endless input stops once the size limit is crossed
+        let err = Compiler::new()
+            .with_max_script_size(100)
+            .compile_reader(std::io::repeat(b' '))
+            .unwrap_err();
+        assert!(matches!(err.error_type(), ErrorType::ScriptTooLong));
+
+        // Errors point at the byte that went over the limit
+        let err = Compiler::new()
+            .with_max_script_size(10)
+            .compile_reader(ChunkedReader(b"ab\ncdefghijkl"))
+            .unwrap_err();
+        assert!(matches!(err.error_type(), ErrorType::ScriptTooLong));
+        assert_eq!((err.line_num(), err.line_pos()), (2, 8));
+        assert_eq!(err.span(), 10..11);
+
+        // or at the first byte that could not be read
+        struct FailingReader<'x>(&'x [u8]);
+
+        impl std::io::Read for FailingReader<'_> {
+            fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+                if self.0.is_empty() {
+                    return Err(std::io::Error::other("connection reset"));
+                }
+                let len = self.0.len().min(buf.len());
+                buf[..len].copy_from_slice(&self.0[..len]);
+                self.0 = &self.0[len..];
+                Ok(len)
+            }
+        }
+
+        let err = compiler
+            .compile_reader(FailingReader(b"keep;\nif"))
+            .unwrap_err();
+        assert!(matches!(err.error_type(), ErrorType::ReadError(_)));
+        assert_eq!((err.line_num(), err.line_pos()), (2, 3));
+    }
+
+    #[test]
+    fn strict_capabilities() {
+        let script = br#"require "variables";
+            let "result" "exec('/usr/bin/true')";
+            while "result == 0" {
+                let "result" "result + 1";
+            }
+            "#;
+        let compiler = Compiler::new()
+            .with_no_capability_check(true)
+            .register_functions(&mut FunctionMap::new().with_external_function("exec", 0, 1));
+        assert!(compiler.compile(script).is_ok());
+
+        let err = compiler
+            .with_strict_capabilities(true)
+            .compile(script)
+            .unwrap_err();
+        assert!(
+            matches!(err.error_type(), ErrorType::MissingCapabilities(missing) if missing == &[
+                Capability::Expressions,
+                Capability::While
+            ]),
+            "{err:?}"
+        );
+        assert_eq!((err.line_num(), err.line_pos()), (2, 13));
+        assert_eq!(
+            err.to_string(),
+            concat!(
+                "Missing require for capabilities \"vnd.stalwart.expressions\", ",
+                "\"vnd.stalwart.while\" at line 2, column 13."
+            )
+        );
+    }
+
+    #[test]
+    fn regex_interning() {
+        let sieve = Compiler::new()
+            .compile(
+                br#"require ["regex", "variables"];
+                if header :regex "subject" "^re: (.*)$" { stop; }
+                if string :regex "${subject}" ["^re: (.*)$", "^fwd: (.*)$"] { stop; }
+                if header :regex "subject" "^fwd: (.*)$" { stop; }
+                "#,
+            )
+            .unwrap();
+
+        let regexes = |sieve: &Sieve| {
+            let mut regexes = Vec::new();
+            for instruction in sieve.instructions() {
+                let key_list = match instruction {
+                    Instruction::Test(Test::Header(test)) => &test.key_list,
+                    Instruction::Test(Test::String(test)) => &test.key_list,
+                    _ => continue,
+                };
+                for key in key_list {
+                    if let Value::Regex(regex) = key {
+                        regexes.push(regex.regex.clone());
+                    }
+                }
+            }
+            regexes
+        };
+
+        // Regexes are shared again after a bincode round trip, but not
+        // between separately deserialized scripts
+        let bytes = sieve.serialize().unwrap();
+        let deserialized = Sieve::deserialize(&bytes).unwrap();
+        let other = Sieve::deserialize(&bytes).unwrap();
+        assert_eq!(deserialized.instructions(), sieve.instructions());
+        for sieve in [&sieve, &deserialized] {
+            let regexes = regexes(sieve);
+            assert_eq!(regexes.len(), 4);
+            assert!(Arc::ptr_eq(&regexes[0], &regexes[1]));
+            assert!(Arc::ptr_eq(&regexes[2], &regexes[3]));
+            assert!(!Arc::ptr_eq(&regexes[0], &regexes[2]));
+        }
+        assert!(!Arc::ptr_eq(
+            &regexes(&deserialized)[0],
+            &regexes(&other)[0]
+        ));
+    }
+
+    #[test]
+    fn capabilities() {
+        let script = br#"require ["fileinto", "copy"];
+                if header :contains "subject" "away" {
+                    vacation "I am away";
+                }
+                fileinto :copy "Junk";
+                "#;
+        assert!(matches!(
+            Compiler::new().compile(script).unwrap_err().error_type(),
+            ErrorType::UndeclaredCapability(Capability::Vacation)
+        ));
+        let sieve = Compiler::new()
+            .with_no_capability_check(true)
+            .compile(script)
+            .unwrap();
+        assert_eq!(
+            sieve.capabilities(),
+            [Capability::Copy, Capability::FileInto, Capability::Vacation]
+        );
+    }
+
+    #[test]
+    fn implied_capabilities() {
+        assert_eq!(
+            Capability::VacationSeconds.implied(),
+            [Capability::Vacation]
+        );
+        assert!(Capability::Vacation.implied().is_empty());
+
+        let sieve = Compiler::new()
+            .compile(br#"require "vacation-seconds"; vacation :seconds 3600 "I am away";"#)
+            .unwrap();
+        assert_eq!(
+            sieve.capabilities(),
+            [Capability::Vacation, Capability::VacationSeconds]
+        );
+        assert_eq!(
+            sieve.instructions()[0],
+            Instruction::Require(vec![Capability::Vacation, Capability::VacationSeconds])
+        );
+        assert!(sieve.warnings().is_empty());
+
+        // Implied capabilities only flow one way
+        assert!(matches!(
+            Compiler::new()
+                .compile(br#"require "vacation"; vacation :seconds 3600 "I am away";"#)
+                .unwrap_err()
+                .error_type(),
+            ErrorType::UndeclaredCapability(Capability::VacationSeconds)
+        ));
+    }
+
+    #[test]
+    fn dangerous_actions() {
+        let compiler = Compiler::new()
+            .register_functions(&mut FunctionMap::new().with_external_function("exec", 0, 1));
+        let sieve = compiler
+            .compile(
+                br#"require ["variables", "editheader", "vnd.stalwart.expressions"];
+                let "result" "exec('/usr/bin/true')";
+                addheader "X-Filtered" "yes";
+                redirect "jdoe@example.org";
+                "#,
+            )
+            .unwrap();
+        assert_eq!(
+            sieve.dangerous_actions(),
+            [ActionKind::Execute, ActionKind::Redirect]
+        );
+        assert!(sieve.uses_action(ActionKind::Redirect));
+        assert!(sieve.uses_action(ActionKind::Execute));
+        assert!(!sieve.uses_action(ActionKind::Include));
+        assert!(!sieve.uses_action(ActionKind::EditProtectedHeader));
+
+        let sieve = compiler
+            .compile(
+                br#"require ["include", "editheader", "variables"];
+                deleteheader "received";
+                deleteheader "original-subject";
+                set "name" "x-header";
+                addheader "${name}" "value";
+                include :personal "other";
+                "#,
+            )
+            .unwrap();
+        assert_eq!(
+            sieve.dangerous_actions(),
+            [ActionKind::EditProtectedHeader, ActionKind::Include]
+        );
+
+        // External functions are found in test arguments as well
+        let sieve = compiler
+            .compile(
+                br#"require ["vnd.stalwart.expressions"];
+                if eval "exec('/usr/bin/true')" {
+                    keep;
+                }
+                "#,
+            )
+            .unwrap();
+        assert_eq!(sieve.dangerous_actions(), [ActionKind::Execute]);
+        assert_eq!(sieve.external_functions(), [0]);
+    }
+
+    #[test]
+    fn requires_body() {
+        let compiler = Compiler::new();
+
+        for (script, expected) in [
+            (&b"if header :contains \"subject\" \"x\" { discard; }"[..], false),
+            (b"require \"envelope\";\nif envelope \"from\" \"a@b\" { keep; }", false),
+            (b"require \"body\";\nif body :contains \"x\" { discard; }", true),
+            (b"require \"foreverypart\";\nforeverypart { keep; }", true),
+            (
+                b"require [\"foreverypart\", \"extracttext\", \"variables\"];\nforeverypart { extracttext \"a\"; }",
+                true,
+            ),
+            (b"require \"mime\";\nif header :mime :anychild \"x\" \"y\" { keep; }", true),
+            (b"require \"enclose\";\nenclose \"x\";", true),
+        ] {
+            let sieve = compiler.compile(script).unwrap();
+            assert_eq!(
+                sieve.requires_body(),
+                expected,
+                "{}",
+                std::str::from_utf8(script).unwrap()
+            );
+
+            // The answer is kept by the serialized form
+            assert_eq!(
+                Sieve::deserialize(&sieve.serialize().unwrap())
+                    .unwrap()
+                    .requires_body(),
+                expected,
+                "{}",
+                std::str::from_utf8(script).unwrap()
+            );
+        }
+    }
+
+    #[test]
+    fn uses_relational() {
+        let compiler = Compiler::new();
+
+        for (script, expected) in [
+            (&b"if header :contains \"subject\" \"x\" { discard; }"[..], false),
+            (b"require \"relational\";\nif true { keep; }", false),
+            (
+                b"require \"relational\";\nif header :value \"gt\" \"x-priority\" \"3\" { keep; }",
+                true,
+            ),
+            (
+                b"require [\"relational\", \"envelope\"];\nif not envelope :count \"ge\" \"to\" \"2\" { keep; }",
+                true,
+            ),
+            (
+                b"require [\"relational\", \"editheader\"];\ndeleteheader :count \"eq\" \"received\" \"3\";",
+                true,
+            ),
+        ] {
+            assert_eq!(
+                compiler.compile(script).unwrap().uses_relational(),
+                expected,
+                "{}",
+                std::str::from_utf8(script).unwrap()
+            );
+        }
+
+        // Relational match types have to be declared
+        for script in [
+            &b"if header :value \"gt\" \"x-priority\" \"3\" { keep; }"[..],
+            b"require \"envelope\";\nif envelope :count \"ge\" \"to\" \"2\" { keep; }",
+        ] {
+            assert!(matches!(
+                compiler.compile(script).unwrap_err().error_type(),
+                ErrorType::UndeclaredCapability(Capability::Relational)
+            ));
+            assert!(compiler
+                .clone()
+                .with_no_capability_check(true)
+                .compile(script)
+                .unwrap()
+                .uses_relational());
+        }
+    }
+
+    #[test]
+    fn external_functions() {
+        let mut fnc_map = FunctionMap::new()
+            .with_function("trim", |_, v| v[0].to_string().trim().to_string().into())
+            .with_external_function("exec", 0, 1)
+            .with_external_function("lookup", 1, 2)
+            .with_external_function("unused", 2, 0);
+        let mut names = fnc_map.names().collect::<Vec<_>>();
+        names.sort_unstable();
+        assert_eq!(names, ["exec", "lookup", "trim", "unused"]);
+
+        let compiler = Compiler::new().register_functions(&mut fnc_map);
+        let sieve = compiler
+            .compile(
+                br#"require ["variables", "vnd.stalwart.expressions", "vnd.stalwart.while"];
+                let "a" "trim(lookup('x', 'y'))";
+                while "exec(a)" {
+                    eval "lookup(a, 'z')";
+                }
+                "#,
+            )
+            .unwrap();
+        assert_eq!(sieve.external_functions(), [1, 0]);
+        assert_eq!(
+            sieve
+                .external_functions()
+                .into_iter()
+                .filter_map(|id| fnc_map.external_name(id))
+                .collect::<Vec<_>>(),
+            ["lookup", "exec"]
+        );
+        assert_eq!(fnc_map.external_name(2), Some("unused"));
+        assert_eq!(fnc_map.external_name(3), None);
+
+        assert!(compiler
+            .compile(b"keep;")
+            .unwrap()
+            .external_functions()
+            .is_empty());
+    }
+
+    #[test]
+    fn function_arity() {
+        let mut fnc_map = FunctionMap::new()
+            .with_function_arity(
+                "join",
+                |_, v| {
+                    v.iter()
+                        .map(|v| v.to_string().into_owned())
+                        .collect::<Vec<_>>()
+                        .join("-")
+                        .into()
+                },
+                1,
+                3,
+            )
+            .with_external_function("exec", 0, 1)
+            .with_external_function_arity("lookup", 1, 1, 2);
+        let mut signatures = fnc_map
+            .iter()
+            .map(|(name, signature)| {
+                (
+                    name,
+                    signature.min_args,
+                    signature.max_args,
+                    signature.external_id(),
+                )
+            })
+            .collect::<Vec<_>>();
+        signatures.sort_unstable();
+        assert_eq!(
+            signatures,
+            [
+                ("exec", 1, 1, Some(0)),
+                ("join", 1, 3, None),
+                ("lookup", 1, 2, Some(1))
+            ]
+        );
+
+        let compiler = Compiler::new().register_functions(&mut fnc_map);
+        let runtime = Runtime::new()
+            .with_capability(Capability::Expressions)
+            .with_functions(&mut fnc_map);
+        let sieve = compiler
+            .compile(
+                br#"require ["variables", "vnd.stalwart.expressions"];
+                set "a" "${join('x')}.${join('x', 'y', 'z')}";
+                eval "lookup('k')";
+                eval "lookup('k', 'default')";
+                "#,
+            )
+            .unwrap();
+        let mut instance = Context::new(
+            &runtime,
+            MessageParser::new()
+                .parse(b"Subject: test\r\n\r\ntest\r\n")
+                .unwrap(),
+        );
+        let mut input = Input::script("", sieve);
+        let mut calls = Vec::new();
+        while let Some(event) = instance.run(input) {
+            if let Event::Function { id, arguments } = event.unwrap() {
+                calls.push((id, arguments.len()));
+            }
+            input = Input::True;
+        }
+        assert_eq!(calls, [(1, 1), (1, 2)]);
+
+        for (expr, name, found) in [
+            ("join()", "join", 0),
+            ("join(1, 2, 3, 4)", "join", 4),
+            ("exec()", "exec", 0),
+            ("lookup(1, 2, 3)", "lookup", 3),
+        ] {
+            let err = compiler
+                .compile(
+                    format!("require \"vnd.stalwart.expressions\";\neval \"{expr}\";").as_bytes(),
+                )
+                .unwrap_err();
+            assert!(
+                matches!(
+                    err.error_type(),
+                    ErrorType::InvalidFunctionArguments { name: n, found: f, .. }
+                        if n == name && *f == found
+                ),
+                "{expr}: {err}"
+            );
+        }
+    }
+
+    #[test]
+    fn walk() {
+        #[derive(Default)]
+        struct Analysis {
+            folders: Vec<String>,
+            headers: Vec<String>,
+            variable_parts: Vec<usize>,
+            keeps: usize,
+        }
+
+        impl Visitor for Analysis {
+            fn visit_keep(&mut self, _: usize, _: &Keep) {
+                self.keeps += 1;
+            }
+
+            fn visit_fileinto(&mut self, _: usize, fileinto: &FileInto) {
+                if let Value::Text(folder) = &fileinto.folder {
+                    self.folders.push(folder.to_string());
+                }
+            }
+
+            fn visit_test(&mut self, _: usize, test: &Test) {
+                if let Test::Header(test) = test {
+                    for header in &test.header_list {
+                        if let Value::Text(header) = header {
+                            self.headers.push(header.to_string());
+                        }
+                    }
+                }
+            }
+
+            fn visit_value_part(&mut self, pos: usize, part: &Value) {
+                if matches!(part, Value::Variable(_)) {
+                    self.variable_parts.push(pos);
+                }
+            }
+        }
+
+        let script = Compiler::new()
+            .compile(
+                br#"require ["fileinto", "variables"];
+                if allof(header :contains "subject" "a", header :is ["from", "to"] "b") {
+                    fileinto "INBOX.a";
+                } elsif header :matches "list-id" "*" {
+                    fileinto "lists.${1}";
+                } else {
+                    set "folder" "c";
+                    fileinto "${folder}";
+                }
+                "#,
+            )
+            .unwrap();
+        let mut analysis = Analysis::default();
+        script.walk(&mut analysis);
+
+        assert_eq!(analysis.folders, ["INBOX.a"]);
+        assert_eq!(analysis.headers, ["subject", "from", "to", "list-id"]);
+        assert_eq!(analysis.keeps, 0);
+        assert_eq!(analysis.variable_parts.len(), 2);
+        assert!(analysis
+            .variable_parts
+            .iter()
+            .all(|pos| matches!(script.instructions()[*pos], Instruction::FileInto(_))));
+    }
+
+    #[test]
+    fn unreachable_branches() {
+        let compiler = Compiler::new();
+
+        for (script, expected) in [
+            (
+                &b"if true {\n  keep;\n} elsif header :is \"subject\" \"x\" {\n  discard;\n}"[..],
+                &[(3, 3)][..],
+            ),
+            (
+                b"if false {\n  keep;\n} elsif not false {\n  stop;\n} elsif true {\n  discard;\n} else {\n  keep;\n}",
+                &[(5, 3), (7, 3)],
+            ),
+            (
+                b"if false {\n  keep;\n} elsif header :is \"subject\" \"x\" {\n  discard;\n} else {\n  keep;\n}",
+                &[],
+            ),
+            (
+                b"if anyof(false, true) {\n  keep;\n} else {\n  discard;\n}\nif allof(false, true) {\n  keep;\n} else {\n  discard;\n}",
+                &[],
+            ),
+            (
+                b"if true {\n  if true {\n    keep;\n  }\n} else {\n  discard;\n}",
+                &[(5, 3)],
+            ),
+        ] {
+            let sieve = compiler.compile(script).unwrap();
+            assert_eq!(
+                sieve
+                    .warnings()
+                    .iter()
+                    .map(|warning| {
+                        assert_eq!(warning.warning_type(), &WarningType::UnreachableBranch);
+                        (warning.line_num(), warning.line_pos())
+                    })
+                    .collect::<Vec<_>>(),
+                expected,
+                "{}",
+                std::str::from_utf8(script).unwrap()
+            );
+        }
+
+        // Warnings do not change the compiled output
+        assert_eq!(
+            compiler
+                .compile(b"if true { keep; } else { discard; }")
+                .unwrap()
+                .instructions(),
+            compiler
+                .compile(b"if true { keep; }\nelse { discard; }")
+                .unwrap()
+                .instructions()
+        );
+    }
+
+    #[test]
+    fn compile_warnings() {
+        let compiler = Compiler::new();
+
+        for (script, expected) in [
+            (
+                &b"keep;\nstop;\ndiscard;\nkeep;"[..],
+                vec![(3, 1, WarningType::UnreachableCode)],
+            ),
+            (
+                b"if true {\n  stop;\n}\nkeep;",
+                vec![],
+            ),
+            (
+                b"require \"fileinto\";\nrequire [\"fileinto\", \"body\"];",
+                vec![(
+                    2,
+                    10,
+                    WarningType::DuplicateRequire(Capability::FileInto),
+                )],
+            ),
+            (
+                b"require \"variables\";\nif true {\n  set \"a\" \"1\";\n  if true {\n    set :local \"a\" \"2\";\n  }\n}",
+                vec![(5, 16, WarningType::ShadowedVariable("a".to_string()))],
+            ),
+            (
+                b"require \"variables\";\nset \"a\" \"1\";\nif true {\n  set :local \"a\" \"2\";\n}",
+                vec![],
+            ),
+            (
+                b"require [\"variables\", \"regex\"];\nset \"user\" \"a\";\nif header :regex \"to\" \"(?<user>.*)@\" {\n  set \"b\" \"${user}\";\n}",
+                vec![(4, 19, WarningType::ShadowedCaptureGroup("user".to_string()))],
+            ),
+            (
+                b"require \"variables\";\nset \"subject\" \"hi\";\nset \"copy\" \"${subjct}\";",
+                vec![(3, 22, WarningType::UnassignedVariable("subjct".to_string()))],
+            ),
+            (
+                b"require \"variables\";\nset \"subject\" \"hi\";\nset \"copy\" \"${subject}\";",
+                vec![],
+            ),
+            (
+                b"require [\"variables\", \"fileinto\"];\nif header :contains \"subject\" \"x\" {\n  set :local \"folder\" \"Spam\";\n}\nfileinto \"${folder}\";",
+                vec![(5, 1, WarningType::UnassignedVariable("folder".to_string()))],
+            ),
+            (
+                b"require [\"variables\", \"fileinto\"];\nif header :contains \"subject\" \"x\" {\n  set :local \"folder\" \"Spam\";\n} else {\n  set :local \"folder\" \"Inbox\";\n}\nfileinto \"${folder}\";",
+                vec![],
+            ),
+        ] {
+            let sieve = compiler.compile(script).unwrap();
+            assert_eq!(
+                sieve
+                    .warnings()
+                    .iter()
+                    .map(|warning| (
+                        warning.line_num(),
+                        warning.line_pos(),
+                        warning.warning_type().clone()
+                    ))
+                    .collect::<Vec<_>>(),
+                expected,
+                "{}",
+                std::str::from_utf8(script).unwrap()
+            );
+        }
+
+        // Warnings do not change the compiled output
+        assert_eq!(
+            compiler
+                .compile(b"require \"fileinto\";\nrequire \"fileinto\";\nstop;\nkeep;")
+                .unwrap()
+                .instructions(),
+            compiler
+                .compile(b"require \"fileinto\";\nstop;\nkeep;")
+                .unwrap()
+                .instructions()
+        );
+    }
+
+    #[test]
+    fn compile_error_position() {
+        let compiler = Compiler::new();
+
+        for (script, one_based, zero_based) in [
+            (&b"keep :zzz;"[..], (1, 6), (0, 5)),
+            (b"keep;\nkeep :zzz;", (2, 6), (1, 5)),
+            (b"keep;\n  frobnicate;", (2, 3), (1, 2)),
+            (b"if true {\n  keep;\n", (1, 9), (0, 8)),
+        ] {
+            let err = compiler.compile(script).unwrap_err();
+            assert_eq!(
+                err.position(IndexBase::One),
+                one_based,
+                "{} {err:?}",
+                std::str::from_utf8(script).unwrap()
+            );
+            assert_eq!(
+                err.position(IndexBase::Zero),
+                zero_based,
+                "{} {err:?}",
+                std::str::from_utf8(script).unwrap()
+            );
+            assert_eq!((err.line_num(), err.line_pos()), one_based);
+        }
+    }
+
+    #[test]
+    fn envelope_parts() {
+        let compiler = Compiler::new();
+
+        for script in [
+            &b"require [\"envelope\", \"envelope-dsn\"]; if envelope :matches \"orcpt\" \"*\" { keep; }"[..],
+            b"require [\"envelope\", \"envelope-dsn\"]; if envelope \"notify\" \"NEVER\" { keep; }",
+            b"require \"envelope\"; if envelope :localpart \"auth\" \"user\" { keep; }",
+            b"require \"variables\"; set \"a\" \"${envelope.orcpt}${envelope.auth}\";",
+        ] {
+            compiler
+                .compile(script)
+                .unwrap_or_else(|err| panic!("{} {err:?}", std::str::from_utf8(script).unwrap()));
+        }
+
+        for (script, name) in [
+            (
+                &b"require \"envelope\"; if envelope \"frob\" \"x\" { keep; }"[..],
+                "frob",
+            ),
+            (
+                b"require [\"envelope\", \"variables\"]; set \"envelope.frob\" \"x\";",
+                "frob",
+            ),
+        ] {
+            assert!(
+                matches!(
+                    compiler.compile(script).unwrap_err().error_type(),
+                    ErrorType::InvalidEnvelope(invalid) if invalid == name
+                ),
+                "{}",
+                std::str::from_utf8(script).unwrap()
+            );
+        }
+    }
+
+    #[test]
+    fn capability_names() {
+        for capability in Capability::all() {
+            assert_eq!(
+                Capability::from_str(&capability.to_string()).as_ref(),
+                Some(capability)
+            );
+        }
+        assert_eq!(
+            Capability::from_str("vnd.stalwart.while"),
+            Some(Capability::While)
+        );
+        assert_eq!(Capability::from_str("fileinto"), Some(Capability::FileInto));
+        assert_eq!(Capability::from_str("vnd.example.unknown"), None);
+        assert_eq!(Capability::FileInto.to_string(), "fileinto");
+        assert_eq!(
+            Capability::parse("comparator-i;custom").to_string(),
+            "comparator-i;custom"
+        );
+
+        // Vendor extensions have to be enabled explicitly
+        let runtime = Runtime::new();
+        for capability in Capability::all() {
+            assert_eq!(
+                runtime.allowed_capabilities.contains(capability),
+                !capability.is_extension(),
+                "{capability}"
+            );
+        }
+
+        let err = Compiler::new().compile(b"fileinto \"INBOX\";").unwrap_err();
+        assert!(matches!(
+            err.error_type(),
+            ErrorType::UndeclaredCapability(Capability::FileInto)
+        ));
+        assert!(err.to_string().contains("'fileinto'"), "{err}");
+    }
+
+    #[test]
+    fn compile_never_panics() {
+        fn read_dir(path: PathBuf, files: &mut Vec<PathBuf>) {
+            for entry in fs::read_dir(path).unwrap() {
+                let entry = entry.unwrap().path();
+                if entry.is_dir() {
+                    read_dir(entry, files);
+                } else if matches!(
+                    entry.extension().and_then(|e| e.to_str()),
+                    Some("sieve" | "svtest")
+                ) {
+                    files.push(entry);
+                }
+            }
+        }
+
+        let mut files = Vec::new();
+        read_dir(
+            PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests"),
+            &mut files,
+        );
+        files.sort();
+        assert!(!files.is_empty());
+
+        // Deterministic xorshift so failures can be reproduced
+        let mut seed = 0x2545_f491_4f6c_dd1du64;
+        let mut next = move || {
+            seed ^= seed << 13;
+            seed ^= seed >> 7;
+            seed ^= seed << 17;
+            seed as usize
+        };
+        let compiler = Compiler::new();
+        let tokens = [
+            &b"{"[..],
+            b"}",
+            b"(",
+            b")",
+            b"[",
+            b"]",
+            b";",
+            b",",
+            b"\"",
+            b":",
+            b"\\",
+            b"${",
+            b"${1}",
+            b"text:\n",
+            b"\n.\n",
+            b"#",
+            b"/*",
+            b"*/",
+            b"\xff",
+            b"%{",
+            b"0",
+            b"99999999999999999999K",
+            b"if ",
+            b"elsif ",
+            b"else ",
+            b"require ",
+            b"\"variables\" ",
+            b":matches ",
+            b":regex ",
+            b"set ",
+            b"let ",
+            b"\"a\" ",
+            b"anyof(",
+            b"allof(",
+            b"not ",
+            b"header ",
+            b"stop;",
+            b"global ",
+            b"return;",
+            b"foreverypart ",
+            b"break;",
+            b"while ",
+            b":count \"eq\" ",
+        ];
+
+        for file in files {
+            let script = fs::read(&file).unwrap();
+            let mut inputs = (0..script.len())
+                .step_by(13)
+                .map(|pos| script[..pos].to_vec())
+                .collect::<Vec<_>>();
+            for _ in 0..8 {
+                let mut input = script.clone();
+                for _ in 0..(next() % 8) + 1 {
+                    let pos = next() % (input.len() + 1);
+                    match next() % 3 {
+                        0 => {
+                            input.splice(pos..pos, tokens[next() % tokens.len()].iter().copied());
+                        }
+                        1 if pos < input.len() => {
+                            let len = std::cmp::min(next() % 16, input.len() - pos);
+                            input.drain(pos..pos + len);
+                        }
+                        _ if pos < input.len() => {
+                            input[pos] = next() as u8;
+                        }
+                        _ => (),
+                    }
+                }
+                inputs.push(input);
+            }
+
+            for input in inputs {
+                let result = std::panic::catch_unwind(|| {
+                    let _ = compiler.compile(&input);
+                });
+                assert!(
+                    result.is_ok(),
+                    "Compiler panicked on input derived from {}: {:?}",
+                    file.display(),
+                    String::from_utf8_lossy(&input)
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn control_flow() {
+        let script = Compiler::new()
+            .compile(
+                concat!(
+                    "if header :is \"subject\" \"a\" { discard; }\r\n",
+                    "elsif header :is \"subject\" \"b\" { keep; }\r\n",
+                    "else { stop; }\r\n",
+                )
+                .as_bytes(),
+            )
+            .unwrap();
+
+        // Each branch ends with a jump past the whole chain, while a failed
+        // test jumps to the next branch.
+        assert_eq!(
+            script.control_flow(),
+            vec![
+                (1, CfEdge::JumpIfFalse(4)),
+                (3, CfEdge::Jump(9)),
+                (5, CfEdge::JumpIfFalse(8)),
+                (7, CfEdge::Jump(9)),
+            ]
+        );
+        assert_eq!(script.instructions()[8], Instruction::Stop);
+    }
+
+    #[test]
+    fn unescape_string() {
+        for (raw, expected) in [
+            (&b"plain text"[..], "plain text"),
+            (b"say \\\"hi\\\"", "say \"hi\""),
+            (b"back\\\\slash", "back\\slash"),
+            (b"\\a\\.", "a."),
+            (b"line\\nbreak\\ttab", "line\nbreak\ttab"),
+            (b"${name} caf\xc3\xa9", "${name} caf\u{e9}"),
+        ] {
+            assert_eq!(
+                super::lexer::tokenizer::unescape_string(raw).unwrap(),
+                expected,
+                "{}",
+                String::from_utf8_lossy(raw)
+            );
+        }
+
+        for (raw, expected) in [
+            (&b"caf\xe9"[..], ErrorType::InvalidUtf8String),
+            (b"trailing\\", ErrorType::UnterminatedString),
+            (b"un\"escaped", ErrorType::InvalidCharacter(b'"')),
+        ] {
+            let err = super::lexer::tokenizer::unescape_string(raw).unwrap_err();
+            assert_eq!(format!("{err:?}"), format!("{expected:?}"));
+        }
+    }
+
+    #[test]
+    fn lint() {
+        let script = concat!(
+            "require [\"fileinto\", \"variables\"];\n",
+            "set \"used\" \"Spam\";\n",
+            "set \"unused\" \"x\";\n",
+            "if false {\n",
+            "  keep;\n",
+            "  fileinto \"Never\";\n",
+            "}\n",
+            "if header :contains \"subject\" \"${used}\" {\n",
+            "  discard;\n",
+            "  fileinto \"Junk\";\n",
+            "}\n",
+            "redirect \"not an address\";\n",
+            "stop;\n",
+            "keep;\n",
+        );
+        let compiler = Compiler::new();
+        let lints = compiler
+            .lint(script.as_bytes())
+            .into_iter()
+            .map(|lint| {
+                (
+                    lint.severity(),
+                    lint.line_num(),
+                    &script[lint.span()],
+                    lint.message().to_string(),
+                )
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            lints,
+            vec![
+                (
+                    LintSeverity::Warning,
+                    3,
+                    "set",
+                    "Variable \"unused\" is set but never read".to_string()
+                ),
+                (
+                    LintSeverity::Warning,
+                    5,
+                    "keep",
+                    "Command 'keep' is unreachable".to_string()
+                ),
+                (
+                    LintSeverity::Warning,
+                    10,
+                    "fileinto",
+                    "Command 'fileinto' after 'discard' cancels the discard".to_string()
+                ),
+                (
+                    LintSeverity::Error,
+                    12,
+                    "redirect",
+                    "Redirect to invalid address \"not an address\"".to_string()
+                ),
+                (
+                    LintSeverity::Warning,
+                    14,
+                    "keep",
+                    "Command 'keep' is unreachable".to_string()
+                ),
+            ]
+        );
+
+        // Variables reused by sibling blocks are tracked separately
+        let lints = compiler.lint(
+            concat!(
+                "require \"variables\";\n",
+                "if true { set \"a\" \"1\"; }\n",
+                "if true { set \"b\" \"2\"; redirect \"${b}@example.org\"; }\n",
+            )
+            .as_bytes(),
+        );
+        assert_eq!(lints.len(), 1, "{lints:?}");
+        assert_eq!(lints[0].message(), "Variable \"a\" is set but never read");
+
+        // Compile errors are the only lint, compiling is unaffected
+        let lints = compiler.lint(b"keep :unknown;");
+        assert_eq!(lints.len(), 1);
+        assert_eq!(lints[0].severity(), LintSeverity::Error);
+        assert!(compiler
+            .compile(b"if false { keep; } redirect \"x\";")
+            .unwrap()
+            .warnings()
+            .is_empty());
+    }
+
+    #[test]
+    fn preserve_comments() {
+        let script = concat!(
+            "# Spam filter\r\n",
+            "if header :contains \"subject\" /* inline */ \"spam\" {\n",
+            "  discard; # drop it\n",
+            "}\n",
+            "/* multi\n   line */\n",
+            "keep; # trailing"
+        );
+        let compiler = Compiler::new().with_preserve_comments(true);
+        let sieve = compiler.compile(script.as_bytes()).unwrap();
+        assert_eq!(
+            sieve.comments(),
+            [
+                (0, "# Spam filter".to_string()),
+                (45, "/* inline */".to_string()),
+                (78, "# drop it".to_string()),
+                (90, "/* multi\n   line */".to_string()),
+                (116, "# trailing".to_string()),
+            ]
+        );
+        for (offset, comment) in sieve.comments() {
+            assert!(script[*offset..].starts_with(comment.as_str()));
+        }
+
+        // Comments are not collected by default and never compiled
+        let plain = Compiler::new().compile(script.as_bytes()).unwrap();
+        assert!(plain.comments().is_empty());
+        assert_eq!(plain.instructions(), sieve.instructions());
+    }
+
+    #[test]
+    fn known_size() {
+        let script = b"if size :over 1K { discard; } else { keep; }";
+        let has_size_test = |sieve: &Sieve| {
+            sieve
+                .instructions()
+                .iter()
+                .any(|i| matches!(i, Instruction::Test(Test::Size(_))))
+        };
+
+        // The ':over' test is folded and the 'else' branch is ruled out
+        let sieve = Compiler::new()
+            .with_known_size(Some(4096))
+            .compile(script)
+            .unwrap();
+        assert!(!has_size_test(&sieve));
+        assert_eq!(sieve.instructions()[0], Instruction::Test(Test::True));
+        assert_eq!(
+            sieve
+                .warnings()
+                .iter()
+                .map(|w| &w.warning_type)
+                .collect::<Vec<_>>(),
+            [&WarningType::UnreachableBranch]
+        );
+
+        let sieve = Compiler::new()
+            .with_known_size(Some(512))
+            .compile(b"if not size :over 1K { keep; }")
+            .unwrap();
+        assert_eq!(sieve.instructions()[0], Instruction::Test(Test::True));
+        let sieve = Compiler::new()
+            .with_known_size(Some(512))
+            .compile(script)
+            .unwrap();
+        assert_eq!(sieve.instructions()[0], Instruction::Test(Test::False));
+
+        // Unknown sizes are tested at runtime
+        let sieve = Compiler::new().compile(script).unwrap();
+        assert!(has_size_test(&sieve));
+        assert!(sieve.warnings().is_empty());
+    }
 }
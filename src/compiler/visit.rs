@@ -0,0 +1,392 @@
+/*
+ * Copyright (c) 2020-2023, Stalwart Labs Ltd.
+ *
+ * This file is part of the Stalwart Sieve Interpreter.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ * in the LICENSE file at the top-level directory of this distribution.
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ * You can be released from the requirements of the AGPLv3 license by
+ * purchasing a commercial license. Please contact licensing@stalw.art
+ * for more details.
+*/
+
+//! Read-only traversal of compiled scripts, see `Sieve::walk`.
+
+use crate::{Metadata, Sieve};
+
+use super::{
+    grammar::{
+        actions::{
+            action_convert::{Convert, TestConvertible},
+            action_discard::Discard,
+            action_editheader::{AddHeader, DeleteHeader},
+            action_fileinto::FileInto,
+            action_flags::EditFlags,
+            action_include::Include,
+            action_keep::Keep,
+            action_mime::{Enclose, ExtractText, MimeOpts, Replace},
+            action_notify::Notify,
+            action_redirect::{ByTime, Redirect},
+            action_reject::Reject,
+            action_set::{Let, Modifier, Set},
+            action_vacation::{TestVacation, Vacation},
+        },
+        expr::Expression,
+        instruction::Instruction,
+        test::Test,
+        tests::{
+            test_body::BodyTransform, test_duplicate::DupMatch, test_ihave::Error,
+            test_mailbox::TestMetadata,
+        },
+        Capability, Invalid, While,
+    },
+    Value,
+};
+
+/// Callbacks invoked by `Sieve::walk` for every instruction of a compiled
+/// script. All methods do nothing by default, so an analysis only needs to
+/// implement the ones it is interested in. `pos` is the position of the
+/// instruction in `Sieve::instructions`.
+///
+/// Jumps and other control flow bookkeeping are not reported.
+#[allow(unused_variables)]
+pub trait Visitor {
+    fn visit_require(&mut self, pos: usize, capabilities: &[Capability]) {}
+    fn visit_keep(&mut self, pos: usize, keep: &Keep) {}
+    fn visit_fileinto(&mut self, pos: usize, fileinto: &FileInto) {}
+    fn visit_redirect(&mut self, pos: usize, redirect: &Redirect) {}
+    fn visit_discard(&mut self, pos: usize, discard: &Discard) {}
+    fn visit_stop(&mut self, pos: usize) {}
+    fn visit_invalid(&mut self, pos: usize, invalid: &Invalid) {}
+    fn visit_foreverypart(&mut self, pos: usize) {}
+    fn visit_replace(&mut self, pos: usize, replace: &Replace) {}
+    fn visit_enclose(&mut self, pos: usize, enclose: &Enclose) {}
+    fn visit_extracttext(&mut self, pos: usize, extracttext: &ExtractText) {}
+    fn visit_convert(&mut self, pos: usize, convert: &Convert) {}
+    fn visit_addheader(&mut self, pos: usize, addheader: &AddHeader) {}
+    fn visit_deleteheader(&mut self, pos: usize, deleteheader: &DeleteHeader) {}
+    fn visit_set(&mut self, pos: usize, set: &Set) {}
+    fn visit_notify(&mut self, pos: usize, notify: &Notify) {}
+    fn visit_reject(&mut self, pos: usize, reject: &Reject) {}
+    fn visit_vacation(&mut self, pos: usize, vacation: &Vacation) {}
+    fn visit_error(&mut self, pos: usize, error: &Error) {}
+    fn visit_editflags(&mut self, pos: usize, editflags: &EditFlags) {}
+    fn visit_include(&mut self, pos: usize, include: &Include) {}
+    fn visit_return(&mut self, pos: usize) {}
+    fn visit_while(&mut self, pos: usize, while_: &While) {}
+    fn visit_eval(&mut self, pos: usize, expr: &[Expression]) {}
+    fn visit_let(&mut self, pos: usize, let_: &Let) {}
+
+    /// Called for every test, including each operand of `allof`/`anyof`.
+    fn visit_test(&mut self, pos: usize, test: &Test) {}
+
+    /// Called for every string argument of the instruction at `pos`, after
+    /// the instruction itself has been visited.
+    fn visit_value(&mut self, pos: usize, value: &Value) {}
+
+    /// Called after `visit_value` for each piece of a string argument.
+    /// Strings mixing text and variables are split into their constant
+    /// (`Value::Text`, `Value::Number`) and variable (`Value::Variable`)
+    /// parts; any other value is reported as a single part.
+    fn visit_value_part(&mut self, pos: usize, part: &Value) {}
+
+    /// Called for every expression of the instruction at `pos` (`eval`
+    /// tests, `let` and `while`), after the instruction itself has been
+    /// visited. Function calls, including external ones, appear in `expr`
+    /// as `Expression::Function`.
+    fn visit_expression(&mut self, pos: usize, expr: &[Expression]) {}
+}
+
+impl Sieve {
+    /// Visits every instruction of the script in order.
+    pub fn walk(&self, visitor: &mut dyn Visitor) {
+        let mut values = Vec::new();
+
+        for (pos, instruction) in self.instructions.iter().enumerate() {
+            walk_instruction(visitor, pos, instruction, &mut values);
+        }
+    }
+}
+
+// Visits a single instruction, `values` is only used as scratch space so
+// that it can be reused between calls.
+pub(crate) fn walk_instruction<'x>(
+    visitor: &mut dyn Visitor,
+    pos: usize,
+    instruction: &'x Instruction,
+    values: &mut Vec<&'x Value>,
+) {
+    match instruction {
+        Instruction::Require(capabilities) => visitor.visit_require(pos, capabilities),
+        Instruction::Keep(keep) => {
+            visitor.visit_keep(pos, keep);
+            values.extend(&keep.flags);
+        }
+        Instruction::FileInto(fileinto) => {
+            visitor.visit_fileinto(pos, fileinto);
+            values.push(&fileinto.folder);
+            values.extend(&fileinto.flags);
+            values.extend(&fileinto.mailbox_id);
+            values.extend(&fileinto.special_use);
+        }
+        Instruction::Redirect(redirect) => {
+            visitor.visit_redirect(pos, redirect);
+            values.push(&redirect.address);
+            if let ByTime::Absolute { alimit, .. } = &redirect.by_time {
+                values.push(alimit);
+            }
+        }
+        Instruction::Discard(discard) => {
+            visitor.visit_discard(pos, discard);
+            values.extend(&discard.reason);
+        }
+        Instruction::Stop => visitor.visit_stop(pos),
+        Instruction::Invalid(invalid) => visitor.visit_invalid(pos, invalid),
+        Instruction::Test(test) => {
+            visitor.visit_test(pos, test);
+            test_values(test, values);
+        }
+        Instruction::ForEveryPart(_) => visitor.visit_foreverypart(pos),
+        Instruction::Replace(replace) => {
+            visitor.visit_replace(pos, replace);
+            values.extend(&replace.subject);
+            values.extend(&replace.from);
+            values.push(&replace.replacement);
+        }
+        Instruction::Enclose(enclose) => {
+            visitor.visit_enclose(pos, enclose);
+            values.extend(&enclose.subject);
+            values.extend(&enclose.headers);
+            values.push(&enclose.value);
+        }
+        Instruction::ExtractText(extracttext) => {
+            visitor.visit_extracttext(pos, extracttext);
+            modifier_values(&extracttext.modifiers, values);
+        }
+        Instruction::Convert(convert) => {
+            visitor.visit_convert(pos, convert);
+            values.push(&convert.from_media_type);
+            values.push(&convert.to_media_type);
+            values.extend(&convert.transcoding_params);
+        }
+        Instruction::AddHeader(addheader) => {
+            visitor.visit_addheader(pos, addheader);
+            values.push(&addheader.field_name);
+            values.push(&addheader.value);
+        }
+        Instruction::DeleteHeader(deleteheader) => {
+            visitor.visit_deleteheader(pos, deleteheader);
+            values.push(&deleteheader.field_name);
+            values.extend(&deleteheader.value_patterns);
+        }
+        Instruction::Set(set) => {
+            visitor.visit_set(pos, set);
+            modifier_values(&set.modifiers, values);
+            values.push(&set.value);
+        }
+        Instruction::Notify(notify) => {
+            visitor.visit_notify(pos, notify);
+            values.extend(&notify.from);
+            values.extend(&notify.importance);
+            values.extend(&notify.options);
+            values.extend(&notify.message);
+            if let Some(fcc) = &notify.fcc {
+                values.push(&fcc.mailbox);
+                values.extend(&fcc.mailbox_id);
+                values.extend(&fcc.flags);
+                values.extend(&fcc.special_use);
+            }
+            values.push(&notify.method);
+        }
+        Instruction::Reject(reject) => {
+            visitor.visit_reject(pos, reject);
+            values.push(&reject.reason);
+        }
+        Instruction::Vacation(vacation) => {
+            visitor.visit_vacation(pos, vacation);
+            values.extend(&vacation.subject);
+            values.extend(&vacation.from);
+            if let Some(fcc) = &vacation.fcc {
+                values.push(&fcc.mailbox);
+                values.extend(&fcc.mailbox_id);
+                values.extend(&fcc.flags);
+                values.extend(&fcc.special_use);
+            }
+            values.push(&vacation.reason);
+        }
+        Instruction::Error(error) => {
+            visitor.visit_error(pos, error);
+            values.push(&error.message);
+        }
+        Instruction::EditFlags(editflags) => {
+            visitor.visit_editflags(pos, editflags);
+            values.extend(&editflags.flags);
+        }
+        Instruction::Include(include) => {
+            visitor.visit_include(pos, include);
+            values.push(&include.value);
+        }
+        Instruction::Return => visitor.visit_return(pos),
+        Instruction::While(while_) => {
+            visitor.visit_while(pos, while_);
+            visitor.visit_expression(pos, &while_.expr);
+        }
+        Instruction::Eval(expr) => {
+            visitor.visit_eval(pos, expr);
+            visitor.visit_expression(pos, expr);
+        }
+        Instruction::Let(let_) => {
+            visitor.visit_let(pos, let_);
+            visitor.visit_expression(pos, &let_.expr);
+        }
+        Instruction::Jmp(_)
+        | Instruction::Jz(_)
+        | Instruction::Jnz(_)
+        | Instruction::ForEveryPartPush
+        | Instruction::ForEveryPartPop(_)
+        | Instruction::Clear(_) => (),
+        #[cfg(test)]
+        Instruction::TestCmd(_) => (),
+    }
+
+    for value in values.drain(..) {
+        visitor.visit_value(pos, value);
+        visit_value_parts(visitor, pos, value);
+    }
+}
+
+fn visit_value_parts(visitor: &mut dyn Visitor, pos: usize, value: &Value) {
+    if let Value::List(parts) = value {
+        for part in parts {
+            visit_value_parts(visitor, pos, part);
+        }
+    } else {
+        visitor.visit_value_part(pos, value);
+    }
+}
+
+fn test_values<'x>(test: &'x Test, values: &mut Vec<&'x Value>) {
+    match test {
+        Test::Address(test) => {
+            values.extend(&test.header_list);
+            values.extend(&test.key_list);
+        }
+        Test::Envelope(test) => values.extend(&test.key_list),
+        Test::Exists(test) => values.extend(&test.header_names),
+        Test::Header(test) => {
+            values.extend(&test.header_list);
+            if let MimeOpts::Param(params) = &test.mime_opts {
+                values.extend(params);
+            }
+            values.extend(&test.key_list);
+        }
+        Test::Body(test) => {
+            if let BodyTransform::Content(content_types) = &test.body_transform {
+                values.extend(content_types);
+            }
+            values.extend(&test.key_list);
+        }
+        Test::Convert(Convert {
+            from_media_type,
+            to_media_type,
+            transcoding_params,
+            ..
+        }) => {
+            values.push(from_media_type);
+            values.push(to_media_type);
+            values.extend(transcoding_params);
+        }
+        Test::Convertible(TestConvertible {
+            from_media_type,
+            to_media_type,
+            ..
+        }) => {
+            values.push(from_media_type);
+            values.push(to_media_type);
+        }
+        Test::Date(test) => {
+            values.push(&test.header_name);
+            values.extend(&test.key_list);
+        }
+        Test::CurrentDate(test) => values.extend(&test.key_list),
+        Test::Duplicate(test) => {
+            values.extend(&test.handle);
+            if let DupMatch::Header(value) | DupMatch::UniqueId(value) = &test.dup_match {
+                values.push(value);
+            }
+        }
+        Test::String(test) | Test::Environment(test) => {
+            values.extend(&test.source);
+            values.extend(&test.key_list);
+        }
+        Test::NotifyMethodCapability(test) => {
+            values.push(&test.notification_uri);
+            values.push(&test.notification_capability);
+            values.extend(&test.key_list);
+        }
+        Test::ValidNotifyMethod(test) => values.extend(&test.notification_uris),
+        Test::ValidExtList(test) => values.extend(&test.list_names),
+        Test::HasFlag(test) => values.extend(&test.flags),
+        Test::MailboxExists(test) => values.extend(&test.mailbox_names),
+        Test::Metadata(TestMetadata {
+            medatata, key_list, ..
+        }) => {
+            match medatata {
+                Metadata::Server { annotation } => values.push(annotation),
+                Metadata::Mailbox { name, annotation } => {
+                    values.push(name);
+                    values.push(annotation);
+                }
+            }
+            values.extend(key_list);
+        }
+        Test::MetadataExists(test) => {
+            values.extend(&test.mailbox);
+            values.extend(&test.annotation_names);
+        }
+        Test::MailboxIdExists(test) => values.extend(&test.mailbox_ids),
+        Test::SpamTest(test) => values.push(&test.value),
+        Test::VirusTest(test) => values.push(&test.value),
+        Test::SpecialUseExists(test) => {
+            values.extend(&test.mailbox);
+            values.extend(&test.attributes);
+        }
+        Test::Vacation(TestVacation {
+            addresses,
+            handle,
+            reason,
+            ..
+        }) => {
+            values.extend(addresses);
+            values.extend(handle);
+            values.push(reason);
+        }
+        Test::True | Test::False | Test::Size(_) | Test::Invalid(_) | Test::Ihave(_) => (),
+        #[cfg(test)]
+        Test::TestCmd { .. } => (),
+    }
+}
+
+fn modifier_values<'x>(modifiers: &'x [Modifier], values: &mut Vec<&'x Value>) {
+    for modifier in modifiers {
+        match modifier {
+            Modifier::Replace { find, replace } => {
+                values.push(find);
+                values.push(replace);
+            }
+            Modifier::Default { value } => values.push(value),
+            _ => (),
+        }
+    }
+}
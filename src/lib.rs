@@ -111,7 +111,7 @@
 //!                     );
 //!                     input = true.into();
 //!                 }
-//!                 Event::Discard => {
+//!                 Event::Discard { .. } => {
 //!                     println!("Discard message.");
 //!                     input = true.into();
 //!                 }
@@ -170,6 +170,18 @@
 //!                    // Return variable result back to interpreter
 //!                    input = Input::result("hello world".into());
 //!                }
+//!                 Event::CpuWarning { used, limit } => {
+//!                     println!("Script executed {used} instructions, soft limit is {limit}.");
+//!                     input = true.into();
+//!                 }
+//!                 Event::InstructionSkipped {
+//!                     name,
+//!                     line_num,
+//!                     line_pos,
+//!                 } => {
+//!                     println!("Skipped unsupported {name:?} at line {line_num}, column {line_pos}.");
+//!                     input = true.into();
+//!                 }
 //!             },
 //!             Err(error) => {
 //!                 match error {
@@ -199,6 +211,12 @@
 //!                     RuntimeError::CPULimitReached => {
 //!                         eprintln!("Script exceeded the configured CPU limit.");
 //!                     }
+//!                     RuntimeError::TimeLimitReached => {
+//!                         eprintln!("Script exceeded the configured time limit.");
+//!                     }
+//!                     RuntimeError::EventLimitReached => {
+//!                         eprintln!("Script exceeded the configured event limit.");
+//!                     }
 //!                 }
 //!                 input = true.into();
 //!             }
@@ -264,29 +282,52 @@
 //! Copyright (C) 2020-2023, Stalwart Labs Ltd.
 //!
 
-use std::{borrow::Cow, sync::Arc, vec::IntoIter};
+use std::{
+    borrow::Cow,
+    sync::Arc,
+    time::{Duration, Instant},
+    vec::IntoIter,
+};
 
 use ahash::{AHashMap, AHashSet};
-use compiler::grammar::{
-    actions::action_redirect::{ByTime, Notify, Ret},
-    instruction::Instruction,
-    Capability,
+use compiler::{
+    grammar::{
+        actions::action_redirect::{ByTime, Notify, Ret},
+        Capability,
+    },
+    CompileWarning,
 };
 use mail_parser::{HeaderName, Message};
-use runtime::{context::ScriptStack, Variable};
+use runtime::{
+    actions::action_fileinto::Delivered, context::ScriptStack, keyword_set::KeywordSet,
+    tests::test_address::SubaddressDelimiter, ImplicitKeep, NotifyTruncation, RuntimeClock,
+    RuntimeWarning, SkippedAction, UnsupportedCapabilityPolicy, Variable,
+};
 use serde::{Deserialize, Serialize};
 
 pub mod compiler;
 pub mod runtime;
 
+pub use compiler::grammar::instruction::Instruction;
+
 pub(crate) const MAX_MATCH_VARIABLES: usize = 63;
 pub(crate) const MAX_LOCAL_VARIABLES: usize = 256;
+pub(crate) const SCORE_VARIABLE: &str = "vnd.stalwart.score";
 
 #[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
 pub struct Sieve {
+    #[serde(deserialize_with = "compiler::deserialize_instructions")]
     instructions: Vec<Instruction>,
     num_vars: usize,
     num_match_vars: usize,
+    capabilities: Vec<Capability>,
+    header_names: Vec<HeaderName<'static>>,
+    body_pos: usize,
+    lines: Vec<u32>,
+    #[serde(skip)]
+    comments: Vec<(usize, String)>,
+    #[serde(skip)]
+    warnings: Vec<CompileWarning>,
 }
 
 #[derive(Clone)]
@@ -302,20 +343,71 @@ pub struct Compiler {
     pub(crate) max_local_variables: usize,
     pub(crate) max_header_size: usize,
     pub(crate) max_includes: usize,
+    pub(crate) max_redirects: usize,
     pub(crate) no_capability_check: bool,
+    pub(crate) strict_capabilities: bool,
+    pub(crate) preserve_comments: bool,
+    pub(crate) known_size: Option<usize>,
 
     // Functions
-    pub(crate) functions: AHashMap<String, (u32, u32)>,
+    pub(crate) functions: AHashMap<String, FunctionSignature>,
+
+    // Comparators
+    pub(crate) comparators: AHashSet<String>,
 }
 
 pub type Function = for<'x> fn(&'x Context<'x>, Vec<Variable>) -> Variable;
 
+/// A comparator implemented by the host, selected in scripts with
+/// `:comparator "<name>"`. Registered names are accepted by the compiler
+/// through `Compiler::register_comparator` and dispatched at runtime through
+/// `Runtime::with_comparator`.
+pub trait ExternalComparator: std::fmt::Debug + Send + Sync {
+    /// Used by the `:is` match type and the `eq`/`ne` relational operators.
+    fn equals(&self, a: &str, b: &str) -> bool;
+    /// Used by the ordering relational operators (`lt`, `le`, `gt`, `ge`).
+    fn less_than(&self, a: &str, b: &str) -> bool;
+    /// Used by the `:contains` match type.
+    fn substring(&self, haystack: &str, needle: &str) -> bool;
+}
+
+/// Returns the subaddress delimiter used by the given domain, see
+/// `Runtime::set_subaddress_resolver`.
+pub type SubaddressResolver = Arc<dyn Fn(&str) -> char + Send + Sync>;
+
+/// Returns the defaults applied to the implicit keep, see
+/// `Runtime::set_implicit_keep_handler`.
+pub type ImplicitKeepHandler = Arc<dyn Fn() -> KeepOptions + Send + Sync>;
+
+/// Returns the current time as seconds since the Unix epoch, see
+/// `Runtime::set_clock`.
+pub type Clock = Arc<dyn Fn() -> i64 + Send + Sync>;
+
+/// Defaults supplied by the host for the implicit keep. The flags are only
+/// used when the script did not set any, and when a mailbox is given the
+/// message is filed into it instead of the default folder.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct KeepOptions {
+    pub flags: Vec<String>,
+    pub mailbox: Option<String>,
+}
+
 #[derive(Default, Clone)]
 pub struct FunctionMap {
-    pub(crate) map: AHashMap<String, (u32, u32)>,
+    pub(crate) map: AHashMap<String, FunctionSignature>,
     pub(crate) functions: Vec<Function>,
 }
 
+/// Arguments accepted by a function registered in a `FunctionMap`. Calls
+/// passing fewer than `min_args` or more than `max_args` arguments are
+/// rejected at compile time.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FunctionSignature {
+    pub(crate) id: u32,
+    pub min_args: u32,
+    pub max_args: u32,
+}
+
 #[derive(Debug, Clone)]
 pub struct Runtime {
     pub(crate) allowed_capabilities: AHashSet<Capability>,
@@ -326,20 +418,34 @@ pub struct Runtime {
     pub(crate) metadata: Vec<(Metadata<String>, Cow<'static, str>)>,
     pub(crate) include_scripts: AHashMap<String, Arc<Sieve>>,
     pub(crate) local_hostname: Cow<'static, str>,
+    pub(crate) subaddress_delimiter: SubaddressDelimiter,
+    pub(crate) mailbox_separator: Option<(char, char)>,
+    pub(crate) implicit_keep_handler: Option<ImplicitKeep>,
+    pub(crate) clock: Option<RuntimeClock>,
     pub(crate) functions: Vec<Function>,
+    pub(crate) comparators: AHashMap<String, Arc<dyn ExternalComparator>>,
+    pub(crate) keyword_sets: AHashMap<String, Arc<KeywordSet>>,
 
     pub(crate) max_nested_includes: usize,
     pub(crate) cpu_limit: usize,
+    pub(crate) cpu_soft_limit: usize,
+    pub(crate) max_duration: Option<Duration>,
+    pub(crate) max_events: usize,
     pub(crate) max_variable_size: usize,
     pub(crate) max_redirects: usize,
     pub(crate) max_received_headers: usize,
     pub(crate) max_header_size: usize,
+    pub(crate) max_addresses_per_header: usize,
     pub(crate) max_out_messages: usize,
+    pub(crate) max_notify_message_len: usize,
+    pub(crate) notify_truncation: NotifyTruncation,
 
     pub(crate) default_vacation_expiry: u64,
     pub(crate) default_duplicate_expiry: u64,
 
     pub(crate) vacation_use_orig_rcpt: bool,
+    pub(crate) keep_on_error: bool,
+    pub(crate) unsupported_capability_policy: UnsupportedCapabilityPolicy,
     pub(crate) vacation_default_subject: Cow<'static, str>,
     pub(crate) vacation_subject_prefix: Cow<'static, str>,
 }
@@ -355,6 +461,7 @@ pub struct Context<'x> {
     pub(crate) current_time: i64,
 
     pub(crate) message: Message<'x>,
+    pub(crate) message_body: Option<&'x [u8]>,
     pub(crate) message_size: usize,
     pub(crate) envelope: Vec<(Envelope, Variable)>,
     pub(crate) metadata: Vec<(Metadata<String>, Cow<'x, str>)>,
@@ -370,9 +477,15 @@ pub struct Context<'x> {
     pub(crate) test_result: bool,
     pub(crate) script_cache: AHashMap<Script, Arc<Sieve>>,
     pub(crate) script_stack: Vec<ScriptStack>,
-    pub(crate) vars_global: AHashMap<Cow<'static, str>, Variable>,
+    pub(crate) duplicate_ids: AHashMap<String, bool>,
+    pub(crate) duplicate_pending: Option<String>,
+    pub(crate) delivered: AHashSet<Delivered>,
+    pub(crate) deliveries: Vec<Delivery>,
+    pub(crate) rejection: Option<(bool, String)>,
+    pub(crate) discarded: bool,
+    pub(crate) vars_global: Arc<AHashMap<Cow<'static, str>, Variable>>,
     pub(crate) vars_env: AHashMap<Cow<'static, str>, Variable>,
-    pub(crate) vars_local: Vec<Variable>,
+    pub(crate) vars_local: Arc<Vec<Variable>>,
     pub(crate) vars_match: Vec<Variable>,
     pub(crate) expr_stack: Vec<Variable>,
     pub(crate) expr_pos: usize,
@@ -385,7 +498,34 @@ pub struct Context<'x> {
     pub(crate) has_changes: bool,
     pub(crate) num_redirects: usize,
     pub(crate) num_instructions: usize,
+    pub(crate) cpu_warned: bool,
+    pub(crate) ignore_input_result: Option<bool>,
+    pub(crate) explicit_keep: bool,
+    pub(crate) time_used: Duration,
+    pub(crate) run_started: Option<Instant>,
     pub(crate) num_out_messages: usize,
+    pub(crate) num_events: usize,
+    pub(crate) warnings: Vec<RuntimeWarning>,
+    pub(crate) skipped_actions: Vec<SkippedAction>,
+    pub(crate) collect_matches: bool,
+    pub(crate) match_details: Vec<MatchDetail>,
+}
+
+/// The values matched by a test, recorded when
+/// `Context::set_collect_matches` is enabled.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MatchDetail {
+    /// Position of the test in the instructions of the script that ran it.
+    pub pos: usize,
+    /// Every value the test matched, in the order they were found.
+    pub values: Vec<String>,
+}
+
+/// A copy of a `Context`'s execution state taken with `Context::snapshot`,
+/// the runtime it references is not part of the snapshot.
+#[derive(Clone, Debug)]
+pub struct Snapshot<'x> {
+    pub(crate) context: Box<Context<'x>>,
 }
 
 #[derive(Debug, Clone, Eq, PartialEq, Hash)]
@@ -406,6 +546,7 @@ pub enum Envelope {
     Orcpt,
     Ret,
     Envid,
+    Auth,
 }
 
 #[derive(Debug, Clone, Eq, PartialEq, Hash, Serialize, Deserialize)]
@@ -429,6 +570,10 @@ pub enum Event {
         values: Vec<String>,
         match_as: MatchAs,
     },
+    /// Asks whether `id` has been seen before. The `expiry` is the number of
+    /// seconds the id should be tracked for, a value of `0` means that it is
+    /// tracked for as long as its handle exists. The answer to each `id` is
+    /// cached for the remainder of the script execution.
     DuplicateId {
         id: String,
         expiry: u64,
@@ -442,17 +587,40 @@ pub enum Event {
         id: ExternalId,
         arguments: Vec<Variable>,
     },
+    /// A command or test from an unknown extension was skipped, only sent
+    /// with `UnsupportedCapabilityPolicy::Ignore`. Execution continues after
+    /// the event and the input passed back is ignored.
+    InstructionSkipped {
+        name: String,
+        line_num: usize,
+        line_pos: usize,
+    },
+    /// Sent once when the script crosses the soft instruction limit set with
+    /// `Runtime::set_cpu_soft_limit`. Execution continues after the event
+    /// and the input passed back is ignored.
+    CpuWarning {
+        used: u64,
+        limit: u64,
+    },
 
     // Actions
     Keep {
         flags: Vec<String>,
         message_id: usize,
     },
-    Discard,
+    /// `reason` is only set by `discard :reason`, it is meant for auditing
+    /// and does not change how the message is dropped.
+    Discard {
+        reason: Option<String>,
+    },
     Reject {
         extended: bool,
         reason: String,
     },
+    /// Repeated `fileinto` actions targeting the same folder with the same
+    /// mailbox id, set of flags, special-use attribute, `:create` option and
+    /// message are only reported once. Deliveries to the same folder that
+    /// differ in any of these are reported as separate events.
     FileInto {
         folder: String,
         flags: Vec<String>,
@@ -461,6 +629,11 @@ pub enum Event {
         create: bool,
         message_id: usize,
     },
+    /// Sent by `redirect`. `notify` and `return_of_content` hold the
+    /// `:notify` and `:ret` options of RFC 6009 (redirect-dsn), to be sent
+    /// as the SMTP `NOTIFY` and `RET` parameters, and `by_time` the
+    /// deliver-by limit, mode and trace flag (redirect-deliverby), with an
+    /// absolute limit resolved to a UNIX timestamp.
     SendMessage {
         recipient: Recipient,
         notify: Notify,
@@ -481,10 +654,130 @@ pub enum Event {
     },
 }
 
+/// The final outcome of a script run, as returned by
+/// `Context::final_disposition`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Disposition {
+    /// The message is delivered to the listed targets.
+    Accept { deliveries: Vec<Delivery> },
+    /// The message was discarded and has no delivery targets.
+    Discard,
+    /// The message was rejected with `reject` or `ereject`, `smtp_code` and
+    /// `enhanced_code` are the suggested SMTP reply when rejecting in-session.
+    Reject {
+        extended: bool,
+        reason: String,
+        smtp_code: u16,
+        enhanced_code: &'static str,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Delivery {
+    Keep {
+        flags: Vec<String>,
+    },
+    /// When `mailbox_id` is set it takes precedence over `folder`, which is
+    /// retained as a fallback for hosts that cannot resolve the id.
+    FileInto {
+        folder: String,
+        mailbox_id: Option<String>,
+        flags: Vec<String>,
+    },
+    Redirect {
+        address: String,
+        list: bool,
+    },
+}
+
 pub type ExternalId = u32;
 
+/// Host callbacks or host-provided data a compiled script may require.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Callback {
+    IncludeScript,
+    MailboxExists,
+    ListContains,
+    DuplicateId,
+    SetEnvelope,
+    Function(ExternalId),
+    Metadata,
+    SpamTest,
+    VirusTest,
+    Convert,
+}
+
+pub type CallbackSet = AHashSet<Callback>;
+
+/// A control-flow edge returned by `Sieve::control_flow`, pointing to the
+/// index of the instruction execution may continue at.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum CfEdge {
+    /// Unconditional jump.
+    Jump(usize),
+    /// Jump taken when the last test was false.
+    JumpIfFalse(usize),
+    /// Jump taken when the last test was true.
+    JumpIfTrue(usize),
+    /// Head of a `foreverypart` loop, jumps past the loop once all parts
+    /// have been visited.
+    ForEveryPart(usize),
+    /// Leaves `levels` nested `foreverypart` loops and continues at the
+    /// next instruction.
+    ForEveryPartPop { levels: usize, target: usize },
+    /// Head of a `while` loop, jumps past the loop once its condition is
+    /// false.
+    While(usize),
+}
+
+/// Weights used by `Sieve::estimated_cost`. Instructions not covered by a
+/// more specific weight, such as jumps, `set` or `keep`, cost
+/// `COST_INSTRUCTION`.
+pub const COST_INSTRUCTION: u64 = 1;
+/// A test on the envelope, headers or other values at hand.
+pub const COST_TEST: u64 = 2;
+/// A test using the `:regex` match type.
+pub const COST_REGEX: u64 = 10;
+/// A test or command that walks the body or the MIME structure: `body`,
+/// `header :mime :anychild`, `foreverypart`, `extracttext`, `replace`,
+/// `enclose` and `convert`.
+pub const COST_BODY: u64 = 20;
+/// An `include`, which loads and runs another script.
+pub const COST_INCLUDE: u64 = 50;
+/// Factor applied to instructions for every `foreverypart` or `while` loop
+/// they are nested in.
+pub const COST_LOOP_FACTOR: u64 = 10;
+
+/// A static estimate of the cost of running a script, see
+/// `Sieve::estimated_cost`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct ScriptCost {
+    /// Sum of the weights of all instructions, saturating at `u64::MAX`.
+    pub total: u64,
+    /// Deepest nesting of `foreverypart` and `while` loops.
+    pub max_loop_depth: usize,
+}
+
+/// A capability used by a script and the line and column of the `require`
+/// that declared it.
+pub type CapabilityUse = (Capability, Option<(usize, usize)>);
+
+/// Actions that should be reviewed before enabling untrusted scripts.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ActionKind {
+    /// Forwarding the message with `redirect`.
+    Redirect,
+    /// Calling external functions provided by the host.
+    Execute,
+    /// Including other scripts.
+    Include,
+    /// Adding or deleting protected headers with `editheader`.
+    EditProtectedHeader,
+}
+
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, Hash)]
-pub(crate) struct FileCarbonCopy<T> {
+#[non_exhaustive]
+pub struct FileCarbonCopy<T> {
     pub mailbox: T,
     pub mailbox_id: Option<T>,
     pub create: bool,
@@ -550,6 +843,8 @@ mod tests {
     use std::{
         fs,
         path::{Path, PathBuf},
+        sync::Arc,
+        time::Duration,
     };
 
     use ahash::{AHashMap, AHashSet};
@@ -559,10 +854,21 @@ mod tests {
     };
 
     use crate::{
-        compiler::grammar::Capability,
-        runtime::{actions::action_mime::reset_test_boundary, Variable},
-        Compiler, Context, Envelope, Event, FunctionMap, Input, Mailbox, Recipient, Runtime,
-        SpamStatus, VirusStatus,
+        compiler::{
+            grammar::{
+                actions::action_redirect::{ByMode, ByTime, Notify, NotifyItem, Ret},
+                instruction::Instruction,
+                test::Test,
+                Capability,
+            },
+            ErrorType, Number,
+        },
+        runtime::{
+            actions::action_mime::reset_test_boundary, EvalError, NotifyTruncation, RuntimeError,
+            RuntimeWarning, SkipReason, SkippedAction, UnsupportedCapabilityPolicy, Variable,
+        },
+        Compiler, Context, Delivery, Disposition, Envelope, Event, ExternalComparator, FunctionMap,
+        Input, KeepOptions, Mailbox, Recipient, Runtime, Script, Sieve, SpamStatus, VirusStatus,
     };
 
     impl Variable {
@@ -571,6 +877,33 @@ mod tests {
         }
     }
 
+    // Runs `script` until it finishes, answering every event with `answer`,
+    // and returns the events raised.
+    fn run_script(
+        instance: &mut Context,
+        script: impl Into<Arc<Sieve>>,
+        mut answer: impl FnMut(&Event) -> Input,
+    ) -> Vec<Event> {
+        let mut input = Input::script("", script);
+        let mut events = Vec::new();
+        while let Some(event) = instance.run(input) {
+            let event = event.unwrap();
+            input = answer(&event);
+            events.push(event);
+        }
+        events
+    }
+
+    fn fileinto_folders(events: Vec<Event>) -> Vec<String> {
+        events
+            .into_iter()
+            .filter_map(|event| match event {
+                Event::FileInto { folder, .. } => Some(folder),
+                _ => None,
+            })
+            .collect()
+    }
+
     #[test]
     fn test_suite() {
         let mut tests = Vec::new();
@@ -634,13 +967,7 @@ mod tests {
                     .all(|c| c.is_uppercase())
                     .into()
             })
-            .with_function("is_ascii", |_, v| {
-                v[0].to_string()
-                    .as_ref()
-                    .chars()
-                    .any(|c| !c.is_ascii())
-                    .into()
-            })
+            .with_function("is_ascii", |_, v| (!v[0].to_string().is_ascii()).into())
             .with_function("char_count", |_, v| {
                 v[0].to_string().as_ref().chars().count().into()
             })
@@ -675,7 +1002,7 @@ mod tests {
                 "in_array",
                 |_, v| {
                     v[0].as_array()
-                        .map_or(false, |arr| arr.contains(&v[1]))
+                        .is_some_and(|arr| arr.contains(&v[1]))
                         .into()
                 },
                 2,
@@ -714,6 +1041,8 @@ mod tests {
                 .with_max_out_messages(100)
                 .with_capability(Capability::While)
                 .with_capability(Capability::Expressions)
+                .with_capability(Capability::Hash)
+                .with_capability(Capability::Encoding)
                 .with_functions(&mut fnc_map.clone());
             let mut instance = Context::new(
                 &runtime,
@@ -990,6 +1319,7 @@ mod tests {
                                                 .insert(params.next().expect("list item value"));
                                         }
                                         "sieve_duplicated_id" => {
+                                            instance.duplicate_ids.remove(&value);
                                             duplicated_ids.insert(value);
                                         }
                                         "sieve_user_email" => {
@@ -1041,6 +1371,11 @@ mod tests {
                                                 3
                                             });
                                         }
+                                        "sieve_max_redirects" => {
+                                            let max_redirects = value.parse::<usize>().unwrap();
+                                            instance.runtime.set_max_redirects(max_redirects);
+                                            compiler.set_max_redirects(max_redirects);
+                                        }
                                         "sieve_include_max_nesting_depth" => {
                                             compiler.set_max_nested_blocks(if !value.is_empty() {
                                                 value.parse::<usize>().unwrap()
@@ -1126,6 +1461,7 @@ mod tests {
                                         message_id: 0,
                                     }
                                     .into();
+                                    instance.explicit_keep = false;
                                     instance.metadata.clear();
                                     instance.has_changes = false;
                                     instance.num_redirects = 0;
@@ -1195,6 +1531,2309 @@ mod tests {
         }
     }
 
+    #[test]
+    fn keep_on_error() {
+        let script = Compiler::new()
+            .compile(b"require \"ihave\";\r\ndiscard;\r\nerror \"failure\";\r\n")
+            .unwrap();
+        let raw_message = b"Subject: test\r\n\r\ntest\r\n";
+
+        for (keep_on_error, expected_event) in [
+            (
+                true,
+                Event::Keep {
+                    flags: vec![],
+                    message_id: 0,
+                },
+            ),
+            (false, Event::Discard { reason: None }),
+        ] {
+            let runtime = Runtime::new().with_keep_on_error(keep_on_error);
+            let mut instance =
+                Context::new(&runtime, MessageParser::new().parse(raw_message).unwrap());
+            let mut input = Input::script("", script.clone());
+            let mut results = Vec::new();
+            while let Some(result) = instance.run(input) {
+                results.push(result);
+                input = Input::True;
+            }
+
+            assert!(
+                matches!(&results[..], [Err(RuntimeError::ScriptErrorMessage(_)), Ok(event)] if event == &expected_event),
+                "{results:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn specialuse_exists() {
+        let script = Compiler::new()
+            .compile(
+                concat!(
+                    "require [\"special-use\", \"fileinto\"];\r\n",
+                    "if specialuse_exists [\"\\\\Junk\", \"\\\\Trash\"] {\r\n",
+                    "  fileinto :specialuse \"\\\\Junk\" \"Spam\";\r\n",
+                    "}\r\n"
+                )
+                .as_bytes(),
+            )
+            .unwrap();
+        let runtime = Runtime::new();
+
+        for is_supported in [true, false] {
+            let mut instance = Context::new(
+                &runtime,
+                MessageParser::new()
+                    .parse(b"Subject: test\r\n\r\ntest\r\n")
+                    .unwrap(),
+            );
+            let events = run_script(&mut instance, script.clone(), |event| {
+                if matches!(event, Event::MailboxExists { .. }) {
+                    is_supported.into()
+                } else {
+                    Input::True
+                }
+            });
+
+            assert_eq!(
+                events[0],
+                Event::MailboxExists {
+                    mailboxes: vec![],
+                    special_use: vec!["\\Junk".to_string(), "\\Trash".to_string()],
+                }
+            );
+            assert_eq!(
+                events[1..]
+                    .iter()
+                    .filter_map(|event| match event {
+                        Event::FileInto { special_use, .. } => special_use.as_deref(),
+                        Event::Keep { .. } => "keep".into(),
+                        _ => None,
+                    })
+                    .collect::<Vec<_>>(),
+                if is_supported {
+                    vec!["\\Junk"]
+                } else {
+                    vec!["keep"]
+                }
+            );
+        }
+    }
+
+    #[test]
+    fn mailboxid() {
+        let script = Compiler::new()
+            .compile(
+                concat!(
+                    "require [\"mailboxid\", \"fileinto\"];\r\n",
+                    "if mailboxidexists \"F6352ae03\" {\r\n",
+                    "  fileinto :mailboxid \"F6352ae03\" \"INBOX.harassment\";\r\n",
+                    "}\r\n",
+                    "fileinto :mailboxid \"Fother\" \"INBOX.harassment\";\r\n",
+                )
+                .as_bytes(),
+            )
+            .unwrap();
+
+        // Compiled scripts keep the mailbox id after a serialization round trip
+        let script = Sieve::deserialize(&script.serialize().unwrap()).unwrap();
+
+        let runtime = Runtime::new();
+        let mut instance = Context::new(
+            &runtime,
+            MessageParser::new()
+                .parse(b"Subject: test\r\n\r\ntest\r\n")
+                .unwrap(),
+        );
+        let events = run_script(&mut instance, script, |_| Input::True);
+
+        assert_eq!(
+            events[0],
+            Event::MailboxExists {
+                mailboxes: vec![Mailbox::Id("F6352ae03".to_string())],
+                special_use: vec![],
+            }
+        );
+        assert_eq!(
+            events[1..]
+                .iter()
+                .filter_map(|event| match event {
+                    Event::FileInto {
+                        folder, mailbox_id, ..
+                    } => Some((folder.as_str(), mailbox_id.as_deref())),
+                    _ => None,
+                })
+                .collect::<Vec<_>>(),
+            vec![
+                ("INBOX.harassment", Some("F6352ae03")),
+                ("INBOX.harassment", Some("Fother"))
+            ]
+        );
+        assert_eq!(
+            instance.final_disposition(),
+            Disposition::Accept {
+                deliveries: vec![
+                    Delivery::FileInto {
+                        folder: "INBOX.harassment".to_string(),
+                        mailbox_id: Some("F6352ae03".to_string()),
+                        flags: vec![],
+                    },
+                    Delivery::FileInto {
+                        folder: "INBOX.harassment".to_string(),
+                        mailbox_id: Some("Fother".to_string()),
+                        flags: vec![],
+                    },
+                ],
+            }
+        );
+    }
+
+    #[test]
+    fn cpu_soft_limit() {
+        let script = Compiler::new()
+            .compile(
+                concat!(
+                    "require \"fileinto\";\r\n",
+                    "if header :contains \"subject\" \"test\" { fileinto \"a\"; }\r\n",
+                    "if header :contains \"subject\" \"other\" { fileinto \"b\"; }\r\n",
+                    "if exists \"subject\" { fileinto \"c\"; }\r\n",
+                )
+                .as_bytes(),
+            )
+            .unwrap();
+        let message = MessageParser::new()
+            .parse(b"Subject: test\r\n\r\ntest\r\n")
+            .unwrap();
+
+        let mut results = Vec::new();
+        for soft_limit in [usize::MAX, 1, 2, 3, 4, 5, 6] {
+            let runtime = Runtime::new().with_cpu_soft_limit(soft_limit);
+            let mut instance = Context::new(&runtime, message.clone());
+            let mut warnings = Vec::new();
+            let events = run_script(&mut instance, script.clone(), |_| Input::True)
+                .into_iter()
+                .filter(|event| match event {
+                    Event::CpuWarning { used, limit } => {
+                        warnings.push((*used, *limit));
+                        false
+                    }
+                    _ => true,
+                })
+                .collect::<Vec<_>>();
+
+            if soft_limit == usize::MAX {
+                assert!(warnings.is_empty());
+            } else {
+                assert_eq!(warnings, [(soft_limit as u64, soft_limit as u64)]);
+            }
+            assert_eq!(
+                instance.instructions_remaining(),
+                5000 - instance.instructions_used()
+            );
+            results.push((events, instance.instructions_used()));
+        }
+
+        // Warnings do not change the outcome or the instruction count
+        assert!(results.windows(2).all(|w| w[0] == w[1]));
+
+        let runtime = Runtime::new().with_cpu_limit(3);
+        let mut instance = Context::new(&runtime, message);
+        let mut input = Input::script("", script);
+        loop {
+            match instance.run(input) {
+                Some(Ok(_)) => input = Input::True,
+                Some(Err(RuntimeError::CPULimitReached)) => break,
+                result => panic!("Unexpected result {result:?}"),
+            }
+        }
+        assert_eq!(instance.instructions_remaining(), 0);
+    }
+
+    #[test]
+    fn max_duration() {
+        let script = Compiler::new()
+            .compile(
+                concat!(
+                    "require \"fileinto\";\r\n",
+                    "if header :contains \"subject\" \"test\" { fileinto \"a\"; }\r\n",
+                    "fileinto \"b\";\r\n",
+                )
+                .as_bytes(),
+            )
+            .unwrap();
+        let message = MessageParser::new()
+            .parse(b"Subject: test\r\n\r\ntest\r\n")
+            .unwrap();
+
+        // An exhausted time budget stops execution before the next instruction
+        let runtime = Runtime::new().with_max_duration(Duration::ZERO);
+        let mut instance = Context::new(&runtime, message.clone());
+        assert!(matches!(
+            instance.run(Input::script("", script.clone())),
+            Some(Err(RuntimeError::TimeLimitReached))
+        ));
+
+        // Time spent by the host handling events is not counted
+        let runtime = Runtime::new().with_max_duration(Duration::from_millis(50));
+        let mut instance = Context::new(&runtime, message);
+        let folders = fileinto_folders(run_script(&mut instance, script, |_| {
+            std::thread::sleep(Duration::from_millis(60));
+            Input::True
+        }));
+        assert_eq!(folders, ["a", "b"]);
+    }
+
+    #[test]
+    fn max_events() {
+        let script = Compiler::new()
+            .compile(
+                concat!(
+                    "require [\"variables\", \"fileinto\", \"copy\", \"vnd.stalwart.while\", \"vnd.stalwart.expressions\"];\r\n",
+                    "let \"i\" \"0\";\r\n",
+                    "while \"i < 100\" {\r\n",
+                    "  fileinto :copy \"folder-${i}\";\r\n",
+                    "  let \"i\" \"i + 1\";\r\n",
+                    "}\r\n",
+                )
+                .as_bytes(),
+            )
+            .unwrap();
+        let message = MessageParser::new()
+            .parse(b"Subject: test\r\n\r\ntest\r\n")
+            .unwrap();
+
+        let runtime = Runtime::new()
+            .with_capability(Capability::While)
+            .with_capability(Capability::Expressions)
+            .with_max_events(5);
+        let mut instance = Context::new(&runtime, message);
+        let mut input = Input::script("", script);
+        let mut folders = Vec::new();
+        let mut error = None;
+        while let Some(result) = instance.run(input) {
+            match result {
+                Ok(Event::FileInto { folder, .. }) => folders.push(folder),
+                Ok(Event::Keep { .. }) => folders.push("INBOX".into()),
+                Ok(_) => (),
+                Err(err) => error = Some(err),
+            }
+            input = Input::True;
+        }
+        assert!(matches!(error, Some(RuntimeError::EventLimitReached)));
+        assert_eq!(
+            folders,
+            ["folder-0", "folder-1", "folder-2", "folder-3", "folder-4", "INBOX"]
+        );
+    }
+
+    #[test]
+    fn discard_reason() {
+        let script = Compiler::new()
+            .compile(
+                concat!(
+                    "require [\"variables\", \"vnd.stalwart.discard-reason\"];\r\n",
+                    "set \"score\" \"12\";\r\n",
+                    "discard :reason \"spam score ${score} too high\";\r\n",
+                )
+                .as_bytes(),
+            )
+            .unwrap();
+        let message = MessageParser::new()
+            .parse(b"Subject: test\r\n\r\ntest\r\n")
+            .unwrap();
+
+        let runtime = Runtime::new().with_capability(Capability::DiscardReason);
+        let mut instance = Context::new(&runtime, message.clone());
+        let events = run_script(&mut instance, script.clone(), |_| Input::True);
+        assert_eq!(
+            events,
+            [Event::Discard {
+                reason: "spam score 12 too high".to_string().into()
+            }]
+        );
+        assert_eq!(instance.final_disposition(), Disposition::Discard);
+
+        // The extension is not enabled by default
+        let runtime = Runtime::new();
+        let mut instance = Context::new(&runtime, message);
+        assert!(matches!(
+            instance.run(Input::script("", script)),
+            Some(Err(RuntimeError::CapabilityNotAllowed(
+                Capability::DiscardReason
+            )))
+        ));
+
+        assert!(Compiler::new()
+            .compile(b"discard :reason \"spam\";")
+            .is_err());
+    }
+
+    #[test]
+    fn subaddress() {
+        let script = Compiler::new()
+            .compile(
+                concat!(
+                    "require [\"envelope\", \"subaddress\", \"variables\", \"fileinto\"];\r\n",
+                    "if envelope :user :matches \"to\" \"*\" { fileinto \"user:${1}\"; }\r\n",
+                    "if envelope :detail :matches \"to\" \"*\" { fileinto \"detail:${1}\"; }\r\n",
+                    "if address :detail :matches \"from\" \"*\" { fileinto \"from:${1}\"; }\r\n",
+                )
+                .as_bytes(),
+            )
+            .unwrap();
+
+        for (delimiter, to, expected) in [
+            (
+                '+',
+                "user+tag@example.com",
+                &["user:user", "detail:tag", "from:news"][..],
+            ),
+            (
+                '+',
+                "user+@example.com",
+                &["user:user", "detail:", "from:news"][..],
+            ),
+            ('+', "user@example.com", &["user:user", "from:news"][..]),
+            (
+                '+',
+                "user+a+b@example.com",
+                &["user:user", "detail:a+b", "from:news"][..],
+            ),
+            (
+                '-',
+                "user-tag+x@example.com",
+                &["user:user", "detail:tag+x"][..],
+            ),
+            ('-', "user+tag@example.com", &["user:user+tag"][..]),
+        ] {
+            let runtime = Runtime::new().with_subaddress_delimiter(delimiter);
+            let mut instance = Context::new(
+                &runtime,
+                MessageParser::new()
+                    .parse(b"From: jane+news@example.org\r\nSubject: test\r\n\r\ntest\r\n")
+                    .unwrap(),
+            )
+            .with_envelope(Envelope::To, to);
+
+            let folders =
+                fileinto_folders(run_script(&mut instance, script.clone(), |_| Input::True));
+            assert_eq!(folders, expected, "{delimiter} {to}");
+        }
+    }
+
+    #[test]
+    fn subaddress_resolver() {
+        let script = Compiler::new()
+            .compile(
+                concat!(
+                    "require [\"envelope\", \"subaddress\", \"variables\", \"fileinto\"];\r\n",
+                    "if envelope :user :matches \"to\" \"*\" { fileinto \"user:${1}\"; }\r\n",
+                    "if envelope :detail :matches \"to\" \"*\" { fileinto \"detail:${1}\"; }\r\n",
+                    "if address :detail :matches \"from\" \"*\" { fileinto \"from:${1}\"; }\r\n",
+                )
+                .as_bytes(),
+            )
+            .unwrap();
+        let runtime = Runtime::new().with_subaddress_resolver(Arc::new(|domain| {
+            if domain.eq_ignore_ascii_case("example.org") {
+                '-'
+            } else {
+                '+'
+            }
+        }));
+
+        for (to, expected) in [
+            (
+                "user+tag-x@example.com",
+                &["user:user", "detail:tag-x", "from:news+x"][..],
+            ),
+            (
+                "user+tag-x@Example.ORG",
+                &["user:user+tag", "detail:x", "from:news+x"][..],
+            ),
+        ] {
+            let mut instance = Context::new(
+                &runtime,
+                MessageParser::new()
+                    .parse(b"From: jane-news+x@example.org\r\nSubject: test\r\n\r\ntest\r\n")
+                    .unwrap(),
+            )
+            .with_envelope(Envelope::To, to);
+
+            let folders =
+                fileinto_folders(run_script(&mut instance, script.clone(), |_| Input::True));
+            assert_eq!(folders, expected, "{to}");
+        }
+    }
+
+    #[test]
+    fn null_sender() {
+        let script = Compiler::new()
+            .compile(
+                concat!(
+                    "require [\"envelope\", \"environment\", \"fileinto\"];\r\n",
+                    "if envelope :is \"from\" \"\" { fileinto \"null\"; }\r\n",
+                    "if environment :is \"vnd.stalwart.is_bounce\" \"1\" { fileinto \"bounce\"; }\r\n",
+                    "if envelope :localpart :is \"to\" \"postmaster\" { fileinto \"postmaster\"; }\r\n",
+                )
+                .as_bytes(),
+            )
+            .unwrap();
+        let runtime = Runtime::new();
+
+        for (from, to, expected) in [
+            (
+                "<>",
+                "postmaster@example.org",
+                &["null", "bounce", "postmaster"][..],
+            ),
+            ("", "jdoe@example.org", &["null", "bounce"][..]),
+            ("<MAILER-DAEMON@example.org>", "jdoe@example.org", &[][..]),
+            (
+                "jane@example.org",
+                "Postmaster@example.org",
+                &["postmaster"][..],
+            ),
+        ] {
+            let mut instance = Context::new(
+                &runtime,
+                MessageParser::new()
+                    .parse(b"Subject: test\r\n\r\ntest\r\n")
+                    .unwrap(),
+            )
+            .with_envelope(Envelope::From, from)
+            .with_envelope(Envelope::To, to);
+            assert_eq!(instance.is_bounce(), from.len() <= 2, "{from}");
+
+            let folders =
+                fileinto_folders(run_script(&mut instance, script.clone(), |_| Input::True));
+            assert_eq!(folders, expected, "{from}");
+        }
+    }
+
+    #[test]
+    fn keyword_sets() {
+        let compiler = Compiler::new();
+        let script = compiler
+            .compile(
+                concat!(
+                    "require [\"vnd.stalwart.keywords\", \"fileinto\", \"body\"];\r\n",
+                    "if header :contains :set \"hostspam\" \"subject\" { fileinto \"header\"; }\r\n",
+                    "if body :contains :set \"hostspam\" { fileinto \"body\"; }\r\n",
+                    "if header :contains :comparator \"i;octet\" :set \"hostspam\" \"subject\" {\r\n",
+                    "  fileinto \"octet\";\r\n",
+                    "}\r\n",
+                    "if header :contains :set \"unknown\" \"subject\" { fileinto \"unknown\"; }\r\n",
+                )
+                .as_bytes(),
+            )
+            .unwrap();
+
+        // Decompiled scripts keep the keyword set reference
+        assert_eq!(
+            compiler
+                .compile(compiler.decompile(&script).as_bytes())
+                .unwrap()
+                .instructions(),
+            script.instructions()
+        );
+        assert!(compiler
+            .compile(b"require \"vnd.stalwart.keywords\"; if header :set \"hostspam\" \"subject\" { keep; }")
+            .is_err());
+
+        let runtime = Runtime::new().with_keyword_set("hostspam", ["viagra", "Lottery Winner"]);
+        for (message, expected) in [
+            (
+                "Subject: You are a LOTTERY WINNER\r\n\r\nBuy cheap Viagra\r\n",
+                &["header", "body"][..],
+            ),
+            (
+                "Subject: Lottery Winner announced\r\n\r\nHello\r\n",
+                &["header", "octet"][..],
+            ),
+            ("Subject: Meeting notes\r\n\r\nHello\r\n", &[][..]),
+        ] {
+            let mut instance = Context::new(
+                &runtime,
+                MessageParser::new().parse(message.as_bytes()).unwrap(),
+            );
+            let folders =
+                fileinto_folders(run_script(&mut instance, script.clone(), |_| Input::True));
+            assert_eq!(folders, expected, "{message}");
+        }
+    }
+
+    #[test]
+    fn snapshot_restore() {
+        let script = Compiler::new()
+            .compile(
+                concat!(
+                    "require [\"variables\", \"include\", \"imap4flags\", \"fileinto\", \"editheader\"];\r\n",
+                    "global \"state\";\r\n",
+                    "set \"state\" \"first\";\r\n",
+                    "addflag \"\\\\Seen\";\r\n",
+                    "fileinto \"first\";\r\n",
+                    "set \"state\" \"second\";\r\n",
+                    "addflag \"\\\\Flagged\";\r\n",
+                    "addheader \"X-State\" \"${state}\";\r\n",
+                    "fileinto \"second\";\r\n",
+                )
+                .as_bytes(),
+            )
+            .unwrap();
+        let runtime = Runtime::new();
+        let mut instance = Context::new(
+            &runtime,
+            MessageParser::new()
+                .parse(b"Subject: test\r\n\r\ntest\r\n")
+                .unwrap(),
+        )
+        .with_envelope(Envelope::From, "jane@example.org");
+
+        let event = instance.run(Input::script("", script)).unwrap().unwrap();
+        assert!(matches!(&event, Event::FileInto { folder, .. } if folder == "first"));
+        let snapshot = instance.snapshot();
+        let snapshot_state = format!("{instance:?}");
+
+        let mut events = Vec::new();
+        while let Some(event) = instance.run(Input::True) {
+            events.push(event.unwrap());
+        }
+        assert_eq!(
+            instance.global_variable("state").unwrap().to_string(),
+            "second"
+        );
+
+        instance.restore(snapshot);
+        assert_eq!(format!("{instance:?}"), snapshot_state);
+        assert_eq!(
+            instance.global_variable("state").unwrap().to_string(),
+            "first"
+        );
+
+        let mut replayed_events = Vec::new();
+        while let Some(event) = instance.run(Input::True) {
+            replayed_events.push(event.unwrap());
+        }
+        assert_eq!(events, replayed_events);
+    }
+
+    #[test]
+    fn snapshot_restore_include() {
+        let compiler = Compiler::new();
+        let script = compiler
+            .compile(
+                concat!(
+                    "require [\"variables\", \"include\", \"fileinto\"];\r\n",
+                    "global \"state\";\r\n",
+                    "set \"state\" \"parent\";\r\n",
+                    "set \"local\" \"parent\";\r\n",
+                    "include :global \"untrusted\";\r\n",
+                    "fileinto \"${state}-${local}\";\r\n",
+                )
+                .as_bytes(),
+            )
+            .unwrap();
+        let mut untrusted = String::from(concat!(
+            "require [\"variables\", \"include\"];\r\n",
+            "global \"state\";\r\n",
+            "set \"state\" \"child\";\r\n",
+        ));
+        for _ in 0..20 {
+            untrusted.push_str("set \"local\" \"child\";\r\n");
+        }
+        let untrusted = compiler.compile(untrusted.as_bytes()).unwrap();
+        let runtime = Runtime::new().with_cpu_limit(10);
+        let mut instance = Context::new(
+            &runtime,
+            MessageParser::new()
+                .parse(b"Subject: test\r\n\r\ntest\r\n")
+                .unwrap(),
+        );
+
+        let event = instance.run(Input::script("", script)).unwrap().unwrap();
+        assert!(matches!(&event, Event::IncludeScript { .. }));
+        let snapshot = instance.snapshot();
+        // Variables are shared with the snapshot until either side changes them
+        assert!(Arc::ptr_eq(
+            &snapshot.context.vars_global,
+            &instance.vars_global
+        ));
+        assert!(Arc::ptr_eq(
+            &snapshot.context.vars_local,
+            &instance.vars_local
+        ));
+
+        let result = instance.run(Input::script(Script::Global("untrusted".into()), untrusted));
+        assert!(matches!(result, Some(Err(RuntimeError::CPULimitReached))));
+        assert_eq!(
+            instance.global_variable("state").unwrap().to_string(),
+            "child"
+        );
+
+        // Roll back the failed include and carry on without it
+        instance.restore(snapshot);
+        assert_eq!(
+            instance.global_variable("state").unwrap().to_string(),
+            "parent"
+        );
+        let mut folders = Vec::new();
+        let mut input = Input::False;
+        while let Some(event) = instance.run(input) {
+            if let Event::FileInto { folder, .. } = event.unwrap() {
+                folders.push(folder);
+            }
+            input = Input::True;
+        }
+        assert_eq!(folders, ["parent-parent"]);
+    }
+
+    #[test]
+    fn duplicate_answer_cached() {
+        let script = Compiler::new()
+            .compile(
+                concat!(
+                    "require [\"duplicate\", \"fileinto\"];\r\n",
+                    "if duplicate { fileinto \"first\"; }\r\n",
+                    "if duplicate :handle \"other\" { fileinto \"other\"; }\r\n",
+                    "if duplicate :seconds 0 { fileinto \"second\"; }\r\n",
+                )
+                .as_bytes(),
+            )
+            .unwrap();
+        let runtime = Runtime::new();
+        let mut instance = Context::new(
+            &runtime,
+            MessageParser::new()
+                .parse(b"Message-ID: <abc@example.org>\r\n\r\ntest\r\n")
+                .unwrap(),
+        );
+        let events = run_script(&mut instance, script, |_| Input::True);
+
+        assert_eq!(
+            events
+                .iter()
+                .filter_map(|event| match event {
+                    Event::DuplicateId { id, .. } => Some(id.as_str()),
+                    Event::FileInto { folder, .. } => Some(folder.as_str()),
+                    _ => None,
+                })
+                .collect::<Vec<_>>(),
+            [
+                "abc@example.org",
+                "first",
+                "otherabc@example.org",
+                "other",
+                "second"
+            ]
+        );
+    }
+
+    #[test]
+    fn max_addresses_per_header() {
+        let script = Compiler::new()
+            .compile(
+                concat!(
+                    "require [\"relational\", \"fileinto\"];\r\n",
+                    "if address :count \"eq\" :comparator \"i;ascii-numeric\" \"to\" \"3\" {\r\n",
+                    "  fileinto \"capped\";\r\n",
+                    "}\r\n",
+                    "if address :is \"to\" \"d@example.org\" {\r\n",
+                    "  fileinto \"uncapped\";\r\n",
+                    "}\r\n",
+                )
+                .as_bytes(),
+            )
+            .unwrap();
+        let raw_message = concat!(
+            "To: a@example.org, b@example.org, c@example.org, d@example.org\r\n",
+            "Subject: test\r\n",
+            "\r\n",
+            "test\r\n"
+        )
+        .as_bytes();
+        let runtime = Runtime::new().with_max_addresses_per_header(3);
+        let mut instance = Context::new(&runtime, MessageParser::new().parse(raw_message).unwrap());
+        let folders = fileinto_folders(run_script(&mut instance, script, |_| Input::True));
+
+        assert_eq!(folders, ["capped"]);
+        assert_eq!(
+            instance.warnings(),
+            [
+                RuntimeWarning::AddressesTruncated { max: 3 },
+                RuntimeWarning::AddressesTruncated { max: 3 }
+            ]
+        );
+    }
+
+    #[test]
+    fn invalid_action_targets() {
+        let script = Compiler::new()
+            .compile(
+                concat!(
+                    "require [\"variables\", \"fileinto\", \"enotify\", \"copy\"];\r\n",
+                    "set \"to\" \"${header.subject}\";\r\n",
+                    "redirect \"${to}\";\r\n",
+                    "fileinto \"${header.x-folder}\";\r\n",
+                    "notify \"${header.x-method}\";\r\n",
+                    "redirect :copy \"jdoe@example.org\";\r\n",
+                )
+                .as_bytes(),
+            )
+            .unwrap();
+        let raw_message = concat!(
+            "Subject: not an address\r\n",
+            "X-Method: mailto\r\n",
+            "\r\n",
+            "test\r\n"
+        )
+        .as_bytes();
+        let runtime = Runtime::new();
+        let mut instance = Context::new(&runtime, MessageParser::new().parse(raw_message).unwrap());
+        let events = run_script(&mut instance, script, |_| Input::True)
+            .into_iter()
+            .filter_map(|event| match event {
+                Event::SendMessage {
+                    recipient: Recipient::Address(address),
+                    ..
+                } => Some(address),
+                Event::FileInto { folder, .. } => Some(folder),
+                Event::Keep { .. } => Some("keep".into()),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+
+        // Invalid targets are skipped, leaving the implicit keep in place
+        assert_eq!(events, ["jdoe@example.org", "keep"]);
+        assert_eq!(
+            instance.warnings(),
+            [
+                RuntimeWarning::InvalidAddress {
+                    address: "not an address".into()
+                },
+                RuntimeWarning::InvalidMailbox { mailbox: "".into() },
+                RuntimeWarning::InvalidNotifyMethod {
+                    method: "mailto".into()
+                }
+            ]
+        );
+    }
+
+    #[test]
+    fn skipped_actions() {
+        let compiler = Compiler::new();
+        let script = compiler
+            .compile(
+                concat!(
+                    "require [\"editheader\", \"fileinto\"];\r\n",
+                    "addheader \"X-Filtered\" \"yes\";\r\n",
+                    "addheader \"Original-Subject\" \"forged\";\r\n",
+                    "if true {\r\n",
+                    "  fileinto \"Archive\";\r\n",
+                    "  fileinto \"Archive\";\r\n",
+                    "}\r\n",
+                    "deleteheader \"original-from\";\r\n",
+                )
+                .as_bytes(),
+            )
+            .unwrap();
+        let raw_message = concat!("Subject: hello\r\n", "\r\n", "test\r\n").as_bytes();
+        let runtime = Runtime::new();
+
+        // Line numbers are kept by the serialized form
+        let deserialized = Sieve::deserialize(&script.serialize().unwrap()).unwrap();
+        for script in [script, deserialized] {
+            let mut instance =
+                Context::new(&runtime, MessageParser::new().parse(raw_message).unwrap());
+            let folders = fileinto_folders(run_script(&mut instance, script, |_| Input::True));
+
+            assert_eq!(folders, ["Archive"]);
+            assert_eq!(
+                instance.skipped_actions(),
+                [
+                    SkippedAction {
+                        line_num: Some(3),
+                        reason: SkipReason::ProtectedHeader {
+                            name: "Original-Subject".into()
+                        }
+                    },
+                    SkippedAction {
+                        line_num: Some(6),
+                        reason: SkipReason::Duplicate
+                    },
+                    SkippedAction {
+                        line_num: Some(8),
+                        reason: SkipReason::ProtectedHeader {
+                            name: "original-from".into()
+                        }
+                    },
+                ]
+            );
+            assert!(instance.warnings().is_empty());
+        }
+    }
+
+    #[test]
+    fn redirect_dsn_parameters() {
+        let compiler = Compiler::new();
+        let script = compiler
+            .compile(
+                concat!(
+                    "require [\"redirect-dsn\", \"redirect-deliverby\"];\r\n",
+                    "redirect :notify \"success,delay\" :ret \"hdrs\" ",
+                    ":bytimerelative 600 :bymode \"return\" :bytrace \"jdoe@example.org\";\r\n",
+                    "redirect :notify \"NEVER\" :ret \"FULL\" \"bob@example.org\";\r\n",
+                )
+                .as_bytes(),
+            )
+            .unwrap();
+        let runtime = Runtime::new().with_max_redirects(2);
+        let mut instance = Context::new(
+            &runtime,
+            MessageParser::new()
+                .parse(b"Subject: test\r\n\r\ntest\r\n".as_slice())
+                .unwrap(),
+        );
+        let redirects = run_script(&mut instance, script, |_| Input::True)
+            .into_iter()
+            .filter_map(|event| match event {
+                Event::SendMessage {
+                    recipient,
+                    notify,
+                    return_of_content,
+                    by_time,
+                    ..
+                } => Some((recipient, notify, return_of_content, by_time)),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+
+        assert_eq!(
+            redirects,
+            vec![
+                (
+                    Recipient::Address("jdoe@example.org".to_string()),
+                    Notify::Items(vec![NotifyItem::Success, NotifyItem::Delay]),
+                    Ret::Hdrs,
+                    ByTime::Relative {
+                        rlimit: 600,
+                        mode: ByMode::Return,
+                        trace: true,
+                    },
+                ),
+                (
+                    Recipient::Address("bob@example.org".to_string()),
+                    Notify::Never,
+                    Ret::Full,
+                    ByTime::None,
+                ),
+            ]
+        );
+
+        // Only FULL and HDRS are valid return options, NEVER excludes any other
+        // notify option
+        for (options, expected) in [
+            (":ret \"body\"", None),
+            (
+                ":notify \"never,success\"",
+                Some(ErrorType::InvalidArguments),
+            ),
+            (
+                ":notify \"failure, NEVER\"",
+                Some(ErrorType::InvalidArguments),
+            ),
+            (":notify \"success,sometimes\"", None),
+            (":notify \"\"", None),
+        ] {
+            let err = compiler
+                .compile(
+                    format!("require \"redirect-dsn\"; redirect {options} \"jdoe@example.org\";")
+                        .as_bytes(),
+                )
+                .unwrap_err();
+            if let Some(expected) = expected {
+                assert_eq!(
+                    format!("{:?}", err.error_type()),
+                    format!("{expected:?}"),
+                    "{options}"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn notify_message_truncation() {
+        let compiler = Compiler::new();
+        let message = b"Subject: test\r\n\r\ntest\r\n";
+
+        for (max_len, policy, text, expected) in [
+            (
+                usize::MAX,
+                NotifyTruncation::Cut,
+                "The quick brown fox jumps",
+                "The quick brown fox jumps",
+            ),
+            (
+                25,
+                NotifyTruncation::WordBoundary,
+                "The quick brown fox jumps",
+                "The quick brown fox jumps",
+            ),
+            (
+                12,
+                NotifyTruncation::Cut,
+                "The quick brown fox jumps",
+                "The quick br",
+            ),
+            (
+                12,
+                NotifyTruncation::WordBoundary,
+                "The quick brown fox jumps",
+                "The quick…",
+            ),
+            (
+                11,
+                NotifyTruncation::WordBoundary,
+                "The quick brown fox jumps",
+                "The quick…",
+            ),
+            (
+                6,
+                NotifyTruncation::WordBoundary,
+                "Supercalifragilistic",
+                "Super…",
+            ),
+            (4, NotifyTruncation::Cut, "Grüße aus Köln", "Grüß"),
+        ] {
+            let script = compiler
+                .compile(
+                    format!(
+                        "require \"enotify\"; notify :message \"{text}\" \"xmpp:jdoe@example.org\";"
+                    )
+                    .as_bytes(),
+                )
+                .unwrap();
+            let runtime = Runtime::new()
+                .with_max_notify_message_len(max_len)
+                .with_notify_truncation(policy);
+            let mut instance =
+                Context::new(&runtime, MessageParser::new().parse(&message[..]).unwrap());
+            let messages = run_script(&mut instance, script, |_| Input::True)
+                .into_iter()
+                .filter_map(|event| match event {
+                    Event::Notify { message, .. } => Some(message),
+                    _ => None,
+                })
+                .collect::<Vec<_>>();
+            assert_eq!(messages, [expected], "{max_len} {policy:?} {text:?}");
+        }
+    }
+
+    #[test]
+    fn address_sender_resent() {
+        let script = Compiler::new()
+            .compile(
+                concat!(
+                    "require \"fileinto\";\r\n",
+                    "if address :domain \"Sender\" \"lists.example.org\" {\r\n",
+                    "  fileinto \"sender\";\r\n",
+                    "}\r\n",
+                    "if address :domain \"Resent-From\" \"example.net\" {\r\n",
+                    "  fileinto \"resent-from\";\r\n",
+                    "}\r\n",
+                    "if address :localpart \"Resent-To\" \"bob\" {\r\n",
+                    "  fileinto \"resent-to\";\r\n",
+                    "}\r\n",
+                    "if address :all [\"Resent-Cc\", \"Resent-Sender\"] \"list@example.com\" {\r\n",
+                    "  fileinto \"resent-sender\";\r\n",
+                    "}\r\n",
+                )
+                .as_bytes(),
+            )
+            .unwrap();
+        let raw_message = concat!(
+            "From: alice@example.org\r\n",
+            "Sender: \"List\" <owner@lists.example.org>\r\n",
+            "Resent-From: Carol <carol@example.net>\r\n",
+            "Resent-To: bob@example.com, dave@example.com\r\n",
+            "Resent-Sender: list@example.com\r\n",
+            "Subject: test\r\n",
+            "\r\n",
+            "test\r\n"
+        )
+        .as_bytes();
+        let runtime = Runtime::new();
+        let mut instance = Context::new(&runtime, MessageParser::new().parse(raw_message).unwrap());
+        let folders = fileinto_folders(run_script(&mut instance, script, |_| Input::True));
+
+        assert_eq!(
+            folders,
+            ["sender", "resent-from", "resent-to", "resent-sender"]
+        );
+    }
+
+    #[test]
+    fn lazy_body() {
+        let raw_message = concat!(
+            "From: alice@example.org\r\n",
+            "Subject: parts\r\n",
+            "Content-Type: multipart/mixed; boundary=\"outer\"\r\n",
+            "\r\n",
+            "--outer\r\n",
+            "Content-Type: text/plain\r\n",
+            "\r\n",
+            "first part\r\n",
+            "--outer\r\n",
+            "Content-Type: multipart/alternative; boundary=\"inner\"\r\n",
+            "\r\n",
+            "--inner\r\n",
+            "Content-Type: text/plain\r\n",
+            "\r\n",
+            "second part\r\n",
+            "--inner\r\n",
+            "Content-Type: text/html\r\n",
+            "\r\n",
+            "<p>third part</p>\r\n",
+            "--inner--\r\n",
+            "--outer--\r\n",
+        )
+        .as_bytes();
+        let compiler = Compiler::new();
+        let headers_only = compiler
+            .compile(b"require \"fileinto\"; if header :contains \"subject\" \"parts\" { fileinto \"Parts\"; }")
+            .unwrap();
+        let with_body = compiler
+            .compile(
+                concat!(
+                    "require [\"foreverypart\", \"mime\", \"extracttext\", \"variables\", ",
+                    "\"fileinto\", \"body\"];\r\n",
+                    "if header :contains \"subject\" \"parts\" { fileinto \"header\"; }\r\n",
+                    "foreverypart :name \"outer\" {\r\n",
+                    "  foreverypart {\r\n",
+                    "    extracttext :first 6 \"text\";\r\n",
+                    "    fileinto \"${text}\";\r\n",
+                    "    if header :mime :subtype \"content-type\" \"html\" { break :name \"outer\"; }\r\n",
+                    "  }\r\n",
+                    "}\r\n",
+                    "if body :contains \"first\" { fileinto \"body\"; }\r\n",
+                )
+                .as_bytes(),
+            )
+            .unwrap();
+        let untaken_body = compiler
+            .compile(
+                concat!(
+                    "require [\"body\", \"fileinto\"];\r\n",
+                    "if header :contains \"subject\" \"other\" {\r\n",
+                    "  if body :contains \"first\" { fileinto \"body\"; }\r\n",
+                    "}\r\n",
+                    "fileinto \"done\";\r\n",
+                )
+                .as_bytes(),
+            )
+            .unwrap();
+        let part_variable = compiler
+            .compile(b"require [\"variables\", \"fileinto\"]; fileinto \"${body.text}\";")
+            .unwrap();
+        let runtime = Runtime::new();
+
+        // Scripts loaded from their serialized form behave the same way
+        let headers_only_stored = Sieve::deserialize(&headers_only.serialize().unwrap()).unwrap();
+
+        for (script, parses_body) in [
+            (headers_only, false),
+            (headers_only_stored, false),
+            (with_body, true),
+            (untaken_body, false),
+            (part_variable, true),
+        ] {
+            let mut results = Vec::new();
+            for is_lazy in [false, true] {
+                let mut instance = if is_lazy {
+                    Context::new_lazy(&runtime, raw_message)
+                } else {
+                    Context::new(&runtime, MessageParser::new().parse(raw_message).unwrap())
+                };
+                let events = run_script(&mut instance, script.clone(), |_| Input::True);
+                results.push((events, instance.message().parts.len()));
+            }
+
+            assert_eq!(results[0].0, results[1].0);
+            if parses_body {
+                assert_eq!(results[0].1, results[1].1);
+            } else {
+                assert_eq!(results[1].1, 1);
+            }
+        }
+    }
+
+    #[test]
+    fn fileinto_dedup() {
+        let script = Compiler::new()
+            .compile(
+                concat!(
+                    "require [\"fileinto\", \"imap4flags\"];\r\n",
+                    "fileinto \"Lists\";\r\n",
+                    "fileinto \"Lists\";\r\n",
+                    "fileinto :flags \"\\\\Seen\" \"Archive\";\r\n",
+                    "fileinto :flags \"\\\\Flagged\" \"Archive\";\r\n",
+                    "fileinto :flags [\"\\\\SEEN\", \"\\\\Seen\"] \"Archive\";\r\n",
+                )
+                .as_bytes(),
+            )
+            .unwrap();
+        let runtime = Runtime::new();
+        let mut instance = Context::new(
+            &runtime,
+            MessageParser::new()
+                .parse(b"Subject: test\r\n\r\ntest\r\n".as_slice())
+                .unwrap(),
+        );
+        let deliveries = run_script(&mut instance, script, |_| Input::True)
+            .into_iter()
+            .filter_map(|event| match event {
+                Event::FileInto { folder, flags, .. } => Some((folder, flags)),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+
+        assert_eq!(
+            deliveries,
+            [
+                ("Lists".to_string(), vec![]),
+                ("Archive".to_string(), vec!["\\Seen".to_string()]),
+                ("Archive".to_string(), vec!["\\Flagged".to_string()]),
+            ]
+        );
+
+        // The special-use attribute and :create are part of the delivery
+        let script = Compiler::new()
+            .compile(
+                concat!(
+                    "require [\"fileinto\", \"special-use\", \"mailbox\"];\r\n",
+                    "fileinto \"Spam\";\r\n",
+                    "fileinto :specialuse \"\\\\Junk\" :create \"Spam\";\r\n",
+                    "fileinto :create \"Spam\";\r\n",
+                    "fileinto :specialuse \"\\\\Junk\" :create \"Spam\";\r\n",
+                )
+                .as_bytes(),
+            )
+            .unwrap();
+        let mut instance = Context::new(
+            &runtime,
+            MessageParser::new()
+                .parse(b"Subject: test\r\n\r\ntest\r\n".as_slice())
+                .unwrap(),
+        );
+        let events = run_script(&mut instance, script, |_| Input::True);
+        assert_eq!(
+            events
+                .iter()
+                .filter_map(|event| match event {
+                    Event::FileInto {
+                        folder,
+                        special_use,
+                        create,
+                        ..
+                    } => Some((folder.as_str(), special_use.as_deref(), *create)),
+                    _ => None,
+                })
+                .collect::<Vec<_>>(),
+            [
+                ("Spam", None, false),
+                ("Spam", Some("\\Junk"), true),
+                ("Spam", None, true),
+            ]
+        );
+        assert_eq!(events.len(), 3);
+    }
+
+    #[test]
+    fn final_disposition() {
+        let runtime = Runtime::new();
+        let compiler = Compiler::new();
+
+        for (script, expected) in [
+            (
+                "require \"fileinto\"; fileinto \"Spam\"; redirect \"jdoe@example.org\";",
+                Disposition::Accept {
+                    deliveries: vec![
+                        Delivery::FileInto {
+                            folder: "Spam".to_string(),
+                            mailbox_id: None,
+                            flags: vec![],
+                        },
+                        Delivery::Redirect {
+                            address: "jdoe@example.org".to_string(),
+                            list: false,
+                        },
+                    ],
+                },
+            ),
+            (
+                "if false { discard; }",
+                Disposition::Accept {
+                    deliveries: vec![Delivery::Keep { flags: vec![] }],
+                },
+            ),
+            ("discard;", Disposition::Discard),
+            (
+                "require \"ereject\"; ereject \"No thanks\";",
+                Disposition::Reject {
+                    extended: true,
+                    reason: "No thanks".to_string(),
+                    smtp_code: 550,
+                    enhanced_code: "5.7.1",
+                },
+            ),
+        ] {
+            let sieve = compiler.compile(script.as_bytes()).unwrap();
+            let mut instance = Context::new(
+                &runtime,
+                MessageParser::new()
+                    .parse(b"Subject: test\r\n\r\ntest\r\n".as_slice())
+                    .unwrap(),
+            );
+            run_script(&mut instance, sieve, |_| Input::True);
+            assert_eq!(instance.final_disposition(), expected);
+        }
+    }
+
+    #[test]
+    fn consolidated_actions() {
+        let script = Compiler::new()
+            .compile(
+                concat!(
+                    "require [\"fileinto\", \"imap4flags\"];\r\n",
+                    "fileinto :flags \"\\\\Seen\" \"Archive\";\r\n",
+                    "addflag \"\\\\Flagged\";\r\n",
+                    "fileinto \"Archive\";\r\n",
+                    "fileinto :flags \"\\\\SEEN\" \"Archive\";\r\n",
+                    "redirect \"jdoe@example.org\";\r\n",
+                    "keep :flags \"$Junk\";\r\n",
+                )
+                .as_bytes(),
+            )
+            .unwrap();
+        let runtime = Runtime::new();
+        let mut instance = Context::new(
+            &runtime,
+            MessageParser::new()
+                .parse(b"Subject: test\r\n\r\ntest\r\n".as_slice())
+                .unwrap(),
+        );
+        run_script(&mut instance, script, |_| Input::True);
+
+        let expected = vec![
+            Delivery::FileInto {
+                folder: "Archive".to_string(),
+                mailbox_id: None,
+                flags: vec!["\\Seen".to_string(), "\\Flagged".to_string()],
+            },
+            Delivery::Redirect {
+                address: "jdoe@example.org".to_string(),
+                list: false,
+            },
+            Delivery::Keep {
+                flags: vec!["$Junk".to_string()],
+            },
+        ];
+        assert_eq!(instance.actions(), expected);
+        assert_eq!(
+            instance.final_disposition(),
+            Disposition::Accept {
+                deliveries: expected
+            }
+        );
+    }
+
+    #[test]
+    fn copy_implicit_keep() {
+        let runtime = Runtime::new();
+        let compiler = Compiler::new();
+        let keep = Delivery::Keep { flags: vec![] };
+        let archive = Delivery::FileInto {
+            folder: "Archive".to_string(),
+            mailbox_id: None,
+            flags: vec![],
+        };
+        let redirect = Delivery::Redirect {
+            address: "jdoe@example.org".to_string(),
+            list: false,
+        };
+
+        for (script, expected) in [
+            (
+                "require [\"fileinto\", \"copy\"]; fileinto :copy \"Archive\";",
+                vec![archive.clone(), keep.clone()],
+            ),
+            (
+                "require \"fileinto\"; fileinto \"Archive\";",
+                vec![archive.clone()],
+            ),
+            (
+                "require \"copy\"; redirect :copy \"jdoe@example.org\";",
+                vec![redirect.clone(), keep.clone()],
+            ),
+            ("redirect \"jdoe@example.org\";", vec![redirect.clone()]),
+            (
+                concat!(
+                    "require [\"fileinto\", \"copy\"]; ",
+                    "fileinto :copy \"Archive\"; redirect \"jdoe@example.org\";"
+                ),
+                vec![archive.clone(), redirect.clone()],
+            ),
+        ] {
+            let script = compiler.compile(script.as_bytes()).unwrap();
+            let mut instance = Context::new(
+                &runtime,
+                MessageParser::new()
+                    .parse(b"Subject: test\r\n\r\ntest\r\n".as_slice())
+                    .unwrap(),
+            );
+            run_script(&mut instance, script, |_| Input::True);
+            assert_eq!(
+                instance.final_disposition(),
+                Disposition::Accept {
+                    deliveries: expected
+                }
+            );
+        }
+    }
+
+    #[test]
+    fn mailbox_separator() {
+        let script = Compiler::new()
+            .compile(
+                concat!(
+                    "require [\"fileinto\", \"variables\"];\r\n",
+                    "set \"list\" \"Rust.Announce\";\r\n",
+                    "fileinto \"INBOX.Lists.Rust\";\r\n",
+                    "fileinto \"INBOX.${list}\";\r\n",
+                    "fileinto \"Archive\";\r\n",
+                )
+                .as_bytes(),
+            )
+            .unwrap();
+
+        for (runtime, expected) in [
+            (
+                Runtime::new(),
+                ["INBOX.Lists.Rust", "INBOX.Rust.Announce", "Archive"],
+            ),
+            (
+                Runtime::new().with_mailbox_separator('.', '/'),
+                ["INBOX/Lists/Rust", "INBOX/Rust/Announce", "Archive"],
+            ),
+        ] {
+            let mut instance = Context::new(
+                &runtime,
+                MessageParser::new()
+                    .parse(b"Subject: test\r\n\r\ntest\r\n".as_slice())
+                    .unwrap(),
+            );
+            let folders =
+                fileinto_folders(run_script(&mut instance, script.clone(), |_| Input::True));
+            assert_eq!(folders, expected);
+        }
+    }
+
+    #[test]
+    fn environment_name_version() {
+        let compiler = Compiler::new().with_no_capability_check(true);
+        let version = env!("CARGO_PKG_VERSION");
+
+        for (runtime, name) in [
+            (Runtime::new(), "Stalwart Sieve"),
+            (
+                Runtime::new().with_env_variable("name", "Acme Mail Filter"),
+                "Acme Mail Filter",
+            ),
+        ] {
+            let instance = Context::new(
+                &runtime,
+                MessageParser::new()
+                    .parse(b"Subject: test\r\n\r\ntest\r\n".as_slice())
+                    .unwrap(),
+            );
+            for (item, value) in [("name", name), ("version", version)] {
+                assert!(
+                    instance
+                        .eval_test(
+                            &compiler,
+                            format!("environment :is :comparator \"i;octet\" {item:?} {value:?}")
+                                .as_bytes()
+                        )
+                        .unwrap(),
+                    "{item} is not {value:?}"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn implicit_keep_handler() {
+        let runtime = Runtime::new().with_implicit_keep_handler(Arc::new(|| KeepOptions {
+            flags: vec!["\\Seen".to_string()],
+            mailbox: None,
+        }));
+        let compiler = Compiler::new();
+
+        for (script, expected) in [
+            (
+                "",
+                vec![Delivery::Keep {
+                    flags: vec!["\\Seen".to_string()],
+                }],
+            ),
+            ("keep;", vec![Delivery::Keep { flags: vec![] }]),
+            (
+                "require \"fileinto\"; fileinto \"Archive\";",
+                vec![Delivery::FileInto {
+                    folder: "Archive".to_string(),
+                    mailbox_id: None,
+                    flags: vec![],
+                }],
+            ),
+        ] {
+            let sieve = compiler.compile(script.as_bytes()).unwrap();
+            let mut instance = Context::new(
+                &runtime,
+                MessageParser::new()
+                    .parse(b"Subject: test\r\n\r\ntest\r\n".as_slice())
+                    .unwrap(),
+            );
+            run_script(&mut instance, sieve, |_| Input::True);
+            assert_eq!(instance.deliveries, expected, "{script:?}");
+        }
+    }
+
+    #[test]
+    fn vacation_seconds() {
+        let compiler = Compiler::new();
+        let runtime = Runtime::new();
+
+        for (script, expected_expiry) in [
+            (
+                "require [\"vacation\", \"vacation-seconds\"]; vacation :seconds 120 \"Away\";",
+                120,
+            ),
+            (
+                "require [\"vacation\", \"vacation-seconds\"]; vacation :seconds 0 \"Away\";",
+                0,
+            ),
+            (
+                "require \"vacation\"; vacation :days 2 \"Away\";",
+                2 * 86400,
+            ),
+            (
+                "require \"vacation\"; vacation \"Away\";",
+                runtime.default_vacation_expiry,
+            ),
+        ] {
+            let script = compiler.compile(script.as_bytes()).unwrap();
+            let mut instance = Context::new(
+                &runtime,
+                MessageParser::new()
+                    .parse(b"From: jdoe@example.org\r\nTo: bill@example.org\r\nSubject: test\r\n\r\ntest\r\n".as_slice())
+                    .unwrap(),
+            )
+            .with_user_address("bill@example.org")
+            .with_envelope(Envelope::From, "jdoe@example.org");
+            let expiries = run_script(&mut instance, script, |event| {
+                if matches!(event, Event::DuplicateId { .. }) {
+                    Input::False
+                } else {
+                    Input::True
+                }
+            })
+            .into_iter()
+            .filter_map(|event| match event {
+                Event::DuplicateId { expiry, .. } => Some(expiry),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+            assert_eq!(expiries, [expected_expiry]);
+        }
+
+        for (script, expected) in [
+            (
+                "require [\"vacation\", \"vacation-seconds\"]; vacation :days 1 :seconds 120 \"Away\";",
+                ErrorType::DuplicatedParameter,
+            ),
+            (
+                "require [\"vacation\", \"vacation-seconds\"]; vacation :seconds 120 :days 1 \"Away\";",
+                ErrorType::DuplicatedParameter,
+            ),
+            (
+                "require \"vacation\"; vacation :seconds 120 \"Away\";",
+                ErrorType::UndeclaredCapability(Capability::VacationSeconds),
+            ),
+        ] {
+            let err = compiler.compile(script.as_bytes()).unwrap_err();
+            assert_eq!(
+                format!("{:?}", err.error_type()),
+                format!("{expected:?}"),
+                "{script}"
+            );
+        }
+    }
+
+    #[test]
+    fn variable_numeric() {
+        for (value, number) in [
+            (Variable::from("42"), Some(Number::Integer(42))),
+            (Variable::from("42abc"), Some(Number::Integer(42))),
+            (Variable::from("-7"), None),
+            (Variable::from("1.5"), Some(Number::Integer(1))),
+            (Variable::from("5.5"), Some(Number::Integer(5))),
+            (Variable::from("abc"), None),
+            (Variable::from(""), None),
+            (Variable::Integer(3), Some(Number::Integer(3))),
+            (Variable::Float(2.9), Some(Number::Float(2.9))),
+            (Variable::from(vec![Variable::Integer(1)]), None),
+        ] {
+            assert_eq!(value.as_number(), number, "{value:?}");
+        }
+        assert_eq!(Variable::from("42abc").as_integer(), Some(42));
+        assert_eq!(Variable::Float(2.9).as_integer(), Some(2));
+        assert_eq!(Variable::from("abc").as_integer(), None);
+        assert_eq!(Variable::from("7 days").as_float(), Some(7.0));
+        assert_eq!(
+            Variable::from("abc").to_ascii_numeric(),
+            Number::Float(f64::INFINITY)
+        );
+
+        // :count keys are read the way i;ascii-numeric reads values
+        let compiler = Compiler::new().with_no_capability_check(true);
+        let runtime = Runtime::new();
+        let message = b"X-Tag: a\r\nX-Tag: b\r\nSubject: test\r\n\r\ntest\r\n";
+        for (test, expected) in [
+            (&b"header :count \"eq\" \"x-tag\" \"2 headers\""[..], true),
+            (b"header :count \"lt\" \"x-tag\" \"none\"", true),
+            (b"header :count \"gt\" \"x-tag\" \"1st\"", true),
+        ] {
+            assert_eq!(
+                runtime
+                    .eval_test(
+                        &compiler,
+                        test,
+                        MessageParser::new().parse(&message[..]).unwrap()
+                    )
+                    .unwrap(),
+                expected,
+                "{}",
+                String::from_utf8_lossy(test)
+            );
+        }
+    }
+
+    #[test]
+    fn variable_bytes() {
+        let bytes = Variable::from(b"caf\xe9".to_vec());
+        assert_eq!(bytes.to_bytes().as_ref(), b"caf\xe9");
+        assert_eq!(bytes.to_string(), "caf\u{fffd}");
+        assert_ne!(bytes, Variable::from("caf\u{fffd}"));
+        assert_eq!(Variable::from(b"cafe".to_vec()), Variable::from("cafe"));
+        assert_eq!(
+            Variable::from(b"42".to_vec()).as_number(),
+            Some(Number::Integer(42))
+        );
+        assert_eq!(
+            Variable::from(b"caf".to_vec())
+                .op_add(Variable::from("\u{e9}"))
+                .to_bytes()
+                .as_ref(),
+            "caf\u{e9}".as_bytes()
+        );
+
+        // Raw headers are compared by their octets, not a lossy decoding
+        let compiler = Compiler::new().with_no_capability_check(true);
+        let runtime = Runtime::new();
+        let message = b"X-Bin: caf\xe9\r\nSubject: test\r\n\r\ntest\r\n";
+        for (test, expected) in [
+            (
+                "string :comparator \"i;octet\" :is \"${header.subject.raw}\" \" test\"",
+                true,
+            ),
+            (
+                "string :comparator \"i;octet\" :is \"${header.x-bin.raw}\" \" caf\u{fffd}\"",
+                false,
+            ),
+            (
+                "string :comparator \"i;octet\" :contains \"${header.x-bin.raw}\" \"caf\"",
+                true,
+            ),
+        ] {
+            assert_eq!(
+                runtime
+                    .eval_test(
+                        &compiler,
+                        test.as_bytes(),
+                        MessageParser::new().parse(&message[..]).unwrap()
+                    )
+                    .unwrap(),
+                expected,
+                "{test}"
+            );
+        }
+    }
+
+    #[test]
+    fn relational_operators() {
+        let compiler = Compiler::new().with_no_capability_check(true);
+        let runtime = Runtime::new();
+        let message = b"X-Tag: a\r\nX-Tag: b\r\nX-Tag: c\r\nX-Num: 3\r\nTo: a@x.org, b@x.org, c@x.org\r\n\r\ntest\r\n";
+
+        // Every test compares 3 against each key: three X-Tag headers and
+        // three addresses are counted, the single X-Num header holds 3
+        for (op, lower, equal, higher) in [
+            ("eq", false, true, false),
+            ("ne", true, false, true),
+            ("gt", false, false, true),
+            ("ge", false, true, true),
+            ("lt", true, false, false),
+            ("le", true, true, false),
+        ] {
+            for (key, expected) in [("4", lower), ("3", equal), ("2", higher)] {
+                for test in [
+                    format!("header :count \"{op}\" \"x-tag\" \"{key}\""),
+                    format!("address :count \"{op}\" \"to\" \"{key}\""),
+                    format!(
+                        "header :value \"{op}\" :comparator \"i;ascii-numeric\" \"x-num\" \"{key}\""
+                    ),
+                ] {
+                    assert_eq!(
+                        runtime
+                            .eval_test(
+                                &compiler,
+                                test.as_bytes(),
+                                MessageParser::new().parse(&message[..]).unwrap()
+                            )
+                            .unwrap(),
+                        expected,
+                        "{test}"
+                    );
+                }
+            }
+        }
+
+        for test in [
+            &b"header :count \"lessthan\" \"x-tag\" \"1\""[..],
+            b"header :value \"EQUALS\" \"x-tag\" \"1\"",
+        ] {
+            assert!(matches!(
+                runtime
+                    .eval_test(
+                        &compiler,
+                        test,
+                        MessageParser::new().parse(&message[..]).unwrap()
+                    )
+                    .unwrap_err(),
+                EvalError::Compile(err) if matches!(err.error_type(), ErrorType::InvalidArguments)
+            ));
+        }
+    }
+
+    #[test]
+    fn runtime_builder() {
+        let script = Compiler::new()
+            .compile(
+                concat!(
+                    "require [\"date\", \"fileinto\", \"relational\", \"vnd.stalwart.while\", ",
+                    "\"vnd.stalwart.expressions\"];\r\n",
+                    "if currentdate :value \"eq\" \"date\" \"2021-06-01\" { fileinto \"dated\"; }\r\n",
+                    "let \"i\" \"0\";\r\n",
+                    "while \"i < 100\" { let \"i\" \"i + 1\"; }\r\n",
+                )
+                .as_bytes(),
+            )
+            .unwrap();
+        let runtime = Runtime::new()
+            .with_max_instructions(50)
+            .with_clock(Arc::new(|| 1622548800))
+            .with_capabilities([
+                Capability::Date,
+                Capability::FileInto,
+                Capability::Relational,
+                Capability::While,
+                Capability::Expressions,
+            ])
+            .with_max_events(10)
+            .with_keep_on_error(false);
+        assert_eq!(runtime.cpu_limit, 50);
+
+        let mut instance = Context::new(
+            &runtime,
+            MessageParser::new()
+                .parse(b"Subject: test\r\n\r\ntest\r\n")
+                .unwrap(),
+        );
+        let mut input = Input::script("", script.clone());
+        let mut folders = Vec::new();
+        let mut error = None;
+        while let Some(result) = instance.run(input) {
+            match result {
+                Ok(Event::FileInto { folder, .. }) => folders.push(folder),
+                Ok(_) => (),
+                Err(err) => error = Some(err),
+            }
+            input = Input::True;
+        }
+        assert_eq!(folders, ["dated"]);
+        assert!(matches!(error, Some(RuntimeError::CPULimitReached)));
+
+        // The list replaces the allowed capabilities instead of adding to them
+        let runtime = runtime.with_capabilities([Capability::Date, Capability::Relational]);
+        let mut instance = Context::new(
+            &runtime,
+            MessageParser::new()
+                .parse(b"Subject: test\r\n\r\ntest\r\n")
+                .unwrap(),
+        );
+        assert!(matches!(
+            instance.run(Input::script("", script)),
+            Some(Err(RuntimeError::CapabilityNotAllowed(
+                Capability::FileInto
+            )))
+        ));
+    }
+
+    #[test]
+    fn eval_test() {
+        let compiler = Compiler::new();
+        let runtime = Runtime::new();
+        let message = b"From: jdoe@example.org\r\nSubject: Big sale\r\nX-Spam: yes\r\n\r\ntest\r\n";
+
+        for (test, expected) in [
+            (&b"header :contains \"subject\" \"sale\""[..], true),
+            (b"not header :contains \"subject\" \"sale\"", false),
+            (b"header :is \"subject\" \"sale\"", false),
+            (
+                b"allof(exists \"x-spam\", header :matches \"from\" \"*@example.org\")",
+                true,
+            ),
+            (b"allof(exists \"x-spam\", not exists \"x-spam\")", false),
+            (
+                b"anyof(false, address :domain \"from\" \"example.org\")",
+                true,
+            ),
+            (b"not anyof(false, size :over 1M)", true),
+            (b"# comment\r\nsize :under 1K", true),
+        ] {
+            assert_eq!(
+                runtime
+                    .eval_test(
+                        &compiler,
+                        test,
+                        MessageParser::new().parse(&message[..]).unwrap()
+                    )
+                    .unwrap(),
+                expected,
+                "{}",
+                String::from_utf8_lossy(test)
+            );
+        }
+
+        // Errors point into the fragment
+        let err = runtime
+            .eval_test(
+                &compiler,
+                b"anyof(true,\n  header :contains \"subject\" 1)",
+                MessageParser::new().parse(&message[..]).unwrap(),
+            )
+            .unwrap_err();
+        assert!(matches!(
+            err,
+            EvalError::Compile(err) if (err.line_num(), err.line_pos()) == (2, 30)
+        ));
+        assert!(runtime
+            .eval_test(
+                &compiler,
+                b"true; keep;",
+                MessageParser::new().parse(&message[..]).unwrap()
+            )
+            .is_err());
+        assert!(matches!(
+            runtime
+                .eval_test(
+                    &compiler,
+                    b"envelope :is \"from\" \"jdoe@example.org\"",
+                    MessageParser::new().parse(&message[..]).unwrap()
+                )
+                .unwrap_err(),
+            EvalError::Compile(err) if matches!(err.error_type(), ErrorType::UndeclaredCapability(Capability::Envelope))
+        ));
+
+        // Extensions and envelope parameters
+        let compiler = Compiler::new().with_no_capability_check(true);
+        let instance = Context::new(&runtime, MessageParser::new().parse(&message[..]).unwrap())
+            .with_envelope(Envelope::From, "jdoe@example.org");
+        assert!(instance
+            .eval_test(&compiler, b"envelope :is \"from\" \"jdoe@example.org\"")
+            .unwrap());
+        assert!(!instance
+            .eval_test(&compiler, b"mailboxexists \"Archive\"")
+            .unwrap());
+        assert!(!instance
+            .eval_test(
+                &compiler,
+                b"spamtest :value \"ge\" :comparator \"i;ascii-numeric\" \"1\""
+            )
+            .unwrap());
+
+        // The context is left as it was and can still run a script
+        let mut instance = instance;
+        let events = run_script(
+            &mut instance,
+            Compiler::new()
+                .compile(b"if header :contains \"subject\" \"sale\" { discard; }")
+                .unwrap(),
+            |_| Input::True,
+        );
+        assert!(events
+            .iter()
+            .any(|event| matches!(event, Event::Discard { .. })));
+        assert!(!instance.has_message_changed());
+
+        // Runtime errors are reported rather than evaluating to false
+        let runtime = Runtime::new().with_max_instructions(1);
+        assert!(matches!(
+            runtime.eval_test(
+                &compiler,
+                b"allof(exists \"x-spam\", exists \"subject\", exists \"from\")",
+                MessageParser::new().parse(&message[..]).unwrap()
+            ),
+            Err(EvalError::Runtime(RuntimeError::CPULimitReached))
+        ));
+    }
+
+    #[test]
+    fn unsupported_capability_policy() {
+        let script = Compiler::new()
+            .compile(
+                concat!(
+                    "require [\"ihave\", \"fileinto\", \"vnd.example.foo\"];\n",
+                    "if foo \"a\" { discard; }\n",
+                    "foocmd \"b\";\n",
+                    "fileinto \"B\";\n",
+                )
+                .as_bytes(),
+            )
+            .unwrap();
+
+        for policy in [
+            UnsupportedCapabilityPolicy::Error,
+            UnsupportedCapabilityPolicy::Ignore,
+        ] {
+            let runtime = Runtime::new().with_unsupported_capability_policy(policy);
+            let mut instance = Context::new(
+                &runtime,
+                MessageParser::new()
+                    .parse(b"Subject: test\r\n\r\ntest\r\n".as_slice())
+                    .unwrap(),
+            );
+            let mut input = Input::script("", script.clone());
+            let mut events = Vec::new();
+            let mut errors = Vec::new();
+            while let Some(result) = instance.run(input) {
+                match result {
+                    Ok(event) => events.push(event),
+                    Err(err) => errors.push(err.to_string()),
+                }
+                // The answer to a skipped test is ignored
+                input = Input::True;
+            }
+
+            if policy == UnsupportedCapabilityPolicy::Error {
+                assert_eq!(errors, ["Capability 'vnd.example.foo' not supported."]);
+                assert_eq!(
+                    events,
+                    [Event::Keep {
+                        flags: vec![],
+                        message_id: 0
+                    }]
+                );
+            } else {
+                assert_eq!(errors, Vec::<String>::new());
+                assert_eq!(
+                    events,
+                    [
+                        Event::InstructionSkipped {
+                            name: "foo".to_string(),
+                            line_num: 2,
+                            line_pos: 4
+                        },
+                        Event::InstructionSkipped {
+                            name: "foocmd".to_string(),
+                            line_num: 3,
+                            line_pos: 1
+                        },
+                        Event::FileInto {
+                            folder: "B".to_string(),
+                            flags: vec![],
+                            mailbox_id: None,
+                            special_use: None,
+                            create: false,
+                            message_id: 0
+                        }
+                    ]
+                );
+            }
+        }
+
+        // Known capabilities that are not allowed are still an error
+        let runtime = Runtime::new()
+            .without_capability(Capability::FileInto)
+            .with_unsupported_capability_policy(UnsupportedCapabilityPolicy::Ignore);
+        let mut instance = Context::new(
+            &runtime,
+            MessageParser::new()
+                .parse(b"Subject: test\r\n\r\ntest\r\n".as_slice())
+                .unwrap(),
+        );
+        assert!(matches!(
+            instance.run(Input::script("", script)),
+            Some(Err(RuntimeError::CapabilityNotAllowed(
+                Capability::FileInto
+            )))
+        ));
+    }
+
+    #[test]
+    fn score_variable() {
+        let script = concat!(
+            "require [\"variables\", \"vnd.stalwart.expressions\", \"vnd.stalwart.score\"];\r\n",
+            "if header :contains \"Subject\" \"free\" {\r\n",
+            "  let \"vnd.stalwart.score\" \"vnd.stalwart.score + 5\";\r\n",
+            "}\r\n",
+            "if header :contains \"Subject\" \"money\" {\r\n",
+            "  let \"vnd.stalwart.score\" \"vnd.stalwart.score + 3\";\r\n",
+            "}\r\n",
+            "if header :contains \"Subject\" \"meeting\" {\r\n",
+            "  let \"vnd.stalwart.score\" \"vnd.stalwart.score - 10\";\r\n",
+            "}\r\n",
+            "if string :is \"${vnd.stalwart.score}\" \"8\" {\r\n",
+            "  set \"vnd.stalwart.score\" \"${vnd.stalwart.score}0\";\r\n",
+            "}\r\n",
+        );
+
+        assert!(Compiler::new()
+            .compile(script.replace(", \"vnd.stalwart.score\"", "").as_bytes())
+            .is_err());
+
+        let script = Compiler::new().compile(script.as_bytes()).unwrap();
+        let runtime = Runtime::new()
+            .with_capability(Capability::Expressions)
+            .with_capability(Capability::Score);
+
+        for (subject, score) in [("Free money", 80), ("Meeting", -10), ("Hello", 0)] {
+            let raw_message = format!("Subject: {subject}\r\n\r\ntest\r\n");
+            let mut instance = Context::new(
+                &runtime,
+                MessageParser::new().parse(raw_message.as_bytes()).unwrap(),
+            );
+            run_script(&mut instance, script.clone(), |_| Input::True);
+            assert_eq!(instance.score(), score, "{subject}");
+            assert_eq!(
+                instance.global_variable_names().count(),
+                usize::from(score != 0),
+                "{subject}"
+            );
+        }
+
+        // Scores saturate at the i64 bounds
+        for (script, score) in [
+            (
+                "set \"vnd.stalwart.score\" \"9223372036854775807\";\r\nlet \"vnd.stalwart.score\" \"vnd.stalwart.score + 1\";",
+                i64::MAX,
+            ),
+            (
+                "set \"vnd.stalwart.score\" \"-9223372036854775807\";\r\nlet \"vnd.stalwart.score\" \"vnd.stalwart.score - 10\";",
+                i64::MIN,
+            ),
+            (
+                "let \"vnd.stalwart.score\" \"vnd.stalwart.score * 2\";",
+                0,
+            ),
+            ("set \"vnd.stalwart.score\" \"1e30\";", i64::MAX),
+        ] {
+            let script = Compiler::new()
+                .compile(
+                    format!(
+                        "require [\"variables\", \"vnd.stalwart.expressions\", \"vnd.stalwart.score\"];\r\n{script}"
+                    )
+                    .as_bytes(),
+                )
+                .unwrap();
+            let mut instance = Context::new(
+                &runtime,
+                MessageParser::new()
+                    .parse(b"Subject: test\r\n\r\ntest\r\n".as_slice())
+                    .unwrap(),
+            );
+            run_script(&mut instance, script, |_| Input::True);
+            assert_eq!(instance.score(), score);
+        }
+    }
+
+    #[derive(Debug)]
+    struct DigitsComparator;
+
+    impl DigitsComparator {
+        fn digits(value: &str) -> String {
+            value.chars().filter(|ch| ch.is_ascii_digit()).collect()
+        }
+    }
+
+    impl ExternalComparator for DigitsComparator {
+        fn equals(&self, a: &str, b: &str) -> bool {
+            Self::digits(a) == Self::digits(b)
+        }
+
+        fn less_than(&self, a: &str, b: &str) -> bool {
+            Self::digits(a) < Self::digits(b)
+        }
+
+        fn substring(&self, haystack: &str, needle: &str) -> bool {
+            Self::digits(haystack).contains(&Self::digits(needle))
+        }
+    }
+
+    #[test]
+    fn external_comparator() {
+        let script = concat!(
+            "require [\"relational\", \"fileinto\", \"variables\", \"comparator-x-digits\"];\r\n",
+            "if header :is :comparator \"x-digits\" \"X-Phone\" \"(555) 123-4567\" {\r\n",
+            "  fileinto \"is\";\r\n",
+            "}\r\n",
+            "if header :contains :comparator \"x-digits\" \"X-Phone\" \"1-2-3\" {\r\n",
+            "  fileinto \"contains\";\r\n",
+            "}\r\n",
+            "if header :value \"gt\" :comparator \"x-digits\" \"X-Phone\" \"555-000\" {\r\n",
+            "  fileinto \"gt\";\r\n",
+            "}\r\n",
+            "if string :is :comparator \"x-digits\" \"a1b2\" \"12\" {\r\n",
+            "  fileinto \"string\";\r\n",
+            "}\r\n",
+            "if string :value \"lt\" :comparator \"x-digits\" \"a1b2\" \"11\" {\r\n",
+            "  fileinto \"string-lt\";\r\n",
+            "}\r\n",
+        )
+        .as_bytes();
+
+        assert!(Compiler::new().compile(script).is_err());
+
+        let script = Compiler::new()
+            .register_comparator("x-digits")
+            .compile(script)
+            .unwrap();
+        let raw_message = concat!(
+            "X-Phone: 555-123-4567\r\n",
+            "Subject: test\r\n",
+            "\r\n",
+            "test\r\n"
+        )
+        .as_bytes();
+        let runtime =
+            Runtime::new().with_comparator("x-digits", std::sync::Arc::new(DigitsComparator));
+        let mut instance = Context::new(&runtime, MessageParser::new().parse(raw_message).unwrap());
+        let folders = fileinto_folders(run_script(&mut instance, script, |_| Input::True));
+
+        assert_eq!(folders, ["is", "contains", "gt", "string"]);
+    }
+
+    #[test]
+    fn run_for_recipients() {
+        let script = Compiler::new()
+            .compile(
+                concat!(
+                    "require [\"envelope\", \"fileinto\", \"imap4flags\", \"variables\", \"editheader\"];\r\n",
+                    "set \"folder\" \"\";\r\n",
+                    "if exists \"X-Alice\" {\r\n",
+                    "  set \"folder\" \"Leaked\";\r\n",
+                    "}\r\n",
+                    "if envelope :localpart :is \"to\" \"alice\" {\r\n",
+                    "  addflag \"\\\\Flagged\";\r\n",
+                    "  addheader \"X-Alice\" \"yes\";\r\n",
+                    "  set \"folder\" \"Alice\";\r\n",
+                    "}\r\n",
+                    "if string :is \"${folder}\" \"\" {\r\n",
+                    "  discard;\r\n",
+                    "} else {\r\n",
+                    "  fileinto \"${folder}\";\r\n",
+                    "}\r\n",
+                )
+                .as_bytes(),
+            )
+            .unwrap();
+
+        let runtime = Runtime::new();
+        let mut instance = Context::new(
+            &runtime,
+            MessageParser::new()
+                .parse(b"From: jane@example.org\r\nSubject: test\r\n\r\ntest\r\n")
+                .unwrap(),
+        )
+        .with_envelope(Envelope::From, "jane@example.org")
+        .with_envelope(Envelope::To, "postmaster@example.com");
+
+        let mut events = Vec::new();
+        let results = instance.run_for_recipients(
+            "",
+            script,
+            ["alice@example.com", "<bob@example.com>"],
+            |recipient, event| {
+                events.push((recipient.to_string(), event));
+                Input::True
+            },
+        );
+
+        // Flags, variables and header edits made for the first recipient do
+        // not leak into the second run
+        assert_eq!(
+            results
+                .into_iter()
+                .map(|(recipient, result)| (recipient, result.unwrap()))
+                .collect::<Vec<_>>(),
+            vec![
+                (
+                    "alice@example.com".to_string(),
+                    Disposition::Accept {
+                        deliveries: vec![Delivery::FileInto {
+                            folder: "Alice".to_string(),
+                            mailbox_id: None,
+                            flags: vec!["\\Flagged".to_string()],
+                        }]
+                    }
+                ),
+                ("<bob@example.com>".to_string(), Disposition::Discard),
+            ]
+        );
+        assert_eq!(
+            events
+                .iter()
+                .map(|(recipient, event)| (
+                    recipient.as_str(),
+                    matches!(event, Event::Discard { .. })
+                ))
+                .collect::<Vec<_>>(),
+            [
+                ("alice@example.com", false),
+                ("alice@example.com", false),
+                ("<bob@example.com>", true)
+            ]
+        );
+    }
+
+    #[test]
+    fn deleteheader_index_last() {
+        let script = Compiler::new()
+            .compile(
+                concat!(
+                    "require [\"editheader\", \"index\", \"variables\", \"fileinto\"];\r\n",
+                    "deleteheader :index 2 :last \"Received\";\r\n",
+                    "deleteheader :index 3 :last \"Received\";\r\n",
+                    "deleteheader :index 3 \"Received\";\r\n",
+                    "if header :index 2 :last \"Received\" \"from a\" {\r\n",
+                    "  fileinto \"${header.received[-1].raw}\";\r\n",
+                    "}\r\n",
+                )
+                .as_bytes(),
+            )
+            .unwrap();
+
+        let runtime = Runtime::new();
+        let mut instance = Context::new(
+            &runtime,
+            MessageParser::new()
+                .parse(concat!(
+                    "Received: from a\r\n",
+                    "Received: from b\r\n",
+                    "Received: from c\r\n",
+                    "Subject: test\r\n",
+                    "\r\n",
+                    "test\r\n"
+                ))
+                .unwrap(),
+        );
+
+        let folders = fileinto_folders(run_script(&mut instance, script, |_| Input::True));
+
+        // Only the middle header is removed, out of range indexes are ignored
+        assert_eq!(folders, [" from c"]);
+        let message = instance.message();
+        assert_eq!(
+            message.parts[0]
+                .headers
+                .iter()
+                .filter(|h| h.name == mail_parser::HeaderName::Received)
+                .map(|h| {
+                    std::str::from_utf8(&message.raw_message()[h.offset_start..h.offset_end])
+                        .unwrap()
+                        .trim()
+                })
+                .collect::<Vec<_>>(),
+            ["from a", "from c"]
+        );
+        assert!(Compiler::new()
+            .compile(b"require \"editheader\"; deleteheader :index 0 \"Received\";")
+            .is_err());
+    }
+
+    #[test]
+    fn collect_matches() {
+        let script = Compiler::new()
+            .compile(
+                concat!(
+                    "require [\"variables\", \"fileinto\"];\r\n",
+                    "if header :contains \"x-tag\" \"a\" { fileinto \"contains\"; }\r\n",
+                    "if header :matches \"x-tag\" \"*a\" { fileinto \"${1}\"; }\r\n",
+                    "if not header :is \"x-tag\" \"delta\" { fileinto \"none\"; }\r\n",
+                )
+                .as_bytes(),
+            )
+            .unwrap();
+        let runtime = Runtime::new();
+
+        for collect in [false, true] {
+            let mut instance = Context::new(
+                &runtime,
+                MessageParser::new()
+                    .parse(b"X-Tag: alpha\r\nX-Tag: beta\r\nX-Tag: gamma\r\nSubject: test\r\n\r\ntest\r\n")
+                    .unwrap(),
+            )
+            .with_collect_matches(collect);
+
+            let folders =
+                fileinto_folders(run_script(&mut instance, script.clone(), |_| Input::True));
+
+            // Collecting does not change the outcome or the match variables
+            assert_eq!(folders, ["contains", "alph", "none"]);
+
+            if collect {
+                let details = instance.match_details();
+                assert_eq!(
+                    details
+                        .iter()
+                        .map(|detail| detail.values.as_slice())
+                        .collect::<Vec<_>>(),
+                    [
+                        &["alpha", "beta", "gamma"][..],
+                        &["alpha", "beta", "gamma"],
+                        &[]
+                    ]
+                );
+                assert!(details.iter().all(|detail| matches!(
+                    script.instructions()[detail.pos],
+                    Instruction::Test(Test::Header(_))
+                )));
+            } else {
+                assert!(instance.match_details().is_empty());
+            }
+        }
+    }
+
     fn add_crlf(bytes: &[u8]) -> Vec<u8> {
         let mut result = Vec::with_capacity(bytes.len());
         let mut last_ch = 0;
@@ -27,7 +27,9 @@ use mail_parser::{
 };
 
 use crate::{
-    compiler::grammar::actions::action_convert::Convert, runtime::tests::TestResult, Context,
+    compiler::grammar::actions::action_convert::{Convert, TestConvertible},
+    runtime::tests::TestResult,
+    Context,
 };
 
 #[derive(Clone, Copy)]
@@ -37,19 +39,11 @@ enum Conversion {
     HtmlToText,
 }
 
-impl Convert {
-    pub(crate) fn exec(&self, ctx: &mut Context) -> TestResult {
-        let _from_media_type = ctx.eval_value(&self.from_media_type);
-        let _to_media_type = ctx.eval_value(&self.to_media_type);
-
-        let from_media_type = _from_media_type.to_string();
-        let to_media_type = _to_media_type.to_string();
-
-        if from_media_type.eq_ignore_ascii_case(to_media_type.as_ref()) {
-            return TestResult::Bool(false ^ self.is_not);
-        }
-
-        let conversion = if (from_media_type.eq_ignore_ascii_case("text")
+impl Conversion {
+    fn new(from_media_type: &str, to_media_type: &str) -> Option<Self> {
+        if from_media_type.eq_ignore_ascii_case(to_media_type) {
+            None
+        } else if (from_media_type.eq_ignore_ascii_case("text")
             || from_media_type.starts_with("text/"))
             && to_media_type.eq_ignore_ascii_case("text/html")
         {
@@ -58,10 +52,42 @@ impl Convert {
             } else {
                 Conversion::TextToHtml
             }
+            .into()
         } else if from_media_type.eq_ignore_ascii_case("text/html")
             && to_media_type.eq_ignore_ascii_case("text/plain")
         {
-            Conversion::HtmlToText
+            Conversion::HtmlToText.into()
+        } else {
+            None
+        }
+    }
+}
+
+impl TestConvertible {
+    pub(crate) fn exec(&self, ctx: &mut Context) -> TestResult {
+        TestResult::Bool(
+            Conversion::new(
+                ctx.eval_value(&self.from_media_type).to_string().as_ref(),
+                ctx.eval_value(&self.to_media_type).to_string().as_ref(),
+            )
+            .is_some()
+                ^ self.is_not,
+        )
+    }
+}
+
+impl Convert {
+    pub(crate) fn exec(&self, ctx: &mut Context) -> TestResult {
+        let _from_media_type = ctx.eval_value(&self.from_media_type);
+        let _to_media_type = ctx.eval_value(&self.to_media_type);
+
+        let from_media_type = _from_media_type.to_string();
+        let to_media_type = _to_media_type.to_string();
+
+        let conversion = if let Some(conversion) =
+            Conversion::new(from_media_type.as_ref(), to_media_type.as_ref())
+        {
+            conversion
         } else {
             return TestResult::Bool(false ^ self.is_not);
         };
@@ -80,7 +106,7 @@ impl Convert {
                     if part
                         .content_type()
                         .and_then(|ct| ct.c_subtype.as_ref())
-                        .map_or(false, |st| st.eq_ignore_ascii_case("plain")) =>
+                        .is_some_and(|st| st.eq_ignore_ascii_case("plain")) =>
                 {
                     (
                         PartType::Html(text_to_html(text.as_ref()).into()),
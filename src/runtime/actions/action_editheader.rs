@@ -33,6 +33,7 @@ use crate::{
         },
         MatchType,
     },
+    runtime::SkipReason,
     Context,
 };
 
@@ -50,7 +51,11 @@ impl AddHeader {
 
         if !header_name.is_empty() {
             if let Some(header_name) = HeaderName::parse(header_name) {
-                if !ctx.runtime.protected_headers.contains(&header_name) {
+                if ctx.runtime.protected_headers.contains(&header_name) {
+                    ctx.skip_action(SkipReason::ProtectedHeader {
+                        name: header_name.as_str().to_string(),
+                    });
+                } else {
                     ctx.has_changes = true;
                     ctx.insert_header(
                         ctx.part,
@@ -81,6 +86,9 @@ impl DeleteHeader {
         let mut deleted_bytes = 0;
 
         if ctx.runtime.protected_headers.contains(&header_name) {
+            ctx.skip_action(SkipReason::ProtectedHeader {
+                name: header_name.as_str().to_string(),
+            });
             return;
         }
 
@@ -116,7 +124,7 @@ impl DeleteHeader {
                                     &mut Vec::new(),
                                 ),
                                 MatchType::Count(_) => false,
-                                MatchType::List => false,
+                                MatchType::List | MatchType::KeywordSet => false,
                             } {
                                 return true;
                             }
@@ -21,15 +21,35 @@
  * for more details.
 */
 
-use crate::{compiler::grammar::actions::action_fileinto::FileInto, Context, Event};
+use crate::{
+    compiler::{grammar::actions::action_fileinto::FileInto, Value},
+    runtime::{RuntimeWarning, SkipReason},
+    Context, Event,
+};
+
+// A delivery made by `fileinto`, repeating it is a no-op
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub(crate) struct Delivered {
+    folder: String,
+    mailbox_id: Option<String>,
+    flags: Vec<String>,
+    special_use: Option<String>,
+    create: bool,
+    message_id: usize,
+}
 
 impl FileInto {
     pub(crate) fn exec(&self, ctx: &mut Context) {
-        let folder = ctx.eval_value(&self.folder).to_string().into_owned();
-        let mut events = Vec::with_capacity(2);
-        if let Some(event) = ctx.build_message_id() {
-            events.push(event);
+        let folder = ctx.eval_mailbox(&self.folder);
+        if !is_valid_mailbox(&folder) {
+            ctx.skip_action(SkipReason::InvalidTarget {
+                target: folder.clone(),
+            });
+            ctx.warnings
+                .push(RuntimeWarning::InvalidMailbox { mailbox: folder });
+            return;
         }
+        let flags = ctx.get_local_or_global_flags(&self.flags);
 
         if !self.copy
             && !matches!(&ctx.final_event, Some(Event::Keep { flags, .. }) if !flags.is_empty())
@@ -37,17 +57,40 @@ impl FileInto {
             ctx.final_event = None;
         }
 
+        let mailbox_id = self
+            .mailbox_id
+            .as_ref()
+            .map(|mi| ctx.eval_value(mi).to_string().into_owned());
+        let special_use = self
+            .special_use
+            .as_ref()
+            .map(|su| ctx.eval_value(su).to_string().into_owned());
+        let mut delivery_flags = flags.iter().map(|f| f.to_lowercase()).collect::<Vec<_>>();
+        delivery_flags.sort_unstable();
+        delivery_flags.dedup();
+
+        // An edited message is always a new delivery
+        let mut events = Vec::with_capacity(2);
+        if let Some(event) = ctx.build_message_id() {
+            events.push(event);
+        }
+        if !ctx.delivered.insert(Delivered {
+            folder: folder.clone(),
+            mailbox_id: mailbox_id.clone(),
+            flags: delivery_flags,
+            special_use: special_use.clone(),
+            create: self.create,
+            message_id: ctx.main_message_id,
+        }) {
+            ctx.skip_action(SkipReason::Duplicate);
+            return;
+        }
+
         events.push(Event::FileInto {
             folder,
-            flags: ctx.get_local_or_global_flags(&self.flags),
-            mailbox_id: self
-                .mailbox_id
-                .as_ref()
-                .map(|mi| ctx.eval_value(mi).to_string().into_owned()),
-            special_use: self
-                .special_use
-                .as_ref()
-                .map(|su| ctx.eval_value(su).to_string().into_owned()),
+            flags,
+            mailbox_id,
+            special_use,
             create: self.create,
             message_id: ctx.main_message_id,
         });
@@ -55,3 +98,19 @@ impl FileInto {
         ctx.queued_events = events.into_iter();
     }
 }
+
+impl<'x> Context<'x> {
+    pub(crate) fn eval_mailbox(&self, mailbox: &Value) -> String {
+        let mailbox = self.eval_value(mailbox).to_string().into_owned();
+        match self.runtime.mailbox_separator {
+            Some((from, to)) if from != to => mailbox.replace(from, to.encode_utf8(&mut [0; 4])),
+            _ => mailbox,
+        }
+    }
+}
+
+// Mailbox names built from variables may expand to an empty string or
+// carry control characters taken from the message.
+fn is_valid_mailbox(mailbox: &str) -> bool {
+    !mailbox.trim().is_empty() && !mailbox.chars().any(char::is_control)
+}
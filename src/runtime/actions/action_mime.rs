@@ -28,10 +28,7 @@ use mail_parser::{
 };
 
 use crate::{
-    compiler::{
-        grammar::actions::action_mime::{Enclose, ExtractText, Replace},
-        VariableType,
-    },
+    compiler::grammar::actions::action_mime::{Enclose, ExtractText, Replace},
     Context, Event,
 };
 
@@ -345,20 +342,13 @@ impl ExtractText {
         let mut value = String::new();
 
         if !ctx.part_iter_stack.is_empty() {
+            // Non-text parts always extract to the empty string.
             match ctx.message.parts.get(ctx.part).map(|p| &p.body) {
                 Some(PartType::Text(text)) => {
-                    value = if let Some(first) = &self.first {
-                        text.chars().take(*first).collect()
-                    } else {
-                        text.as_ref().to_string()
-                    };
+                    value = normalize_text(text.as_ref(), self.first);
                 }
                 Some(PartType::Html(html)) => {
-                    value = if let Some(first) = &self.first {
-                        html_to_text(html.as_ref()).chars().take(*first).collect()
-                    } else {
-                        html_to_text(html.as_ref())
-                    };
+                    value = normalize_text(&html_to_text(html.as_ref()), self.first);
                 }
                 _ => (),
             }
@@ -370,27 +360,19 @@ impl ExtractText {
             }
         }
 
-        match &self.name {
-            VariableType::Local(var_id) => {
-                if let Some(var) = ctx.vars_local.get_mut(*var_id) {
-                    *var = value.into();
-                } else {
-                    debug_assert!(false, "Non-existent local variable {var_id}");
-                }
-            }
-            VariableType::Global(var_name) => {
-                ctx.vars_global
-                    .insert(var_name.to_string().into(), value.into());
-            }
-            VariableType::Envelope(env) => {
-                ctx.queued_events = vec![Event::SetEnvelope {
-                    envelope: *env,
-                    value,
-                }]
-                .into_iter();
-            }
-            _ => (),
-        }
+        ctx.set_variable(&self.name, value.into());
+    }
+}
+
+// Removes any byte order mark left over from charset decoding. When `first`
+// is set, at most that many characters (Unicode scalar values, not bytes)
+// are returned so that a multi-byte sequence is never split.
+fn normalize_text(text: &str, first: Option<usize>) -> String {
+    let text = text.strip_prefix('\u{feff}').unwrap_or(text);
+    if let Some(first) = first {
+        text.chars().take(first).collect()
+    } else {
+        text.to_string()
     }
 }
 
@@ -29,6 +29,7 @@ use crate::{
         action_notify::Notify,
         action_redirect::{ByTime, Ret},
     },
+    runtime::{NotifyTruncation, RuntimeWarning, SkipReason},
     Context, Event, Importance, Recipient,
 };
 
@@ -42,7 +43,7 @@ impl Notify {
                 && header
                     .value
                     .as_text()
-                    .map_or(true, |v| !v.eq_ignore_ascii_case("no"))
+                    .is_none_or(|v| !v.eq_ignore_ascii_case("no"))
             {
                 return;
             }
@@ -52,9 +53,21 @@ impl Notify {
         let (scheme, params) = if let Some(parts) = parse_uri(&uri) {
             parts
         } else {
+            ctx.skip_action(SkipReason::InvalidTarget {
+                target: uri.clone(),
+            });
+            ctx.warnings
+                .push(RuntimeWarning::InvalidNotifyMethod { method: uri });
             return;
         };
 
+        let notify_message = self.message.as_ref().map(|m| {
+            truncate_message(
+                ctx.eval_value(m).to_string().into_owned(),
+                ctx.runtime.max_notify_message_len,
+                ctx.runtime.notify_truncation,
+            )
+        });
         let has_fcc = self.fcc.is_some();
         let is_mailto = scheme.eq_ignore_ascii_case("mailto")
             && ctx.num_out_messages < ctx.runtime.max_out_messages;
@@ -65,6 +78,12 @@ impl Notify {
                 if let Some(params) = parse_mailto(params) {
                     params
                 } else {
+                    ctx.skip_action(SkipReason::InvalidTarget {
+                        target: uri.clone(),
+                    });
+                    ctx.warnings.push(RuntimeWarning::InvalidNotifyMethod {
+                        method: uri.clone(),
+                    });
                     return;
                 }
             } else {
@@ -89,10 +108,6 @@ impl Notify {
             } else {
                 ctx.user_from_field()
             };
-            let notify_message = self
-                .message
-                .as_ref()
-                .map(|m| ctx.eval_value(m).to_string().into_owned());
             let message_len = params
                 .to
                 .iter()
@@ -194,10 +209,8 @@ impl Notify {
                 subject.as_str()
             } else if let Some(subject) = &notify_message {
                 subject.as_ref()
-            } else if let Some(subject) = ctx.message.subject() {
-                subject
             } else {
-                ""
+                ctx.message.subject().unwrap_or_default()
             };
             let mut iter = subject.chars().enumerate();
             let mut buf = [0; 4];
@@ -274,10 +287,7 @@ impl Notify {
                     }
                 }),
                 options: ctx.eval_values_owned(&self.options),
-                message: self
-                    .message
-                    .as_ref()
-                    .map(|m| ctx.eval_value(m).to_string().into_owned())
+                message: notify_message
                     .or_else(|| ctx.message.subject().map(|s| s.to_string()))
                     .unwrap_or_default(),
             });
@@ -287,7 +297,7 @@ impl Notify {
         if let Some(fcc) = &self.fcc {
             // File carbon copy
             events.push(Event::FileInto {
-                folder: ctx.eval_value(&fcc.mailbox).to_string().into_owned(),
+                folder: ctx.eval_mailbox(&fcc.mailbox),
                 flags: ctx.get_local_flags(&fcc.flags),
                 mailbox_id: fcc
                     .mailbox_id
@@ -305,6 +315,35 @@ impl Notify {
     }
 }
 
+fn truncate_message(message: String, max_len: usize, policy: NotifyTruncation) -> String {
+    if message.chars().count() <= max_len {
+        return message;
+    }
+
+    match policy {
+        NotifyTruncation::Cut => message.chars().take(max_len).collect(),
+        NotifyTruncation::WordBoundary => {
+            let Some(max_len) = max_len.checked_sub(1) else {
+                return String::new();
+            };
+            let mut result = message.chars().take(max_len).collect::<String>();
+            // Drop the last word if it was cut, unless it is the only one
+            let next_is_space = message
+                .chars()
+                .nth(max_len)
+                .is_some_and(char::is_whitespace);
+            if !next_is_space {
+                if let Some(pos) = result.rfind(char::is_whitespace) {
+                    result.truncate(pos);
+                }
+            }
+            result.truncate(result.trim_end().len());
+            result.push('…');
+            result
+        }
+    }
+}
+
 pub fn validate_from(addr: &str) -> bool {
     let mut has_at = false;
     let mut has_dot = false;
@@ -314,10 +353,8 @@ pub fn validate_from(addr: &str) -> bool {
 
     for &ch in addr.as_bytes().iter() {
         match ch {
-            b'\"' => {
-                if last_ch != b'\\' {
-                    in_quote = !in_quote;
-                }
+            b'\"' if last_ch != b'\\' => {
+                in_quote = !in_quote;
             }
             b'<' if !in_quote => {
                 if !in_angle {
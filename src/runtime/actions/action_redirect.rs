@@ -25,86 +25,101 @@ use mail_parser::{DateTime, HeaderName};
 
 use crate::{
     compiler::grammar::actions::action_redirect::{ByTime, Redirect},
-    Context, Envelope, Event, Recipient,
+    runtime::{RuntimeWarning, SkipReason},
+    Context, Delivery, Envelope, Event, Recipient,
 };
 
 impl Redirect {
     pub(crate) fn exec(&self, ctx: &mut Context) {
-        if let Some(address) = sanitize_address(ctx.eval_value(&self.address).to_string().as_ref())
+        let address = ctx.eval_value(&self.address).to_string().into_owned();
+        let Some(address) = sanitize_address(&address) else {
+            ctx.skip_action(SkipReason::InvalidTarget {
+                target: address.clone(),
+            });
+            ctx.warnings
+                .push(RuntimeWarning::InvalidAddress { address });
+            return;
+        };
+
+        if ctx.num_redirects < ctx.runtime.max_redirects
+            && ctx.num_out_messages < ctx.runtime.max_out_messages
+            && ctx.message.parts[0]
+                .headers
+                .iter()
+                .filter(|h| matches!(&h.name, HeaderName::Received))
+                .count()
+                < ctx.runtime.max_received_headers
         {
-            if ctx.num_redirects < ctx.runtime.max_redirects
-                && ctx.num_out_messages < ctx.runtime.max_out_messages
-                && ctx.message.parts[0]
-                    .headers
-                    .iter()
-                    .filter(|h| matches!(&h.name, HeaderName::Received))
-                    .count()
-                    < ctx.runtime.max_received_headers
+            // Try to avoid forwarding loops
+            if !self.list
+                && (address.eq_ignore_ascii_case(ctx.user_address.as_ref())
+                    || ctx.envelope.iter().any(|(e, v)| {
+                        matches!(e, Envelope::From)
+                            && v.to_string().eq_ignore_ascii_case(address.as_str())
+                    }))
             {
-                // Try to avoid forwarding loops
-                if !self.list
-                    && (address.eq_ignore_ascii_case(ctx.user_address.as_ref())
-                        || ctx.envelope.iter().any(|(e, v)| {
-                            matches!(e, Envelope::From)
-                                && v.to_string().eq_ignore_ascii_case(address.as_str())
-                        }))
-                {
-                    return;
-                }
+                ctx.skip_action(SkipReason::ForwardingLoop);
+                return;
+            }
 
-                if !self.copy && matches!(&ctx.final_event, Some(Event::Keep { .. })) {
-                    ctx.final_event = None;
-                }
+            if !self.copy && matches!(&ctx.final_event, Some(Event::Keep { .. })) {
+                ctx.final_event = None;
+            }
 
-                let mut events = Vec::with_capacity(2);
-                if let Some(event) = ctx.build_message_id() {
-                    events.push(event);
-                }
-                ctx.num_redirects += 1;
-                ctx.num_out_messages += 1;
-                events.push(Event::SendMessage {
-                    recipient: if !self.list {
-                        Recipient::Address(address)
-                    } else {
-                        Recipient::List(address)
+            let mut events = Vec::with_capacity(2);
+            if let Some(event) = ctx.build_message_id() {
+                events.push(event);
+            }
+            ctx.num_redirects += 1;
+            ctx.num_out_messages += 1;
+            ctx.deliveries.push(Delivery::Redirect {
+                address: address.clone(),
+                list: self.list,
+            });
+            events.push(Event::SendMessage {
+                recipient: if !self.list {
+                    Recipient::Address(address)
+                } else {
+                    Recipient::List(address)
+                },
+                notify: self.notify.clone(),
+                return_of_content: self.return_of_content.clone(),
+                by_time: match &self.by_time {
+                    ByTime::Relative {
+                        rlimit,
+                        mode,
+                        trace,
+                    } => ByTime::Relative {
+                        rlimit: *rlimit,
+                        mode: mode.clone(),
+                        trace: *trace,
                     },
-                    notify: self.notify.clone(),
-                    return_of_content: self.return_of_content.clone(),
-                    by_time: match &self.by_time {
-                        ByTime::Relative {
-                            rlimit,
-                            mode,
-                            trace,
-                        } => ByTime::Relative {
-                            rlimit: *rlimit,
-                            mode: mode.clone(),
-                            trace: *trace,
-                        },
-                        ByTime::Absolute {
-                            alimit,
-                            mode,
-                            trace,
-                        } => ByTime::Absolute {
-                            alimit: DateTime::parse_rfc3339(
-                                ctx.eval_value(alimit).to_string().as_ref(),
-                            )
-                            .and_then(|d| {
-                                if d.is_valid() {
-                                    d.to_timestamp().into()
-                                } else {
-                                    None
-                                }
-                            })
-                            .unwrap_or(0),
-                            mode: mode.clone(),
-                            trace: *trace,
-                        },
-                        ByTime::None => ByTime::None,
+                    ByTime::Absolute {
+                        alimit,
+                        mode,
+                        trace,
+                    } => ByTime::Absolute {
+                        alimit: DateTime::parse_rfc3339(
+                            ctx.eval_value(alimit).to_string().as_ref(),
+                        )
+                        .and_then(|d| {
+                            if d.is_valid() {
+                                d.to_timestamp().into()
+                            } else {
+                                None
+                            }
+                        })
+                        .unwrap_or(0),
+                        mode: mode.clone(),
+                        trace: *trace,
                     },
-                    message_id: ctx.main_message_id,
-                });
-                ctx.queued_events = events.into_iter();
-            }
+                    ByTime::None => ByTime::None,
+                },
+                message_id: ctx.main_message_id,
+            });
+            ctx.queued_events = events.into_iter();
+        } else {
+            ctx.skip_action(SkipReason::LimitReached);
         }
     }
 }
@@ -23,13 +23,13 @@
 
 use crate::{
     compiler::{
-        grammar::actions::action_set::{Modifier, Set},
+        grammar::actions::action_set::{HashAlgo, Modifier, Set, TransferEncoding, UrlProfile},
         VariableType,
     },
-    runtime::Variable,
+    runtime::{hash, quoted_printable, Variable},
     Context, Event,
 };
-use std::fmt::Write;
+use std::{fmt::Write, sync::Arc};
 
 impl Set {
     pub(crate) fn exec(&self, ctx: &mut Context) {
@@ -58,14 +58,14 @@ impl<'x> Context<'x> {
 
         match var_name {
             VariableType::Local(var_id) => {
-                if let Some(var) = self.vars_local.get_mut(*var_id) {
+                if let Some(var) = Arc::make_mut(&mut self.vars_local).get_mut(*var_id) {
                     *var = variable.clone();
                 } else {
                     debug_assert!(false, "Non-existent local variable {var_id}");
                 }
             }
             VariableType::Global(var_name) => {
-                self.vars_global
+                Arc::make_mut(&mut self.vars_global)
                     .insert(var_name.to_string().into(), variable.clone());
             }
             VariableType::Envelope(env) => {
@@ -180,13 +180,56 @@ impl Modifier {
                 }
                 result
             }
+            Modifier::QuoteMailto => {
+                // Keeps the characters allowed unencoded in an RFC 6068 hfvalue.
+                let mut buf = [0; 4];
+                let mut result = String::with_capacity(input.len());
+
+                for char in input.chars() {
+                    if char.is_ascii_alphanumeric()
+                        || [
+                            '-', '.', '_', '~', '!', '$', '\'', '(', ')', '*', '+', ',', ';', ':',
+                            '@',
+                        ]
+                        .contains(&char)
+                    {
+                        if result.len() < max_len {
+                            result.push(char);
+                        } else {
+                            return result;
+                        }
+                    } else if result.len() + (char.len_utf8() * 3) <= max_len {
+                        for byte in char.encode_utf8(&mut buf).as_bytes().iter() {
+                            write!(result, "%{byte:02X}").ok();
+                        }
+                    } else {
+                        return result;
+                    }
+                }
+                result
+            }
             Modifier::Length => input.chars().count().to_string(),
-            Modifier::EncodeUrl => {
+            Modifier::Trim => input.trim().to_string(),
+            Modifier::TrimStart => input.trim_start().to_string(),
+            Modifier::TrimEnd => input.trim_end().to_string(),
+            Modifier::EncodeUrl { profile } => {
                 let mut buf = [0; 4];
                 let mut result = String::with_capacity(input.len());
+                let reserved: &[char] = match profile {
+                    UrlProfile::Component => &[],
+                    UrlProfile::Path => &[
+                        '!', '$', '&', '\'', '(', ')', '*', '+', ',', ';', '=', ':', '@', '/',
+                    ],
+                    UrlProfile::Query => &[
+                        '!', '$', '&', '\'', '(', ')', '*', ',', ';', '=', ':', '@', '/', '?',
+                    ],
+                };
 
                 for char in input.chars() {
-                    if char.is_ascii_alphanumeric() || ['-', '.', '_', '~'].contains(&char) {
+                    if char.is_ascii_alphanumeric()
+                        || ['-', '.', '_', '~'].contains(&char)
+                        || reserved.contains(&char)
+                    {
                         if result.len() < max_len {
                             result.push(char);
                         } else {
@@ -202,10 +245,89 @@ impl Modifier {
                 }
                 result
             }
+            Modifier::DecodeUrl => {
+                let mut result = decode_url(input);
+                if result.len() > max_len {
+                    let mut end = max_len;
+                    while !result.is_char_boundary(end) {
+                        end -= 1;
+                    }
+                    result.truncate(end);
+                }
+                result
+            }
             Modifier::Replace { find, replace } => input.replace(
                 ctx.eval_value(find).to_string().as_ref(),
                 ctx.eval_value(replace).to_string().as_ref(),
             ),
+            Modifier::Default { value } => {
+                if input.is_empty() {
+                    ctx.eval_value(value).to_string().into_owned()
+                } else {
+                    input.to_string()
+                }
+            }
+            Modifier::Hash { algorithm } => {
+                let digest = match algorithm {
+                    HashAlgo::Md5 => hash::md5(input.as_bytes()),
+                    HashAlgo::Sha1 => hash::sha1(input.as_bytes()),
+                    HashAlgo::Sha256 => hash::sha256(input.as_bytes()),
+                };
+                let mut result = String::with_capacity(digest.len() * 2);
+                for byte in digest {
+                    if result.len() + 2 <= max_len {
+                        write!(result, "{byte:02x}").ok();
+                    } else {
+                        break;
+                    }
+                }
+                result
+            }
+            Modifier::Encode {
+                encoding: TransferEncoding::QuotedPrintable,
+            } => quoted_printable::encode(input, max_len),
+            Modifier::Decode {
+                encoding: TransferEncoding::QuotedPrintable,
+            } => {
+                let mut result = quoted_printable::decode(input);
+                if result.len() > max_len {
+                    let mut end = max_len;
+                    while !result.is_char_boundary(end) {
+                        end -= 1;
+                    }
+                    result.truncate(end);
+                }
+                result
+            }
+        }
+    }
+}
+
+// Malformed `%` sequences are copied as they are, decoded octets that are
+// not valid UTF-8 are replaced.
+fn decode_url(input: &str) -> String {
+    let bytes = input.as_bytes();
+    let mut result = Vec::with_capacity(bytes.len());
+    let mut pos = 0;
+
+    while pos < bytes.len() {
+        let byte = bytes[pos];
+        if byte == b'%' {
+            if let Some(decoded) = bytes
+                .get(pos + 1..pos + 3)
+                .filter(|hex| hex.iter().all(u8::is_ascii_hexdigit))
+                .and_then(|hex| std::str::from_utf8(hex).ok())
+                .and_then(|hex| u8::from_str_radix(hex, 16).ok())
+            {
+                result.push(decoded);
+                pos += 3;
+                continue;
+            }
         }
+        result.push(byte);
+        pos += 1;
     }
+
+    String::from_utf8(result)
+        .unwrap_or_else(|err| String::from_utf8_lossy(err.as_bytes()).into_owned())
 }
@@ -55,15 +55,11 @@ impl TestVacation {
                     Envelope::From => {
                         from = value.to_string().to_ascii_lowercase();
                     }
-                    Envelope::To => {
-                        if !ctx.runtime.vacation_use_orig_rcpt {
-                            user_addresses.push(value.to_string());
-                        }
+                    Envelope::To if !ctx.runtime.vacation_use_orig_rcpt => {
+                        user_addresses.push(value.to_string());
                     }
-                    Envelope::Orcpt => {
-                        if ctx.runtime.vacation_use_orig_rcpt {
-                            user_addresses.push(value.to_string());
-                        }
+                    Envelope::Orcpt if ctx.runtime.vacation_use_orig_rcpt => {
+                        user_addresses.push(value.to_string());
                     }
                     _ => (),
                 }
@@ -105,9 +101,10 @@ impl TestVacation {
                 | HeaderName::ResentCc
                     if !found_rcpt =>
                 {
-                    found_rcpt = ctx.find_addresses(header, &AddressPart::All, |addr| {
-                        user_addresses.iter().any(|a| a.eq_ignore_ascii_case(addr))
-                    });
+                    found_rcpt =
+                        ctx.find_addresses(header, &AddressPart::All, &mut false, |addr| {
+                            user_addresses.iter().any(|a| a.eq_ignore_ascii_case(addr))
+                        });
                 }
                 HeaderName::ListArchive
                 | HeaderName::ListHelp
@@ -127,12 +124,12 @@ impl TestVacation {
                         if header
                             .value
                             .as_text()
-                            .map_or(true, |v| !v.eq_ignore_ascii_case("no"))
+                            .is_none_or(|v| !v.eq_ignore_ascii_case("no"))
                         {
                             return TestResult::Bool(false);
                         }
                     } else if header_name.eq_ignore_ascii_case("X-Auto-Response-Suppress") {
-                        if header.value.as_text().map_or(false, |v| {
+                        if header.value.as_text().is_some_and(|v| {
                             v.to_ascii_lowercase()
                                 .split(',')
                                 .any(|v| ["all", "oof"].contains(&v.trim()))
@@ -143,7 +140,7 @@ impl TestVacation {
                         && header
                             .value
                             .as_text()
-                            .map_or(false, |v| v.eq_ignore_ascii_case("bulk"))
+                            .is_some_and(|v| v.eq_ignore_ascii_case("bulk"))
                     {
                         return TestResult::Bool(false);
                     }
@@ -227,21 +224,16 @@ impl Vacation {
                 HeaderName::MessageId => {
                     message_id = header.value.as_text();
                 }
-                HeaderName::References => {
-                    if header.offset_start > 0 {
-                        references = (&ctx.message.raw_message
-                            [header.offset_start..header.offset_end])
-                            .into();
-                    }
+                HeaderName::References if header.offset_start > 0 => {
+                    references =
+                        (&ctx.message.raw_message[header.offset_start..header.offset_end]).into();
                 }
-                HeaderName::From | HeaderName::Sender => {
+                HeaderName::From | HeaderName::Sender
                     if matches!(&header.value, HeaderValue::Address(address) if address.contains(vacation_to.as_ref()))
-                        && header.offset_start > 0
-                    {
-                        vacation_to_full = (&ctx.message.raw_message
-                            [header.offset_start..header.offset_end])
-                            .into();
-                    }
+                        && header.offset_start > 0 =>
+                {
+                    vacation_to_full =
+                        (&ctx.message.raw_message[header.offset_start..header.offset_end]).into();
                 }
                 _ => (),
             }
@@ -335,7 +327,7 @@ impl Vacation {
         // File carbon copy
         if let Some(fcc) = &self.fcc {
             events.push(Event::FileInto {
-                folder: ctx.eval_value(&fcc.mailbox).to_string().into_owned(),
+                folder: ctx.eval_mailbox(&fcc.mailbox),
                 flags: ctx.get_local_flags(&fcc.flags),
                 mailbox_id: fcc
                     .mailbox_id
@@ -21,28 +21,38 @@
  * for more details.
 */
 
-use std::{borrow::Cow, sync::Arc, time::SystemTime};
+use std::{
+    borrow::Cow,
+    sync::Arc,
+    time::{Duration, Instant},
+};
 
-use ahash::AHashMap;
-use mail_parser::Message;
+use ahash::{AHashMap, AHashSet};
+use mail_parser::{Message, MessageParser};
 
 use crate::{
-    compiler::grammar::{instruction::Instruction, Capability},
-    Context, Envelope, Event, Input, Metadata, Runtime, Sieve, SpamStatus, VirusStatus,
-    MAX_LOCAL_VARIABLES, MAX_MATCH_VARIABLES,
+    compiler::{
+        grammar::{instruction::Instruction, Capability, Invalid},
+        Number,
+    },
+    Compiler, Context, Delivery, Disposition, Envelope, Event, Input, MatchDetail, Metadata,
+    Runtime, Script, Sieve, Snapshot, SpamStatus, VirusStatus, MAX_LOCAL_VARIABLES,
+    MAX_MATCH_VARIABLES, SCORE_VARIABLE,
 };
 
 use super::{
     actions::action_include::IncludeResult,
+    empty_message,
     tests::{test_envelope::parse_envelope_address, TestResult},
-    RuntimeError, Variable,
+    EvalError, RuntimeError, RuntimeWarning, SkipReason, SkippedAction,
+    UnsupportedCapabilityPolicy, Variable,
 };
 
 #[derive(Clone, Debug)]
 pub(crate) struct ScriptStack {
     pub(crate) script: Arc<Sieve>,
     pub(crate) prev_pos: usize,
-    pub(crate) prev_vars_local: Vec<Variable>,
+    pub(crate) prev_vars_local: Arc<Vec<Variable>>,
     pub(crate) prev_vars_match: Vec<Variable>,
 }
 
@@ -55,6 +65,7 @@ impl<'x> Context<'x> {
             #[cfg(not(test))]
             runtime,
             message,
+            message_body: None,
             part: 0,
             part_iter: Vec::new().into_iter(),
             part_iter_stack: Vec::new(),
@@ -62,9 +73,15 @@ impl<'x> Context<'x> {
             test_result: false,
             script_cache: AHashMap::new(),
             script_stack: Vec::with_capacity(0),
-            vars_global: AHashMap::new(),
+            duplicate_ids: AHashMap::new(),
+            duplicate_pending: None,
+            delivered: AHashSet::new(),
+            deliveries: Vec::new(),
+            rejection: None,
+            discarded: false,
+            vars_global: Arc::new(AHashMap::new()),
             vars_env: AHashMap::new(),
-            vars_local: Vec::with_capacity(0),
+            vars_local: Arc::new(Vec::with_capacity(0)),
             vars_match: Vec::with_capacity(0),
             expr_stack: Vec::with_capacity(16),
             expr_pos: 0,
@@ -80,13 +97,20 @@ impl<'x> Context<'x> {
             has_changes: false,
             user_address: "".into(),
             user_full_name: "".into(),
-            current_time: SystemTime::now()
-                .duration_since(SystemTime::UNIX_EPOCH)
-                .map(|d| d.as_secs())
-                .unwrap_or(0) as i64,
+            current_time: runtime.now(),
             num_redirects: 0,
             num_instructions: 0,
+            cpu_warned: false,
+            ignore_input_result: None,
+            explicit_keep: false,
+            collect_matches: false,
+            match_details: Vec::new(),
+            time_used: Duration::ZERO,
+            run_started: None,
             num_out_messages: 0,
+            num_events: 0,
+            warnings: Vec::new(),
+            skipped_actions: Vec::new(),
             last_message_id: 0,
             main_message_id: 0,
             virus_status: VirusStatus::Unknown,
@@ -94,38 +118,96 @@ impl<'x> Context<'x> {
         }
     }
 
-    #[allow(clippy::while_let_on_iterator)]
     pub fn run(&mut self, input: Input) -> Option<Result<Event, RuntimeError>> {
-        match input {
-            Input::True => self.test_result ^= true,
-            Input::False => self.test_result ^= false,
-            Input::FncResult(result) => {
-                self.expr_stack.push(result);
+        // Only time spent inside the interpreter counts towards the limit
+        if self.runtime.max_duration.is_some() {
+            self.run_started = Some(Instant::now());
+        }
+        let result = self.run_(input);
+        if let Some(run_started) = self.run_started.take() {
+            self.time_used += run_started.elapsed();
+        }
+        if let Some(Ok(event)) = &result {
+            // Events queued once the script has finished are not counted
+            if !self.script_stack.is_empty() {
+                self.num_events += 1;
+                if self.num_events > self.runtime.max_events {
+                    self.finish_loop();
+                    return Some(Err(RuntimeError::EventLimitReached));
+                }
             }
-            Input::Script { name, script } => {
-                let num_vars = script.num_vars;
-                let num_match_vars = script.num_match_vars;
+            match event {
+                Event::Keep { flags, .. } => {
+                    self.deliveries.push(Delivery::Keep {
+                        flags: flags.clone(),
+                    });
+                }
+                Event::FileInto {
+                    folder,
+                    mailbox_id,
+                    flags,
+                    ..
+                } => {
+                    self.deliveries.push(Delivery::FileInto {
+                        folder: folder.clone(),
+                        mailbox_id: mailbox_id.clone(),
+                        flags: flags.clone(),
+                    });
+                }
+                Event::Discard { .. } => {
+                    self.discarded = true;
+                }
+                Event::Reject { extended, reason } => {
+                    self.rejection = Some((*extended, reason.clone()));
+                }
+                _ => (),
+            }
+        }
+        result
+    }
 
-                if num_match_vars <= MAX_MATCH_VARIABLES && num_vars <= MAX_LOCAL_VARIABLES {
-                    if self.message_size == usize::MAX {
-                        self.message_size = self.message.raw_message.len();
+    #[allow(clippy::while_let_on_iterator)]
+    fn run_(&mut self, input: Input) -> Option<Result<Event, RuntimeError>> {
+        if let Some(test_result) = self.ignore_input_result.take() {
+            // The answer to a notice such as a CPU warning carries no result
+            self.test_result = test_result;
+        } else {
+            match input {
+                Input::True | Input::False => {
+                    let result = matches!(input, Input::True);
+                    self.test_result ^= result;
+                    if let Some(id) = self.duplicate_pending.take() {
+                        self.duplicate_ids.insert(id, result);
                     }
+                }
+                Input::FncResult(result) => {
+                    self.expr_stack.push(result);
+                }
+                Input::Script { name, script } => {
+                    let num_vars = script.num_vars;
+                    let num_match_vars = script.num_match_vars;
 
-                    self.script_cache.insert(name, script.clone());
-                    self.script_stack.push(ScriptStack {
-                        script,
-                        prev_pos: self.pos,
-                        prev_vars_local: std::mem::replace(
-                            &mut self.vars_local,
-                            vec![Variable::default(); num_vars],
-                        ),
-                        prev_vars_match: std::mem::replace(
-                            &mut self.vars_match,
-                            vec![Variable::default(); num_match_vars],
-                        ),
-                    });
-                    self.pos = 0;
-                    self.test_result = false;
+                    if num_match_vars <= MAX_MATCH_VARIABLES && num_vars <= MAX_LOCAL_VARIABLES {
+                        if self.message_size == usize::MAX {
+                            self.message_size = self.message.raw_message.len();
+                        }
+
+                        self.script_cache.insert(name, script.clone());
+                        self.script_stack.push(ScriptStack {
+                            script,
+                            prev_pos: self.pos,
+                            prev_vars_local: std::mem::replace(
+                                &mut self.vars_local,
+                                Arc::new(vec![Variable::default(); num_vars]),
+                            ),
+                            prev_vars_match: std::mem::replace(
+                                &mut self.vars_match,
+                                vec![Variable::default(); num_match_vars],
+                            ),
+                        });
+                        self.pos = 0;
+                        self.test_result = false;
+                    }
                 }
             }
         }
@@ -140,12 +222,31 @@ impl<'x> Context<'x> {
 
         'outer: loop {
             while let Some(instruction) = iter.next() {
+                if self.num_instructions >= self.runtime.cpu_soft_limit && !self.cpu_warned {
+                    self.cpu_warned = true;
+                    self.ignore_input_result = Some(self.test_result);
+                    return Some(Ok(Event::CpuWarning {
+                        used: self.num_instructions as u64,
+                        limit: self.runtime.cpu_soft_limit as u64,
+                    }));
+                }
                 self.num_instructions += 1;
                 if self.num_instructions > self.runtime.cpu_limit {
                     self.finish_loop();
                     return Some(Err(RuntimeError::CPULimitReached));
                 }
+                if let (Some(max_duration), Some(run_started)) =
+                    (self.runtime.max_duration, self.run_started)
+                {
+                    if self.time_used + run_started.elapsed() > max_duration {
+                        self.finish_loop();
+                        return Some(Err(RuntimeError::TimeLimitReached));
+                    }
+                }
                 self.pos += 1;
+                if self.message_body.is_some() && instruction.needs_body() {
+                    self.parse_body();
+                }
 
                 match instruction {
                     Instruction::Jz(jmp_pos) => {
@@ -178,6 +279,13 @@ impl<'x> Context<'x> {
                             self.test_result = is_not;
                             return Some(Ok(event));
                         }
+                        TestResult::Error(RuntimeError::InvalidInstruction(invalid))
+                            if self.runtime.unsupported_capability_policy
+                                == UnsupportedCapabilityPolicy::Ignore =>
+                        {
+                            self.test_result = false;
+                            return Some(Ok(self.skip_instruction(&invalid)));
+                        }
                         TestResult::Error(err) => {
                             self.finish_loop();
                             return Some(Err(err));
@@ -193,7 +301,7 @@ impl<'x> Context<'x> {
                     },
                     Instruction::Clear(clear) => {
                         if clear.local_vars_num > 0 {
-                            if let Some(local_vars) = self.vars_local.get_mut(
+                            if let Some(local_vars) = Arc::make_mut(&mut self.vars_local).get_mut(
                                 clear.local_vars_idx as usize
                                     ..(clear.local_vars_idx + clear.local_vars_num) as usize,
                             ) {
@@ -212,6 +320,7 @@ impl<'x> Context<'x> {
                     }
                     Instruction::Keep(keep) => {
                         let next_event = self.build_message_id();
+                        self.explicit_keep = true;
                         self.final_event = Event::Keep {
                             flags: self.get_local_or_global_flags(&keep.flags),
                             message_id: self.main_message_id,
@@ -233,8 +342,14 @@ impl<'x> Context<'x> {
                             return Some(Ok(event));
                         }
                     }
-                    Instruction::Discard => {
-                        self.final_event = Event::Discard.into();
+                    Instruction::Discard(discard) => {
+                        self.final_event = Event::Discard {
+                            reason: discard
+                                .reason
+                                .as_ref()
+                                .map(|reason| self.eval_value(reason).to_string().into_owned()),
+                        }
+                        .into();
                     }
                     Instruction::Stop => {
                         self.script_stack.clear();
@@ -345,7 +460,7 @@ impl<'x> Context<'x> {
                                 prev_pos: self.pos,
                                 prev_vars_local: std::mem::replace(
                                     &mut self.vars_local,
-                                    vec![Variable::default(); script.num_vars],
+                                    Arc::new(vec![Variable::default(); script.num_vars]),
                                 ),
                                 prev_vars_match: std::mem::replace(
                                     &mut self.vars_match,
@@ -374,16 +489,21 @@ impl<'x> Context<'x> {
                     }
                     Instruction::Require(capabilities) => {
                         for capability in capabilities {
-                            if !self.runtime.allowed_capabilities.contains(capability) {
-                                self.finish_loop();
-                                return Some(Err(
-                                    if let Capability::Other(not_supported) = capability {
-                                        RuntimeError::CapabilityNotSupported(not_supported.clone())
-                                    } else {
-                                        RuntimeError::CapabilityNotAllowed(capability.clone())
-                                    },
-                                ));
+                            if self.runtime.allowed_capabilities.contains(capability) {
+                                continue;
                             }
+                            let err = if let Capability::Other(not_supported) = capability {
+                                if self.runtime.unsupported_capability_policy
+                                    == UnsupportedCapabilityPolicy::Ignore
+                                {
+                                    continue;
+                                }
+                                RuntimeError::CapabilityNotSupported(not_supported.clone())
+                            } else {
+                                RuntimeError::CapabilityNotAllowed(capability.clone())
+                            };
+                            self.finish_loop();
+                            return Some(Err(err));
                         }
                     }
                     Instruction::Error(err) => {
@@ -393,6 +513,11 @@ impl<'x> Context<'x> {
                         )));
                     }
                     Instruction::Invalid(invalid) => {
+                        if self.runtime.unsupported_capability_policy
+                            == UnsupportedCapabilityPolicy::Ignore
+                        {
+                            return Some(Ok(self.skip_instruction(invalid)));
+                        }
                         self.finish_loop();
                         return Some(Err(RuntimeError::InvalidInstruction(invalid.clone())));
                     }
@@ -424,32 +549,20 @@ impl<'x> Context<'x> {
         }
 
         match self.final_event.take() {
-            Some(Event::Keep {
-                mut flags,
-                message_id,
-            }) => {
+            Some(Event::Keep { flags, message_id }) => {
                 let create_event = if self.has_changes {
                     self.build_message_id()
                 } else {
                     None
                 };
 
-                let global_flags = self.get_global_flags();
-                if flags.is_empty() && !global_flags.is_empty() {
-                    flags = global_flags;
-                }
                 if let Some(create_event) = create_event {
-                    self.queued_events = vec![
-                        create_event,
-                        Event::Keep {
-                            flags,
-                            message_id: self.main_message_id,
-                        },
-                    ]
-                    .into_iter();
+                    self.queued_events =
+                        vec![create_event, self.keep_event(flags, self.main_message_id)]
+                            .into_iter();
                     self.queued_events.next().map(Ok)
                 } else {
-                    Some(Ok(Event::Keep { flags, message_id }))
+                    Some(Ok(self.keep_event(flags, message_id)))
                 }
             }
             Some(event) => Some(Ok(event)),
@@ -457,33 +570,76 @@ impl<'x> Context<'x> {
         }
     }
 
+    /// Builds the event for the final keep, applying the global flags and,
+    /// unless the script requested an explicit `keep`, the defaults returned
+    /// by the implicit keep handler.
+    fn keep_event(&mut self, mut flags: Vec<String>, message_id: usize) -> Event {
+        let global_flags = self.get_global_flags();
+        if flags.is_empty() && !global_flags.is_empty() {
+            flags = global_flags;
+        }
+        if !self.explicit_keep {
+            if let Some(handler) = &self.runtime.implicit_keep_handler {
+                let options = (handler.0)();
+                if flags.is_empty() {
+                    flags = options.flags;
+                }
+                if let Some(folder) = options.mailbox {
+                    return Event::FileInto {
+                        folder,
+                        flags,
+                        mailbox_id: None,
+                        special_use: None,
+                        create: false,
+                        message_id,
+                    };
+                }
+            }
+        }
+        Event::Keep { flags, message_id }
+    }
+
+    // Records that the instruction being run had no effect
+    pub(crate) fn skip_action(&mut self, reason: SkipReason) {
+        let line_num = self
+            .script_stack
+            .last()
+            .and_then(|stack| stack.script.line_num(self.pos.wrapping_sub(1)));
+        self.skipped_actions
+            .push(SkippedAction { line_num, reason });
+    }
+
+    fn skip_instruction(&mut self, invalid: &Invalid) -> Event {
+        self.skip_action(SkipReason::UnsupportedCapability {
+            name: invalid.name().to_string(),
+        });
+        self.ignore_input_result = Some(self.test_result);
+        Event::InstructionSkipped {
+            name: invalid.name().to_string(),
+            line_num: invalid.line_num(),
+            line_pos: invalid.line_pos(),
+        }
+    }
+
     pub(crate) fn finish_loop(&mut self) {
         self.script_stack.clear();
+        if self.runtime.keep_on_error && !matches!(self.final_event, Some(Event::Keep { .. })) {
+            self.final_event = Event::Keep {
+                flags: Vec::with_capacity(0),
+                message_id: self.main_message_id,
+            }
+            .into();
+        }
         if let Some(event) = self.final_event.take() {
-            self.queued_events = if let Event::Keep {
-                mut flags,
-                message_id,
-            } = event
-            {
-                let global_flags = self.get_global_flags();
-                if flags.is_empty() && !global_flags.is_empty() {
-                    flags = global_flags;
-                }
-
+            self.queued_events = if let Event::Keep { flags, message_id } = event {
                 if self.has_changes {
                     if let Some(event) = self.build_message_id() {
-                        vec![
-                            event,
-                            Event::Keep {
-                                flags,
-                                message_id: self.main_message_id,
-                            },
-                        ]
+                        vec![event, self.keep_event(flags, self.main_message_id)]
                     } else {
-                        vec![Event::Keep { flags, message_id }]
+                        vec![self.keep_event(flags, message_id)]
                     }
                 } else {
-                    vec![Event::Keep { flags, message_id }]
+                    vec![self.keep_event(flags, message_id)]
                 }
             } else {
                 vec![event]
@@ -492,6 +648,9 @@ impl<'x> Context<'x> {
         }
     }
 
+    /// Sets an envelope value. `From` and `To` addresses may be given with or
+    /// without angle brackets; a null sender (`MAIL FROM:<>`) is stored as an
+    /// empty string, so `envelope :is "from" ""` matches bounces.
     pub fn set_envelope(
         &mut self,
         envelope: impl TryInto<Envelope>,
@@ -509,6 +668,15 @@ impl<'x> Context<'x> {
         }
     }
 
+    /// Returns whether the envelope sender is the null reverse-path, as used
+    /// by bounces and other delivery notifications. Scripts can test this
+    /// with the `vnd.stalwart.is_bounce` environment item.
+    pub fn is_bounce(&self) -> bool {
+        self.envelope
+            .iter()
+            .any(|(e, v)| *e == Envelope::From && v.to_string().is_empty())
+    }
+
     pub fn with_vars_env(mut self, vars_env: AHashMap<Cow<'static, str>, Variable>) -> Self {
         self.vars_env = vars_env;
         self
@@ -626,7 +794,28 @@ impl<'x> Context<'x> {
         self
     }
 
+    pub(crate) fn new_lazy(runtime: &'x Runtime, raw_message: &'x [u8]) -> Self {
+        if let Some(message) = MessageParser::new().parse_headers(raw_message) {
+            let mut ctx = Context::new(runtime, message);
+            ctx.message_body = raw_message.into();
+            ctx
+        } else {
+            Context::new(runtime, empty_message())
+        }
+    }
+
+    pub(crate) fn parse_body(&mut self) {
+        if let Some(message) = self
+            .message_body
+            .take()
+            .and_then(|raw_message| MessageParser::new().parse(raw_message))
+        {
+            self.message = message;
+        }
+    }
+
     pub fn take_message(&mut self) -> Message<'x> {
+        self.parse_body();
         std::mem::take(&mut self.message)
     }
 
@@ -642,14 +831,24 @@ impl<'x> Context<'x> {
         }
     }
 
+    /// Returns the names of the global variables in alphabetical order.
     pub fn global_variable_names(&self) -> impl Iterator<Item = &str> {
-        self.vars_global.keys().map(|k| k.as_ref())
+        let mut names = self
+            .vars_global
+            .keys()
+            .map(|k| k.as_ref())
+            .collect::<Vec<_>>();
+        names.sort_unstable();
+        names.into_iter()
     }
 
     pub fn global_variable(&self, name: &str) -> Option<&Variable> {
         self.vars_global.get(name)
     }
 
+    /// Returns the message being filtered. When the context was created
+    /// with [`Runtime::filter_lazy`], the body parts are only available
+    /// once the script has accessed them.
     pub fn message(&self) -> &Message<'x> {
         &self.message
     }
@@ -657,6 +856,291 @@ impl<'x> Context<'x> {
     pub fn part(&self) -> usize {
         self.part
     }
+
+    pub fn warnings(&self) -> &[RuntimeWarning] {
+        &self.warnings
+    }
+
+    /// Returns the actions that ran without effect and why, in the order they
+    /// ran. Warnings about the same actions are also listed in `warnings`.
+    pub fn skipped_actions(&self) -> &[SkippedAction] {
+        &self.skipped_actions
+    }
+
+    /// Returns the number of instructions executed so far, including jumps.
+    pub fn instructions_used(&self) -> u64 {
+        self.num_instructions as u64
+    }
+
+    /// Returns the number of instructions left before the hard CPU limit
+    /// aborts the script.
+    pub fn instructions_remaining(&self) -> u64 {
+        self.runtime.cpu_limit.saturating_sub(self.num_instructions) as u64
+    }
+
+    /// Returns the value of `vnd.stalwart.score`, which is 0 until a script
+    /// sets it. Integer arithmetic in expressions saturates at the `i64`
+    /// bounds rather than wrapping, and a score set to a value outside that
+    /// range, or to a fraction, is clamped and truncated here.
+    pub fn score(&self) -> i64 {
+        match self
+            .vars_global
+            .get(SCORE_VARIABLE)
+            .map(|v| v.to_number())
+            .unwrap_or(Number::Integer(0))
+        {
+            Number::Integer(n) => n,
+            Number::Float(n) => n as i64,
+        }
+    }
+
+    /// Makes `header` tests visit every value instead of stopping at the
+    /// first match, recording the values that matched so they can be
+    /// inspected with `match_details`. Meant for diagnostics, as it slows
+    /// down header tests.
+    pub fn set_collect_matches(&mut self, collect: bool) {
+        self.collect_matches = collect;
+    }
+
+    pub fn with_collect_matches(mut self, collect: bool) -> Self {
+        self.set_collect_matches(collect);
+        self
+    }
+
+    /// Returns the values matched by each test executed so far, in order of
+    /// execution. Only populated when `set_collect_matches` is enabled.
+    pub fn match_details(&self) -> &[MatchDetail] {
+        &self.match_details
+    }
+
+    /// Summarizes the actions emitted so far into a single outcome, meant to
+    /// be called once `run` has returned `None`.
+    pub fn final_disposition(&self) -> Disposition {
+        if let Some((extended, reason)) = &self.rejection {
+            Disposition::Reject {
+                extended: *extended,
+                reason: reason.clone(),
+                smtp_code: 550,
+                enhanced_code: "5.7.1",
+            }
+        } else if self.deliveries.is_empty() && self.discarded {
+            Disposition::Discard
+        } else {
+            Disposition::Accept {
+                deliveries: self.actions(),
+            }
+        }
+    }
+
+    /// Returns the deliveries emitted so far, with the implicit keep already
+    /// resolved and duplicates collapsed as described in RFC 5228, section
+    /// 2.10.3: keeps, and fileintos into the same mailbox, are merged into
+    /// the first of them and carry the flags of all of them, redirects to the
+    /// same address are listed once. Meant to be called once `run` has
+    /// returned `None`, rejections and discards are reported by
+    /// `final_disposition`.
+    pub fn actions(&self) -> Vec<Delivery> {
+        let mut actions: Vec<Delivery> = Vec::with_capacity(self.deliveries.len());
+
+        for delivery in &self.deliveries {
+            let existing = actions.iter_mut().find(|action| match (action, delivery) {
+                (Delivery::Keep { .. }, Delivery::Keep { .. }) => true,
+                (
+                    Delivery::FileInto {
+                        folder, mailbox_id, ..
+                    },
+                    Delivery::FileInto {
+                        folder: other_folder,
+                        mailbox_id: other_mailbox_id,
+                        ..
+                    },
+                ) => folder == other_folder && mailbox_id == other_mailbox_id,
+                (
+                    Delivery::Redirect { address, .. },
+                    Delivery::Redirect {
+                        address: other_address,
+                        ..
+                    },
+                ) => address.eq_ignore_ascii_case(other_address),
+                _ => false,
+            });
+
+            match (existing, delivery) {
+                (
+                    Some(Delivery::Keep { flags } | Delivery::FileInto { flags, .. }),
+                    Delivery::Keep { flags: new_flags }
+                    | Delivery::FileInto {
+                        flags: new_flags, ..
+                    },
+                ) => {
+                    for flag in new_flags {
+                        if !flags.iter().any(|f| f.eq_ignore_ascii_case(flag)) {
+                            flags.push(flag.clone());
+                        }
+                    }
+                }
+                (Some(_), _) => (),
+                (None, _) => actions.push(delivery.clone()),
+            }
+        }
+
+        actions
+    }
+
+    /// Captures the execution state so it can be rolled back later with
+    /// `restore`, for example to undo an `include` of an untrusted script
+    /// that failed. The snapshot covers local, global and match variables,
+    /// flags, envelope overrides, message edits, the program counter, the
+    /// include stack and any pending events. It does not cover the runtime,
+    /// nor effects the host has already applied in response to events, such
+    /// as messages sent or mailboxes created.
+    ///
+    /// Local and global variables are shared copy-on-write with the
+    /// snapshot, so they are only copied once either side modifies them.
+    pub fn snapshot(&self) -> Snapshot<'x> {
+        Snapshot {
+            context: Box::new(self.clone()),
+        }
+    }
+
+    /// Rolls the execution state back to a previous snapshot, the runtime
+    /// currently in use is kept.
+    pub fn restore(&mut self, snapshot: Snapshot<'x>) {
+        let mut context = *snapshot.context;
+        std::mem::swap(&mut context.runtime, &mut self.runtime);
+        *self = context;
+    }
+
+    /// Evaluates a single test, such as `header :contains "subject" "sale"`,
+    /// against the message without wrapping it in a script. The test runs on
+    /// a copy of this context, which is left untouched. Tests from
+    /// extensions only compile when `compiler` has `no_capability_check`
+    /// enabled, and evaluate to `false` if their capability is not allowed
+    /// by the runtime. Tests that need an answer from the host, such as
+    /// `mailboxexists` or `duplicate`, are evaluated as if it had answered
+    /// `false`.
+    pub fn eval_test(&self, compiler: &Compiler, test: &[u8]) -> Result<bool, EvalError> {
+        let sieve = compiler.compile_test(test)?;
+        if sieve
+            .capabilities
+            .iter()
+            .any(|capability| !self.runtime.allowed_capabilities.contains(capability))
+        {
+            return Ok(false);
+        }
+
+        let mut ctx = self.clone();
+        let mut input = Input::script("", sieve);
+        while let Some(event) = ctx.run_(input) {
+            input = match event? {
+                Event::Function { .. } => Input::FncResult(Variable::default()),
+                _ => Input::False,
+            };
+        }
+        Ok(ctx.test_result)
+    }
+
+    /// Runs `script` once for each recipient with the envelope `to` address
+    /// replaced by the recipient. The message is parsed only once and
+    /// settings such as the envelope sender or environment variables are
+    /// shared, while variables, flags and actions are reset before each
+    /// recipient's run, so this is meant to be called on a context that has
+    /// not run yet. The message is only copied again when a run modified it,
+    /// for example with `editheader`. When this returns, the context holds
+    /// the state left by the last recipient's run.
+    ///
+    /// This lives on `Context` rather than `Sieve` because every run needs
+    /// the runtime and the host's answers to its events: these are passed to
+    /// `on_event` along with the recipient they belong to, and the returned
+    /// input is used to resume that recipient's run. Returns the final
+    /// disposition of each recipient, or the error that stopped its script.
+    pub fn run_for_recipients<T: Into<String>>(
+        &mut self,
+        name: impl Into<Script>,
+        script: impl Into<Arc<Sieve>>,
+        recipients: impl IntoIterator<Item = T>,
+        mut on_event: impl FnMut(&str, Event) -> Input,
+    ) -> Vec<(String, Result<Disposition, RuntimeError>)> {
+        let name = name.into();
+        let script = script.into();
+        let message = (self.message.clone(), self.message_body);
+        let vars_global = self.vars_global.clone();
+        let mut is_first = true;
+
+        recipients
+            .into_iter()
+            .map(|recipient| {
+                let recipient = recipient.into();
+                if !std::mem::take(&mut is_first) {
+                    // Restore the message if it was edited or its body parsed
+                    if self.has_changes
+                        || self.has_message_changed()
+                        || (self.message_body.is_none() && message.1.is_some())
+                    {
+                        self.message = message.0.clone();
+                        self.message_body = message.1;
+                    }
+                    self.reset_run(vars_global.clone());
+                }
+                self.envelope
+                    .retain(|(envelope, _)| *envelope != Envelope::To);
+                self.set_envelope(Envelope::To, recipient.clone());
+
+                let mut input = Input::script(name.clone(), script.clone());
+                let result = loop {
+                    match self.run(input) {
+                        Some(Ok(event)) => input = on_event(&recipient, event),
+                        Some(Err(err)) => break Err(err),
+                        None => break Ok(self.final_disposition()),
+                    }
+                };
+                (recipient, result)
+            })
+            .collect()
+    }
+
+    // Clears the state of a previous run, keeping the message, the envelope
+    // and the settings provided by the host.
+    fn reset_run(&mut self, vars_global: Arc<AHashMap<Cow<'static, str>, Variable>>) {
+        self.part = 0;
+        self.part_iter = Vec::new().into_iter();
+        self.part_iter_stack.clear();
+        self.pos = usize::MAX;
+        self.test_result = false;
+        self.script_stack.clear();
+        self.duplicate_ids.clear();
+        self.duplicate_pending = None;
+        self.delivered.clear();
+        self.deliveries.clear();
+        self.rejection = None;
+        self.discarded = false;
+        self.vars_global = vars_global;
+        self.vars_local = Arc::new(Vec::with_capacity(0));
+        self.vars_match.clear();
+        self.expr_stack.clear();
+        self.expr_pos = 0;
+        self.final_event = Event::Keep {
+            flags: Vec::with_capacity(0),
+            message_id: 0,
+        }
+        .into();
+        self.queued_events = vec![].into_iter();
+        self.has_changes = false;
+        self.num_redirects = 0;
+        self.num_instructions = 0;
+        self.cpu_warned = false;
+        self.ignore_input_result = None;
+        self.explicit_keep = false;
+        self.match_details.clear();
+        self.time_used = Duration::ZERO;
+        self.run_started = None;
+        self.num_out_messages = 0;
+        self.num_events = 0;
+        self.warnings.clear();
+        self.skipped_actions.clear();
+        self.last_message_id = 0;
+        self.main_message_id = 0;
+    }
 }
 
 #[cfg(test)]
@@ -665,6 +1149,7 @@ impl<'x> Context<'x> {
         Context {
             runtime: runtime.clone(),
             message,
+            message_body: None,
             part: 0,
             part_iter: Vec::new().into_iter(),
             part_iter_stack: Vec::new(),
@@ -672,9 +1157,15 @@ impl<'x> Context<'x> {
             test_result: false,
             script_cache: AHashMap::new(),
             script_stack: Vec::with_capacity(0),
-            vars_global: AHashMap::new(),
+            duplicate_ids: AHashMap::new(),
+            duplicate_pending: None,
+            delivered: AHashSet::new(),
+            deliveries: Vec::new(),
+            rejection: None,
+            discarded: false,
+            vars_global: Arc::new(AHashMap::new()),
             vars_env: AHashMap::new(),
-            vars_local: Vec::with_capacity(0),
+            vars_local: Arc::new(Vec::with_capacity(0)),
             vars_match: Vec::with_capacity(0),
             expr_stack: Vec::with_capacity(16),
             expr_pos: 0,
@@ -690,13 +1181,20 @@ impl<'x> Context<'x> {
             has_changes: false,
             user_address: "".into(),
             user_full_name: "".into(),
-            current_time: SystemTime::now()
-                .duration_since(SystemTime::UNIX_EPOCH)
-                .map(|d| d.as_secs())
-                .unwrap_or(0) as i64,
+            current_time: runtime.now(),
             num_redirects: 0,
             num_instructions: 0,
+            cpu_warned: false,
+            ignore_input_result: None,
+            explicit_keep: false,
+            collect_matches: false,
+            match_details: Vec::new(),
+            time_used: Duration::ZERO,
+            run_started: None,
             num_out_messages: 0,
+            num_events: 0,
+            warnings: Vec::new(),
+            skipped_actions: Vec::new(),
             last_message_id: 0,
             main_message_id: 0,
             virus_status: VirusStatus::Unknown,
@@ -34,17 +34,35 @@ use crate::{
         ContentTypePart, HeaderPart, HeaderVariable, MessagePart, ReceivedHostname, ReceivedPart,
         Value, VariableType,
     },
-    Context,
+    Context, SCORE_VARIABLE,
 };
 
-use super::Variable;
+use super::{tests::test_address::SubaddressDelimiter, Variable};
 
 impl<'x> Context<'x> {
     pub(crate) fn variable<'y: 'x>(&'y self, var: &VariableType) -> Option<Variable> {
         match var {
             VariableType::Local(var_num) => self.vars_local.get(*var_num).cloned(),
             VariableType::Match(var_num) => self.vars_match.get(*var_num).cloned(),
-            VariableType::Global(var_name) => self.vars_global.get(var_name.as_str()).cloned(),
+            VariableType::MatchGroups(num_groups) => {
+                let groups = self.vars_match.get(1..=*num_groups).unwrap_or_default();
+                Some(Variable::Array(
+                    if groups.iter().any(|group| !group.is_empty()) {
+                        groups.to_vec()
+                    } else {
+                        vec![]
+                    }
+                    .into(),
+                ))
+            }
+            VariableType::Global(var_name) => self
+                .vars_global
+                .get(var_name.as_str())
+                .cloned()
+                .or_else(|| (var_name == SCORE_VARIABLE).then_some(Variable::Integer(0))),
+            VariableType::Environment(var_name) if var_name == "vnd.stalwart.is_bounce" => {
+                Some(Variable::Integer(self.is_bounce() as i64))
+            }
             VariableType::Environment(var_name) => self
                 .vars_env
                 .get(var_name.as_str())
@@ -88,7 +106,7 @@ impl<'x> Context<'x> {
                         Variable::from(text.as_ref()).into()
                     }
                     PartType::Binary(bin) | PartType::InlineBinary(bin) => {
-                        Variable::from(String::from_utf8_lossy(bin.as_ref())).into()
+                        Variable::from(bin.to_vec()).into()
                     }
                     _ => None,
                 },
@@ -97,7 +115,21 @@ impl<'x> Context<'x> {
                     self.message
                         .raw_message()
                         .get(part.raw_body_offset()..part.raw_end_offset())
-                        .map(|v| Variable::from(String::from_utf8_lossy(v)))
+                        .map(|v| Variable::from(v.to_vec()))
+                }
+                MessagePart::Headers => {
+                    let part = self.message.parts.get(self.part)?;
+                    let headers = self
+                        .message
+                        .raw_message()
+                        .get(part.raw_header_offset()..part.raw_body_offset())?;
+                    // Exclude the empty line separating the headers from the body
+                    let headers = headers
+                        .strip_suffix(b"\r\n")
+                        .or_else(|| headers.strip_suffix(b"\n"))
+                        .filter(|headers| headers.ends_with(b"\n"))
+                        .unwrap_or(headers);
+                    Variable::from(headers.to_vec()).into()
                 }
             },
         }
@@ -116,7 +148,11 @@ impl<'x> Context<'x> {
                         }
                         Value::Variable(var) => {
                             if let Some(value) = self.variable(var) {
-                                data.push_str(&value.to_string());
+                                push_truncated(
+                                    &mut data,
+                                    &value.to_string(),
+                                    self.runtime.max_variable_size,
+                                );
                             }
                         }
                         Value::List(_) => {
@@ -140,14 +176,17 @@ impl<'x> Context<'x> {
         let part = self.message.part(self.part)?;
         let raw = self.message.raw_message();
         if !header.name.is_empty() {
-            let mut headers = part
-                .headers
-                .iter()
-                .filter(|h| header.name.contains(&h.name));
+            let script = &self.script_stack.last()?.script;
+            let mut headers = part.headers.iter().filter(|h| {
+                header
+                    .name
+                    .iter()
+                    .any(|id| script.header_name(*id).is_some_and(|name| name == &h.name))
+            });
             match header.index_hdr.cmp(&0) {
                 Ordering::Greater => {
                     if let Some(h) = headers.nth((header.index_hdr - 1) as usize) {
-                        header.eval_part(h, raw, &mut result);
+                        header.eval_part(h, raw, &self.runtime.subaddress_delimiter, &mut result);
                     }
                 }
                 Ordering::Less => {
@@ -155,12 +194,12 @@ impl<'x> Context<'x> {
                         .rev()
                         .nth((header.index_hdr.unsigned_abs() - 1) as usize)
                     {
-                        header.eval_part(h, raw, &mut result);
+                        header.eval_part(h, raw, &self.runtime.subaddress_delimiter, &mut result);
                     }
                 }
                 Ordering::Equal => {
                     for h in headers {
-                        header.eval_part(h, raw, &mut result);
+                        header.eval_part(h, raw, &self.runtime.subaddress_delimiter, &mut result);
                     }
                 }
             }
@@ -186,7 +225,7 @@ impl<'x> Context<'x> {
                         }
                     }
                     _ => {
-                        header.eval_part(h, raw, &mut result);
+                        header.eval_part(h, raw, &self.runtime.subaddress_delimiter, &mut result);
                     }
                 }
             }
@@ -213,8 +252,28 @@ impl<'x> Context<'x> {
     }
 }
 
+// Interpolated values, such as ${header.subject}, are capped to the
+// maximum variable size.
+fn push_truncated(data: &mut String, value: &str, max_len: usize) {
+    if value.len() <= max_len {
+        data.push_str(value);
+    } else {
+        let mut end = max_len;
+        while !value.is_char_boundary(end) {
+            end -= 1;
+        }
+        data.push_str(&value[..end]);
+    }
+}
+
 impl HeaderVariable {
-    fn eval_part<'x>(&self, header: &'x Header<'x>, raw: &'x [u8], result: &mut Vec<Variable>) {
+    fn eval_part<'x>(
+        &self,
+        header: &'x Header<'x>,
+        raw: &'x [u8],
+        delimiter: &SubaddressDelimiter,
+        result: &mut Vec<Variable>,
+    ) {
         let var = match &self.part {
             HeaderPart::Text => match &header.value {
                 HeaderValue::Text(v) if self.include_single_part() => {
@@ -273,17 +332,17 @@ impl HeaderVariable {
                     match self.index_part.cmp(&0) {
                         Ordering::Greater => list
                             .nth((self.index_part - 1) as usize)
-                            .and_then(|a| part.eval_strict(a))
+                            .and_then(|a| part.eval_strict(a, delimiter))
                             .map(Variable::from),
                         Ordering::Less => list
                             .rev()
                             .nth((self.index_part.unsigned_abs() - 1) as usize)
-                            .and_then(|a| part.eval_strict(a))
+                            .and_then(|a| part.eval_strict(a, delimiter))
                             .map(Variable::from),
                         Ordering::Equal => {
                             for item in list {
                                 result.push(
-                                    part.eval_strict(item)
+                                    part.eval_strict(item, delimiter)
                                         .map(Variable::from)
                                         .unwrap_or_default(),
                                 );
@@ -304,17 +363,17 @@ impl HeaderVariable {
                         match self.index_part.cmp(&0) {
                             Ordering::Greater => list
                                 .nth((self.index_part - 1) as usize)
-                                .and_then(|a| part.eval_strict(a))
+                                .and_then(|a| part.eval_strict(a, delimiter))
                                 .map(|s| Variable::String(s.to_string().into())),
                             Ordering::Less => list
                                 .rev()
                                 .nth((self.index_part.unsigned_abs() - 1) as usize)
-                                .and_then(|a| part.eval_strict(a))
+                                .and_then(|a| part.eval_strict(a, delimiter))
                                 .map(|s| Variable::String(s.to_string().into())),
                             Ordering::Equal => {
                                 for item in list {
                                     result.push(
-                                        part.eval_strict(item)
+                                        part.eval_strict(item, delimiter)
                                             .map(|s| Variable::String(s.to_string().into()))
                                             .unwrap_or_default(),
                                     );
@@ -478,7 +537,7 @@ impl IntoString for Vec<u8> {
     }
 }
 
-fn sanitize_raw_header(bytes: &[u8]) -> String {
+fn sanitize_raw_header(bytes: &[u8]) -> Vec<u8> {
     let mut result = Vec::with_capacity(bytes.len());
     let mut last_is_space = false;
 
@@ -494,5 +553,5 @@ fn sanitize_raw_header(bytes: &[u8]) -> String {
         }
     }
 
-    result.into_string()
+    result
 }
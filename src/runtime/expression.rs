@@ -91,7 +91,7 @@ impl<'x> Context<'x> {
                     }
                 }
                 Expression::JmpIf { val, pos } => {
-                    if self.expr_stack.last().map_or(false, |v| v.to_bool()) == *val {
+                    if self.expr_stack.last().is_some_and(|v| v.to_bool()) == *val {
                         self.expr_pos += *pos as usize;
                         for _ in 0..*pos {
                             exprs.next();
@@ -138,6 +138,20 @@ impl Variable {
                 .chain(b.iter().cloned())
                 .collect::<Vec<_>>()
                 .into(),
+            (Variable::Bytes(a), b) => {
+                if !a.is_empty() {
+                    Variable::Bytes([a.as_slice(), b.to_bytes().as_ref()].concat().into())
+                } else {
+                    b
+                }
+            }
+            (a, Variable::Bytes(b)) => {
+                if !b.is_empty() {
+                    Variable::Bytes([a.to_bytes().as_ref(), b.as_slice()].concat().into())
+                } else {
+                    a
+                }
+            }
             (Variable::String(a), b) => {
                 if !a.is_empty() {
                     Variable::String(format!("{}{}", a, b).into())
@@ -262,6 +276,9 @@ impl Variable {
             Variable::Integer(n) => Variable::Integer(*n),
             Variable::Float(n) => Variable::Float(*n),
             Variable::Array(l) => Variable::Integer(l.is_empty() as i64),
+            Variable::Bytes(b) if !b.is_empty() => {
+                Variable::from(String::from_utf8_lossy(b)).parse_number()
+            }
             _ => Variable::Integer(0),
         }
     }
@@ -271,6 +288,7 @@ impl Variable {
             Variable::Float(f) => *f != 0.0,
             Variable::Integer(n) => *n != 0,
             Variable::String(s) => !s.is_empty(),
+            Variable::Bytes(b) => !b.is_empty(),
             Variable::Array(a) => !a.is_empty(),
         }
     }
@@ -285,8 +303,16 @@ impl PartialEq for Variable {
                 *a as f64 == *b
             }
             (Self::String(a), Self::String(b)) => a == b,
-            (Self::String(_), Self::Integer(_) | Self::Float(_)) => &self.parse_number() == other,
-            (Self::Integer(_) | Self::Float(_), Self::String(_)) => self == &other.parse_number(),
+            (Self::Bytes(a), Self::Bytes(b)) => a == b,
+            (Self::String(a), Self::Bytes(b)) | (Self::Bytes(b), Self::String(a)) => {
+                a.as_bytes() == b.as_slice()
+            }
+            (Self::String(_) | Self::Bytes(_), Self::Integer(_) | Self::Float(_)) => {
+                &self.parse_number() == other
+            }
+            (Self::Integer(_) | Self::Float(_), Self::String(_) | Self::Bytes(_)) => {
+                self == &other.parse_number()
+            }
             (Self::Array(a), Self::Array(b)) => a == b,
             _ => false,
         }
@@ -304,14 +330,17 @@ impl PartialOrd for Variable {
             (Self::Integer(a), Self::Float(b)) => (*a as f64).partial_cmp(b),
             (Self::Float(a), Self::Integer(b)) => a.partial_cmp(&(*b as f64)),
             (Self::String(a), Self::String(b)) => a.partial_cmp(b),
-            (Self::String(_), Self::Integer(_) | Self::Float(_)) => {
+            (Self::String(_) | Self::Bytes(_), Self::String(_) | Self::Bytes(_)) => {
+                self.to_bytes().partial_cmp(&other.to_bytes())
+            }
+            (Self::String(_) | Self::Bytes(_), Self::Integer(_) | Self::Float(_)) => {
                 self.parse_number().partial_cmp(other)
             }
-            (Self::Integer(_) | Self::Float(_), Self::String(_)) => {
+            (Self::Integer(_) | Self::Float(_), Self::String(_) | Self::Bytes(_)) => {
                 self.partial_cmp(&other.parse_number())
             }
             (Self::Array(a), Self::Array(b)) => a.partial_cmp(b),
-            (Self::Array(_) | Self::String(_), _) => Ordering::Greater.into(),
+            (Self::Array(_) | Self::String(_) | Self::Bytes(_), _) => Ordering::Greater.into(),
             (_, Self::Array(_)) => Ordering::Less.into(),
         }
     }
@@ -327,8 +356,9 @@ impl Display for Variable {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
             Variable::String(v) => v.fmt(f),
+            Variable::Bytes(v) => String::from_utf8_lossy(v).fmt(f),
             Variable::Integer(v) => v.fmt(f),
-            Variable::Float(v) => v.fmt(f),
+            Variable::Float(v) => Number::Float(*v).fmt(f),
             Variable::Array(v) => {
                 for (i, v) in v.iter().enumerate() {
                     if i > 0 {
@@ -357,24 +387,6 @@ impl Default for Number {
     }
 }
 
-trait IntoBool {
-    fn into_bool(self) -> bool;
-}
-
-impl IntoBool for f64 {
-    #[inline(always)]
-    fn into_bool(self) -> bool {
-        self != 0.0
-    }
-}
-
-impl IntoBool for i64 {
-    #[inline(always)]
-    fn into_bool(self) -> bool {
-        self != 0
-    }
-}
-
 impl From<bool> for Number {
     #[inline(always)]
     fn from(b: bool) -> Self {
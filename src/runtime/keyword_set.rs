@@ -0,0 +1,72 @@
+/*
+ * Copyright (c) 2020-2023, Stalwart Labs Ltd.
+ *
+ * This file is part of the Stalwart Sieve Interpreter.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ * in the LICENSE file at the top-level directory of this distribution.
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ * You can be released from the requirements of the AGPLv3 license by
+ * purchasing a commercial license. Please contact licensing@stalw.art
+ * for more details.
+*/
+
+// Host-provided keyword lists matched with `:contains :set`, each one is
+// compiled once into an Aho-Corasick automaton shared by all scripts.
+
+use aho_corasick::AhoCorasick;
+
+use crate::{compiler::grammar::Comparator, runtime::Variable, Context};
+
+#[derive(Debug, Clone)]
+pub(crate) struct KeywordSet {
+    automaton: AhoCorasick,
+    keywords: Vec<String>,
+}
+
+impl KeywordSet {
+    pub(crate) fn new(keywords: Vec<String>) -> Option<Self> {
+        AhoCorasick::builder()
+            .ascii_case_insensitive(true)
+            .build(&keywords)
+            .ok()
+            .map(|automaton| KeywordSet {
+                automaton,
+                keywords,
+            })
+    }
+
+    pub(crate) fn contains(&self, haystack: &str, comparator: &Comparator) -> bool {
+        match comparator {
+            Comparator::Octet => self
+                .automaton
+                .find_overlapping_iter(haystack)
+                .any(|m| haystack[m.range()] == self.keywords[m.pattern().as_usize()]),
+            _ => self.automaton.is_match(haystack),
+        }
+    }
+}
+
+impl Context<'_> {
+    pub(crate) fn keyword_set_contains(
+        &self,
+        name: &Variable,
+        haystack: &str,
+        comparator: &Comparator,
+    ) -> bool {
+        self.runtime
+            .keyword_sets
+            .get(name.to_string().as_ref())
+            .is_some_and(|set| set.contains(haystack, comparator))
+    }
+}
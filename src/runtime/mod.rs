@@ -25,31 +25,40 @@ pub mod actions;
 pub mod context;
 pub mod eval;
 pub mod expression;
+pub(crate) mod hash;
+pub(crate) mod keyword_set;
+pub(crate) mod quoted_printable;
 pub mod serialize;
 pub mod tests;
 pub mod variables;
 
-use std::{borrow::Cow, fmt::Display, hash::Hash, ops::Deref, sync::Arc};
+use std::{
+    borrow::Cow,
+    fmt::Display,
+    hash::Hash,
+    ops::Deref,
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
 
 use ahash::{AHashMap, AHashSet};
 #[cfg(not(test))]
-use mail_parser::{Encoding, Message, MessageParser, MessagePart, PartType};
+use mail_parser::MessageParser;
 
-use mail_parser::HeaderName;
+use mail_parser::{Encoding, HeaderName, Message, MessagePart, PartType};
 use serde::{Deserialize, Serialize};
 
-#[cfg(not(test))]
-use crate::Context;
-
 use crate::{
     compiler::{
-        grammar::{expr::parser::ID_EXTERNAL, Capability, Invalid},
-        Number,
+        grammar::{expr::parser::ID_EXTERNAL, Capability, Comparator, Invalid},
+        CompileError, Number,
     },
-    ExternalId, Function, FunctionMap, Input, Metadata, Runtime, Script, Sieve,
+    Clock, Compiler, Context, ExternalComparator, ExternalId, Function, FunctionMap,
+    FunctionSignature, ImplicitKeepHandler, Input, Metadata, Runtime, Script, Sieve,
+    SubaddressResolver,
 };
 
-use self::eval::ToString;
+use self::{eval::ToString, keyword_set::KeywordSet, tests::test_address::SubaddressDelimiter};
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum Variable {
@@ -57,6 +66,98 @@ pub enum Variable {
     Integer(i64),
     Float(f64),
     Array(Arc<Vec<Variable>>),
+    /// Raw octets, such as unparsed headers and message parts, which are
+    /// only decoded as UTF-8 when a string operation needs them.
+    Bytes(Arc<Vec<u8>>),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RuntimeWarning {
+    /// An address header had more addresses than the configured maximum,
+    /// only the first `max` were evaluated.
+    AddressesTruncated { max: usize },
+    /// The address of a `redirect` expanded to an invalid value, the
+    /// action was skipped.
+    InvalidAddress { address: String },
+    /// The mailbox of a `fileinto` expanded to an invalid value, the action
+    /// was skipped.
+    InvalidMailbox { mailbox: String },
+    /// The method of a `notify` expanded to an invalid URI, the action was
+    /// skipped.
+    InvalidNotifyMethod { method: String },
+}
+
+/// An action the script ran that had no effect, see
+/// `Context::skipped_actions`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SkippedAction {
+    /// Line of the command in the script, `None` for tests evaluated on
+    /// their own with `eval_test`.
+    pub line_num: Option<usize>,
+    pub reason: SkipReason,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SkipReason {
+    /// `addheader` or `deleteheader` named a header protected with
+    /// `Runtime::set_protected_header`.
+    ProtectedHeader { name: String },
+    /// The address, mailbox or notification method expanded to an invalid
+    /// value.
+    InvalidTarget { target: String },
+    /// The command needs a capability this interpreter does not support,
+    /// it was skipped under `UnsupportedCapabilityPolicy::Ignore`.
+    UnsupportedCapability { name: String },
+    /// The message was already filed into the same mailbox with the same
+    /// flags.
+    Duplicate,
+    /// A limit on redirects, outgoing messages or `Received` headers was
+    /// reached.
+    LimitReached,
+    /// A `redirect` back to the user or to the envelope sender.
+    ForwardingLoop,
+}
+
+/// What to do when a script requires a capability this interpreter does not
+/// know about, see `Runtime::set_unsupported_capability_policy`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum UnsupportedCapabilityPolicy {
+    /// Stop with `RuntimeError::CapabilityNotSupported`.
+    #[default]
+    Error,
+    /// Run the script anyway, skipping the commands that could not be
+    /// compiled and evaluating unknown tests as false.
+    Ignore,
+}
+
+/// How a `notify :message` longer than `Runtime::set_max_notify_message_len`
+/// is shortened.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum NotifyTruncation {
+    /// Cut the message at the limit.
+    #[default]
+    Cut,
+    /// Cut the message at the last word boundary before the limit and end it
+    /// with an ellipsis, which counts towards the limit.
+    WordBoundary,
+}
+
+#[derive(Clone)]
+pub(crate) struct ImplicitKeep(pub(crate) ImplicitKeepHandler);
+
+impl std::fmt::Debug for ImplicitKeep {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("ImplicitKeep")
+    }
+}
+
+#[derive(Clone)]
+pub(crate) struct RuntimeClock(pub(crate) Clock);
+
+impl std::fmt::Debug for RuntimeClock {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("RuntimeClock")
+    }
 }
 
 #[derive(Debug)]
@@ -67,6 +168,15 @@ pub enum RuntimeError {
     CapabilityNotAllowed(Capability),
     CapabilityNotSupported(String),
     CPULimitReached,
+    TimeLimitReached,
+    EventLimitReached,
+}
+
+/// Error returned by `Runtime::eval_test` and `Context::eval_test`.
+#[derive(Debug)]
+pub enum EvalError {
+    Compile(CompileError),
+    Runtime(RuntimeError),
 }
 
 impl Default for Variable {
@@ -80,8 +190,22 @@ impl Variable {
         match self {
             Variable::String(s) => Cow::Borrowed(s.as_str()),
             Variable::Integer(n) => Cow::Owned(n.to_string()),
-            Variable::Float(n) => Cow::Owned(n.to_string()),
+            Variable::Float(n) => Cow::Owned(Number::Float(*n).to_string()),
             Variable::Array(l) => Cow::Owned(l.to_string()),
+            Variable::Bytes(b) => String::from_utf8_lossy(b),
+        }
+    }
+
+    /// Returns the raw octets of the variable, which for strings are those
+    /// of their UTF-8 encoding.
+    pub fn to_bytes(&self) -> Cow<'_, [u8]> {
+        match self {
+            Variable::String(s) => Cow::Borrowed(s.as_bytes()),
+            Variable::Bytes(b) => Cow::Borrowed(b.as_slice()),
+            _ => match self.to_string() {
+                Cow::Borrowed(s) => Cow::Borrowed(s.as_bytes()),
+                Cow::Owned(s) => Cow::Owned(s.into_bytes()),
+            },
         }
     }
 
@@ -95,6 +219,7 @@ impl Variable {
             Variable::Integer(n) => return Number::Integer(*n).into(),
             Variable::Float(n) => return Number::Float(*n).into(),
             Variable::String(s) if !s.is_empty() => s.as_str(),
+            Variable::Bytes(b) if !b.is_empty() => std::str::from_utf8(b).ok()?,
             _ => return None,
         };
 
@@ -105,11 +230,56 @@ impl Variable {
         }
     }
 
+    /// Returns the value of the variable under the `i;ascii-numeric`
+    /// comparator, as used by `:count` and `:value` tests. Integers and
+    /// floats computed by the interpreter are returned as they are, strings
+    /// are taken by their leading digits only, so `"5.5"` is 5. `None` is
+    /// returned for strings without a leading digit, such as `"-3"`, which
+    /// the comparator orders as positive infinity (RFC 4790, section 9.1.1).
+    pub fn as_number(&self) -> Option<Number> {
+        match self {
+            Variable::Integer(n) => Number::Integer(*n).into(),
+            Variable::Float(n) => Number::Float(*n).into(),
+            Variable::String(s) => parse_ascii_numeric(s),
+            Variable::Bytes(b) => parse_ascii_numeric(std::str::from_utf8(b).ok()?),
+            Variable::Array(_) => None,
+        }
+    }
+
+    /// Returns the `i;ascii-numeric` value of the variable as an integer,
+    /// truncating floats. See `Variable::as_number`.
+    pub fn as_integer(&self) -> Option<i64> {
+        match self.as_number()? {
+            Number::Integer(n) => Some(n),
+            Number::Float(n) if n.is_finite() => Some(n as i64),
+            Number::Float(_) => None,
+        }
+    }
+
+    /// Returns the `i;ascii-numeric` value of the variable as a float. See
+    /// `Variable::as_number`.
+    pub fn as_float(&self) -> Option<f64> {
+        match self.as_number()? {
+            Number::Integer(n) => Some(n as f64),
+            Number::Float(n) => Some(n),
+        }
+    }
+
+    /// Returns the `i;ascii-numeric` value of the variable, with values
+    /// that are not numbers mapped to positive infinity.
+    pub fn to_ascii_numeric(&self) -> Number {
+        self.as_number().unwrap_or(Number::Float(f64::INFINITY))
+    }
+
     pub fn to_integer(&self) -> i64 {
         match self {
             Variable::Integer(n) => *n,
             Variable::Float(n) => *n as i64,
             Variable::String(s) if !s.is_empty() => s.parse::<i64>().unwrap_or(0),
+            Variable::Bytes(b) if !b.is_empty() => std::str::from_utf8(b)
+                .ok()
+                .and_then(|s| s.parse::<i64>().ok())
+                .unwrap_or(0),
             _ => 0,
         }
     }
@@ -119,6 +289,10 @@ impl Variable {
             Variable::Integer(n) => *n as usize,
             Variable::Float(n) => *n as usize,
             Variable::String(s) if !s.is_empty() => s.parse::<usize>().unwrap_or(0),
+            Variable::Bytes(b) if !b.is_empty() => std::str::from_utf8(b)
+                .ok()
+                .and_then(|s| s.parse::<usize>().ok())
+                .unwrap_or(0),
             _ => 0,
         }
     }
@@ -126,6 +300,7 @@ impl Variable {
     pub fn len(&self) -> usize {
         match self {
             Variable::String(s) => s.len(),
+            Variable::Bytes(b) => b.len(),
             Variable::Integer(_) | Variable::Float(_) => 2,
             Variable::Array(l) => l.iter().map(|v| v.len() + 2).sum(),
         }
@@ -134,6 +309,7 @@ impl Variable {
     pub fn is_empty(&self) -> bool {
         match self {
             Variable::String(s) => s.is_empty(),
+            Variable::Bytes(b) => b.is_empty(),
             _ => false,
         }
     }
@@ -178,6 +354,19 @@ impl Variable {
     }
 }
 
+pub(crate) fn parse_ascii_numeric(value: &str) -> Option<Number> {
+    let digits = &value[..value.bytes().take_while(u8::is_ascii_digit).count()];
+    if !digits.is_empty() {
+        digits
+            .parse::<i64>()
+            .map(Number::Integer)
+            .unwrap_or_else(|_| Number::Float(digits.parse().unwrap_or(f64::INFINITY)))
+            .into()
+    } else {
+        None
+    }
+}
+
 impl From<String> for Variable {
     fn from(s: String) -> Self {
         Variable::String(s.into())
@@ -205,6 +394,12 @@ impl<'x> From<Cow<'x, str>> for Variable {
     }
 }
 
+impl From<Vec<u8>> for Variable {
+    fn from(b: Vec<u8>) -> Self {
+        Variable::Bytes(b.into())
+    }
+}
+
 impl From<Vec<Variable>> for Variable {
     fn from(l: Vec<Variable>) -> Self {
         Variable::Array(l.into())
@@ -297,7 +492,8 @@ impl self::eval::ToString for Vec<Variable> {
             match item {
                 Variable::String(v) => result.push_str(v),
                 Variable::Integer(v) => result.push_str(&v.to_string()),
-                Variable::Float(v) => result.push_str(&v.to_string()),
+                Variable::Float(v) => result.push_str(&Number::Float(*v).to_string()),
+                Variable::Bytes(v) => result.push_str(&String::from_utf8_lossy(v)),
                 Variable::Array(_) => {}
             }
         }
@@ -312,6 +508,11 @@ impl Hash for Variable {
             Variable::Integer(n) => n.hash(state),
             Variable::Float(n) => n.to_bits().hash(state),
             Variable::Array(l) => l.hash(state),
+            // Hashed as a string when valid UTF-8, as both compare equal
+            Variable::Bytes(b) => match std::str::from_utf8(b) {
+                Ok(s) => s.hash(state),
+                Err(_) => b.hash(state),
+            },
         }
     }
 }
@@ -323,27 +524,44 @@ impl Runtime {
             self,
             MessageParser::new()
                 .parse(raw_message)
-                .unwrap_or_else(|| Message {
-                    parts: vec![MessagePart {
-                        headers: vec![],
-                        is_encoding_problem: false,
-                        body: PartType::Text("".into()),
-                        encoding: Encoding::None,
-                        offset_header: 0,
-                        offset_body: 0,
-                        offset_end: 0,
-                    }],
-                    raw_message: b""[..].into(),
-                    ..Default::default()
-                }),
+                .unwrap_or_else(empty_message),
         )
     }
 
+    /// Same as [`Runtime::filter`] but only the header block is parsed
+    /// upfront. The body parts are parsed from the borrowed message the
+    /// first time the script runs an instruction that needs them, so
+    /// scripts that only look at headers never pay for MIME parsing.
+    pub fn filter_lazy<'z: 'x, 'x>(&'z self, raw_message: &'x [u8]) -> Context<'x> {
+        Context::new_lazy(self, raw_message)
+    }
+
     pub fn filter_parsed<'z: 'x, 'x>(&'z self, message: Message<'x>) -> Context<'x> {
         Context::new(self, message)
     }
 }
 
+pub(crate) fn empty_message<'x>() -> Message<'x> {
+    Message {
+        parts: vec![MessagePart {
+            headers: vec![],
+            is_encoding_problem: false,
+            body: PartType::Text("".into()),
+            encoding: Encoding::None,
+            offset_header: 0,
+            offset_body: 0,
+            offset_end: 0,
+        }],
+        raw_message: b""[..].into(),
+        ..Default::default()
+    }
+}
+
+// Headers that `addheader` and `deleteheader` may not modify unless the
+// host changes them with `Runtime::with_protected_headers`. Also used by
+// `Sieve::dangerous_actions` to flag edits of these headers at compile time.
+pub(crate) static DEFAULT_PROTECTED_HEADERS: &[&str] = &["Original-Subject", "Original-From"];
+
 impl Default for Runtime {
     fn default() -> Self {
         Self::new()
@@ -353,7 +571,12 @@ impl Default for Runtime {
 impl Runtime {
     pub fn new() -> Self {
         #[allow(unused_mut)]
-        let mut allowed_capabilities = AHashSet::from_iter(Capability::all().iter().cloned());
+        let mut allowed_capabilities = AHashSet::from_iter(
+            Capability::all()
+                .iter()
+                .filter(|capability| !capability.is_extension())
+                .cloned(),
+        );
 
         #[cfg(test)]
         allowed_capabilities.insert(Capability::Other("vnd.stalwart.testsuite".to_string()));
@@ -368,24 +591,38 @@ impl Runtime {
             include_scripts: AHashMap::new(),
             max_nested_includes: 3,
             cpu_limit: 5000,
+            cpu_soft_limit: usize::MAX,
+            max_duration: None,
+            max_events: usize::MAX,
             max_variable_size: 4096,
             max_redirects: 1,
             max_received_headers: 10,
-            protected_headers: vec![
-                HeaderName::Other("Original-Subject".into()),
-                HeaderName::Other("Original-From".into()),
-            ],
+            protected_headers: DEFAULT_PROTECTED_HEADERS
+                .iter()
+                .filter_map(|name| HeaderName::parse(*name))
+                .collect(),
             valid_notification_uris: AHashSet::new(),
             valid_ext_lists: AHashSet::new(),
             vacation_use_orig_rcpt: false,
+            keep_on_error: true,
+            unsupported_capability_policy: UnsupportedCapabilityPolicy::Error,
             vacation_default_subject: "Automated reply".into(),
             vacation_subject_prefix: "Auto: ".into(),
             max_header_size: 1024,
+            max_addresses_per_header: 1000,
             max_out_messages: 3,
+            max_notify_message_len: usize::MAX,
+            notify_truncation: NotifyTruncation::Cut,
             default_vacation_expiry: 30 * 86400,
             default_duplicate_expiry: 7 * 86400,
             local_hostname: "localhost".into(),
+            subaddress_delimiter: SubaddressDelimiter::Fixed('+'),
+            mailbox_separator: None,
+            implicit_keep_handler: None,
+            clock: None,
             functions: Vec::new(),
+            comparators: AHashMap::new(),
+            keyword_sets: AHashMap::new(),
         }
     }
 
@@ -398,6 +635,51 @@ impl Runtime {
         self
     }
 
+    /// Sets the maximum number of instructions a script may execute, the
+    /// same limit as `Runtime::set_cpu_limit`.
+    pub fn set_max_instructions(&mut self, size: usize) {
+        self.set_cpu_limit(size);
+    }
+
+    pub fn with_max_instructions(mut self, size: usize) -> Self {
+        self.set_max_instructions(size);
+        self
+    }
+
+    pub fn set_cpu_soft_limit(&mut self, size: usize) {
+        self.cpu_soft_limit = size;
+    }
+
+    pub fn with_cpu_soft_limit(mut self, size: usize) -> Self {
+        self.cpu_soft_limit = size;
+        self
+    }
+
+    /// Sets the maximum wall-clock time a script may spend executing, time
+    /// spent by the host handling events is not counted. The limit is checked
+    /// between instructions, so a single expensive instruction such as a
+    /// `:regex` match with catastrophic backtracking may overshoot it.
+    pub fn set_max_duration(&mut self, duration: Duration) {
+        self.max_duration = Some(duration);
+    }
+
+    pub fn with_max_duration(mut self, duration: Duration) -> Self {
+        self.max_duration = Some(duration);
+        self
+    }
+
+    /// Sets the maximum number of events a script may emit before execution
+    /// is aborted with `RuntimeError::EventLimitReached`. All event types are
+    /// counted, the final keep queued after the error is always delivered.
+    pub fn set_max_events(&mut self, size: usize) {
+        self.max_events = size;
+    }
+
+    pub fn with_max_events(mut self, size: usize) -> Self {
+        self.max_events = size;
+        self
+    }
+
     pub fn set_max_nested_includes(&mut self, size: usize) {
         self.max_nested_includes = size;
     }
@@ -416,6 +698,37 @@ impl Runtime {
         self
     }
 
+    /// When enabled, a runtime error always results in an implicit keep,
+    /// even if the script had cancelled it with `discard` or `fileinto`.
+    /// The keep event is returned by the next call to `run` after the error.
+    pub fn set_keep_on_error(&mut self, value: bool) {
+        self.keep_on_error = value;
+    }
+
+    pub fn with_keep_on_error(mut self, value: bool) -> Self {
+        self.keep_on_error = value;
+        self
+    }
+
+    /// Sets how scripts requiring unknown capabilities are handled. With
+    /// `UnsupportedCapabilityPolicy::Ignore` commands from unknown extensions
+    /// are skipped and their tests evaluate as false, which can change the
+    /// delivery outcome (a skipped `fileinto` leaves the implicit keep in
+    /// place, for example). Each skipped command or test is reported with
+    /// `Event::InstructionSkipped`. Capabilities that are known but not
+    /// allowed by this runtime are still an error.
+    pub fn set_unsupported_capability_policy(&mut self, policy: UnsupportedCapabilityPolicy) {
+        self.unsupported_capability_policy = policy;
+    }
+
+    pub fn with_unsupported_capability_policy(
+        mut self,
+        policy: UnsupportedCapabilityPolicy,
+    ) -> Self {
+        self.set_unsupported_capability_policy(policy);
+        self
+    }
+
     pub fn set_max_out_messages(&mut self, size: usize) {
         self.max_out_messages = size;
     }
@@ -425,6 +738,27 @@ impl Runtime {
         self
     }
 
+    /// Sets the maximum length, in characters, of the expanded message of a
+    /// `notify` action. Longer messages are shortened following the
+    /// `NotifyTruncation` policy. There is no limit by default.
+    pub fn set_max_notify_message_len(&mut self, size: usize) {
+        self.max_notify_message_len = size;
+    }
+
+    pub fn with_max_notify_message_len(mut self, size: usize) -> Self {
+        self.max_notify_message_len = size;
+        self
+    }
+
+    pub fn set_notify_truncation(&mut self, policy: NotifyTruncation) {
+        self.notify_truncation = policy;
+    }
+
+    pub fn with_notify_truncation(mut self, policy: NotifyTruncation) -> Self {
+        self.notify_truncation = policy;
+        self
+    }
+
     pub fn set_max_received_headers(&mut self, size: usize) {
         self.max_received_headers = size;
     }
@@ -452,6 +786,17 @@ impl Runtime {
         self
     }
 
+    /// Limits the number of addresses evaluated per header by `address`
+    /// tests, additional addresses are ignored and a warning is recorded.
+    pub fn set_max_addresses_per_header(&mut self, size: usize) {
+        self.max_addresses_per_header = size;
+    }
+
+    pub fn with_max_addresses_per_header(mut self, size: usize) -> Self {
+        self.max_addresses_per_header = size;
+        self
+    }
+
     pub fn set_default_vacation_expiry(&mut self, expiry: u64) {
         self.default_vacation_expiry = expiry;
     }
@@ -479,6 +824,24 @@ impl Runtime {
         self
     }
 
+    /// Replaces the allowed capabilities with `capabilities`. Scripts
+    /// requiring any capability left out of the list fail with
+    /// `RuntimeError::CapabilityNotAllowed`.
+    pub fn set_capabilities(
+        &mut self,
+        capabilities: impl IntoIterator<Item = impl Into<Capability>>,
+    ) {
+        self.allowed_capabilities = capabilities.into_iter().map(Into::into).collect();
+    }
+
+    pub fn with_capabilities(
+        mut self,
+        capabilities: impl IntoIterator<Item = impl Into<Capability>>,
+    ) -> Self {
+        self.set_capabilities(capabilities);
+        self
+    }
+
     pub fn unset_capability(&mut self, capability: impl Into<Capability>) {
         self.allowed_capabilities.remove(&capability.into());
     }
@@ -520,6 +883,10 @@ impl Runtime {
         self
     }
 
+    /// Sets an item returned by the `environment` test and `${env.*}`
+    /// variables. The RFC 5183 `name` and `version` items default to
+    /// `Stalwart Sieve` and the version of this crate, and may be overridden
+    /// here.
     pub fn set_env_variable(
         &mut self,
         name: impl Into<Cow<'static, str>>,
@@ -624,6 +991,77 @@ impl Runtime {
         self
     }
 
+    /// Sets the separator between the user and detail parts of an address
+    /// used by the `:user` and `:detail` address parts, defaults to `+`.
+    pub fn set_subaddress_delimiter(&mut self, delimiter: char) {
+        self.subaddress_delimiter = SubaddressDelimiter::Fixed(delimiter);
+    }
+
+    pub fn with_subaddress_delimiter(mut self, delimiter: char) -> Self {
+        self.set_subaddress_delimiter(delimiter);
+        self
+    }
+
+    /// Resolves the subaddress delimiter from the domain of each address,
+    /// for hosts serving domains that use different delimiters. Replaces
+    /// the delimiter set with `set_subaddress_delimiter`.
+    pub fn set_subaddress_resolver(&mut self, resolver: SubaddressResolver) {
+        self.subaddress_delimiter = SubaddressDelimiter::Resolver(resolver);
+    }
+
+    pub fn with_subaddress_resolver(mut self, resolver: SubaddressResolver) -> Self {
+        self.set_subaddress_resolver(resolver);
+        self
+    }
+
+    /// Replaces the hierarchy separator used by scripts with the one used by
+    /// the mailbox store in `fileinto` and `:fcc` targets, for example `.` to
+    /// `/` turns `INBOX.Lists.Rust` into `INBOX/Lists/Rust`. By default
+    /// mailbox names are passed on unchanged.
+    pub fn set_mailbox_separator(&mut self, script_separator: char, separator: char) {
+        self.mailbox_separator = Some((script_separator, separator));
+    }
+
+    pub fn with_mailbox_separator(mut self, script_separator: char, separator: char) -> Self {
+        self.set_mailbox_separator(script_separator, separator);
+        self
+    }
+
+    /// Consults `handler` whenever the implicit keep is emitted, letting the
+    /// host supply default flags or a mailbox. It is not called when the
+    /// script requested an explicit `keep` or cancelled the implicit keep.
+    pub fn set_implicit_keep_handler(&mut self, handler: ImplicitKeepHandler) {
+        self.implicit_keep_handler = Some(ImplicitKeep(handler));
+    }
+
+    pub fn with_implicit_keep_handler(mut self, handler: ImplicitKeepHandler) -> Self {
+        self.set_implicit_keep_handler(handler);
+        self
+    }
+
+    /// Replaces the system clock read when a context is created from this
+    /// runtime, which `currentdate` tests compare against. Useful for
+    /// reproducible runs.
+    pub fn set_clock(&mut self, clock: Clock) {
+        self.clock = Some(RuntimeClock(clock));
+    }
+
+    pub fn with_clock(mut self, clock: Clock) -> Self {
+        self.set_clock(clock);
+        self
+    }
+
+    pub(crate) fn now(&self) -> i64 {
+        if let Some(clock) = &self.clock {
+            (clock.0)()
+        } else {
+            SystemTime::now()
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or(0) as i64
+        }
+    }
+
     pub fn with_functions(mut self, fnc_map: &mut FunctionMap) -> Self {
         self.functions = std::mem::take(&mut fnc_map.functions);
         self
@@ -632,6 +1070,61 @@ impl Runtime {
     pub fn set_functions(&mut self, fnc_map: &mut FunctionMap) {
         self.functions = std::mem::take(&mut fnc_map.functions);
     }
+
+    pub fn set_comparator(
+        &mut self,
+        name: impl Into<String>,
+        comparator: Arc<dyn ExternalComparator>,
+    ) {
+        let name = name.into();
+        self.allowed_capabilities
+            .insert(Capability::Comparator(Comparator::Other(name.clone())));
+        self.comparators.insert(name, comparator);
+    }
+
+    pub fn with_comparator(
+        mut self,
+        name: impl Into<String>,
+        comparator: Arc<dyn ExternalComparator>,
+    ) -> Self {
+        self.set_comparator(name, comparator);
+        self
+    }
+
+    /// Registers a keyword set that scripts can match against with
+    /// `:contains :set "name"`. The keywords are compiled once and shared by
+    /// every script run with this runtime.
+    pub fn set_keyword_set(
+        &mut self,
+        name: impl Into<String>,
+        keywords: impl IntoIterator<Item = impl Into<String>>,
+    ) {
+        if let Some(keyword_set) = KeywordSet::new(keywords.into_iter().map(Into::into).collect()) {
+            self.allowed_capabilities.insert(Capability::Keywords);
+            self.keyword_sets.insert(name.into(), Arc::new(keyword_set));
+        }
+    }
+
+    pub fn with_keyword_set(
+        mut self,
+        name: impl Into<String>,
+        keywords: impl IntoIterator<Item = impl Into<String>>,
+    ) -> Self {
+        self.set_keyword_set(name, keywords);
+        self
+    }
+
+    /// Evaluates a single test against `message`, see `Context::eval_test`.
+    /// Use `Context::eval_test` directly to set the envelope or other
+    /// parameters first.
+    pub fn eval_test(
+        &self,
+        compiler: &Compiler,
+        test: &[u8],
+        message: Message<'_>,
+    ) -> Result<bool, EvalError> {
+        Context::new(self, message).eval_test(compiler, test)
+    }
 }
 
 impl FunctionMap {
@@ -650,14 +1143,27 @@ impl FunctionMap {
         self.with_function_args(name, fnc, 0)
     }
 
-    pub fn with_function_args(
+    pub fn with_function_args(self, name: impl Into<String>, fnc: Function, num_args: u32) -> Self {
+        self.with_function_arity(name, fnc, num_args, num_args)
+    }
+
+    /// Registers a function accepting between `min_args` and `max_args`
+    /// arguments. The function receives the arguments actually passed.
+    pub fn with_function_arity(
         mut self,
         name: impl Into<String>,
         fnc: Function,
-        num_args: u32,
+        min_args: u32,
+        max_args: u32,
     ) -> Self {
-        self.map
-            .insert(name.into(), (self.functions.len() as u32, num_args));
+        self.map.insert(
+            name.into(),
+            FunctionSignature {
+                id: self.functions.len() as u32,
+                min_args,
+                max_args,
+            },
+        );
         self.functions.push(fnc);
         self
     }
@@ -678,7 +1184,67 @@ impl FunctionMap {
         id: ExternalId,
         num_args: u32,
     ) {
-        self.map.insert(name.into(), (ID_EXTERNAL - id, num_args));
+        self.set_external_function_arity(name, id, num_args, num_args);
+    }
+
+    pub fn with_external_function_arity(
+        mut self,
+        name: impl Into<String>,
+        id: ExternalId,
+        min_args: u32,
+        max_args: u32,
+    ) -> Self {
+        self.set_external_function_arity(name, id, min_args, max_args);
+        self
+    }
+
+    /// Registers an external function accepting between `min_args` and
+    /// `max_args` arguments. `Event::Function` carries the arguments
+    /// actually passed.
+    pub fn set_external_function_arity(
+        &mut self,
+        name: impl Into<String>,
+        id: ExternalId,
+        min_args: u32,
+        max_args: u32,
+    ) {
+        self.map.insert(
+            name.into(),
+            FunctionSignature {
+                id: ID_EXTERNAL - id,
+                min_args,
+                max_args,
+            },
+        );
+    }
+
+    /// Returns the names of all registered functions, in no particular order.
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.map.keys().map(|name| name.as_str())
+    }
+
+    /// Returns the name and signature of all registered functions, in no
+    /// particular order.
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &FunctionSignature)> {
+        self.map
+            .iter()
+            .map(|(name, signature)| (name.as_str(), signature))
+    }
+
+    /// Returns the name an external function was registered with.
+    pub fn external_name(&self, id: ExternalId) -> Option<&str> {
+        self.map
+            .iter()
+            .find(|(_, signature)| signature.external_id() == Some(id))
+            .map(|(name, _)| name.as_str())
+    }
+}
+
+impl FunctionSignature {
+    /// Returns the id of an external function, or `None` for functions
+    /// implemented in Rust.
+    pub fn external_id(&self) -> Option<ExternalId> {
+        (self.id > u32::MAX / 2).then(|| ID_EXTERNAL - self.id)
     }
 }
 
@@ -820,3 +1386,15 @@ impl From<(&'_ str, &'_ str)> for Metadata<String> {
         }
     }
 }
+
+impl From<CompileError> for EvalError {
+    fn from(err: CompileError) -> Self {
+        EvalError::Compile(err)
+    }
+}
+
+impl From<RuntimeError> for EvalError {
+    fn from(err: RuntimeError) -> Self {
+        EvalError::Runtime(err)
+    }
+}
@@ -0,0 +1,146 @@
+/*
+ * Copyright (c) 2020-2023, Stalwart Labs Ltd.
+ *
+ * This file is part of the Stalwart Sieve Interpreter.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ * in the LICENSE file at the top-level directory of this distribution.
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ * You can be released from the requirements of the AGPLv3 license by
+ * purchasing a commercial license. Please contact licensing@stalw.art
+ * for more details.
+*/
+
+// Quoted-printable (RFC 2045, section 6.7) helpers used by the `:encode` and
+// `:decode` modifiers.
+
+use std::fmt::Write;
+
+const MAX_LINE_LEN: usize = 76;
+
+/// Encodes `input` as quoted-printable text, keeping its line breaks as
+/// hard breaks and stopping before `max_len` bytes would be exceeded.
+pub(crate) fn encode(input: &str, max_len: usize) -> String {
+    let bytes = input.as_bytes();
+    let mut result = String::with_capacity(bytes.len());
+    let mut line_len = 0;
+    let mut pos = 0;
+
+    while let Some(&ch) = bytes.get(pos) {
+        // Hard line break, bare LFs are written as CRLF
+        if ch == b'\n' || (ch == b'\r' && bytes.get(pos + 1) == Some(&b'\n')) {
+            if result.len() + 2 > max_len {
+                break;
+            }
+            result.push_str("\r\n");
+            line_len = 0;
+            pos += if ch == b'\r' { 2 } else { 1 };
+            continue;
+        }
+
+        let at_line_end = matches!(
+            bytes.get(pos + 1..),
+            Some([] | [b'\n', ..] | [b'\r', b'\n', ..])
+        );
+        let is_literal = ((b'!'..=b'~').contains(&ch) && ch != b'=')
+            || (matches!(ch, b' ' | b'\t') && !at_line_end);
+        let token_len = if is_literal { 1 } else { 3 };
+
+        // Soft line break, the trailing '=' counts towards the line length
+        if line_len + token_len > MAX_LINE_LEN - 1 {
+            if result.len() + 3 > max_len {
+                break;
+            }
+            result.push_str("=\r\n");
+            line_len = 0;
+        }
+        if result.len() + token_len > max_len {
+            break;
+        }
+        if is_literal {
+            result.push(ch as char);
+        } else {
+            write!(result, "={ch:02X}").ok();
+        }
+        line_len += token_len;
+        pos += 1;
+    }
+
+    result
+}
+
+/// Decodes quoted-printable text. Soft line breaks and whitespace at the end
+/// of lines are removed, malformed `=` sequences are kept as they are.
+pub(crate) fn decode(input: &str) -> String {
+    let bytes = input.as_bytes();
+    let mut result = Vec::with_capacity(bytes.len());
+    let mut pos = 0;
+
+    while let Some(&ch) = bytes.get(pos) {
+        match ch {
+            b'=' => {
+                // Soft line break, allowing for whitespace added in transport
+                let mut end = pos + 1;
+                while matches!(bytes.get(end), Some(b' ' | b'\t')) {
+                    end += 1;
+                }
+                match bytes.get(end..) {
+                    Some([b'\r', b'\n', ..]) => pos = end + 2,
+                    Some([b'\n', ..]) => pos = end + 1,
+                    Some([]) => pos = end,
+                    _ => {
+                        if let (Some(hi), Some(lo)) = (
+                            bytes.get(pos + 1).and_then(|ch| hex_value(*ch)),
+                            bytes.get(pos + 2).and_then(|ch| hex_value(*ch)),
+                        ) {
+                            result.push((hi << 4) | lo);
+                            pos += 3;
+                        } else {
+                            result.push(b'=');
+                            pos += 1;
+                        }
+                    }
+                }
+            }
+            b' ' | b'\t' => {
+                let mut end = pos + 1;
+                while matches!(bytes.get(end), Some(b' ' | b'\t')) {
+                    end += 1;
+                }
+                if !matches!(
+                    bytes.get(end..),
+                    Some([] | [b'\n', ..] | [b'\r', b'\n', ..])
+                ) {
+                    result.extend_from_slice(&bytes[pos..end]);
+                }
+                pos = end;
+            }
+            _ => {
+                result.push(ch);
+                pos += 1;
+            }
+        }
+    }
+
+    String::from_utf8(result)
+        .unwrap_or_else(|err| String::from_utf8_lossy(err.as_bytes()).into_owned())
+}
+
+fn hex_value(ch: u8) -> Option<u8> {
+    match ch {
+        b'0'..=b'9' => Some(ch - b'0'),
+        b'A'..=b'F' => Some(ch - b'A' + 10),
+        b'a'..=b'f' => Some(ch - b'a' + 10),
+        _ => None,
+    }
+}
@@ -0,0 +1,186 @@
+/*
+ * Copyright (c) 2020-2023, Stalwart Labs Ltd.
+ *
+ * This file is part of the Stalwart Sieve Interpreter.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ * in the LICENSE file at the top-level directory of this distribution.
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ * You can be released from the requirements of the AGPLv3 license by
+ * purchasing a commercial license. Please contact licensing@stalw.art
+ * for more details.
+*/
+
+// RFC 5051 i;unicode-casemap canonicalization: each character is converted
+// to titlecase and then to its compatibility decomposition, so that "ä"
+// becomes "A" followed by a combining diaeresis and sorts next to "A".
+// Decompositions are only included for the Latin blocks up to Latin
+// Extended-B.
+pub(crate) fn unicode_casemap(value: &str) -> String {
+    let mut result = String::with_capacity(value.len());
+    for ch in value.chars() {
+        unicode_casemap_char(ch, |ch| result.push(ch));
+    }
+    result
+}
+
+pub(crate) fn unicode_casemap_char(ch: char, mut cb: impl FnMut(char)) {
+    for ch in ch.to_uppercase() {
+        if let Ok(pos) = DECOMPOSITION.binary_search_by(|(c, _)| c.cmp(&ch)) {
+            DECOMPOSITION[pos].1.chars().for_each(&mut cb);
+        } else {
+            cb(ch);
+        }
+    }
+}
+
+// Every combining mark produced by the decompositions below belongs to the
+// Combining Diacritical Marks block.
+pub(crate) fn is_combining_mark(ch: char) -> bool {
+    ('\u{300}'..='\u{36f}').contains(&ch)
+}
+
+static DECOMPOSITION: &[(char, &str)] = &[
+    ('\u{c0}', "A\u{300}"),
+    ('\u{c1}', "A\u{301}"),
+    ('\u{c2}', "A\u{302}"),
+    ('\u{c3}', "A\u{303}"),
+    ('\u{c4}', "A\u{308}"),
+    ('\u{c5}', "A\u{30a}"),
+    ('\u{c7}', "C\u{327}"),
+    ('\u{c8}', "E\u{300}"),
+    ('\u{c9}', "E\u{301}"),
+    ('\u{ca}', "E\u{302}"),
+    ('\u{cb}', "E\u{308}"),
+    ('\u{cc}', "I\u{300}"),
+    ('\u{cd}', "I\u{301}"),
+    ('\u{ce}', "I\u{302}"),
+    ('\u{cf}', "I\u{308}"),
+    ('\u{d1}', "N\u{303}"),
+    ('\u{d2}', "O\u{300}"),
+    ('\u{d3}', "O\u{301}"),
+    ('\u{d4}', "O\u{302}"),
+    ('\u{d5}', "O\u{303}"),
+    ('\u{d6}', "O\u{308}"),
+    ('\u{d9}', "U\u{300}"),
+    ('\u{da}', "U\u{301}"),
+    ('\u{db}', "U\u{302}"),
+    ('\u{dc}', "U\u{308}"),
+    ('\u{dd}', "Y\u{301}"),
+    ('\u{100}', "A\u{304}"),
+    ('\u{102}', "A\u{306}"),
+    ('\u{104}', "A\u{328}"),
+    ('\u{106}', "C\u{301}"),
+    ('\u{108}', "C\u{302}"),
+    ('\u{10a}', "C\u{307}"),
+    ('\u{10c}', "C\u{30c}"),
+    ('\u{10e}', "D\u{30c}"),
+    ('\u{112}', "E\u{304}"),
+    ('\u{114}', "E\u{306}"),
+    ('\u{116}', "E\u{307}"),
+    ('\u{118}', "E\u{328}"),
+    ('\u{11a}', "E\u{30c}"),
+    ('\u{11c}', "G\u{302}"),
+    ('\u{11e}', "G\u{306}"),
+    ('\u{120}', "G\u{307}"),
+    ('\u{122}', "G\u{327}"),
+    ('\u{124}', "H\u{302}"),
+    ('\u{128}', "I\u{303}"),
+    ('\u{12a}', "I\u{304}"),
+    ('\u{12c}', "I\u{306}"),
+    ('\u{12e}', "I\u{328}"),
+    ('\u{130}', "I\u{307}"),
+    ('\u{132}', "IJ"),
+    ('\u{134}', "J\u{302}"),
+    ('\u{136}', "K\u{327}"),
+    ('\u{139}', "L\u{301}"),
+    ('\u{13b}', "L\u{327}"),
+    ('\u{13d}', "L\u{30c}"),
+    ('\u{13f}', "L\u{b7}"),
+    ('\u{143}', "N\u{301}"),
+    ('\u{145}', "N\u{327}"),
+    ('\u{147}', "N\u{30c}"),
+    ('\u{14c}', "O\u{304}"),
+    ('\u{14e}', "O\u{306}"),
+    ('\u{150}', "O\u{30b}"),
+    ('\u{154}', "R\u{301}"),
+    ('\u{156}', "R\u{327}"),
+    ('\u{158}', "R\u{30c}"),
+    ('\u{15a}', "S\u{301}"),
+    ('\u{15c}', "S\u{302}"),
+    ('\u{15e}', "S\u{327}"),
+    ('\u{160}', "S\u{30c}"),
+    ('\u{162}', "T\u{327}"),
+    ('\u{164}', "T\u{30c}"),
+    ('\u{168}', "U\u{303}"),
+    ('\u{16a}', "U\u{304}"),
+    ('\u{16c}', "U\u{306}"),
+    ('\u{16e}', "U\u{30a}"),
+    ('\u{170}', "U\u{30b}"),
+    ('\u{172}', "U\u{328}"),
+    ('\u{174}', "W\u{302}"),
+    ('\u{176}', "Y\u{302}"),
+    ('\u{178}', "Y\u{308}"),
+    ('\u{179}', "Z\u{301}"),
+    ('\u{17b}', "Z\u{307}"),
+    ('\u{17d}', "Z\u{30c}"),
+    ('\u{1a0}', "O\u{31b}"),
+    ('\u{1af}', "U\u{31b}"),
+    ('\u{1c4}', "DZ\u{30c}"),
+    ('\u{1c7}', "LJ"),
+    ('\u{1ca}', "NJ"),
+    ('\u{1cd}', "A\u{30c}"),
+    ('\u{1cf}', "I\u{30c}"),
+    ('\u{1d1}', "O\u{30c}"),
+    ('\u{1d3}', "U\u{30c}"),
+    ('\u{1d5}', "U\u{308}\u{304}"),
+    ('\u{1d7}', "U\u{308}\u{301}"),
+    ('\u{1d9}', "U\u{308}\u{30c}"),
+    ('\u{1db}', "U\u{308}\u{300}"),
+    ('\u{1de}', "A\u{308}\u{304}"),
+    ('\u{1e0}', "A\u{307}\u{304}"),
+    ('\u{1e2}', "\u{c6}\u{304}"),
+    ('\u{1e6}', "G\u{30c}"),
+    ('\u{1e8}', "K\u{30c}"),
+    ('\u{1ea}', "O\u{328}"),
+    ('\u{1ec}', "O\u{328}\u{304}"),
+    ('\u{1ee}', "\u{1b7}\u{30c}"),
+    ('\u{1f1}', "DZ"),
+    ('\u{1f4}', "G\u{301}"),
+    ('\u{1f8}', "N\u{300}"),
+    ('\u{1fa}', "A\u{30a}\u{301}"),
+    ('\u{1fc}', "\u{c6}\u{301}"),
+    ('\u{1fe}', "\u{d8}\u{301}"),
+    ('\u{200}', "A\u{30f}"),
+    ('\u{202}', "A\u{311}"),
+    ('\u{204}', "E\u{30f}"),
+    ('\u{206}', "E\u{311}"),
+    ('\u{208}', "I\u{30f}"),
+    ('\u{20a}', "I\u{311}"),
+    ('\u{20c}', "O\u{30f}"),
+    ('\u{20e}', "O\u{311}"),
+    ('\u{210}', "R\u{30f}"),
+    ('\u{212}', "R\u{311}"),
+    ('\u{214}', "U\u{30f}"),
+    ('\u{216}', "U\u{311}"),
+    ('\u{218}', "S\u{326}"),
+    ('\u{21a}', "T\u{326}"),
+    ('\u{21e}', "H\u{30c}"),
+    ('\u{226}', "A\u{307}"),
+    ('\u{228}', "E\u{327}"),
+    ('\u{22a}', "O\u{308}\u{304}"),
+    ('\u{22c}', "O\u{303}\u{304}"),
+    ('\u{22e}', "O\u{307}"),
+    ('\u{230}', "O\u{307}\u{304}"),
+    ('\u{232}', "Y\u{304}"),
+];
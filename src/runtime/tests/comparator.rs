@@ -21,29 +21,62 @@
  * for more details.
 */
 
-use std::borrow::Cow;
+use std::{borrow::Cow, sync::Arc};
 
 use crate::{
     compiler::{
         grammar::{Comparator, RelationalMatch},
         Number, Value,
     },
-    runtime::Variable,
-    MatchAs,
+    runtime::{parse_ascii_numeric, Variable},
+    Context, ExternalComparator, MatchAs,
 };
 
-use super::glob::GlobPattern;
+use super::{
+    casemap::unicode_casemap,
+    glob::{CaseFold, GlobPattern},
+};
 
 pub(crate) trait Comparable {
-    fn to_str(&self) -> Cow<str>;
+    fn to_str(&self) -> Cow<'_, str>;
+    fn to_bytes(&self) -> Cow<'_, [u8]>;
     fn to_number(&self) -> Number;
 }
 
+impl Context<'_> {
+    pub(crate) fn external_comparator(
+        &self,
+        comparator: &Comparator,
+    ) -> Option<Arc<dyn ExternalComparator>> {
+        if let Comparator::Other(name) = comparator {
+            self.runtime.comparators.get(name).cloned()
+        } else {
+            None
+        }
+    }
+}
+
+impl RelationalMatch {
+    pub(crate) fn cmp_external(&self, cmp: &dyn ExternalComparator, a: &str, b: &str) -> bool {
+        match self {
+            RelationalMatch::Gt => cmp.less_than(b, a),
+            RelationalMatch::Ge => !cmp.less_than(a, b),
+            RelationalMatch::Lt => cmp.less_than(a, b),
+            RelationalMatch::Le => !cmp.less_than(b, a),
+            RelationalMatch::Eq => cmp.equals(a, b),
+            RelationalMatch::Ne => !cmp.equals(a, b),
+        }
+    }
+}
+
 impl Comparator {
     pub(crate) fn is(&self, a: &impl Comparable, b: &impl Comparable) -> bool {
         match self {
-            Comparator::Octet => a.to_str() == b.to_str(),
-            Comparator::AsciiNumeric => RelationalMatch::Eq.cmp(&a.to_number(), &b.to_number()),
+            Comparator::Octet => a.to_bytes() == b.to_bytes(),
+            Comparator::AsciiNumeric => RelationalMatch::Eq.cmp_num(&a.to_number(), &b.to_number()),
+            Comparator::UnicodeCaseMap => {
+                unicode_casemap(a.to_str().as_ref()) == unicode_casemap(b.to_str().as_ref())
+            }
             _ => a.to_str().to_lowercase() == b.to_str().to_lowercase(),
         }
     }
@@ -52,6 +85,9 @@ impl Comparator {
         needle.is_empty()
             || match self {
                 Comparator::Octet => haystack.contains(needle),
+                Comparator::UnicodeCaseMap => {
+                    unicode_casemap(haystack).contains(&unicode_casemap(needle))
+                }
                 _ => haystack.to_lowercase().contains(&needle.to_lowercase()),
             }
     }
@@ -63,8 +99,12 @@ impl Comparator {
         b: &impl Comparable,
     ) -> bool {
         match self {
-            Comparator::Octet => relation.cmp(a.to_str().as_ref(), b.to_str().as_ref()),
-            Comparator::AsciiNumeric => relation.cmp(&a.to_number(), &b.to_number()),
+            Comparator::Octet => relation.cmp(a.to_bytes().as_ref(), b.to_bytes().as_ref()),
+            Comparator::AsciiNumeric => relation.cmp_num(&a.to_number(), &b.to_number()),
+            Comparator::UnicodeCaseMap => relation.cmp(
+                &unicode_casemap(a.to_str().as_ref()),
+                &unicode_casemap(b.to_str().as_ref()),
+            ),
             _ => relation.cmp(&a.to_str().to_lowercase(), &b.to_str().to_lowercase()),
         }
     }
@@ -76,12 +116,18 @@ impl Comparator {
         capture_positions: u64,
         captured_values: &mut Vec<(usize, String)>,
     ) -> bool {
-        let pattern = GlobPattern::compile(pattern, matches!(self, Comparator::AsciiCaseMap));
-        match self {
-            Comparator::AsciiCaseMap if capture_positions == 0 => pattern.matches(value),
-            Comparator::AsciiCaseMap => pattern.capture(value, capture_positions, captured_values),
-            _ if capture_positions == 0 => pattern.matches(value),
-            _ => pattern.capture(value, capture_positions, captured_values),
+        let pattern = GlobPattern::compile(
+            pattern,
+            match self {
+                Comparator::AsciiCaseMap => CaseFold::Lowercase,
+                Comparator::UnicodeCaseMap => CaseFold::Uppercase,
+                _ => CaseFold::None,
+            },
+        );
+        if capture_positions == 0 {
+            pattern.matches(value)
+        } else {
+            pattern.capture(value, capture_positions, captured_values)
         }
     }
 
@@ -94,7 +140,7 @@ impl Comparator {
         captured_values: &mut Vec<(usize, String)>,
     ) -> bool {
         let regex = if let Value::Regex(regex) = pattern {
-            Cow::Borrowed(&regex.regex)
+            Cow::Borrowed(regex.regex.as_ref())
         } else {
             match fancy_regex::Regex::new(pattern_expr.to_string().as_ref()) {
                 Ok(regex) => Cow::Owned(regex),
@@ -124,7 +170,7 @@ impl Comparator {
 
     pub(crate) fn as_match(&self) -> MatchAs {
         match self {
-            Comparator::AsciiCaseMap => MatchAs::Lowercase,
+            Comparator::AsciiCaseMap | Comparator::UnicodeCaseMap => MatchAs::Lowercase,
             Comparator::AsciiNumeric => MatchAs::Number,
             _ => MatchAs::Octet,
         }
@@ -132,28 +178,53 @@ impl Comparator {
 }
 
 impl Comparable for Variable {
-    fn to_str(&self) -> Cow<str> {
+    fn to_str(&self) -> Cow<'_, str> {
         self.to_string()
     }
 
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Variable::to_bytes(self)
+    }
+
     fn to_number(&self) -> Number {
-        self.to_number()
+        self.to_ascii_numeric()
     }
 }
 
 impl Comparable for &str {
-    fn to_str(&self) -> Cow<str> {
+    fn to_str(&self) -> Cow<'_, str> {
         (*self).into()
     }
 
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        self.as_bytes().into()
+    }
+
+    // RFC 4790 i;ascii-numeric: strings are compared by their leading
+    // digits only, strings without a leading digit (including negative
+    // numbers) are treated as positive infinity.
     fn to_number(&self) -> Number {
-        self.parse::<f64>()
-            .map(Number::Float)
-            .unwrap_or(Number::Float(0.0))
+        parse_ascii_numeric(self).unwrap_or(Number::Float(f64::INFINITY))
     }
 }
 
 impl RelationalMatch {
+    /// Compares two numbers without converting between integer and float
+    /// representations. Comparisons involving NaN fail, including `ne`.
+    pub fn cmp_num(&self, a: &Number, b: &Number) -> bool {
+        match a.compare(b) {
+            Some(ordering) => match self {
+                RelationalMatch::Gt => ordering.is_gt(),
+                RelationalMatch::Ge => ordering.is_ge(),
+                RelationalMatch::Lt => ordering.is_lt(),
+                RelationalMatch::Le => ordering.is_le(),
+                RelationalMatch::Eq => ordering.is_eq(),
+                RelationalMatch::Ne => ordering.is_ne(),
+            },
+            None => false,
+        }
+    }
+
     pub fn cmp<T>(&self, a: &T, b: &T) -> bool
     where
         T: PartialOrd + ?Sized,
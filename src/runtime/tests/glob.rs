@@ -21,25 +21,46 @@
  * for more details.
 */
 
-use std::char::REPLACEMENT_CHARACTER;
+use std::{fmt::Display, ops::Range};
 
 use crate::MAX_MATCH_VARIABLES;
 
+use super::casemap::{is_combining_mark, unicode_casemap_char};
+
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct GlobPattern {
     pattern: Vec<PatternChar>,
-    to_lower: bool,
+    case_fold: CaseFold,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CaseFold {
+    None,
+    // Uppercase characters are mapped to lowercase
+    Lowercase,
+    // Characters are uppercased and decomposed following the
+    // i;unicode-casemap canonicalization. A character and the combining
+    // marks that follow it are matched as a single unit, so that '?' matches
+    // "Ä" whether it is precomposed or written as "A" and a diaeresis.
+    Uppercase,
 }
 
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub enum PatternChar {
     WildcardMany { num: usize, match_pos: usize },
     WildcardSingle { match_pos: usize },
-    Char { char: char, match_pos: usize },
+    Char { char: Unit, match_pos: usize },
+}
+
+// A folded character along with the combining marks attached to it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Unit {
+    char: char,
+    marks: Vec<char>,
 }
 
 impl GlobPattern {
-    pub fn compile(pattern: &str, to_lower: bool) -> Self {
+    pub fn compile(pattern: &str, case_fold: CaseFold) -> Self {
         let mut chars = Vec::new();
         let mut is_escaped = false;
         let mut str = pattern.chars().peekable();
@@ -65,30 +86,31 @@ impl GlobPattern {
                     if is_escaped {
                         is_escaped = false;
                     }
-                    if to_lower && char.is_uppercase() {
-                        for char in char.to_lowercase() {
-                            chars.push(PatternChar::Char { char, match_pos: 0 });
+                    case_fold.fold(char, |char| {
+                        if case_fold.joins_marks() && is_combining_mark(char) {
+                            if let Some(PatternChar::Char { char: unit, .. }) = chars.last_mut() {
+                                unit.marks.push(char);
+                                return;
+                            }
                         }
-                    } else {
-                        chars.push(PatternChar::Char { char, match_pos: 0 });
-                    }
+                        chars.push(PatternChar::Char {
+                            char: Unit::new(char),
+                            match_pos: 0,
+                        });
+                    });
                 }
             }
         }
 
         GlobPattern {
             pattern: chars,
-            to_lower,
+            case_fold,
         }
     }
 
     // Credits: Algorithm ported from https://research.swtch.com/glob
     pub fn matches(&self, value: &str) -> bool {
-        let value = if self.to_lower {
-            value.to_lowercase().chars().collect::<Vec<_>>()
-        } else {
-            value.chars().collect::<Vec<_>>()
-        };
+        let value = self.case_fold.fold_units(value);
 
         let mut px = 0;
         let mut nx = 0;
@@ -98,18 +120,16 @@ impl GlobPattern {
         while px < self.pattern.len() || nx < value.len() {
             match self.pattern.get(px) {
                 Some(PatternChar::Char { char, .. }) => {
-                    if matches!(value.get(nx), Some(nc) if nc == char ) {
+                    if matches!(value.get(nx), Some((nc, _)) if nc == char ) {
                         px += 1;
                         nx += 1;
                         continue;
                     }
                 }
-                Some(PatternChar::WildcardSingle { .. }) => {
-                    if nx < value.len() {
-                        px += 1;
-                        nx += 1;
-                        continue;
-                    }
+                Some(PatternChar::WildcardSingle { .. }) if nx < value.len() => {
+                    px += 1;
+                    nx += 1;
+                    continue;
                 }
                 Some(PatternChar::WildcardMany { .. }) => {
                     next_px = px;
@@ -135,28 +155,7 @@ impl GlobPattern {
         capture_positions: u64,
         captured_values: &mut Vec<(usize, String)>,
     ) -> bool {
-        let value = if self.to_lower {
-            let mut value = Vec::with_capacity(value_.len());
-            for char in value_.chars() {
-                if char.is_uppercase() {
-                    for (pos, lowerchar) in char.to_lowercase().enumerate() {
-                        value.push((
-                            lowerchar,
-                            if pos == 0 {
-                                char
-                            } else {
-                                REPLACEMENT_CHARACTER
-                            },
-                        ));
-                    }
-                } else {
-                    value.push((char, char));
-                }
-            }
-            value
-        } else {
-            value_.chars().map(|char| (char, char)).collect::<Vec<_>>()
-        };
+        let value = self.case_fold.fold_units(value_);
 
         let mut px = 0;
         let mut nx = 0;
@@ -166,20 +165,18 @@ impl GlobPattern {
         while px < self.pattern.len() || nx < value.len() {
             match self.pattern.get_mut(px) {
                 Some(PatternChar::Char { char, match_pos }) => {
-                    if matches!(value.get(nx), Some(nc) if &nc.0 == char ) {
+                    if matches!(value.get(nx), Some((nc, _)) if nc == char ) {
                         *match_pos = nx;
                         px += 1;
                         nx += 1;
                         continue;
                     }
                 }
-                Some(PatternChar::WildcardSingle { match_pos }) => {
-                    if nx < value.len() {
-                        *match_pos = nx;
-                        px += 1;
-                        nx += 1;
-                        continue;
-                    }
+                Some(PatternChar::WildcardSingle { match_pos }) if nx < value.len() => {
+                    *match_pos = nx;
+                    px += 1;
+                    nx += 1;
+                    continue;
                 }
                 Some(PatternChar::WildcardMany { match_pos, .. }) => {
                     *match_pos = nx;
@@ -224,13 +221,7 @@ impl GlobPattern {
                                     wildcard_pos,
                                     range
                                         .iter()
-                                        .filter_map(|(_, char)| {
-                                            if char != &REPLACEMENT_CHARACTER {
-                                                Some(char)
-                                            } else {
-                                                None
-                                            }
-                                        })
+                                        .map(|(_, orig)| &value_[orig.clone()])
                                         .collect::<String>(),
                                 ));
                             } else {
@@ -243,15 +234,14 @@ impl GlobPattern {
                     }
                     PatternChar::WildcardSingle { match_pos } => {
                         if capture_positions & (1 << wildcard_pos) != 0 {
-                            if let Some((char, orig_char)) = value.get(*match_pos) {
+                            if let Some((unit, orig)) = value.get(*match_pos) {
                                 captured_values.push((
                                     wildcard_pos,
-                                    (if orig_char != &REPLACEMENT_CHARACTER {
-                                        orig_char
+                                    if !orig.is_empty() {
+                                        value_[orig.clone()].to_string()
                                     } else {
-                                        char
-                                    })
-                                    .to_string(),
+                                        unit.to_string()
+                                    },
                                 ));
                             } else {
                                 debug_assert!(false, "Glob pattern failure.");
@@ -271,9 +261,68 @@ impl GlobPattern {
     }
 }
 
+impl CaseFold {
+    fn fold(self, char: char, mut cb: impl FnMut(char)) {
+        match self {
+            CaseFold::Lowercase if char.is_uppercase() => char.to_lowercase().for_each(cb),
+            CaseFold::Uppercase => unicode_casemap_char(char, cb),
+            _ => cb(char),
+        }
+    }
+
+    fn joins_marks(self) -> bool {
+        self == CaseFold::Uppercase
+    }
+
+    // Folds a value into units, each one paired with the range of the value
+    // it was produced from. When a character folds into several units, such
+    // as "ß" into "SS", the range belongs to the first unit and the others
+    // get an empty range.
+    fn fold_units(self, value: &str) -> Vec<(Unit, Range<usize>)> {
+        let mut units: Vec<(Unit, Range<usize>)> = Vec::with_capacity(value.len());
+        for (pos, char) in value.char_indices() {
+            let mut orig = pos..pos + char.len_utf8();
+            self.fold(char, |char| {
+                if self.joins_marks() && is_combining_mark(char) {
+                    if let Some((unit, range)) = units.last_mut() {
+                        unit.marks.push(char);
+                        if range.start == range.end {
+                            *range = orig.clone();
+                        } else {
+                            range.end = orig.end;
+                        }
+                        orig.start = orig.end;
+                        return;
+                    }
+                }
+                units.push((Unit::new(char), orig.clone()));
+                orig.start = orig.end;
+            });
+        }
+        units
+    }
+}
+
+impl Unit {
+    fn new(char: char) -> Self {
+        Unit {
+            char,
+            marks: Vec::new(),
+        }
+    }
+}
+
+impl Display for Unit {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        std::iter::once(&self.char)
+            .chain(&self.marks)
+            .try_for_each(|char| write!(f, "{char}"))
+    }
+}
+
 #[cfg(test)]
 mod tests {
-    use crate::runtime::tests::glob::GlobPattern;
+    use crate::runtime::tests::glob::{CaseFold, GlobPattern};
 
     #[test]
     fn glob_match() {
@@ -303,19 +352,21 @@ mod tests {
                 vec!["Straße"],
             ),
         ] {
-            let p = GlobPattern::compile(pattern, true);
-            let mut match_values = Vec::new();
-            assert!(
-                p.clone().capture(value, u64::MAX ^ 1, &mut match_values),
-                "{value:?} {pattern:?}",
-            );
+            for case_fold in [CaseFold::Lowercase, CaseFold::Uppercase] {
+                let p = GlobPattern::compile(pattern, case_fold);
+                let mut match_values = Vec::new();
+                assert!(
+                    p.clone().capture(value, u64::MAX ^ 1, &mut match_values),
+                    "{value:?} {pattern:?} {case_fold:?}",
+                );
 
-            assert_eq!(
-                match_values.into_iter().map(|(_, v)| v).collect::<Vec<_>>(),
-                expected_result,
-                "{value:?} {pattern:?}",
-            );
-            assert!(p.matches(value), "{value:?} {pattern:?}",);
+                assert_eq!(
+                    match_values.into_iter().map(|(_, v)| v).collect::<Vec<_>>(),
+                    expected_result,
+                    "{value:?} {pattern:?} {case_fold:?}",
+                );
+                assert!(p.matches(value), "{value:?} {pattern:?} {case_fold:?}",);
+            }
         }
     }
 }
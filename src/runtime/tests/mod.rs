@@ -28,6 +28,7 @@ use crate::{
 
 use super::RuntimeError;
 
+pub(crate) mod casemap;
 pub mod comparator;
 pub mod glob;
 pub mod mime;
@@ -117,6 +118,7 @@ impl Test {
                 is_not: test.is_not,
             },
             Test::Convert(test) => test.exec(ctx),
+            Test::Convertible(test) => test.exec(ctx),
             Test::True => TestResult::Bool(true),
             Test::False => TestResult::Bool(false),
             Test::Invalid(invalid) => {
@@ -21,12 +21,11 @@
  * for more details.
 */
 
+use std::fmt::Debug;
+
 use mail_parser::{
     parsers::{
-        fields::address::{
-            parse_address_detail_part, parse_address_domain, parse_address_local_part,
-            parse_address_user_part,
-        },
+        fields::address::{parse_address_domain, parse_address_local_part},
         MessageStream,
     },
     Addr, Address, Header, HeaderValue,
@@ -37,7 +36,8 @@ use crate::{
         grammar::{tests::test_address::TestAddress, AddressPart, MatchType},
         Number,
     },
-    Context, Event,
+    runtime::RuntimeWarning,
+    Context, Event, SubaddressResolver,
 };
 
 use super::TestResult;
@@ -46,6 +46,7 @@ impl TestAddress {
     pub(crate) fn exec(&self, ctx: &mut Context) -> TestResult {
         let key_list = ctx.eval_values(&self.key_list);
         let header_list = ctx.parse_header_names(&self.header_list);
+        let mut truncated = false;
 
         let result = match &self.match_type {
             MatchType::Is | MatchType::Contains => {
@@ -55,7 +56,7 @@ impl TestAddress {
                     self.index,
                     self.mime_anychild,
                     |header, _, _| {
-                        ctx.find_addresses(header, &self.address_part, |value| {
+                        ctx.find_addresses(header, &self.address_part, &mut truncated, |value| {
                             for key in &key_list {
                                 if is_is {
                                     if self.comparator.is(&value, key) {
@@ -76,7 +77,7 @@ impl TestAddress {
                 self.index,
                 self.mime_anychild,
                 |header, _, _| {
-                    ctx.find_addresses(header, &self.address_part, |value| {
+                    ctx.find_addresses(header, &self.address_part, &mut truncated, |value| {
                         for key in &key_list {
                             if self.comparator.relational(rel_match, &value, key) {
                                 return true;
@@ -94,7 +95,7 @@ impl TestAddress {
                     self.index,
                     self.mime_anychild,
                     |header, _, _| {
-                        ctx.find_addresses(header, &self.address_part, |value| {
+                        ctx.find_addresses(header, &self.address_part, &mut truncated, |value| {
                             for (pattern_expr, pattern) in key_list.iter().zip(self.key_list.iter())
                             {
                                 if is_matches {
@@ -132,7 +133,7 @@ impl TestAddress {
                     self.index,
                     self.mime_anychild,
                     |header, _, _| {
-                        ctx.find_addresses(header, &self.address_part, |value| {
+                        ctx.find_addresses(header, &self.address_part, &mut truncated, |value| {
                             if !value.is_empty() {
                                 count += 1;
                             }
@@ -143,13 +144,14 @@ impl TestAddress {
 
                 let mut result = false;
                 for key in &key_list {
-                    if rel_match.cmp(&Number::from(count), &key.to_number()) {
+                    if rel_match.cmp_num(&Number::from(count), &key.to_ascii_numeric()) {
                         result = true;
                         break;
                     }
                 }
                 result
             }
+            MatchType::KeywordSet => false,
             MatchType::List => {
                 let mut values: Vec<String> = Vec::new();
 
@@ -158,7 +160,7 @@ impl TestAddress {
                     self.index,
                     self.mime_anychild,
                     |header, _, _| {
-                        ctx.find_addresses(header, &self.address_part, |value| {
+                        ctx.find_addresses(header, &self.address_part, &mut truncated, |value| {
                             if !value.is_empty() && !values.iter().any(|v| v.eq(value)) {
                                 values.push(value.to_string());
                             }
@@ -168,6 +170,7 @@ impl TestAddress {
                 );
 
                 if !values.is_empty() {
+                    ctx.warn_truncated(truncated);
                     return TestResult::Event {
                         event: Event::ListContains {
                             lists: ctx.eval_values_owned(&self.key_list),
@@ -182,6 +185,7 @@ impl TestAddress {
             }
         };
 
+        ctx.warn_truncated(truncated);
         TestResult::Bool(result ^ self.is_not)
     }
 }
@@ -192,31 +196,28 @@ impl<'x> Context<'x> {
         &self,
         header: &Header,
         part: &AddressPart,
+        truncated: &mut bool,
         mut visitor_fnc: impl FnMut(&str) -> bool,
     ) -> bool {
+        let max_addresses = self.runtime.max_addresses_per_header;
+        let delimiter = &self.runtime.subaddress_delimiter;
         match &header.value {
-            HeaderValue::Address(Address::List(addr_list)) => {
-                for addr in addr_list {
-                    if let Some(addr) = part.eval(addr) {
-                        if visitor_fnc(addr) {
-                            return true;
-                        }
-                    }
-                }
-                false
-            }
-            HeaderValue::Address(Address::Group(group_list)) => {
-                for group in group_list {
-                    for addr in &group.addresses {
-                        if let Some(addr) = part.eval(addr) {
-                            if visitor_fnc(addr) {
-                                return true;
-                            }
-                        }
-                    }
-                }
-                false
-            }
+            HeaderValue::Address(Address::List(addr_list)) => visit_addresses(
+                addr_list.iter(),
+                part,
+                delimiter,
+                max_addresses,
+                truncated,
+                visitor_fnc,
+            ),
+            HeaderValue::Address(Address::Group(group_list)) => visit_addresses(
+                group_list.iter().flat_map(|group| group.addresses.iter()),
+                part,
+                delimiter,
+                max_addresses,
+                truncated,
+                visitor_fnc,
+            ),
             _ => {
                 let mut raw_header = None;
                 let bytes = if header.offset_end > 0 {
@@ -233,37 +234,65 @@ impl<'x> Context<'x> {
                 };
 
                 match MessageStream::new(bytes).parse_address() {
-                    HeaderValue::Address(Address::List(addr_list)) => {
-                        for addr in &addr_list {
-                            if let Some(addr) = part.eval(addr) {
-                                if visitor_fnc(addr) {
-                                    return true;
-                                }
-                            }
-                        }
-                        false
-                    }
-                    HeaderValue::Address(Address::Group(group_list)) => {
-                        for group in group_list {
-                            for addr in &group.addresses {
-                                if let Some(addr) = part.eval(addr) {
-                                    if visitor_fnc(addr) {
-                                        return true;
-                                    }
-                                }
-                            }
-                        }
-                        false
-                    }
+                    HeaderValue::Address(Address::List(addr_list)) => visit_addresses(
+                        addr_list.iter(),
+                        part,
+                        delimiter,
+                        max_addresses,
+                        truncated,
+                        visitor_fnc,
+                    ),
+                    HeaderValue::Address(Address::Group(group_list)) => visit_addresses(
+                        group_list.iter().flat_map(|group| group.addresses.iter()),
+                        part,
+                        delimiter,
+                        max_addresses,
+                        truncated,
+                        visitor_fnc,
+                    ),
                     _ => visitor_fnc(""),
                 }
             }
         }
     }
+
+    pub(crate) fn warn_truncated(&mut self, truncated: bool) {
+        if truncated {
+            self.warnings.push(RuntimeWarning::AddressesTruncated {
+                max: self.runtime.max_addresses_per_header,
+            });
+        }
+    }
+}
+
+fn visit_addresses<'y>(
+    addresses: impl Iterator<Item = &'y Addr<'y>>,
+    part: &AddressPart,
+    delimiter: &SubaddressDelimiter,
+    max_addresses: usize,
+    truncated: &mut bool,
+    mut visitor_fnc: impl FnMut(&str) -> bool,
+) -> bool {
+    for (num, addr) in addresses.enumerate() {
+        if num == max_addresses {
+            *truncated = true;
+            break;
+        }
+        if let Some(addr) = part.eval(addr, delimiter) {
+            if visitor_fnc(addr) {
+                return true;
+            }
+        }
+    }
+    false
 }
 
 impl AddressPart {
-    pub(crate) fn eval<'x>(&self, addr: &'x Addr<'x>) -> Option<&'x str> {
+    pub(crate) fn eval<'x>(
+        &self,
+        addr: &'x Addr<'x>,
+        delimiter: &SubaddressDelimiter,
+    ) -> Option<&'x str> {
         let email = addr.address.as_deref().or(addr.name.as_deref());
         match (self, email) {
             (AddressPart::All, _) => email,
@@ -271,39 +300,53 @@ impl AddressPart {
                 parse_address_local_part(email)
             }
             (AddressPart::Domain, Some(email)) if !email.is_empty() => parse_address_domain(email),
-            (AddressPart::User, Some(email)) if !email.is_empty() => parse_address_user_part(email),
+            (AddressPart::User, Some(email)) if !email.is_empty() => {
+                parse_subaddress(email, delimiter).map(|(user, _)| user)
+            }
             (AddressPart::Detail, Some(email)) if !email.is_empty() => {
-                parse_address_detail_part(email)
+                parse_subaddress(email, delimiter).and_then(|(_, detail)| detail)
             }
             (AddressPart::Name, _) => addr.name.as_deref(),
             _ => email,
         }
     }
 
-    pub(crate) fn eval_strict<'x>(&self, addr: &'x Addr<'x>) -> Option<&'x str> {
+    pub(crate) fn eval_strict<'x>(
+        &self,
+        addr: &'x Addr<'x>,
+        delimiter: &SubaddressDelimiter,
+    ) -> Option<&'x str> {
         match (self, addr.address.as_deref()) {
             (AddressPart::All, Some(email)) => Some(email),
             (AddressPart::LocalPart, Some(email)) if !email.is_empty() => {
                 parse_address_local_part(email)
             }
             (AddressPart::Domain, Some(email)) if !email.is_empty() => parse_address_domain(email),
-            (AddressPart::User, Some(email)) if !email.is_empty() => parse_address_user_part(email),
+            (AddressPart::User, Some(email)) if !email.is_empty() => {
+                parse_subaddress(email, delimiter).map(|(user, _)| user)
+            }
             (AddressPart::Detail, Some(email)) if !email.is_empty() => {
-                parse_address_detail_part(email)
+                parse_subaddress(email, delimiter).and_then(|(_, detail)| detail)
             }
             (AddressPart::Name, _) => addr.name.as_deref(),
             (_, email) => email,
         }
     }
 
-    pub(crate) fn eval_string<'x>(&self, addr: &'x str) -> Option<&'x str> {
+    pub(crate) fn eval_string<'x>(
+        &self,
+        addr: &'x str,
+        delimiter: &SubaddressDelimiter,
+    ) -> Option<&'x str> {
         if !addr.is_empty() {
             match self {
                 AddressPart::All => addr.into(),
                 AddressPart::LocalPart => parse_address_local_part(addr),
                 AddressPart::Domain => parse_address_domain(addr),
-                AddressPart::User => parse_address_user_part(addr),
-                AddressPart::Detail => parse_address_detail_part(addr),
+                AddressPart::User => parse_subaddress(addr, delimiter).map(|(user, _)| user),
+                AddressPart::Detail => {
+                    parse_subaddress(addr, delimiter).and_then(|(_, detail)| detail)
+                }
                 _ => addr.into(),
             }
         } else {
@@ -311,3 +354,44 @@ impl AddressPart {
         }
     }
 }
+
+// Splits the local part of an address into its user and detail parts
+// (RFC 5233). The detail starts after the first delimiter, so further
+// delimiters are part of it, and it is `None` when there is no delimiter.
+fn parse_subaddress<'x>(
+    addr: &'x str,
+    delimiter: &SubaddressDelimiter,
+) -> Option<(&'x str, Option<&'x str>)> {
+    let (local_part, domain) = addr.rsplit_once('@')?;
+    if local_part.is_empty() || domain.is_empty() {
+        None
+    } else if let Some((user, detail)) = local_part.split_once(delimiter.resolve(domain)) {
+        Some((user, Some(detail)))
+    } else {
+        Some((local_part, None))
+    }
+}
+
+#[derive(Clone)]
+pub(crate) enum SubaddressDelimiter {
+    Fixed(char),
+    Resolver(SubaddressResolver),
+}
+
+impl SubaddressDelimiter {
+    fn resolve(&self, domain: &str) -> char {
+        match self {
+            SubaddressDelimiter::Fixed(delimiter) => *delimiter,
+            SubaddressDelimiter::Resolver(resolver) => resolver(domain),
+        }
+    }
+}
+
+impl Debug for SubaddressDelimiter {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SubaddressDelimiter::Fixed(delimiter) => write!(f, "Fixed({delimiter:?})"),
+            SubaddressDelimiter::Resolver(_) => f.write_str("Resolver"),
+        }
+    }
+}
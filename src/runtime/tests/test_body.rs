@@ -66,6 +66,9 @@ impl TestBody {
                         self.comparator
                             .regex(pattern, key, subject, 0, &mut Vec::new())
                     }
+                    MatchType::KeywordSet => {
+                        ctx.keyword_set_contains(key, subject, &self.comparator)
+                    }
                     _ => break,
                 };
 
@@ -103,7 +106,10 @@ impl TestBody {
             });
 
             for key in &self.key_list {
-                if rel_match.cmp(&Number::from(count), &ctx.eval_value(key).to_number()) {
+                if rel_match.cmp_num(
+                    &Number::from(count),
+                    &ctx.eval_value(key).to_ascii_numeric(),
+                ) {
                     result = true;
                     break;
                 }
@@ -176,9 +182,9 @@ impl TestBody {
                     (
                         BodyTransform::Text,
                         PartType::Binary(bytes) | PartType::InlineBinary(bytes),
-                    ) if part.content_type().map_or(false, |ct| {
+                    ) if part.content_type().is_some_and(|ct| {
                         ct.c_type.eq_ignore_ascii_case("application")
-                            && ct.c_subtype.as_ref().map_or(false, |st| st.contains("xml"))
+                            && ct.c_subtype.as_ref().is_some_and(|st| st.contains("xml"))
                     }) =>
                     {
                         html_to_text(std::str::from_utf8(bytes.as_ref()).unwrap_or("")).into()
@@ -212,6 +218,9 @@ impl TestBody {
                             self.comparator
                                 .regex(pattern, key, text.as_ref(), 0, &mut Vec::new())
                         }
+                        MatchType::KeywordSet => {
+                            ctx.keyword_set_contains(key, text.as_ref(), &self.comparator)
+                        }
                         _ => false,
                     };
 
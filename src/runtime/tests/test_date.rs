@@ -63,7 +63,10 @@ impl TestDate {
 
                 let mut result = false;
                 for key in &self.key_list {
-                    if rel_match.cmp(&Number::from(date_count), &ctx.eval_value(key).to_number()) {
+                    if rel_match.cmp_num(
+                        &Number::from(date_count),
+                        &ctx.eval_value(key).to_ascii_numeric(),
+                    ) {
                         result = true;
                         break;
                     }
@@ -136,7 +139,9 @@ impl TestDate {
                                         *capture_positions,
                                         &mut captured_values,
                                     ),
-                                    MatchType::Count(_) | MatchType::List => false,
+                                    MatchType::Count(_)
+                                    | MatchType::List
+                                    | MatchType::KeywordSet => false,
                                 } {
                                     return true;
                                 }
@@ -164,7 +169,9 @@ impl TestCurrentDate {
         match &self.match_type {
             MatchType::Count(rel_match) => {
                 for key in &self.key_list {
-                    if rel_match.cmp(&Number::from(1.0), &ctx.eval_value(key).to_number()) {
+                    if rel_match
+                        .cmp_num(&Number::from(1.0), &ctx.eval_value(key).to_ascii_numeric())
+                    {
                         result = true;
                         break;
                     }
@@ -223,7 +230,7 @@ impl TestCurrentDate {
                             *capture_positions,
                             &mut captured_values,
                         ),
-                        MatchType::Count(_) | MatchType::List => false,
+                        MatchType::Count(_) | MatchType::List | MatchType::KeywordSet => false,
                     } {
                         result = true;
                         break;
@@ -72,15 +72,23 @@ impl TestDuplicate {
             DupMatch::Default => ctx.message.message_id().unwrap_or("").into(),
         };
 
+        let id = if id.is_empty() {
+            return TestResult::Bool(false ^ self.is_not);
+        } else if let Some(handle) = &self.handle {
+            format!("{}{}", ctx.eval_value(handle).to_string(), id)
+        } else {
+            id.into_owned()
+        };
+
+        // Repeated tests for the same id get the same answer
+        if let Some(is_duplicate) = ctx.duplicate_ids.get(&id) {
+            return TestResult::Bool(*is_duplicate ^ self.is_not);
+        }
+        ctx.duplicate_pending = id.clone().into();
+
         TestResult::Event {
             event: Event::DuplicateId {
-                id: if id.is_empty() {
-                    return TestResult::Bool(false ^ self.is_not);
-                } else if let Some(handle) = &self.handle {
-                    format!("{}{}", ctx.eval_value(handle).to_string(), id)
-                } else {
-                    id.into_owned()
-                },
+                id,
                 expiry: self.seconds.unwrap_or(ctx.runtime.default_duplicate_expiry),
                 last: self.last,
             },
@@ -113,13 +113,14 @@ impl TestEnvelope {
 
                 let mut result = false;
                 for key in &key_list {
-                    if rel_match.cmp(&Number::from(count), &key.to_number()) {
+                    if rel_match.cmp_num(&Number::from(count), &key.to_ascii_numeric()) {
                         result = true;
                         break;
                     }
                 }
                 result
             }
+            MatchType::KeywordSet => false,
             MatchType::List => {
                 let mut values: Vec<String> = Vec::new();
 
@@ -158,11 +159,11 @@ impl<'x> Context<'x> {
         for (name, value) in &self.envelope {
             if test_envelope.envelope_list.contains(name)
                 && match name {
-                    Envelope::From | Envelope::To | Envelope::Orcpt => {
-                        if let Some(value) = test_envelope
-                            .address_part
-                            .eval_string(value.to_string().as_ref())
-                        {
+                    Envelope::From | Envelope::To | Envelope::Orcpt | Envelope::Auth => {
+                        if let Some(value) = test_envelope.address_part.eval_string(
+                            value.to_string().as_ref(),
+                            &self.runtime.subaddress_delimiter,
+                        ) {
                             cb(value)
                         } else {
                             false
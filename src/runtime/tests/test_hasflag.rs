@@ -53,9 +53,9 @@ impl TestHasFlag {
 
             let mut result = false;
             for key in &self.flags {
-                if rel_match.cmp(
+                if rel_match.cmp_num(
                     &Number::from(flag_count as i64),
-                    &ctx.eval_value(key).to_number(),
+                    &ctx.eval_value(key).to_ascii_numeric(),
                 ) {
                     result = true;
                     break;
@@ -91,7 +91,9 @@ impl TestHasFlag {
                                         *capture_positions,
                                         &mut captured_values,
                                     ),
-                                    MatchType::Count(_) | MatchType::List => false,
+                                    MatchType::Count(_)
+                                    | MatchType::List
+                                    | MatchType::KeywordSet => false,
                                 } {
                                     return true;
                                 }
@@ -29,7 +29,7 @@ use crate::{
         Number, Value,
     },
     runtime::Variable,
-    Context, Event,
+    Context, Event, MatchDetail,
 };
 
 use super::{mime::SubpartIterator, TestResult};
@@ -46,6 +46,11 @@ impl TestHeader {
             MimeOpts::None => MimeOpts::None,
         };
 
+        let external = ctx.external_comparator(&self.comparator);
+
+        let collect = ctx.collect_matches;
+        let mut matched_values = Vec::new();
+
         let result = match &self.match_type {
             MatchType::Is | MatchType::Contains => {
                 let is_is = matches!(&self.match_type, MatchType::Is);
@@ -55,17 +60,21 @@ impl TestHeader {
                     self.mime_anychild,
                     |header, _, _| {
                         ctx.find_header_values(header, &mime_opts, |value| {
-                            for key in &key_list {
-                                if is_is {
-                                    if self.comparator.is(&value, key) {
-                                        return true;
+                            let matched = key_list.iter().any(|key| {
+                                if let Some(external) = &external {
+                                    let key = key.to_string();
+                                    if is_is {
+                                        external.equals(value, key.as_ref())
+                                    } else {
+                                        external.substring(value, key.as_ref())
                                     }
-                                } else if self.comparator.contains(value, key.to_string().as_ref())
-                                {
-                                    return true;
+                                } else if is_is {
+                                    self.comparator.is(&value, key)
+                                } else {
+                                    self.comparator.contains(value, key.to_string().as_ref())
                                 }
-                            }
-                            false
+                            });
+                            collect_match(collect, &mut matched_values, value, matched)
                         })
                     },
                 )
@@ -76,12 +85,18 @@ impl TestHeader {
                 self.mime_anychild,
                 |header, _, _| {
                     ctx.find_header_values(header, &mime_opts, |value| {
-                        for key in &key_list {
-                            if self.comparator.relational(rel_match, &value, key) {
-                                return true;
+                        let matched = key_list.iter().any(|key| {
+                            if let Some(external) = &external {
+                                rel_match.cmp_external(
+                                    external.as_ref(),
+                                    value,
+                                    key.to_string().as_ref(),
+                                )
+                            } else {
+                                self.comparator.relational(rel_match, &value, key)
                             }
-                        }
-                        false
+                        });
+                        collect_match(collect, &mut matched_values, value, matched)
                     })
                 },
             ),
@@ -94,28 +109,33 @@ impl TestHeader {
                     self.mime_anychild,
                     |header, _, _| {
                         ctx.find_header_values(header, &mime_opts, |value| {
-                            for (pattern_expr, pattern) in key_list.iter().zip(self.key_list.iter())
-                            {
-                                if is_matches {
-                                    if self.comparator.matches(
-                                        value,
-                                        pattern_expr.to_string().as_ref(),
-                                        *capture_positions,
-                                        &mut captured_values,
-                                    ) {
-                                        return true;
+                            // Match variables are only set by the first match
+                            let capture_positions = if matched_values.is_empty() {
+                                *capture_positions
+                            } else {
+                                0
+                            };
+                            let matched = key_list.iter().zip(self.key_list.iter()).any(
+                                |(pattern_expr, pattern)| {
+                                    if is_matches {
+                                        self.comparator.matches(
+                                            value,
+                                            pattern_expr.to_string().as_ref(),
+                                            capture_positions,
+                                            &mut captured_values,
+                                        )
+                                    } else {
+                                        self.comparator.regex(
+                                            pattern,
+                                            pattern_expr,
+                                            value,
+                                            capture_positions,
+                                            &mut captured_values,
+                                        )
                                     }
-                                } else if self.comparator.regex(
-                                    pattern,
-                                    pattern_expr,
-                                    value,
-                                    *capture_positions,
-                                    &mut captured_values,
-                                ) {
-                                    return true;
-                                }
-                            }
-                            false
+                                },
+                            );
+                            collect_match(collect, &mut matched_values, value, matched)
                         })
                     },
                 );
@@ -161,13 +181,26 @@ impl TestHeader {
 
                 let mut result = false;
                 for key in &key_list {
-                    if rel_match.cmp(&Number::from(count), &key.to_number()) {
+                    if rel_match.cmp_num(&Number::from(count), &key.to_ascii_numeric()) {
                         result = true;
                         break;
                     }
                 }
                 result
             }
+            MatchType::KeywordSet => ctx.find_headers(
+                &header_list,
+                self.index,
+                self.mime_anychild,
+                |header, _, _| {
+                    ctx.find_header_values(header, &mime_opts, |value| {
+                        let matched = key_list
+                            .iter()
+                            .any(|key| ctx.keyword_set_contains(key, value, &self.comparator));
+                        collect_match(collect, &mut matched_values, value, matched)
+                    })
+                },
+            ),
             MatchType::List => {
                 let mut values: Vec<String> = Vec::new();
                 ctx.find_headers(
@@ -199,10 +232,38 @@ impl TestHeader {
             }
         };
 
+        let result =
+            if collect && !matches!(&self.match_type, MatchType::Count(_) | MatchType::List) {
+                let result = result || !matched_values.is_empty();
+                ctx.match_details.push(MatchDetail {
+                    pos: ctx.pos - 1,
+                    values: matched_values,
+                });
+                result
+            } else {
+                result
+            };
+
         TestResult::Bool(result ^ self.is_not)
     }
 }
 
+// When collecting matches every value is visited, so a match is recorded
+// instead of ending the search.
+fn collect_match(
+    collect: bool,
+    matched_values: &mut Vec<String>,
+    value: &str,
+    matched: bool,
+) -> bool {
+    if matched && collect {
+        matched_values.push(value.to_string());
+        false
+    } else {
+        matched
+    }
+}
+
 impl<'x> Context<'x> {
     pub(crate) fn parse_header_names<'z: 'y, 'y>(
         &'z self,
@@ -73,7 +73,8 @@ impl TestMetadata {
         let mut result = false;
         if let MatchType::Count(match_type) = &self.match_type {
             for key in &self.key_list {
-                if match_type.cmp(&Number::Float(1.0), &ctx.eval_value(key).to_number()) {
+                if match_type.cmp_num(&Number::Float(1.0), &ctx.eval_value(key).to_ascii_numeric())
+                {
                     result = true;
                     break;
                 }
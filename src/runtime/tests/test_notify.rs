@@ -68,7 +68,7 @@ impl TestNotifyMethodCapability {
             .eval_value(&self.notification_capability)
             .to_string()
             .eq_ignore_ascii_case("online")
-            || !validate_uri(uri.as_ref()).map_or(false, |scheme| {
+            || !validate_uri(uri.as_ref()).is_some_and(|scheme| {
                 ctx.runtime
                     .valid_notification_uris
                     .contains(&Cow::from(scheme))
@@ -80,7 +80,7 @@ impl TestNotifyMethodCapability {
 
         if let MatchType::Count(rel_match) = &self.match_type {
             for key in &self.key_list {
-                if rel_match.cmp(&Number::from(1.0), &ctx.eval_value(key).to_number()) {
+                if rel_match.cmp_num(&Number::from(1.0), &ctx.eval_value(key).to_ascii_numeric()) {
                     return TestResult::Bool(true ^ self.is_not);
                 }
             }
@@ -26,6 +26,7 @@ use crate::{
         grammar::{tests::test_string::TestString, MatchType},
         Number,
     },
+    runtime::Variable,
     Context, Event,
 };
 
@@ -37,16 +38,21 @@ impl TestString {
 
         match &self.match_type {
             MatchType::Count(match_type) => {
+                // Lists such as `${match.groups}` count each of their items
                 let num_items = self
                     .source
                     .iter()
-                    .filter(|x| !ctx.eval_value(x).is_empty())
-                    .count() as i64;
+                    .map(|x| match ctx.eval_value(x) {
+                        Variable::Array(items) => items.iter().filter(|i| !i.is_empty()).count(),
+                        value => usize::from(!value.is_empty()),
+                    })
+                    .sum::<usize>() as i64;
                 if !empty_is_null || num_items > 0 {
                     for key in &self.key_list {
-                        if match_type
-                            .cmp(&Number::from(num_items), &ctx.eval_value(key).to_number())
-                        {
+                        if match_type.cmp_num(
+                            &Number::from(num_items),
+                            &ctx.eval_value(key).to_ascii_numeric(),
+                        ) {
                             result = true;
                             break;
                         }
@@ -75,27 +81,42 @@ impl TestString {
             _ => {
                 let mut captured_values = Vec::new();
                 let sources = ctx.eval_values(&self.source);
+                let external = ctx.external_comparator(&self.comparator);
 
                 for pattern in &self.key_list {
                     let key = ctx.eval_value(pattern);
                     for source in &sources {
                         if !empty_is_null || !source.is_empty() {
-                            result = match &self.match_type {
-                                MatchType::Is => self.comparator.is(source, &key),
-                                MatchType::Contains => self.comparator.contains(
+                            result = match (&self.match_type, &external) {
+                                (MatchType::Is, Some(external)) => external
+                                    .equals(source.to_string().as_ref(), key.to_string().as_ref()),
+                                (MatchType::Contains, Some(external)) => external.substring(
                                     source.to_string().as_ref(),
                                     key.to_string().as_ref(),
                                 ),
-                                MatchType::Value(relation) => {
-                                    self.comparator.relational(relation, source, &key)
-                                }
-                                MatchType::Matches(capture_positions) => self.comparator.matches(
+                                (MatchType::Value(relation), Some(external)) => relation
+                                    .cmp_external(
+                                        external.as_ref(),
+                                        source.to_string().as_ref(),
+                                        key.to_string().as_ref(),
+                                    ),
+                                (MatchType::Is, _) => self.comparator.is(source, &key),
+                                (MatchType::Contains, _) => self.comparator.contains(
                                     source.to_string().as_ref(),
                                     key.to_string().as_ref(),
-                                    *capture_positions,
-                                    &mut captured_values,
                                 ),
-                                MatchType::Regex(capture_positions) => self.comparator.regex(
+                                (MatchType::Value(relation), _) => {
+                                    self.comparator.relational(relation, source, &key)
+                                }
+                                (MatchType::Matches(capture_positions), _) => {
+                                    self.comparator.matches(
+                                        source.to_string().as_ref(),
+                                        key.to_string().as_ref(),
+                                        *capture_positions,
+                                        &mut captured_values,
+                                    )
+                                }
+                                (MatchType::Regex(capture_positions), _) => self.comparator.regex(
                                     pattern,
                                     &key,
                                     source.to_string().as_ref(),